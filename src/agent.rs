@@ -0,0 +1,2514 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use async_openai::{
+    Client,
+    config::Config,
+    error::OpenAIError,
+    types::{
+        ChatCompletionRequestMessage, ChatCompletionTool, CreateEmbeddingRequest,
+        CreateEmbeddingResponse, EmbeddingInput, FinishReason,
+    },
+};
+use futures::StreamExt;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use tracing::{Instrument, debug, info, trace, warn};
+
+use crate::cache::{self, ResponseCache};
+use crate::chat::{self, Reply};
+use crate::config::{AgentConfig, RequestParams, normalize_base_url};
+use crate::context::{self, Compressed, ContextManager, TokenEstimator, TrimStrategy, Trimmed};
+use crate::conversation::{Conversation, describe};
+use crate::embeddings::{self, EMBEDDING_BATCH_SIZE, Index};
+use crate::error::AgentError;
+use crate::finish::{self, ReplyIssue};
+use crate::metrics::{RequestMetrics, RequestStats, RequestTimer};
+use crate::models::{self, ModelInfo};
+use crate::provider::{Provider, ProviderConfig};
+use crate::ratelimit::{self, RateLimiter};
+use crate::retry::{self, Failure, RetryAttempt, RetryPolicy};
+use crate::schema::{self, JsonSchema};
+use crate::secret::SecretString;
+use crate::session::{
+    ConversationSettings, ConversationStore, ConversationSummary, Deleted, ParkedConversation,
+    Session,
+};
+use crate::stream::{Delta, StreamAccumulator};
+use crate::tools::{Tool, ToolExecution, ToolRegistry};
+use crate::usage::{Budget, ModelPrice, TurnUsage, Usage, UsageTracker};
+
+type RetryHook = Box<dyn Fn(&RetryAttempt) + Send + Sync>;
+type ToolHook = Box<dyn Fn(&ToolExecution) + Send + Sync>;
+
+/// One model's answer in [`DeepSeekAgent::ask_models`].
+#[derive(Debug)]
+pub struct ModelAnswer {
+    pub model: String,
+    pub reply: Result<Reply, AgentError>,
+    /// Tokens and estimated cost, if the API reported them.
+    pub usage: Option<TurnUsage>,
+    /// From sending the request to the reply, retries included.
+    pub latency: Duration,
+}
+
+/// The request [`DeepSeekAgent::dry_run`] built, and what it would cost.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DryRun {
+    pub base_url: String,
+    pub model: String,
+    /// The body of the chat completion request, exactly as it would be sent.
+    pub request: Value,
+    /// Estimated prompt tokens of the request.
+    pub prompt_tokens: usize,
+    /// What the prompt tokens would cost, if the model has a price.
+    pub cost: Option<f64>,
+    /// How the history would be trimmed first, if it would.
+    pub trimmed: Option<Trimmed>,
+}
+
+impl DryRun {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "base_url": self.base_url,
+            "model": self.model,
+            "prompt_tokens_estimate": self.prompt_tokens,
+            "cost_estimate_usd": self.cost,
+            "trimmed_messages": self.trimmed.as_ref().map(|trimmed| trimmed.messages),
+            "request": self.request,
+        })
+    }
+}
+
+/// A chat agent holding the client, request defaults, tools and the running conversation.
+pub struct DeepSeekAgent {
+    backend: Backend,
+    conversation: Conversation,
+    /// The conversations set aside, to switch back to.
+    conversations: ConversationStore,
+    /// What new conversations start with.
+    system_prompt: String,
+    startup_settings: ConversationSettings,
+    context: ContextManager,
+    last_trimmed: Option<Trimmed>,
+    context_index: Option<(Index, usize)>,
+    /// Where the excerpts added for the last question came from, with their scores.
+    last_excerpts: Vec<(String, f32)>,
+    tools: ToolRegistry,
+    max_tool_iterations: usize,
+    prefill: Option<String>,
+    /// Length of the conversation before the question being answered, if any.
+    pending: Option<usize>,
+    last_issue: Option<ReplyIssue>,
+    last_finish_reason: Option<FinishReason>,
+    last_reasoning: Option<String>,
+    last_cached: bool,
+    last_tool_calls: Vec<ToolExecution>,
+    usage: UsageTracker,
+    last_usage: Option<TurnUsage>,
+    stats: RequestStats,
+    last_metrics: Option<RequestMetrics>,
+    budget: Option<Budget>,
+    on_tool_call: Option<ToolHook>,
+}
+
+impl DeepSeekAgent {
+    pub fn new(config: AgentConfig) -> Result<Self, AgentError> {
+        let http_client = config.network.client()?;
+        Self::with_http_client(config, http_client)
+    }
+
+    /// Like [`new`](Self::new), but sending requests through `http_client`
+    /// instead of one set up from the config's network settings, e.g. one
+    /// pointed at a mock server in tests.
+    pub fn with_http_client(
+        config: AgentConfig,
+        http_client: reqwest::Client,
+    ) -> Result<Self, AgentError> {
+        // SecretString displays redacted
+        debug!(
+            provider = %config.provider,
+            base_url = %config.base_url,
+            model = %config.model,
+            api_key = %config.api_key,
+            timeout_secs = config.timeout.as_secs_f64(),
+            max_retries = config.retry.max_retries,
+            "configuring agent"
+        );
+        let base_url = normalize_base_url(&config.base_url)?;
+        chat::check_stop_sequences(&config.params.stop)?;
+        let startup_settings = ConversationSettings {
+            model: config.model.clone(),
+            temperature: config.params.temperature,
+            top_p: config.params.top_p,
+            max_tokens: config.params.max_tokens,
+        };
+        Ok(Self {
+            backend: Backend {
+                http_client,
+                provider: config.provider,
+                api_key: config.api_key,
+                base_url,
+                model: config.model,
+                params: config.params,
+                retry: config.retry,
+                timeout: config.timeout,
+                cache: config.cache,
+                rate_limiter: config.rate_limiter,
+                on_retry: None,
+            },
+            conversation: Conversation::new(&config.system_prompt),
+            conversations: ConversationStore::default(),
+            system_prompt: config.system_prompt,
+            startup_settings,
+            context: config.context,
+            last_trimmed: None,
+            context_index: None,
+            last_excerpts: Vec::new(),
+            tools: ToolRegistry::default(),
+            max_tool_iterations: config.max_tool_iterations,
+            prefill: config.prefill,
+            pending: None,
+            last_issue: None,
+            last_finish_reason: None,
+            last_reasoning: None,
+            last_cached: false,
+            last_tool_calls: Vec::new(),
+            usage: UsageTracker::new(config.prices),
+            last_usage: None,
+            stats: RequestStats::default(),
+            last_metrics: None,
+            budget: config.budget,
+            on_tool_call: None,
+        })
+    }
+
+    /// Call `hook` before every retry, e.g. to log it.
+    pub fn on_retry(&mut self, hook: impl Fn(&RetryAttempt) + Send + Sync + 'static) {
+        self.backend.on_retry = Some(Box::new(hook));
+    }
+
+    /// Call `hook` after every tool the model had run.
+    pub fn on_tool_call(&mut self, hook: impl Fn(&ToolExecution) + Send + Sync + 'static) {
+        self.on_tool_call = Some(Box::new(hook));
+    }
+
+    /// Offer `tool` to the model on every request.
+    pub fn register_tool(&mut self, tool: impl Tool + 'static) {
+        self.tools.register(tool);
+    }
+
+    /// Offer every tool in `tools`, replacing the current set.
+    pub fn set_tools(&mut self, tools: ToolRegistry) {
+        self.tools = tools;
+    }
+
+    /// Before each question, embed it and add the `top_k` chunks of `index`
+    /// closest to it to the system prompt, replacing those of the last one.
+    pub fn set_context_index(&mut self, index: Index, top_k: usize) {
+        self.context_index = Some((index, top_k));
+    }
+
+    /// Embed `inputs` with `model`, retrying as for chat, and add the tokens
+    /// to the usage totals. Inputs are sent [`EMBEDDING_BATCH_SIZE`] at a time.
+    pub async fn embed(
+        &mut self,
+        model: &str,
+        inputs: &[String],
+    ) -> Result<Vec<Vec<f32>>, AgentError> {
+        embed(&self.backend, &mut self.usage, model, inputs).await
+    }
+
+    /// The models the endpoint serves, sorted by id.
+    ///
+    /// Unlike chat this isn't retried: the list is a convenience, and a server
+    /// without one shouldn't hold up the question that follows.
+    pub async fn list_models(&self) -> Result<Vec<ModelInfo>, AgentError> {
+        self.backend.list_models().await
+    }
+
+    /// Send `prompt` with the conversation so far and return the reply.
+    ///
+    /// If the model calls tools they are run and their results sent back until
+    /// it answers in text. On failure the prompt and any tool turns are dropped
+    /// from the history so they aren't sent twice. The same happens if the
+    /// returned future is dropped, e.g. on Ctrl-C, once
+    /// [`cancel_pending`](Self::cancel_pending) or the next question runs.
+    ///
+    /// A history that would go over the context budget is trimmed first; see
+    /// [`last_trimmed`](Self::last_trimmed).
+    ///
+    /// With a context index, excerpts are added to the system prompt first; see
+    /// [`set_context_index`](Self::set_context_index).
+    ///
+    /// With a prefill the model continues it, and the reply starts with it.
+    /// That leaves no room for tool calls, so tools must not be registered.
+    pub async fn ask(&mut self, prompt: &str) -> Result<String, AgentError> {
+        self.check_prefill()?;
+        self.retrieve(prompt).await?;
+        let summary_usage = self.fit_context(prompt).await;
+        let checkpoint = self.begin(prompt);
+        let definitions = self.tools.definitions();
+        let mut completer = Budgeted {
+            inner: Plain {
+                backend: &self.backend,
+                model: &self.backend.model,
+                tools: &definitions,
+            },
+            allowance: self.allowance(&self.backend.model, summary_usage),
+        };
+        let mut executed = Vec::new();
+        let result = run_tool_loop(
+            &mut self.conversation,
+            &self.tools,
+            self.max_tool_iterations,
+            self.prefill.as_deref(),
+            &mut completer,
+            |execution| {
+                notify_tool_call(&self.on_tool_call, execution);
+                executed.push(execution.clone());
+            },
+        )
+        .await;
+        self.last_tool_calls = executed;
+        if result.is_err() {
+            completer.record_spent(&mut self.usage, &self.backend.model);
+        }
+        let result = result.map(|reply| include_usage(reply, summary_usage));
+        self.settle(checkpoint, result)
+    }
+
+    /// Like [`ask`](Self::ask), but streams the reply and calls `on_delta` with each
+    /// piece of text as it arrives, reasoning first if the model sends any. A
+    /// prefill is passed on first, as the model won't send it.
+    pub async fn ask_streaming(
+        &mut self,
+        prompt: &str,
+        mut on_delta: impl FnMut(&Delta),
+    ) -> Result<String, AgentError> {
+        self.check_prefill()?;
+        self.retrieve(prompt).await?;
+        let summary_usage = self.fit_context(prompt).await;
+        let checkpoint = self.begin(prompt);
+        if let Some(prefill) = &self.prefill {
+            on_delta(&Delta::Content(prefill.clone()));
+        }
+        let definitions = self.tools.definitions();
+        let mut completer = Budgeted {
+            inner: Streaming {
+                backend: &self.backend,
+                tools: &definitions,
+                on_delta,
+            },
+            allowance: self.allowance(&self.backend.model, summary_usage),
+        };
+        let mut executed = Vec::new();
+        let result = run_tool_loop(
+            &mut self.conversation,
+            &self.tools,
+            self.max_tool_iterations,
+            self.prefill.as_deref(),
+            &mut completer,
+            |execution| {
+                notify_tool_call(&self.on_tool_call, execution);
+                executed.push(execution.clone());
+            },
+        )
+        .await;
+        self.last_tool_calls = executed;
+        if result.is_err() {
+            completer.record_spent(&mut self.usage, &self.backend.model);
+        }
+        let result = result.map(|reply| include_usage(reply, summary_usage));
+        self.settle(checkpoint, result)
+    }
+
+    /// Like [`ask`](Self::ask), but the answer has to be JSON matching
+    /// `schema`, which is added to the system prompt. The JSON is taken out of
+    /// any markdown fence or prose around it; a reply without any, or that
+    /// doesn't match, is sent back with what was wrong, up to `max_repairs`
+    /// times, before giving up with [`AgentError::SchemaMismatch`].
+    ///
+    /// Only the JSON that matched joins the history, compacted, not the
+    /// replies that were repaired. Set [`RequestParams::json_mode`] too where
+    /// the server has a JSON mode.
+    pub async fn ask_json(
+        &mut self,
+        prompt: &str,
+        schema: &JsonSchema,
+        max_repairs: usize,
+    ) -> Result<Value, AgentError> {
+        self.check_prefill()?;
+        self.retrieve(prompt).await?;
+        add_instructions(&mut self.conversation, &schema.instructions());
+        let mut usage = self.fit_context(prompt).await;
+        let checkpoint = self.begin(prompt);
+        let definitions = self.tools.definitions();
+        let mut executed = Vec::new();
+        // where the first reply that had to be repaired went
+        let mut repairs_from = None;
+        let mut attempts = 0;
+        let mut metrics = None;
+        // one for every attempt, so the budget covers the repairs too
+        let mut completer = Budgeted {
+            inner: Plain {
+                backend: &self.backend,
+                model: &self.backend.model,
+                tools: &definitions,
+            },
+            allowance: self.allowance(&self.backend.model, usage),
+        };
+        let result = loop {
+            attempts += 1;
+            let reply = match run_tool_loop(
+                &mut self.conversation,
+                &self.tools,
+                self.max_tool_iterations,
+                self.prefill.as_deref(),
+                &mut completer,
+                |execution| {
+                    notify_tool_call(&self.on_tool_call, execution);
+                    executed.push(execution.clone());
+                },
+            )
+            .await
+            {
+                Ok(reply) => reply,
+                Err(e) => break Err(e),
+            };
+            if let Some(round) = reply.usage {
+                *usage.get_or_insert_with(Usage::default) += round;
+            }
+            if let Some(round) = reply.metrics {
+                *metrics.get_or_insert_with(RequestMetrics::default) += round;
+            }
+            match schema.check(&reply.content) {
+                Ok(value) => break Ok((value, reply)),
+                Err(errors) if attempts > max_repairs => {
+                    break Err(AgentError::SchemaMismatch {
+                        attempts,
+                        errors,
+                        content: reply.content,
+                    });
+                }
+                Err(errors) => {
+                    debug!(
+                        attempts,
+                        ?errors,
+                        "the reply didn't match the schema; asking for a repair"
+                    );
+                    repairs_from.get_or_insert(self.conversation.len());
+                    self.conversation.push_assistant(&reply.content);
+                    self.conversation.push_user(&schema::repair_prompt(&errors));
+                }
+            }
+        };
+        self.last_tool_calls = executed;
+        if let Some(len) = repairs_from {
+            self.conversation.truncate(len);
+        }
+        match result {
+            Ok((value, reply)) => {
+                let content = value.to_string();
+                self.settle(
+                    checkpoint,
+                    Ok(Reply {
+                        content,
+                        usage,
+                        metrics,
+                        ..reply
+                    }),
+                )?;
+                Ok(value)
+            }
+            Err(e) => {
+                // the replies that didn't match were paid for all the same
+                if completer.allowance.is_some() {
+                    completer.record_spent(&mut self.usage, &self.backend.model);
+                } else if let Some(usage) = usage {
+                    self.usage.record(&self.backend.model, usage);
+                }
+                self.pending = None;
+                self.conversation.truncate(checkpoint);
+                Err(e)
+            }
+        }
+    }
+
+    /// Like [`ask`](Self::ask), but sends the question to each of `models` at
+    /// once, with the same history, and returns every answer in that order.
+    ///
+    /// One model failing doesn't stop the others; its answer holds the error.
+    /// Only the answer of `history_model`, one of `models`, joins the
+    /// conversation, and without one the question is dropped. Tools are not
+    /// offered: the models would each run them.
+    pub async fn ask_models(
+        &mut self,
+        prompt: &str,
+        models: &[String],
+        history_model: &str,
+    ) -> Result<Vec<ModelAnswer>, AgentError> {
+        if !self.tools.is_empty() {
+            return Err(AgentError::InvalidConfig(
+                "asking several models can't be combined with tools".to_string(),
+            ));
+        }
+        if !models.iter().any(|model| model == history_model) {
+            return Err(AgentError::InvalidConfig(format!(
+                "the history model '{}' is not one of the models asked ({})",
+                history_model,
+                models.join(", ")
+            )));
+        }
+        self.retrieve(prompt).await?;
+        let summary_usage = self.fit_context(prompt).await;
+        let checkpoint = self.begin(prompt);
+        let (backend, tools, conversation) = (&self.backend, &self.tools, &self.conversation);
+        let (max_iterations, prefill) = (self.max_tool_iterations, self.prefill.as_deref());
+        let allowances = |model: &str| self.allowance(model, summary_usage);
+        let replies = futures::future::join_all(models.iter().map(|model| async move {
+            // each gets a copy, so what one adds can't reach the others
+            let mut conversation = conversation.clone();
+            let mut completer = Budgeted {
+                inner: Plain {
+                    backend,
+                    model,
+                    tools: &[],
+                },
+                allowance: allowances(model),
+            };
+            let started = Instant::now();
+            let reply = run_tool_loop(
+                &mut conversation,
+                tools,
+                max_iterations,
+                prefill,
+                &mut completer,
+                |_| {},
+            )
+            .await;
+            (reply, started.elapsed())
+        }))
+        .await;
+
+        self.last_tool_calls.clear();
+        let mut answers = Vec::with_capacity(models.len());
+        let mut kept = None;
+        for (model, (reply, latency)) in models.iter().zip(replies) {
+            let mut usage = None;
+            if let Ok(reply) = &reply {
+                debug!(%model, latency_ms = latency.as_millis() as u64, "model answered");
+                usage = reply.usage.map(|turn| self.usage.record(model, turn));
+                if let Some(metrics) = reply.metrics {
+                    self.stats.record(metrics);
+                }
+                if model == history_model && kept.is_none() {
+                    kept = Some(reply.clone());
+                }
+            }
+            answers.push(ModelAnswer {
+                model: model.clone(),
+                reply,
+                usage,
+                latency,
+            });
+        }
+        // the summary request was made for all of them, so it counts once
+        if let Some(extra) = summary_usage {
+            self.usage.record(&self.backend.model, extra);
+        }
+        match kept {
+            Some(reply) => {
+                self.settle(
+                    checkpoint,
+                    Ok(Reply {
+                        usage: None,
+                        metrics: None,
+                        ..reply
+                    }),
+                )?;
+                let answer = answers.iter().find(|answer| answer.model == history_model);
+                self.last_usage = answer.and_then(|answer| answer.usage.clone());
+                self.last_metrics = answer
+                    .and_then(|answer| answer.reply.as_ref().ok())
+                    .and_then(|reply| reply.metrics);
+            }
+            None => {
+                self.pending = None;
+                self.conversation.truncate(checkpoint);
+                self.last_issue = None;
+                self.last_finish_reason = None;
+                self.last_reasoning = None;
+                self.last_cached = false;
+                self.last_usage = None;
+                self.last_metrics = None;
+            }
+        }
+        Ok(answers)
+    }
+
+    /// The request [`ask`](Self::ask) would send for `prompt`, built the
+    /// same way but never sent; with `stream`, that of
+    /// [`ask_streaming`](Self::ask_streaming), and with a `schema`, that of
+    /// [`ask_json`](Self::ask_json). The history is left as it was.
+    ///
+    /// Nothing goes over the network, so excerpts from a context index are
+    /// not added, and turns the history would be summarized into get a
+    /// placeholder for the summary.
+    pub async fn dry_run(
+        &self,
+        prompt: &str,
+        stream: bool,
+        schema: Option<&JsonSchema>,
+    ) -> Result<DryRun, AgentError> {
+        self.check_prefill()?;
+        let mut conversation = self.conversation.clone();
+        if let Some(checkpoint) = self.pending {
+            conversation.truncate(checkpoint);
+        }
+        if let Some(schema) = schema {
+            add_instructions(&mut conversation, &schema.instructions());
+        }
+        let trimmed = match self.context.plan(conversation.messages(), prompt) {
+            Some(count) => {
+                let (trimmed, _) =
+                    trim_history(&mut conversation, &self.context, count, &mut Placeholder).await;
+                Some(trimmed)
+            }
+            None => None,
+        };
+        conversation.push_user(prompt);
+        let definitions = self.tools.definitions();
+        let mut capture = Capture {
+            backend: &self.backend,
+            tools: &definitions,
+            stream,
+            estimator: self.context.estimator,
+            request: None,
+        };
+        run_tool_loop(
+            &mut conversation,
+            &self.tools,
+            self.max_tool_iterations,
+            self.prefill.as_deref(),
+            &mut capture,
+            |_| {},
+        )
+        .await?;
+        let (request, prompt_tokens) = capture
+            .request
+            .ok_or(AgentError::EmptyResponse("no request was built"))?;
+        let cost = self.usage.price(&self.backend.model).map(|price| {
+            price.cost(&Usage {
+                prompt_tokens: prompt_tokens as u64,
+                completion_tokens: 0,
+            })
+        });
+        Ok(DryRun {
+            base_url: self.backend.base_url.clone(),
+            model: self.backend.model.clone(),
+            request,
+            prompt_tokens,
+            cost,
+            trimmed,
+        })
+    }
+
+    /// Drop the unanswered question of an `ask` whose future was dropped
+    /// before it finished. Returns whether there was one.
+    pub fn cancel_pending(&mut self) -> bool {
+        match self.pending.take() {
+            Some(checkpoint) => {
+                self.conversation.truncate(checkpoint);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Snapshot the conversations, each with its settings, and the usage
+    /// totals for saving.
+    pub fn session(&self) -> Session {
+        Session::new(&self.backend.model, &self.conversation, self.usage.totals())
+            .with_store(&self.conversations)
+            .with_settings(self.conversation_settings())
+    }
+
+    /// Replace the conversations and usage totals with those of a saved
+    /// session, continuing the one that was in use with its settings.
+    ///
+    /// A session saved without them, as older ones are, keeps the configured
+    /// model and request settings.
+    pub fn resume(&mut self, session: &Session) -> Result<(), AgentError> {
+        let conversations = session.store()?;
+        let conversation = session.conversation()?;
+        self.conversations = conversations;
+        self.usage.restore(session.usage.clone());
+        match &session.settings {
+            Some(settings) => self.take_up(ParkedConversation {
+                conversation,
+                settings: settings.clone(),
+            }),
+            None => {
+                self.conversation = conversation;
+                self.forget_last_reply();
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget what the last reply left behind, for a conversation taken up
+    /// afresh.
+    fn forget_last_reply(&mut self) {
+        self.last_issue = None;
+        self.last_finish_reason = None;
+        self.last_reasoning = None;
+        self.last_cached = false;
+        self.last_trimmed = None;
+        self.last_tool_calls.clear();
+        self.last_usage = None;
+        self.last_metrics = None;
+    }
+
+    /// The name of the conversation in use, and the others held beside it.
+    pub fn conversations(&self) -> &ConversationStore {
+        &self.conversations
+    }
+
+    /// Every conversation by name, with its size and when it was last used.
+    pub fn list_conversations(&self) -> Vec<ConversationSummary> {
+        self.conversations.summaries(&self.conversation)
+    }
+
+    /// Set the conversation in use aside and start `name`, with the system
+    /// prompt and settings the agent was made with.
+    pub fn new_conversation(&mut self, name: &str) -> Result<(), String> {
+        self.cancel_pending();
+        let current = self.park();
+        self.conversations.create(name, current)?;
+        let fresh = ParkedConversation {
+            conversation: Conversation::new(&self.system_prompt),
+            settings: self.startup_settings.clone(),
+        };
+        self.take_up(fresh);
+        Ok(())
+    }
+
+    /// Set the conversation in use aside and continue `name`'s, with its
+    /// own system prompt and settings.
+    pub fn switch_conversation(&mut self, name: &str) -> Result<(), String> {
+        self.cancel_pending();
+        let target = self.conversations.switch(name, self.park())?;
+        self.take_up(target);
+        Ok(())
+    }
+
+    /// Forget `name`'s conversation. Deleting the one in use continues the
+    /// default conversation, started afresh if it was the one deleted.
+    pub fn delete_conversation(&mut self, name: &str) -> Result<(), String> {
+        self.cancel_pending();
+        match self.conversations.delete(name)? {
+            Deleted::Parked => {}
+            Deleted::Active(parked) => {
+                let fallback = parked.unwrap_or_else(|| ParkedConversation {
+                    conversation: Conversation::new(&self.system_prompt),
+                    settings: self.startup_settings.clone(),
+                });
+                self.take_up(fallback);
+            }
+        }
+        Ok(())
+    }
+
+    /// The settings the conversation in use keeps for itself.
+    pub fn conversation_settings(&self) -> ConversationSettings {
+        ConversationSettings {
+            model: self.backend.model.clone(),
+            temperature: self.backend.params.temperature,
+            top_p: self.backend.params.top_p,
+            max_tokens: self.backend.params.max_tokens,
+        }
+    }
+
+    /// The conversation in use, to set aside.
+    fn park(&self) -> ParkedConversation {
+        ParkedConversation {
+            conversation: self.conversation.clone(),
+            settings: self.conversation_settings(),
+        }
+    }
+
+    /// Continue `parked` where it was left.
+    fn take_up(&mut self, parked: ParkedConversation) {
+        self.conversation = parked.conversation;
+        self.backend.model = parked.settings.model;
+        self.backend.params.temperature = parked.settings.temperature;
+        self.backend.params.top_p = parked.settings.top_p;
+        self.backend.params.max_tokens = parked.settings.max_tokens;
+        self.forget_last_reply();
+    }
+
+    /// Forget every turn and what the last reply left behind, keeping the
+    /// system prompt. Usage totals are kept.
+    pub fn reset(&mut self) {
+        self.conversation.clear();
+        self.forget_last_reply();
+    }
+
+    /// Take back the last question and everything after it: its answer, and
+    /// any tool calls in between. Returns the question, or `None` if there
+    /// isn't one. Usage totals are kept.
+    pub fn undo(&mut self) -> Option<String> {
+        self.cancel_pending();
+        let question = self.conversation.pop_last_exchange()?;
+        self.last_issue = None;
+        self.last_trimmed = None;
+        Some(question)
+    }
+
+    /// Put back a conversation taken from [`conversation`](Self::conversation)
+    /// earlier, e.g. when asking a question again failed.
+    pub fn restore_conversation(&mut self, conversation: Conversation) {
+        self.pending = None;
+        self.conversation = conversation;
+    }
+
+    /// Swap in a new system prompt, keeping every turn, and return how many
+    /// characters of it changed. Excerpts from the context index are left out
+    /// of the comparison; the next turn adds them again.
+    pub fn replace_system_prompt(&mut self, system_prompt: &str) -> usize {
+        let current = describe(&self.conversation.messages()[0]).1;
+        let base = embeddings::base_prompt(&current);
+        if base.len() != current.len() {
+            self.conversation.set_system_prompt(base);
+        }
+        self.conversation.replace_system_prompt(system_prompt)
+    }
+
+    pub fn conversation(&self) -> &Conversation {
+        &self.conversation
+    }
+
+    pub fn model(&self) -> &str {
+        &self.backend.model
+    }
+
+    /// Where requests go, without a trailing slash.
+    pub fn base_url(&self) -> &str {
+        &self.backend.base_url
+    }
+
+    pub fn params(&self) -> &RequestParams {
+        &self.backend.params
+    }
+
+    /// Send later requests to `model`.
+    pub fn set_model(&mut self, model: impl Into<String>) {
+        self.backend.model = model.into();
+    }
+
+    /// Send later requests with `params`.
+    pub fn set_params(&mut self, params: RequestParams) -> Result<(), AgentError> {
+        chat::check_stop_sequences(&params.stop)?;
+        self.backend.params = params;
+        Ok(())
+    }
+
+    pub fn tools(&self) -> &ToolRegistry {
+        &self.tools
+    }
+
+    /// Whether the last reply stopped because it hit the token limit.
+    pub fn last_reply_truncated(&self) -> bool {
+        self.last_issue == Some(ReplyIssue::Truncated)
+    }
+
+    /// Why the last reply may not be the whole answer, if it may not.
+    pub fn last_issue(&self) -> Option<ReplyIssue> {
+        self.last_issue
+    }
+
+    /// Why the model stopped generating the last reply, if it said.
+    pub fn last_finish_reason(&self) -> Option<FinishReason> {
+        self.last_finish_reason
+    }
+
+    /// The reasoning the model showed before its last answer, if any, as
+    /// deepseek-reasoner does. It is never sent back in the conversation.
+    pub fn last_reasoning(&self) -> Option<&str> {
+        self.last_reasoning.as_deref()
+    }
+
+    /// Whether the last reply came from the response cache, in every round
+    /// if tools were called. Cached replies have no usage.
+    pub fn last_reply_cached(&self) -> bool {
+        self.last_cached
+    }
+
+    /// How the history was trimmed to fit the context budget before the last
+    /// question, if it was.
+    pub fn last_trimmed(&self) -> Option<&Trimmed> {
+        self.last_trimmed.as_ref()
+    }
+
+    /// Where the excerpts added to the system prompt for the last question
+    /// came from, as `source:start-end`, with their similarity to it.
+    pub fn last_excerpts(&self) -> &[(String, f32)] {
+        &self.last_excerpts
+    }
+
+    /// The tools run while answering the last question, in order.
+    pub fn last_tool_calls(&self) -> &[ToolExecution] {
+        &self.last_tool_calls
+    }
+
+    /// Tokens and estimated cost of every turn so far.
+    pub fn usage(&self) -> &UsageTracker {
+        &self.usage
+    }
+
+    /// Tokens and estimated cost of the last answered question, if the API reported them.
+    pub fn last_usage(&self) -> Option<&TurnUsage> {
+        self.last_usage.as_ref()
+    }
+
+    /// How the last answer was received, unless it came from the cache.
+    pub fn last_metrics(&self) -> Option<&RequestMetrics> {
+        self.last_metrics.as_ref()
+    }
+
+    /// How every answer so far was received.
+    pub fn stats(&self) -> &RequestStats {
+        &self.stats
+    }
+
+    fn check_prefill(&self) -> Result<(), AgentError> {
+        if self.prefill.is_some() && !self.tools.is_empty() {
+            return Err(AgentError::InvalidConfig(
+                "a prefill can't be combined with tools: the model has to continue the answer, not call them"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Replace the excerpts in the system prompt with those closest to
+    /// `prompt`, if there is a context index.
+    async fn retrieve(&mut self, prompt: &str) -> Result<(), AgentError> {
+        self.last_excerpts.clear();
+        let Some((index, top_k)) = &self.context_index else {
+            return Ok(());
+        };
+        let query = embed(
+            &self.backend,
+            &mut self.usage,
+            &index.model,
+            &[prompt.to_string()],
+        )
+        .await?
+        .remove(0);
+        if let Some(chunk) = index.chunks.first()
+            && chunk.embedding.len() != query.len()
+        {
+            return Err(AgentError::InvalidConfig(format!(
+                "the context index has {}-dimensional embeddings but {} returned {}; was it built with another model?",
+                chunk.embedding.len(),
+                index.model,
+                query.len()
+            )));
+        }
+        let excerpts = embeddings::top_k(&index.chunks, &query, *top_k);
+        let system_prompt = describe(&self.conversation.messages()[0]).1;
+        self.conversation
+            .set_system_prompt(&embeddings::context_prompt(&system_prompt, &excerpts));
+        self.last_excerpts = excerpts
+            .iter()
+            .map(|(score, chunk)| (chunk.location(), *score))
+            .collect();
+        debug!(excerpts = ?self.last_excerpts, "added excerpts from the context index");
+        Ok(())
+    }
+
+    /// What requests to `model` may spend, if there is a budget, with `extra`
+    /// used already on top of the totals.
+    fn allowance(&self, model: &str, extra: Option<Usage>) -> Option<Allowance> {
+        let budget = self.budget?;
+        let price = self.usage.price(model).copied();
+        let extra = match (price, extra) {
+            (Some(price), Some(extra)) => price.cost(&extra),
+            _ => 0.0,
+        };
+        Some(Allowance {
+            budget,
+            spent: self.usage.cost() + extra,
+            used: Usage::default(),
+            price,
+            estimator: self.context.estimator,
+        })
+    }
+
+    /// Trim the history if it and `prompt` would go over the context budget.
+    /// Returns the usage of the summary request, if one was made.
+    async fn fit_context(&mut self, prompt: &str) -> Option<Usage> {
+        self.cancel_pending();
+        self.last_trimmed = None;
+        if let Some(compressed) = compress_tool_results(&mut self.conversation, &self.context) {
+            info!(
+                results = compressed.results,
+                tokens_saved = compressed.tokens_saved,
+                "compressed repeated and long tool results"
+            );
+        }
+        let count = self.context.plan(self.conversation.messages(), prompt)?;
+        let mut completer = Budgeted {
+            inner: Plain {
+                backend: &self.backend,
+                model: &self.backend.model,
+                tools: &[],
+            },
+            allowance: self.allowance(&self.backend.model, None),
+        };
+        let (trimmed, usage) =
+            trim_history(&mut self.conversation, &self.context, count, &mut completer).await;
+        info!(
+            messages = trimmed.messages,
+            summarized = trimmed.summarized,
+            tokens_before = trimmed.tokens_before,
+            tokens_after = trimmed.tokens_after,
+            "trimmed history to fit the context budget"
+        );
+        self.last_trimmed = Some(trimmed);
+        usage
+    }
+
+    fn begin(&mut self, prompt: &str) -> usize {
+        self.cancel_pending();
+        let checkpoint = self.conversation.len();
+        self.pending = Some(checkpoint);
+        self.conversation.push_user(prompt);
+        checkpoint
+    }
+
+    fn settle(
+        &mut self,
+        checkpoint: usize,
+        result: Result<Reply, AgentError>,
+    ) -> Result<String, AgentError> {
+        self.pending = None;
+        match result {
+            Ok(reply) => {
+                self.conversation
+                    .push_answer(&reply.content, reply.reasoning.as_deref());
+                self.last_issue = finish::classify(&reply);
+                self.last_finish_reason = reply.finish_reason;
+                self.last_reasoning = reply.reasoning;
+                self.last_cached = reply.cached;
+                self.last_usage = reply
+                    .usage
+                    .map(|usage| self.usage.record(&self.backend.model, usage));
+                self.last_metrics = reply.metrics;
+                if let Some(metrics) = reply.metrics {
+                    self.stats.record(metrics);
+                }
+                Ok(reply.content)
+            }
+            Err(e) => {
+                self.conversation.truncate(checkpoint);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// End the system prompt with `instructions`, unless it already does.
+fn add_instructions(conversation: &mut Conversation, instructions: &str) {
+    let system_prompt = describe(&conversation.messages()[0]).1;
+    if !system_prompt.ends_with(instructions) {
+        conversation.set_system_prompt(&format!("{}\n\n{}", system_prompt, instructions));
+    }
+}
+
+/// Embed `inputs` in batches, recording the usage in `usage`.
+async fn embed(
+    backend: &Backend,
+    usage: &mut UsageTracker,
+    model: &str,
+    inputs: &[String],
+) -> Result<Vec<Vec<f32>>, AgentError> {
+    let mut embeddings = Vec::with_capacity(inputs.len());
+    for batch in inputs.chunks(EMBEDDING_BATCH_SIZE) {
+        let response = backend.embed(model, batch.to_vec()).await?;
+        usage.add(
+            model,
+            Usage {
+                prompt_tokens: response.usage.prompt_tokens.into(),
+                completion_tokens: 0,
+            },
+        );
+        let mut data = response.data;
+        if data.len() != batch.len() {
+            return Err(AgentError::EmptyResponse("fewer embeddings than inputs"));
+        }
+        data.sort_by_key(|embedding| embedding.index);
+        embeddings.extend(data.into_iter().map(|embedding| embedding.embedding));
+    }
+    Ok(embeddings)
+}
+
+/// Count a summary request made before the question as part of its reply.
+fn include_usage(mut reply: Reply, extra: Option<Usage>) -> Reply {
+    if let Some(extra) = extra {
+        *reply.usage.get_or_insert_with(Usage::default) += extra;
+    }
+    reply
+}
+
+fn notify_tool_call(hook: &Option<ToolHook>, execution: &ToolExecution) {
+    if let Some(hook) = hook {
+        hook(execution);
+    }
+}
+
+/// One request and its reply, so the tool loop can be driven by a fake in tests.
+trait Completer {
+    async fn complete(
+        &mut self,
+        messages: Vec<ChatCompletionRequestMessage>,
+    ) -> Result<Reply, AgentError>;
+}
+
+/// Shorten the tool results `context` finds repeated or too long, or `None`
+/// if there are none.
+fn compress_tool_results(
+    conversation: &mut Conversation,
+    context: &ContextManager,
+) -> Option<Compressed> {
+    let changes = context.compress(conversation.messages());
+    if changes.is_empty() {
+        return None;
+    }
+    let tokens_before = context.estimator.estimate(conversation.messages());
+    for (index, content) in &changes {
+        conversation.replace_tool_result(*index, content);
+    }
+    Some(Compressed {
+        results: changes.len(),
+        tokens_saved: tokens_before
+            .saturating_sub(context.estimator.estimate(conversation.messages())),
+    })
+}
+
+/// Remove the `count` oldest messages after the system prompt, summarizing
+/// them first if that is the strategy. A summary that can't be had is logged
+/// and the messages dropped instead, so the question can still be asked.
+async fn trim_history(
+    conversation: &mut Conversation,
+    context: &ContextManager,
+    count: usize,
+    completer: &mut impl Completer,
+) -> (Trimmed, Option<Usage>) {
+    let tokens_before = context.estimator.estimate(conversation.messages());
+    let mut summary = None;
+    if context.strategy == TrimStrategy::Summarize {
+        let request = context::summary_request(&conversation.messages()[1..=count]);
+        match completer.complete(request).await {
+            Ok(reply) if !reply.content.trim().is_empty() => summary = Some(reply),
+            Ok(_) => warn!("the summary came back empty; dropping the oldest turns instead"),
+            Err(e) => warn!(error = %e, "could not summarize; dropping the oldest turns instead"),
+        }
+    }
+    let usage = summary.as_ref().and_then(|reply| reply.usage);
+    match &summary {
+        Some(reply) => {
+            conversation.summarize_oldest(count, &context::summary_message(&reply.content))
+        }
+        None => conversation.drop_oldest(count),
+    }
+    let trimmed = Trimmed {
+        messages: count,
+        summarized: summary.is_some(),
+        tokens_before,
+        tokens_after: context.estimator.estimate(conversation.messages()),
+    };
+    (trimmed, usage)
+}
+
+/// Query the model, running the tools it calls, until it answers in text.
+///
+/// Allows `max_iterations` rounds of tool calls; a model still calling tools
+/// after that is an error rather than an endless loop. The final reply's usage
+/// covers every round.
+///
+/// A `prefill` goes at the end of every request, and the answer that
+/// continues it comes back with it in front.
+async fn run_tool_loop(
+    conversation: &mut Conversation,
+    tools: &ToolRegistry,
+    max_iterations: usize,
+    prefill: Option<&str>,
+    completer: &mut impl Completer,
+    mut on_tool_call: impl FnMut(&ToolExecution),
+) -> Result<Reply, AgentError> {
+    let mut usage = None;
+    let mut metrics = None;
+    let mut cached = true;
+    for round in 0..=max_iterations {
+        let mut messages = conversation.messages().to_vec();
+        if let Some(prefill) = prefill {
+            messages.push(chat::prefill_message(prefill));
+        }
+        let mut reply = completer.complete(messages).await?;
+        if let Some(round_usage) = reply.usage {
+            *usage.get_or_insert_with(Usage::default) += round_usage;
+        }
+        if let Some(round_metrics) = reply.metrics {
+            *metrics.get_or_insert_with(RequestMetrics::default) += round_metrics;
+        }
+        cached &= reply.cached;
+        if reply.tool_calls.is_empty() {
+            reply.usage = usage;
+            reply.metrics = metrics;
+            reply.cached = cached;
+            if let Some(prefill) = prefill {
+                reply.content.insert_str(0, prefill);
+            }
+            return Ok(reply);
+        }
+        if round == max_iterations {
+            break;
+        }
+
+        conversation.push_tool_calls(&reply.content, reply.tool_calls.clone());
+        for call in &reply.tool_calls {
+            let output = tools.call(call).await;
+            conversation.push_tool_result(&call.id, &output);
+            on_tool_call(&ToolExecution {
+                name: call.function.name.clone(),
+                arguments: call.function.arguments.clone(),
+                output,
+            });
+        }
+    }
+    Err(AgentError::ToolLoopLimit(max_iterations))
+}
+
+/// Wait at most `limit` for `future`.
+async fn with_timeout<T>(
+    limit: Duration,
+    future: impl Future<Output = T>,
+) -> Result<T, AgentError> {
+    tokio::time::timeout(limit, future)
+        .await
+        .map_err(|_| AgentError::Timeout(limit))
+}
+
+/// The client and everything needed to make a request with it.
+struct Backend {
+    http_client: reqwest::Client,
+    provider: Provider,
+    api_key: SecretString,
+    base_url: String,
+    model: String,
+    params: RequestParams,
+    retry: RetryPolicy,
+    timeout: Duration,
+    cache: Option<ResponseCache>,
+    rate_limiter: Option<RateLimiter>,
+    on_retry: Option<RetryHook>,
+}
+
+/// What a question's requests may still spend under the budget.
+#[derive(Debug, Clone, Copy)]
+struct Allowance {
+    budget: Budget,
+    /// Spent before the question.
+    spent: f64,
+    /// Used by the question's requests so far.
+    used: Usage,
+    price: Option<ModelPrice>,
+    estimator: TokenEstimator,
+}
+
+impl Allowance {
+    /// Fail if sending `messages` could go over the budget, estimated from
+    /// their tokens alone, as the reply's are unknown.
+    fn check(&self, messages: &[ChatCompletionRequestMessage]) -> Result<(), AgentError> {
+        let Some(price) = self.price else {
+            return Ok(());
+        };
+        let estimate = price.cost(&Usage {
+            prompt_tokens: self.estimator.estimate(messages) as u64,
+            completion_tokens: 0,
+        });
+        self.budget
+            .check(self.spent + price.cost(&self.used), estimate)
+    }
+}
+
+/// A completer that checks every request against the budget first, tool
+/// rounds included.
+struct Budgeted<C> {
+    inner: C,
+    allowance: Option<Allowance>,
+}
+
+impl<C> Budgeted<C> {
+    /// Add what the requests used to `usage`, for a question that failed
+    /// after some of them were paid for.
+    fn record_spent(&self, usage: &mut UsageTracker, model: &str) {
+        if let Some(allowance) = &self.allowance
+            && allowance.used.total_tokens() > 0
+        {
+            usage.record(model, allowance.used);
+        }
+    }
+}
+
+impl<C: Completer> Completer for Budgeted<C> {
+    async fn complete(
+        &mut self,
+        messages: Vec<ChatCompletionRequestMessage>,
+    ) -> Result<Reply, AgentError> {
+        if let Some(allowance) = &self.allowance {
+            allowance.check(&messages)?;
+        }
+        let reply = self.inner.complete(messages).await?;
+        if let Some(allowance) = &mut self.allowance
+            && let Some(usage) = reply.usage
+        {
+            allowance.used += usage;
+        }
+        Ok(reply)
+    }
+}
+
+struct Plain<'a> {
+    backend: &'a Backend,
+    model: &'a str,
+    tools: &'a [ChatCompletionTool],
+}
+
+impl Completer for Plain<'_> {
+    async fn complete(
+        &mut self,
+        messages: Vec<ChatCompletionRequestMessage>,
+    ) -> Result<Reply, AgentError> {
+        let request = self
+            .backend
+            .request(self.model, messages, self.tools, false)?;
+        self.backend.send(request).await
+    }
+}
+
+/// Builds the request instead of sending it, for a dry run. The reply is
+/// empty, so the tool loop ends with the first request.
+struct Capture<'a> {
+    backend: &'a Backend,
+    tools: &'a [ChatCompletionTool],
+    stream: bool,
+    estimator: TokenEstimator,
+    /// The request and its estimated prompt tokens.
+    request: Option<(serde_json::Value, usize)>,
+}
+
+impl Completer for Capture<'_> {
+    async fn complete(
+        &mut self,
+        messages: Vec<ChatCompletionRequestMessage>,
+    ) -> Result<Reply, AgentError> {
+        let prompt_tokens = self.estimator.estimate(&messages);
+        let request =
+            self.backend
+                .request(&self.backend.model, messages, self.tools, self.stream)?;
+        self.request = Some((request, prompt_tokens));
+        Ok(Reply::default())
+    }
+}
+
+/// Stands in for the model when summarizing on a dry run.
+struct Placeholder;
+
+impl Completer for Placeholder {
+    async fn complete(
+        &mut self,
+        _messages: Vec<ChatCompletionRequestMessage>,
+    ) -> Result<Reply, AgentError> {
+        Ok(Reply {
+            content: "[the model's summary of these turns, written when the request is sent]"
+                .to_string(),
+            ..Reply::default()
+        })
+    }
+}
+
+struct Streaming<'a, F> {
+    backend: &'a Backend,
+    tools: &'a [ChatCompletionTool],
+    on_delta: F,
+}
+
+impl<F: FnMut(&Delta)> Completer for Streaming<'_, F> {
+    async fn complete(
+        &mut self,
+        messages: Vec<ChatCompletionRequestMessage>,
+    ) -> Result<Reply, AgentError> {
+        let request = self
+            .backend
+            .request(&self.backend.model, messages, self.tools, true)?;
+        self.backend
+            .send_streaming(request, &mut self.on_delta)
+            .await
+    }
+}
+
+impl Backend {
+    /// A client for requests to `model`, which on Azure names the deployment.
+    fn client(&self, model: &str) -> Client<ProviderConfig> {
+        let config = self
+            .provider
+            .client_config(self.api_key.expose(), &self.base_url, model);
+        // retries are ours to make, with our own classification; async-openai
+        // would otherwise silently back off on 429s for up to 15 minutes
+        let no_backoff = backoff::ExponentialBackoff {
+            max_elapsed_time: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        Client::with_config(config)
+            .with_http_client(self.http_client.clone())
+            .with_backoff(no_backoff)
+    }
+
+    /// POST `request` to `path` for `model` with the HTTP client itself, as
+    /// async-openai drops the status and `Retry-After` header of a failed
+    /// response, which retrying goes by.
+    async fn post<O: DeserializeOwned>(
+        &self,
+        model: &str,
+        path: &str,
+        request: &impl Serialize,
+    ) -> Result<O, Failure> {
+        let config = self
+            .provider
+            .client_config(self.api_key.expose(), &self.base_url, model);
+        let response = self
+            .http_client
+            .post(config.url(path))
+            .query(&config.query())
+            .headers(config.headers())
+            .json(request)
+            .send()
+            .await
+            .map_err(OpenAIError::Reqwest)?;
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| retry::parse_retry_after_header(value, chrono::Utc::now()));
+        let body = response.bytes().await.map_err(OpenAIError::Reqwest)?;
+        if !status.is_success() {
+            return Err(Failure::response(status.as_u16(), retry_after, &body));
+        }
+        serde_json::from_slice(&body).map_err(|e| OpenAIError::JSONDeserialize(e).into())
+    }
+
+    fn request(
+        &self,
+        model: &str,
+        messages: Vec<ChatCompletionRequestMessage>,
+        tools: &[ChatCompletionTool],
+        stream: bool,
+    ) -> Result<serde_json::Value, AgentError> {
+        chat::build_request(model, &self.params, messages, tools, stream)
+    }
+
+    /// Send `request`, retrying as the policy allows, all within the timeout.
+    /// With a cache, a fresh cached reply is returned instead, and a new one cached.
+    async fn send(&self, request: serde_json::Value) -> Result<Reply, AgentError> {
+        let key = self.cache.as_ref().map(|_| ResponseCache::key(&request));
+        if let Some(reply) = self.cached(key.as_deref()) {
+            return Ok(reply);
+        }
+        let tokens = ratelimit::request_tokens(&request);
+        // the first wait doesn't count towards the timeout; retries' do
+        self.pace(tokens).await;
+        let mut timer = RequestTimer::start(Instant::now());
+        let mut retried = false;
+        let model = request["model"].as_str().unwrap_or(&self.model);
+        let attempts = retry::with_retry(
+            &self.retry,
+            || {
+                let request = &request;
+                let pace = std::mem::replace(&mut retried, true);
+                // untyped, so fields the library doesn't know, like
+                // `reasoning_content`, survive
+                async move {
+                    if pace {
+                        self.pace(tokens).await;
+                    }
+                    self.post::<serde_json::Value>(model, "/chat/completions", request)
+                        .await
+                }
+            },
+            |attempt| {
+                timer.retried();
+                self.notify_retry(attempt)
+            },
+        );
+        let span = tracing::info_span!("chat_completion", model = %model);
+        let response = with_timeout(self.timeout, attempts.instrument(span))
+            .await?
+            .map_err(|failure| self.api_key.scrub_error(failure.error))?;
+        let mut reply = match key {
+            Some(key) => {
+                let reply = chat::parse_response(response.clone())?;
+                self.store(&key, &response);
+                reply
+            }
+            None => chat::parse_response(response)?,
+        };
+        reply.metrics = Some(timer.finish(
+            Instant::now(),
+            reply.usage.map(|usage| usage.completion_tokens),
+        ));
+        self.spend(tokens, reply.usage.map(|usage| usage.total_tokens()))
+            .await;
+        Ok(reply)
+    }
+
+    /// Wait for the rate limit, if there is one, to send a request of `tokens`.
+    async fn pace(&self, tokens: u64) {
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.acquire(tokens).await;
+        }
+    }
+
+    /// Take what a request `used` beyond the `tokens` paced for it from the
+    /// rate limit, so the requests after it make up for its reply.
+    async fn spend(&self, tokens: u64, used: Option<u64>) {
+        if let (Some(limiter), Some(used)) = (&self.rate_limiter, used) {
+            limiter.spend(used.saturating_sub(tokens)).await;
+        }
+    }
+
+    /// The cached reply to the request with `key`, if there is a cache and a fresh one.
+    fn cached(&self, key: Option<&str>) -> Option<Reply> {
+        self.cache.as_ref()?.get(key?)
+    }
+
+    /// Cache `response`. A cache that can't be written only costs a request next time.
+    fn store(&self, key: &str, response: &serde_json::Value) {
+        if let Some(cache) = &self.cache
+            && let Err(e) = cache.put(key, response)
+        {
+            warn!(dir = %cache.dir.display(), error = %e, "could not cache the reply");
+        }
+    }
+
+    /// List the endpoint's models within the timeout.
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, AgentError> {
+        let client = self.client(&self.model);
+        let models = client.models();
+        // untyped, as DeepSeek leaves out `created`
+        let request = models.list_byot::<serde_json::Value>();
+        let span = tracing::info_span!("list_models");
+        let response = with_timeout(self.timeout, request.instrument(span))
+            .await?
+            .map_err(|e| self.api_key.scrub_error(e))?;
+        models::parse_list(response)
+    }
+
+    /// Embed `inputs`, retrying as the policy allows, all within the timeout.
+    async fn embed(
+        &self,
+        model: &str,
+        inputs: Vec<String>,
+    ) -> Result<CreateEmbeddingResponse, AgentError> {
+        let tokens = inputs
+            .iter()
+            .map(|input| input.chars().count())
+            .sum::<usize>()
+            .div_ceil(4) as u64;
+        let request = CreateEmbeddingRequest {
+            model: model.to_string(),
+            input: EmbeddingInput::StringArray(inputs),
+            ..Default::default()
+        };
+        self.pace(tokens).await;
+        let mut retried = false;
+        let attempts = retry::with_retry(
+            &self.retry,
+            || {
+                let request = &request;
+                let pace = std::mem::replace(&mut retried, true);
+                async move {
+                    if pace {
+                        self.pace(tokens).await;
+                    }
+                    self.post::<CreateEmbeddingResponse>(model, "/embeddings", request)
+                        .await
+                }
+            },
+            |attempt| self.notify_retry(attempt),
+        );
+        let span = tracing::info_span!("embedding", model = %model);
+        let response = with_timeout(self.timeout, attempts.instrument(span))
+            .await?
+            .map_err(|failure| self.api_key.scrub_error(failure.error))?;
+        self.spend(tokens, Some(response.usage.total_tokens.into()))
+            .await;
+        Ok(response)
+    }
+
+    async fn send_streaming(
+        &self,
+        request: serde_json::Value,
+        on_delta: &mut impl FnMut(&Delta),
+    ) -> Result<Reply, AgentError> {
+        let key = self.cache.as_ref().map(|_| ResponseCache::key(&request));
+        if let Some(reply) = self.cached(key.as_deref()) {
+            if let Some(reasoning) = &reply.reasoning {
+                on_delta(&Delta::Reasoning(reasoning.clone()));
+            }
+            if !reply.content.is_empty() {
+                on_delta(&Delta::Content(reply.content.clone()));
+            }
+            return Ok(reply);
+        }
+        let mut accumulator = StreamAccumulator::default();
+        let mut attempt = 0;
+        let tokens = ratelimit::request_tokens(&request);
+        self.pace(tokens).await;
+        let mut timer = RequestTimer::start(Instant::now());
+
+        loop {
+            let span = tracing::info_span!("chat_completion_stream", model = %self.model, attempt);
+            let Err(source) = self
+                .stream_once(request.clone(), &mut accumulator, &mut timer, on_delta)
+                .instrument(span)
+                .await?
+            else {
+                let mut reply = accumulator.finish();
+                reply.metrics = Some(timer.finish(
+                    Instant::now(),
+                    reply.usage.map(|usage| usage.completion_tokens),
+                ));
+                trace!(
+                    finish_reason = ?reply.finish_reason,
+                    usage = ?reply.usage,
+                    tool_calls = reply.tool_calls.len(),
+                    chars = reply.content.chars().count(),
+                    "stream finished"
+                );
+                if let Some(key) = &key {
+                    self.store(key, &cache::completion_json(&reply));
+                }
+                self.spend(tokens, reply.usage.map(|usage| usage.total_tokens()))
+                    .await;
+                return Ok(reply);
+            };
+
+            // once text has been shown, a retry would print it twice
+            let delay = if accumulator.is_empty() {
+                self.retry.retry_delay(attempt, &source)
+            } else {
+                None
+            };
+            let Some(delay) = delay else {
+                let source = self.api_key.scrub_error(source);
+                return Err(if accumulator.is_empty() {
+                    source.into()
+                } else {
+                    AgentError::StreamInterrupted {
+                        partial: accumulator.content().to_string(),
+                        source,
+                    }
+                });
+            };
+
+            attempt += 1;
+            self.notify_retry(&RetryAttempt {
+                attempt,
+                max_retries: self.retry.max_retries,
+                delay,
+                error: self.api_key.scrub(&source.to_string()),
+            });
+            timer.retried();
+            accumulator = StreamAccumulator::default();
+            tokio::time::sleep(delay).await;
+            self.pace(tokens).await;
+        }
+    }
+
+    /// Stream one attempt into `accumulator`, noting the first text on
+    /// `timer`. The outer error is a timeout waiting for the stream or its
+    /// next chunk, which isn't retried; the inner one is left to the retry
+    /// policy.
+    async fn stream_once(
+        &self,
+        request: serde_json::Value,
+        accumulator: &mut StreamAccumulator,
+        timer: &mut RequestTimer,
+        on_delta: &mut impl FnMut(&Delta),
+    ) -> Result<Result<(), OpenAIError>, AgentError> {
+        let client = self.client(request["model"].as_str().unwrap_or(&self.model));
+        let chat = client.chat();
+        let stream = chat.create_stream_byot::<_, serde_json::Value>(request);
+        let mut stream = match with_timeout(self.timeout, stream).await? {
+            Ok(stream) => stream,
+            Err(e) => return Ok(Err(e)),
+        };
+        while let Some(chunk) = with_timeout(self.timeout, stream.next()).await? {
+            match chunk {
+                Ok(chunk) => match accumulator.push_raw(chunk) {
+                    Ok(deltas) => {
+                        if !deltas.is_empty() {
+                            timer.first_token(Instant::now());
+                        }
+                        deltas.iter().for_each(&mut *on_delta)
+                    }
+                    Err(e) => return Ok(Err(e)),
+                },
+                Err(e) => return Ok(Err(e)),
+            }
+        }
+        Ok(Ok(()))
+    }
+
+    fn notify_retry(&self, attempt: &RetryAttempt) {
+        let attempt = RetryAttempt {
+            error: self.api_key.scrub(&attempt.error),
+            ..attempt.clone()
+        };
+        debug!(
+            attempt = attempt.attempt,
+            delay_ms = attempt.delay.as_millis() as u64,
+            error = %attempt.error,
+            "retrying request"
+        );
+        if let Some(hook) = &self.on_retry {
+            hook(&attempt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::describe;
+    use std::collections::VecDeque;
+
+    fn config() -> AgentConfig {
+        AgentConfig {
+            // nothing listens on port 9, so requests fail fast
+            base_url: "http://127.0.0.1:9/v1".to_string(),
+            retry: RetryPolicy::none(),
+            ..AgentConfig::new("sk-test")
+        }
+    }
+
+    #[test]
+    fn new_uses_configured_model_and_params() {
+        let mut config = config();
+        config.model = "deepseek-reasoner".to_string();
+        config.params.temperature = Some(0.7);
+
+        let agent = DeepSeekAgent::new(config).unwrap();
+        assert_eq!(agent.model(), "deepseek-reasoner");
+        assert_eq!(agent.params().temperature, Some(0.7));
+        assert!(agent.conversation().is_empty());
+    }
+
+    #[test]
+    fn new_rejects_malformed_base_url() {
+        let mut config = config();
+        config.base_url = "api.deepseek.com".to_string();
+        assert!(matches!(
+            DeepSeekAgent::new(config),
+            Err(AgentError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn history_starts_with_system_prompt() {
+        let mut config = config();
+        config.system_prompt = "Answer in French.".to_string();
+
+        let agent = DeepSeekAgent::new(config).unwrap();
+        let (role, text) = describe(&agent.conversation().messages()[0]);
+        assert_eq!(role, "system");
+        assert_eq!(text, "Answer in French.");
+    }
+
+    #[test]
+    fn debug_output_never_contains_the_key() {
+        let key = "sk-verysecretkey00001234";
+        let config = AgentConfig {
+            api_key: key.into(),
+            ..config()
+        };
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains(key));
+        assert!(debug.contains("sk-****1234"));
+    }
+
+    #[tokio::test]
+    async fn failed_ask_leaves_history_unchanged() {
+        let mut agent = DeepSeekAgent::new(config()).unwrap();
+        let err = agent.ask("hello?").await.unwrap_err();
+
+        assert!(matches!(err, AgentError::Api(_)));
+        assert!(agent.conversation().is_empty());
+    }
+
+    #[tokio::test]
+    async fn retried_requests_wait_for_the_rate_limit_again() {
+        let limiter = RateLimiter::new(crate::ratelimit::RateLimits {
+            requests_per_minute: Some(3),
+            tokens_per_minute: None,
+        });
+        let mut agent = DeepSeekAgent::new(AgentConfig {
+            retry: RetryPolicy {
+                max_retries: 2,
+                base_delay: Duration::ZERO,
+                ..RetryPolicy::default()
+            },
+            rate_limiter: Some(limiter.clone()),
+            ..config()
+        })
+        .unwrap();
+        agent.ask("hello?").await.unwrap_err();
+
+        // all three attempts took a request, so the next waits for a refill
+        tokio::time::pause();
+        let wait = limiter.acquire(0).await;
+        assert!(wait > Duration::from_secs(19), "{wait:?}");
+    }
+
+    /// A config whose server accepts connections but never answers.
+    fn silent_server() -> (std::net::TcpListener, AgentConfig) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = AgentConfig {
+            base_url: format!("http://{}/v1", listener.local_addr().unwrap()),
+            timeout: Duration::from_millis(100),
+            ..config()
+        };
+        (listener, config)
+    }
+
+    #[tokio::test]
+    async fn timeout_gives_up_on_a_future_that_never_resolves() {
+        let limit = Duration::from_millis(10);
+        let result = with_timeout(limit, std::future::pending::<()>()).await;
+        assert!(matches!(result, Err(AgentError::Timeout(l)) if l == limit));
+
+        assert_eq!(with_timeout(limit, async { 7 }).await.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn unanswered_requests_time_out() {
+        let (_listener, config) = silent_server();
+        let mut agent = DeepSeekAgent::new(config).unwrap();
+
+        let err = agent.ask("hello?").await.unwrap_err();
+        assert!(matches!(err, AgentError::Timeout(_)), "{err}");
+        let err = agent.ask_streaming("hello?", |_| {}).await.unwrap_err();
+        assert!(matches!(err, AgentError::Timeout(_)), "{err}");
+        assert!(agent.conversation().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dropped_questions_are_cancelled() {
+        let (_listener, mut config) = silent_server();
+        config.timeout = Duration::from_secs(60);
+        let mut agent = DeepSeekAgent::new(config).unwrap();
+
+        let abandoned = tokio::time::timeout(Duration::from_millis(50), agent.ask("hello?")).await;
+        assert!(abandoned.is_err());
+        assert_eq!(agent.conversation().len(), 1);
+
+        assert!(agent.cancel_pending());
+        assert!(agent.conversation().is_empty());
+        assert!(!agent.cancel_pending());
+    }
+
+    #[tokio::test]
+    async fn questions_taken_back_can_be_restored() {
+        let (_listener, mut config) = silent_server();
+        config.timeout = Duration::from_secs(60);
+        let mut agent = DeepSeekAgent::new(config).unwrap();
+        agent.conversation.push_user("one");
+        agent.conversation.push_assistant("1");
+        agent.conversation.push_user("two");
+        agent.conversation.push_assistant("2");
+        let before = agent.conversation().clone();
+
+        assert_eq!(agent.undo().as_deref(), Some("two"));
+        assert_eq!(agent.conversation().len(), 2);
+
+        // asking again is abandoned: the earlier answer comes back, and the
+        // question left pending with it
+        let abandoned = tokio::time::timeout(Duration::from_millis(50), agent.ask("two")).await;
+        assert!(abandoned.is_err());
+        agent.restore_conversation(before);
+        assert!(!agent.cancel_pending());
+        assert_eq!(agent.conversation().len(), 4);
+
+        assert_eq!(agent.undo().as_deref(), Some("two"));
+        assert_eq!(agent.undo().as_deref(), Some("one"));
+        assert_eq!(agent.undo(), None);
+        assert!(agent.conversation().is_empty());
+    }
+
+    #[tokio::test]
+    async fn connection_failures_are_retried_and_reported() {
+        use std::sync::{Arc, Mutex};
+
+        let mut config = config();
+        config.retry = RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+        };
+        let mut agent = DeepSeekAgent::new(config).unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        agent.on_retry(move |attempt| sink.lock().unwrap().push(attempt.attempt));
+
+        assert!(agent.ask("hello?").await.is_err());
+        assert_eq!(*seen.lock().unwrap(), [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn failed_ask_with_tools_leaves_history_unchanged() {
+        let mut agent = DeepSeekAgent::new(config()).unwrap();
+        agent.set_tools(ToolRegistry::builtin());
+        assert!(agent.ask("what time is it?").await.is_err());
+        assert!(agent.conversation().is_empty());
+    }
+
+    #[test]
+    fn requests_offer_registered_tools() {
+        let mut agent = DeepSeekAgent::new(config()).unwrap();
+        agent.register_tool(crate::tools::Calculator);
+        let request = agent
+            .backend
+            .request("deepseek-chat", vec![], &agent.tools.definitions(), false)
+            .unwrap();
+        let body = serde_json::to_value(&request).unwrap();
+        assert_eq!(body["tools"][0]["function"]["name"], "calculator");
+    }
+
+    /// Replays canned replies and records the messages each request carried.
+    struct Scripted {
+        replies: VecDeque<Reply>,
+        requests: Vec<Vec<ChatCompletionRequestMessage>>,
+    }
+
+    impl Scripted {
+        fn new(replies: impl IntoIterator<Item = Reply>) -> Self {
+            Self {
+                replies: replies.into_iter().collect(),
+                requests: Vec::new(),
+            }
+        }
+    }
+
+    impl Completer for Scripted {
+        async fn complete(
+            &mut self,
+            messages: Vec<ChatCompletionRequestMessage>,
+        ) -> Result<Reply, AgentError> {
+            self.requests.push(messages);
+            self.replies
+                .pop_front()
+                .ok_or(AgentError::EmptyResponse("script ran out"))
+        }
+    }
+
+    fn text(content: &str) -> Reply {
+        Reply {
+            content: content.to_string(),
+            ..Reply::default()
+        }
+    }
+
+    fn calls(calls: &[(&str, &str, &str)]) -> Reply {
+        Reply {
+            tool_calls: calls
+                .iter()
+                .map(|(id, name, arguments)| {
+                    serde_json::from_value(serde_json::json!({
+                        "id": id,
+                        "type": "function",
+                        "function": { "name": name, "arguments": arguments }
+                    }))
+                    .unwrap()
+                })
+                .collect(),
+            ..Reply::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn tool_results_are_sent_back_until_the_model_answers() {
+        let mut conversation = Conversation::new("sys");
+        conversation.push_user("what is 6*7, and what time is it?");
+        let mut completer = Scripted::new([
+            calls(&[
+                ("call_1", "calculator", r#"{"expression":"6*7"}"#),
+                ("call_2", "current_time", "{}"),
+            ]),
+            text("42, and it's noon."),
+        ]);
+        let mut executions = Vec::new();
+
+        let reply = run_tool_loop(
+            &mut conversation,
+            &ToolRegistry::builtin(),
+            5,
+            None,
+            &mut completer,
+            |execution| executions.push(execution.clone()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(reply.content, "42, and it's noon.");
+        assert_eq!(completer.requests.len(), 2);
+        let second: Vec<_> = completer.requests[1].iter().map(describe).collect();
+        assert_eq!(second[2].0, "assistant");
+        assert_eq!(second[3], ("tool", "42".to_string()));
+        assert_eq!(second[4].0, "tool");
+        assert_eq!(executions.len(), 2);
+        assert_eq!(executions[0].name, "calculator");
+        assert_eq!(executions[0].output, "42");
+    }
+
+    #[tokio::test]
+    async fn usage_is_summed_over_tool_rounds() {
+        let with_usage = |mut reply: Reply, prompt_tokens, completion_tokens| {
+            reply.usage = Some(Usage {
+                prompt_tokens,
+                completion_tokens,
+            });
+            reply
+        };
+        let mut conversation = Conversation::new("sys");
+        let mut completer = Scripted::new([
+            with_usage(calls(&[("call_1", "current_time", "{}")]), 100, 10),
+            with_usage(text("It's noon."), 130, 5),
+        ]);
+
+        let reply = run_tool_loop(
+            &mut conversation,
+            &ToolRegistry::builtin(),
+            5,
+            None,
+            &mut completer,
+            |_| {},
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            reply.usage,
+            Some(Usage {
+                prompt_tokens: 230,
+                completion_tokens: 15
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn budgets_stop_tool_rounds_before_the_next_request() {
+        let mut conversation = Conversation::new("sys");
+        conversation.push_user("what time is it?");
+        let mut round = calls(&[("call_1", "current_time", "{}")]);
+        round.usage = Some(Usage {
+            prompt_tokens: 1_000_000,
+            completion_tokens: 0,
+        });
+        let mut completer = Budgeted {
+            inner: Scripted::new([round.clone(), round, text("It's noon.")]),
+            allowance: Some(Allowance {
+                budget: Budget::new(1.0),
+                spent: 0.0,
+                used: Usage::default(),
+                price: Some(ModelPrice {
+                    input_per_million: 1.0,
+                    output_per_million: 1.0,
+                }),
+                estimator: TokenEstimator::default(),
+            }),
+        };
+
+        let err = run_tool_loop(
+            &mut conversation,
+            &ToolRegistry::builtin(),
+            5,
+            None,
+            &mut completer,
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+
+        // the first round spends the whole dollar, so the second can't be sent
+        assert!(
+            matches!(err, AgentError::BudgetExceeded { spent, .. } if spent == 1.0),
+            "{err:?}"
+        );
+        assert_eq!(completer.inner.requests.len(), 1);
+        let mut usage = UsageTracker::new(crate::usage::PriceTable::default());
+        completer.record_spent(&mut usage, "deepseek-chat");
+        assert_eq!(usage.usage().prompt_tokens, 1_000_000);
+    }
+
+    #[tokio::test]
+    async fn a_prefill_is_sent_last_and_kept_with_the_answer() {
+        let mut conversation = Conversation::new("sys");
+        conversation.push_user("List three primes as JSON");
+        let mut completer = Scripted::new([text("[2, 3, 5]\n```")]);
+
+        let reply = run_tool_loop(
+            &mut conversation,
+            &ToolRegistry::default(),
+            5,
+            Some("```json\n"),
+            &mut completer,
+            |_| {},
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(reply.content, "```json\n[2, 3, 5]\n```");
+        let sent: Vec<_> = completer.requests[0].iter().map(describe).collect();
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[2], ("assistant", "```json\n".to_string()));
+        // the prefill is only ever part of the request
+        assert_eq!(conversation.len(), 1);
+    }
+
+    #[test]
+    fn prefills_and_tools_dont_mix() {
+        let mut agent = DeepSeekAgent::new(AgentConfig {
+            prefill: Some("{".to_string()),
+            ..config()
+        })
+        .unwrap();
+        assert!(agent.check_prefill().is_ok());
+        agent.set_tools(ToolRegistry::builtin());
+        let err = agent.check_prefill().unwrap_err();
+        assert!(err.to_string().contains("prefill"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn dry_runs_send_nothing_and_leave_the_history() {
+        // nothing listens on port 9, so a request that was sent would fail
+        let mut agent = DeepSeekAgent::new(AgentConfig {
+            context: ContextManager {
+                budget: 1,
+                keep_turns: 1,
+                strategy: TrimStrategy::Summarize,
+                ..ContextManager::default()
+            },
+            ..config()
+        })
+        .unwrap();
+        for turn in ["one", "two"] {
+            agent.conversation.push_user(turn);
+            agent.conversation.push_assistant(turn);
+        }
+        let before = agent.conversation().messages().to_vec();
+
+        let dry_run = agent.dry_run("three", true, None).await.unwrap();
+        assert_eq!(agent.conversation().messages(), before);
+        assert_eq!(dry_run.trimmed.unwrap().messages, 2);
+        assert_eq!(dry_run.request["stream"], true);
+        let messages = dry_run.request["messages"].as_array().unwrap();
+        let contents: Vec<_> = messages
+            .iter()
+            .map(|message| message["content"].as_str().unwrap())
+            .collect();
+        assert_eq!(contents.len(), 5);
+        assert!(contents[1].contains("written when the request is sent"));
+        assert_eq!(contents[2..], ["two", "two", "three"]);
+        assert!(dry_run.prompt_tokens > 0);
+        assert!(dry_run.cost.is_some());
+    }
+
+    #[test]
+    fn too_many_stop_sequences_are_rejected() {
+        let mut config = config();
+        config.params.stop = vec!["a".to_string(); 5];
+        assert!(matches!(
+            DeepSeekAgent::new(config),
+            Err(AgentError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn answered_turns_are_priced_and_totalled() {
+        let mut agent = DeepSeekAgent::new(config()).unwrap();
+        let usage = Usage {
+            prompt_tokens: 1_000,
+            completion_tokens: 500,
+        };
+        let reply = Reply {
+            usage: Some(usage),
+            ..text("hi")
+        };
+        agent.settle(0, Ok(reply.clone())).unwrap();
+        agent.settle(2, Ok(reply)).unwrap();
+
+        let last = agent.last_usage().unwrap();
+        assert_eq!(last.usage, usage);
+        assert!(last.cost.is_some());
+        assert_eq!(agent.usage().turns(), 2);
+        assert_eq!(agent.usage().usage().total_tokens(), 3_000);
+
+        agent.settle(4, Ok(text("no usage reported"))).unwrap();
+        assert!(agent.last_usage().is_none());
+        assert_eq!(agent.usage().turns(), 2);
+    }
+
+    #[tokio::test]
+    async fn tool_errors_go_back_to_the_model() {
+        let mut conversation = Conversation::new("sys");
+        let mut completer = Scripted::new([
+            calls(&[("call_1", "calculator", r#"{"expression":"1/0"}"#)]),
+            text("That's undefined."),
+        ]);
+
+        let reply = run_tool_loop(
+            &mut conversation,
+            &ToolRegistry::builtin(),
+            5,
+            None,
+            &mut completer,
+            |_| {},
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(reply.content, "That's undefined.");
+        let (role, output) = describe(conversation.messages().last().unwrap());
+        assert_eq!(role, "tool");
+        assert!(output.contains("division by zero"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn endless_tool_calls_hit_the_iteration_cap() {
+        let mut conversation = Conversation::new("sys");
+        let mut completer =
+            Scripted::new((0..10).map(|_| calls(&[("call_1", "current_time", "{}")])));
+
+        let err = run_tool_loop(
+            &mut conversation,
+            &ToolRegistry::builtin(),
+            2,
+            None,
+            &mut completer,
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(err, AgentError::ToolLoopLimit(2)));
+        assert_eq!(completer.requests.len(), 3);
+    }
+
+    /// Six turns of about 50 tokens each, and a manager that keeps two.
+    fn over_budget(strategy: TrimStrategy) -> (Conversation, ContextManager) {
+        let mut conversation = Conversation::new("sys");
+        for turn in 0..6 {
+            conversation.push_user(&format!("question {turn} {}", "q".repeat(100)));
+            conversation.push_tool_calls("", calls(&[("call_1", "current_time", "{}")]).tool_calls);
+            conversation.push_tool_result("call_1", "2024-01-01T00:00:00Z");
+            conversation.push_assistant(&format!("answer {turn}"));
+        }
+        let context = ContextManager {
+            budget: 100,
+            keep_turns: 2,
+            strategy,
+            ..ContextManager::default()
+        };
+        (conversation, context)
+    }
+
+    #[tokio::test]
+    async fn old_turns_are_summarized_into_a_system_message() {
+        let (mut conversation, context) = over_budget(TrimStrategy::Summarize);
+        let count = context.plan(conversation.messages(), "next").unwrap();
+        let mut completer = Scripted::new([Reply {
+            usage: Some(Usage {
+                prompt_tokens: 300,
+                completion_tokens: 20,
+            }),
+            ..text("Six questions about q, answered with the time.")
+        }]);
+
+        let (trimmed, usage) =
+            trim_history(&mut conversation, &context, count, &mut completer).await;
+
+        assert_eq!(trimmed.messages, 16);
+        assert!(trimmed.summarized);
+        assert!(trimmed.tokens_after < trimmed.tokens_before);
+        assert_eq!(usage.unwrap().completion_tokens, 20);
+        let request = &completer.requests[0];
+        assert!(describe(&request[1]).1.contains("calls current_time({})"));
+        let history: Vec<_> = conversation.messages().iter().map(describe).collect();
+        assert_eq!(history.len(), 10);
+        assert_eq!(history[0].1, "sys");
+        assert_eq!(
+            history[1],
+            (
+                "system",
+                "Summary of the earlier conversation:\nSix questions about q, answered with the time."
+                    .to_string()
+            )
+        );
+        assert!(history[2].1.starts_with("question 4 "));
+    }
+
+    #[tokio::test]
+    async fn failed_summaries_fall_back_to_dropping() {
+        let (mut conversation, context) = over_budget(TrimStrategy::Summarize);
+        let count = context.plan(conversation.messages(), "next").unwrap();
+        let mut completer = Scripted::new([]);
+
+        let (trimmed, usage) =
+            trim_history(&mut conversation, &context, count, &mut completer).await;
+
+        assert!(!trimmed.summarized);
+        assert_eq!(usage, None);
+        assert_eq!(conversation.len(), 8);
+        assert!(
+            describe(&conversation.messages()[1])
+                .1
+                .starts_with("question 4 ")
+        );
+    }
+
+    #[tokio::test]
+    async fn dropping_makes_no_request() {
+        let (mut conversation, context) = over_budget(TrimStrategy::DropOldest);
+        let count = context.plan(conversation.messages(), "next").unwrap();
+        let mut completer = Scripted::new([]);
+
+        let (trimmed, _) = trim_history(&mut conversation, &context, count, &mut completer).await;
+
+        assert!(completer.requests.is_empty());
+        assert!(!trimmed.summarized);
+        assert_eq!(trimmed.messages, count);
+        assert_eq!(describe(&conversation.messages()[0]).1, "sys");
+        assert_eq!(describe(&conversation.messages()[1]).0, "user");
+    }
+
+    #[test]
+    fn resume_restores_history_and_usage() {
+        let mut agent = DeepSeekAgent::new(config()).unwrap();
+        agent.conversation.push_user("hi");
+        let reply = Reply {
+            usage: Some(Usage {
+                prompt_tokens: 10,
+                completion_tokens: 2,
+            }),
+            ..text("hello")
+        };
+        agent.settle(1, Ok(reply)).unwrap();
+        let session = agent.session();
+
+        let mut resumed = DeepSeekAgent::new(config()).unwrap();
+        resumed.resume(&session).unwrap();
+        assert_eq!(resumed.conversation().len(), 2);
+        assert_eq!(describe(&resumed.conversation().messages()[2]).1, "hello");
+        assert_eq!(resumed.usage().turns(), 1);
+        assert_eq!(resumed.usage().usage().total_tokens(), 12);
+    }
+
+    #[test]
+    fn conversations_keep_their_own_history_and_settings() {
+        let mut agent = DeepSeekAgent::new(config()).unwrap();
+        agent.conversation.push_user("about rust");
+        agent.conversation.push_assistant("ownership");
+
+        agent.new_conversation("work").unwrap();
+        assert_eq!(agent.conversations().active(), "work");
+        assert!(agent.conversation().is_empty());
+        agent.replace_system_prompt("Be terse.");
+        agent.set_model("deepseek-reasoner");
+        agent.conversation.push_user("about sql");
+
+        agent.switch_conversation("default").unwrap();
+        let history: Vec<_> = agent
+            .conversation()
+            .messages()
+            .iter()
+            .map(describe)
+            .collect();
+        assert_eq!(history[1], ("user", "about rust".to_string()));
+        assert!(!history.iter().any(|(_, text)| text == "about sql"));
+        assert_eq!(agent.model(), "deepseek-chat");
+
+        agent.switch_conversation("work").unwrap();
+        assert_eq!(describe(&agent.conversation().messages()[0]).1, "Be terse.");
+        assert_eq!(describe(&agent.conversation().messages()[1]).1, "about sql");
+        assert_eq!(agent.model(), "deepseek-reasoner");
+        assert!(agent.switch_conversation("work").is_err());
+        assert!(agent.new_conversation("default").is_err());
+    }
+
+    #[test]
+    fn deleting_the_active_conversation_falls_back_to_the_default() {
+        let mut agent = DeepSeekAgent::new(config()).unwrap();
+        agent.conversation.push_user("kept");
+        agent.new_conversation("scratch").unwrap();
+        agent.conversation.push_user("thrown away");
+        agent.delete_conversation("scratch").unwrap();
+        assert_eq!(agent.conversations().active(), "default");
+        assert_eq!(describe(&agent.conversation().messages()[1]).1, "kept");
+
+        // deleting the default itself starts it afresh
+        agent.delete_conversation("default").unwrap();
+        assert_eq!(agent.conversations().active(), "default");
+        assert!(agent.conversation().is_empty());
+        assert!(agent.delete_conversation("scratch").is_err());
+    }
+
+    #[test]
+    fn sessions_save_and_resume_every_conversation() {
+        let mut agent = DeepSeekAgent::new(config()).unwrap();
+        agent.conversation.push_user("first");
+        agent.new_conversation("second").unwrap();
+        agent.conversation.push_user("in second");
+        let session = agent.session();
+
+        let mut resumed = DeepSeekAgent::new(config()).unwrap();
+        resumed.resume(&session).unwrap();
+        assert_eq!(resumed.conversations().active(), "second");
+        assert_eq!(
+            describe(&resumed.conversation().messages()[1]).1,
+            "in second"
+        );
+        let names: Vec<_> = resumed
+            .list_conversations()
+            .into_iter()
+            .map(|summary| (summary.name, summary.active, summary.turns))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("default".to_string(), false, 1),
+                ("second".to_string(), true, 1)
+            ]
+        );
+        resumed.switch_conversation("default").unwrap();
+        assert_eq!(describe(&resumed.conversation().messages()[1]).1, "first");
+    }
+
+    #[test]
+    fn the_active_conversation_resumes_with_its_settings() {
+        let mut agent = DeepSeekAgent::new(config()).unwrap();
+        agent.new_conversation("precise").unwrap();
+        agent
+            .set_params(RequestParams {
+                temperature: Some(0.1),
+                max_tokens: Some(200),
+                ..agent.params().clone()
+            })
+            .unwrap();
+        agent.set_model("deepseek-reasoner");
+        let session = Session::from_json(&agent.session().to_json()).unwrap();
+
+        let mut resumed = DeepSeekAgent::new(config()).unwrap();
+        resumed.resume(&session).unwrap();
+        assert_eq!(resumed.conversations().active(), "precise");
+        assert_eq!(resumed.params().temperature, Some(0.1));
+        assert_eq!(resumed.params().max_tokens, Some(200));
+        assert_eq!(resumed.model(), "deepseek-reasoner");
+        // the one set aside still has the startup settings
+        resumed.switch_conversation("default").unwrap();
+        assert_eq!(resumed.params().temperature, None);
+        assert_eq!(resumed.model(), "deepseek-chat");
+    }
+
+    #[test]
+    fn reasoning_is_kept_out_of_the_history() {
+        let mut agent = DeepSeekAgent::new(config()).unwrap();
+        let raw =
+            serde_json::from_str(include_str!("../tests/fixtures/reasoner_response.json")).unwrap();
+        let checkpoint = agent.begin("Is 1013 prime?");
+        let answer = agent.settle(checkpoint, chat::parse_response(raw)).unwrap();
+
+        assert_eq!(answer, "1013 is prime.");
+        assert!(agent.last_reasoning().unwrap().contains("sqrt(1013)"));
+        let history: Vec<_> = agent
+            .conversation()
+            .messages()
+            .iter()
+            .map(describe)
+            .collect();
+        assert_eq!(history[2], ("assistant", "1013 is prime.".to_string()));
+        assert!(
+            history.iter().all(|(_, text)| !text.contains("sqrt")),
+            "{history:?}"
+        );
+        // saved beside the answer for transcripts, but never in a message
+        let session = agent.session();
+        assert!(
+            session.messages[2]
+                .reasoning
+                .as_ref()
+                .unwrap()
+                .contains("sqrt")
+        );
+        assert!(
+            session
+                .messages
+                .iter()
+                .all(|m| !serde_json::to_string(&m.message).unwrap().contains("sqrt"))
+        );
+    }
+
+    #[test]
+    fn reset_clears_turns() {
+        let mut agent = DeepSeekAgent::new(config()).unwrap();
+        agent.conversation.push_user("hi");
+        agent.conversation.push_assistant("hello");
+        agent.last_issue = Some(ReplyIssue::Truncated);
+        agent.last_finish_reason = Some(FinishReason::Length);
+        agent.last_reasoning = Some("thinking".to_string());
+        agent.last_cached = true;
+        agent.last_trimmed = Some(Trimmed {
+            messages: 2,
+            summarized: false,
+            tokens_before: 100,
+            tokens_after: 40,
+        });
+        agent.last_tool_calls.push(ToolExecution {
+            name: "read_file".to_string(),
+            arguments: "{}".to_string(),
+            output: "text".to_string(),
+        });
+        agent.last_usage = Some(TurnUsage {
+            model: "deepseek-chat".to_string(),
+            usage: Usage::default(),
+            cost: None,
+        });
+        agent.last_metrics = Some(RequestMetrics::default());
+
+        agent.reset();
+        assert!(agent.conversation().is_empty());
+        assert!(!agent.last_reply_truncated());
+        assert_eq!(agent.last_issue(), None);
+        assert_eq!(agent.last_finish_reason(), None);
+        assert_eq!(agent.last_reasoning(), None);
+        assert!(!agent.last_reply_cached());
+        assert_eq!(agent.last_trimmed(), None);
+        assert!(agent.last_tool_calls().is_empty());
+        assert_eq!(agent.last_usage(), None);
+        assert_eq!(agent.last_metrics(), None);
+    }
+}
@@ -0,0 +1,65 @@
+//! Asking the user before the model runs a command or writes a file.
+
+use std::io::{BufRead, BufReader, Write};
+
+use async_trait::async_trait;
+use deepseek_tutor::tools::Approver;
+
+/// Asks on the terminal, which still works when stdin is a pipe.
+pub struct TerminalApprover;
+
+#[async_trait]
+impl Approver for TerminalApprover {
+    async fn approve(&self, action: &str) -> bool {
+        let action = action.to_string();
+        tokio::task::spawn_blocking(move || ask(&action))
+            .await
+            .unwrap_or(false)
+    }
+}
+
+fn ask(action: &str) -> bool {
+    let tty = match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+    {
+        Ok(tty) => tty,
+        Err(_) => {
+            eprintln!(
+                "[approve] no terminal to ask for approval, so declining to {}\n\
+                 [approve] pass --auto-approve to allow it without asking",
+                action
+            );
+            return false;
+        }
+    };
+    let mut writer = &tty;
+    let _ = write!(writer, "[approve] {}? [y/N] ", action);
+    let _ = writer.flush();
+    let mut answer = String::new();
+    match BufReader::new(&tty).read_line(&mut answer) {
+        Ok(_) => is_yes(&answer),
+        Err(_) => false,
+    }
+}
+
+/// Only an explicit yes approves; anything else, including Enter, declines.
+pub fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_yes_approves() {
+        for answer in ["y\n", "Y", " yes ", "YES\r\n"] {
+            assert!(is_yes(answer), "{answer:?}");
+        }
+        for answer in ["", "\n", "n", "no", "yep", "y es"] {
+            assert!(!is_yes(answer), "{answer:?}");
+        }
+    }
+}
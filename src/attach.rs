@@ -0,0 +1,395 @@
+//! Local files sent along with a question, for `--attach` and `/attach`.
+//!
+//! Each file goes ahead of the question in a fenced block, under a line with
+//! its path and size, and tagged with a language guessed from its extension
+//! so the model reads it as code. Only text files can be attached. Files that
+//! together go over the size cap are cut, the biggest the most, each with a
+//! marker saying how much of it was sent.
+
+use std::io;
+use std::path::{Component, Path};
+
+use crate::chat::{estimate_tokens, user_message};
+use crate::error::AgentError;
+
+/// Default cap on the combined size of the files attached to one message.
+pub const DEFAULT_MAX_ATTACH_BYTES: usize = 100 * 1024;
+
+/// A file to send with the next question.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    /// The path as shown to the model, relative to the working directory
+    /// where it is under it.
+    pub name: String,
+    /// What is sent of the file: all of it, unless [`fit`] cut it.
+    pub text: String,
+    /// The file's size in bytes.
+    pub size: usize,
+}
+
+impl Attachment {
+    /// Read the text file at `path`, naming it relative to `base`. Files
+    /// with NUL bytes or that aren't UTF-8 are taken to be binary and refused.
+    pub fn read(path: &Path, base: &Path) -> Result<Self, AgentError> {
+        let name = relative_name(path, base);
+        if path.is_dir() {
+            return Err(AgentError::Input(format!(
+                "'{}' is a directory; attach the files in it one at a time",
+                name
+            )));
+        }
+        let bytes = std::fs::read(path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => {
+                AgentError::Input(format!("attachment '{}' does not exist", name))
+            }
+            _ => AgentError::Input(format!("could not read attachment '{}': {}", name, e)),
+        })?;
+        let size = bytes.len();
+        let binary = || {
+            AgentError::Input(format!(
+                "'{}' looks like a binary file, and only text can be attached; \
+                 convert it to text first, or describe it in the message",
+                name
+            ))
+        };
+        if bytes.contains(&0) {
+            return Err(binary());
+        }
+        let text = String::from_utf8(bytes).map_err(|_| binary())?;
+        Ok(Self { name, text, size })
+    }
+
+    /// Whether [`fit`] left out some of the file.
+    pub fn truncated(&self) -> bool {
+        self.text.len() < self.size
+    }
+
+    /// The block sent for the file: a header line, then the text fenced.
+    pub fn block(&self) -> String {
+        let mut text = self.text.clone();
+        if self.truncated() {
+            if !text.is_empty() && !text.ends_with('\n') {
+                text.push('\n');
+            }
+            text.push_str(&format!(
+                "[... truncated: the first {} of {} bytes are shown ...]",
+                self.text.len(),
+                self.size
+            ));
+        }
+        let fence = "`".repeat(longest_backtick_run(&text).max(2) + 1);
+        format!(
+            "Attached file {} ({} bytes):\n{}{}\n{}\n{}",
+            self.name,
+            self.size,
+            fence,
+            language(&self.name).unwrap_or(""),
+            text.trim_end_matches('\n'),
+            fence
+        )
+    }
+
+    /// Estimated tokens the block adds to the message.
+    pub fn tokens(&self) -> usize {
+        estimate_tokens(&[user_message(&self.block())])
+    }
+}
+
+/// The path as it is shown: under `base` if it is, with `/` between parts.
+fn relative_name(path: &Path, base: &Path) -> String {
+    let path = path.strip_prefix(base).unwrap_or(path);
+    let parts: Vec<_> = path
+        .components()
+        .filter(|part| !matches!(part, Component::CurDir))
+        .map(|part| part.as_os_str().to_string_lossy())
+        .collect();
+    match path.has_root() {
+        true => path.display().to_string(),
+        false => parts.join("/"),
+    }
+}
+
+/// A fence has to be longer than any run of backticks in what it holds.
+fn longest_backtick_run(text: &str) -> usize {
+    text.split(|c| c != '`').map(str::len).max().unwrap_or(0)
+}
+
+/// The fence's language tag for a file, from its extension or, for files
+/// that usually have none, its name.
+pub fn language(name: &str) -> Option<&'static str> {
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    match file_name {
+        "Dockerfile" => return Some("dockerfile"),
+        "Makefile" | "makefile" | "GNUmakefile" => return Some("makefile"),
+        _ => {}
+    }
+    let (_, extension) = file_name.rsplit_once('.')?;
+    Some(match extension.to_ascii_lowercase().as_str() {
+        "rs" => "rust",
+        "py" | "pyi" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "jsx" => "jsx",
+        "ts" | "mts" | "cts" => "typescript",
+        "tsx" => "tsx",
+        "go" => "go",
+        "java" => "java",
+        "kt" | "kts" => "kotlin",
+        "scala" => "scala",
+        "swift" => "swift",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => "cpp",
+        "cs" => "csharp",
+        "rb" => "ruby",
+        "php" => "php",
+        "lua" => "lua",
+        "ex" | "exs" => "elixir",
+        "hs" => "haskell",
+        "ml" | "mli" => "ocaml",
+        "dart" => "dart",
+        "r" => "r",
+        "sh" | "bash" => "bash",
+        "zsh" => "zsh",
+        "fish" => "fish",
+        "ps1" => "powershell",
+        "sql" => "sql",
+        "html" | "htm" => "html",
+        "css" => "css",
+        "scss" => "scss",
+        "vue" => "vue",
+        "svelte" => "svelte",
+        "json" => "json",
+        "toml" => "toml",
+        "yaml" | "yml" => "yaml",
+        "xml" => "xml",
+        "md" | "markdown" => "markdown",
+        "proto" => "protobuf",
+        "tf" | "hcl" => "hcl",
+        "ini" | "cfg" => "ini",
+        "diff" | "patch" => "diff",
+        _ => return None,
+    })
+}
+
+/// Cut `attachments` to `max_bytes` of text between them. Files smaller than
+/// an equal share are sent whole and what they leave is shared by the rest;
+/// each cut file ends on a line, where there is one within its share.
+pub fn fit(attachments: &mut [Attachment], max_bytes: usize) {
+    let mut order: Vec<usize> = (0..attachments.len()).collect();
+    order.sort_by_key(|&i| attachments[i].text.len());
+    let mut left = max_bytes;
+    for (done, &i) in order.iter().enumerate() {
+        let share = left / (order.len() - done);
+        let text = &mut attachments[i].text;
+        if text.len() > share {
+            let mut end = share;
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            if let Some(line_end) = text[..end].rfind('\n') {
+                end = line_end + 1;
+            }
+            text.truncate(end);
+        }
+        left -= text.len();
+    }
+}
+
+/// `question` with the blocks of `attachments` ahead of it.
+pub fn with_attachments(question: &str, attachments: &[Attachment]) -> String {
+    if attachments.is_empty() {
+        return question.to_string();
+    }
+    let mut message: Vec<String> = attachments.iter().map(Attachment::block).collect();
+    message.push(question.to_string());
+    message.join("\n\n")
+}
+
+/// Files queued for the next message, and only that one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pending {
+    files: Vec<Attachment>,
+}
+
+impl Pending {
+    /// Queue `attachment`, in place of the same file queued before.
+    pub fn add(&mut self, attachment: Attachment) {
+        match self.files.iter_mut().find(|f| f.name == attachment.name) {
+            Some(queued) => *queued = attachment,
+            None => self.files.push(attachment),
+        }
+    }
+
+    pub fn files(&self) -> &[Attachment] {
+        &self.files
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+
+    /// The queued files, for the message being sent; none are left queued.
+    pub fn take(&mut self) -> Vec<Attachment> {
+        std::mem::take(&mut self.files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::ContextManager;
+
+    fn attachment(name: &str, text: &str) -> Attachment {
+        Attachment {
+            name: name.to_string(),
+            text: text.to_string(),
+            size: text.len(),
+        }
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("deepseek_attach_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn blocks_are_fenced_under_a_header() {
+        let file = attachment("src/main.rs", "fn main() {}\n");
+        assert_eq!(
+            file.block(),
+            "Attached file src/main.rs (13 bytes):\n```rust\nfn main() {}\n```"
+        );
+        // a fence inside the file doesn't end the block
+        let readme = attachment("README.md", "```bash\ncargo run\n```\n");
+        assert_eq!(
+            readme.block(),
+            "Attached file README.md (22 bytes):\n````markdown\n```bash\ncargo run\n```\n````"
+        );
+        assert_eq!(
+            with_attachments("explain this", &[file.clone(), readme]),
+            format!(
+                "{}\n\n{}\n\nexplain this",
+                file.block(),
+                attachment("README.md", "```bash\ncargo run\n```\n").block()
+            )
+        );
+        assert_eq!(with_attachments("just this", &[]), "just this");
+    }
+
+    #[test]
+    fn languages_come_from_the_extension_or_name() {
+        assert_eq!(language("src/main.rs"), Some("rust"));
+        assert_eq!(language("Cargo.toml"), Some("toml"));
+        assert_eq!(language("web/App.TSX"), Some("tsx"));
+        assert_eq!(language("ci/deploy.yml"), Some("yaml"));
+        assert_eq!(language("docker/Dockerfile"), Some("dockerfile"));
+        assert_eq!(language("notes.txt"), None);
+        assert_eq!(language("LICENSE"), None);
+        assert_eq!(
+            attachment("LICENSE", "MIT\n").block(),
+            "Attached file LICENSE (4 bytes):\n```\nMIT\n```"
+        );
+    }
+
+    #[test]
+    fn files_are_named_relative_to_the_working_directory() {
+        let dir = temp_dir("names");
+        std::fs::write(dir.join("src/lib.rs"), "pub mod agent;\n").unwrap();
+        let file = Attachment::read(&dir.join("src/lib.rs"), &dir).unwrap();
+        assert_eq!(file.name, "src/lib.rs");
+        assert_eq!(file.size, 15);
+        assert!(!file.truncated());
+        assert_eq!(relative_name(Path::new("./src/lib.rs"), &dir), "src/lib.rs");
+        assert_eq!(relative_name(Path::new("/etc/hosts"), &dir), "/etc/hosts");
+    }
+
+    #[test]
+    fn binary_and_missing_files_are_refused() {
+        let dir = temp_dir("binary");
+        std::fs::write(dir.join("logo.png"), [0x89, b'P', b'N', b'G', 0, 0]).unwrap();
+        let err = Attachment::read(&dir.join("logo.png"), &dir).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("'logo.png' looks like a binary file"),
+            "{err}"
+        );
+        std::fs::write(dir.join("latin1.txt"), [b'c', b'a', b'f', 0xe9]).unwrap();
+        assert!(Attachment::read(&dir.join("latin1.txt"), &dir).is_err());
+
+        let err = Attachment::read(&dir.join("gone.rs"), &dir).unwrap_err();
+        assert_eq!(err.to_string(), "attachment 'gone.rs' does not exist");
+        let err = Attachment::read(&dir.join("src"), &dir).unwrap_err();
+        assert!(err.to_string().contains("is a directory"), "{err}");
+    }
+
+    #[test]
+    fn files_over_the_cap_are_cut_with_a_marker() {
+        let small = attachment("a.rs", "small\n");
+        let big = attachment("b.rs", &"0123456789\n".repeat(10));
+        let mut files = vec![small.clone(), big];
+        fit(&mut files, 50);
+        // the small file is sent whole and the big one takes what's left, in
+        // whole lines
+        assert_eq!(files[0], small);
+        assert_eq!(files[1].text, "0123456789\n".repeat(4));
+        assert!(files[1].truncated());
+        assert_eq!(
+            files[1].block(),
+            format!(
+                "Attached file b.rs (110 bytes):\n```rust\n{}[... truncated: the first 44 of 110 bytes are shown ...]\n```",
+                "0123456789\n".repeat(4)
+            )
+        );
+
+        // two big files share the cap equally
+        let mut files = vec![
+            attachment("x.txt", &"x".repeat(100)),
+            attachment("y.txt", &"y".repeat(80)),
+        ];
+        fit(&mut files, 60);
+        assert_eq!(files[0].text.len(), 30);
+        assert_eq!(files[1].text.len(), 30);
+
+        // under the cap nothing changes
+        let mut files = vec![small.clone()];
+        fit(&mut files, 100);
+        assert_eq!(files, [small]);
+    }
+
+    #[test]
+    fn attachments_count_towards_the_token_estimate() {
+        let file = attachment("src/main.rs", &"x".repeat(400));
+        let message = with_attachments("explain this", std::slice::from_ref(&file));
+        let estimator = ContextManager::default().estimator;
+        let question = estimator.estimate(&[user_message("explain this")]);
+        assert!(file.tokens() > 100);
+        // the message is estimated with the files in it, give or take rounding
+        assert!(estimator.estimate(&[user_message(&message)]) >= file.tokens() + question - 1);
+    }
+
+    #[test]
+    fn queued_files_go_with_the_next_message_only() {
+        let mut pending = Pending::default();
+        pending.add(attachment("src/main.rs", "old\n"));
+        pending.add(attachment("Cargo.toml", "[package]\n"));
+        // attaching a file again replaces it
+        pending.add(attachment("src/main.rs", "new\n"));
+        assert_eq!(pending.files().len(), 2);
+        assert_eq!(pending.files()[0].text, "new\n");
+
+        let sent = pending.take();
+        assert_eq!(sent.len(), 2);
+        assert!(pending.is_empty());
+        assert!(pending.take().is_empty());
+
+        pending.add(attachment("notes.md", "- a\n"));
+        pending.clear();
+        assert!(pending.take().is_empty());
+    }
+}
@@ -0,0 +1,470 @@
+//! Answering a file of independent prompts, several at a time.
+//!
+//! The input is JSONL, one `{"id": ..., "prompt": ...}` object per line, each
+//! optionally with its own model and sampling parameters. Every prompt gets a
+//! fresh conversation; results are handed back as they complete, in whatever
+//! order that is.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_openai::types::FinishReason;
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+
+use crate::agent::{DeepSeekAgent, DryRun};
+use crate::config::{AgentConfig, RequestParams};
+use crate::error::AgentError;
+use crate::finish::ReplyIssue;
+use crate::metrics::RequestMetrics;
+use crate::settings::{self, Settings};
+use crate::usage::{Budget, TurnUsage};
+
+/// Requests in flight at once unless configured otherwise.
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// One line of the input file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchItem {
+    /// Copied to the result so it can be matched up; a string or a number.
+    pub id: Value,
+    pub prompt: String,
+    pub model: Option<String>,
+    pub system_prompt: Option<String>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+}
+
+impl BatchItem {
+    /// `config` with this item's overrides applied.
+    fn config(&self, config: &AgentConfig) -> AgentConfig {
+        AgentConfig {
+            model: self.model.clone().unwrap_or_else(|| config.model.clone()),
+            system_prompt: self
+                .system_prompt
+                .clone()
+                .unwrap_or_else(|| config.system_prompt.clone()),
+            params: RequestParams {
+                temperature: self.temperature.or(config.params.temperature),
+                top_p: self.top_p.or(config.params.top_p),
+                max_tokens: self.max_tokens.or(config.params.max_tokens),
+                ..config.params.clone()
+            },
+            ..config.clone()
+        }
+    }
+}
+
+/// Parse the input file. Blank lines are skipped; errors name the line.
+pub fn parse_items(text: &str) -> Result<Vec<BatchItem>, AgentError> {
+    let mut items: Vec<BatchItem> = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid =
+            |message: String| AgentError::Input(format!("line {}: {}", index + 1, message));
+        let item: BatchItem = serde_json::from_str(line).map_err(|e| invalid(e.to_string()))?;
+        if !(item.id.is_string() || item.id.is_number()) {
+            return Err(invalid(format!(
+                "id must be a string or a number, got {}",
+                item.id
+            )));
+        }
+        if items.iter().any(|other| other.id == item.id) {
+            return Err(invalid(format!("duplicate id {}", item.id)));
+        }
+        settings::validate(&Settings {
+            temperature: item.temperature,
+            top_p: item.top_p,
+            max_tokens: item.max_tokens,
+            ..Settings::default()
+        })
+        .map_err(invalid)?;
+        items.push(item);
+    }
+    Ok(items)
+}
+
+/// Ids with a successful result in earlier output, so a rerun can skip them.
+///
+/// Failed results don't count, so they are tried again, and neither do
+/// those flagged with an `issue`, like a truncated answer. Lines that aren't
+/// results, like one cut off by a crash, are ignored.
+pub fn completed_ids(output: &str) -> Vec<Value> {
+    output
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|result| result.get("error").is_none_or(Value::is_null))
+        .filter(|result| result.get("issue").is_none_or(Value::is_null))
+        .filter_map(|mut result| result.get_mut("id").map(Value::take))
+        .collect()
+}
+
+/// How one prompt went.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchResult {
+    pub id: Value,
+    pub model: String,
+    /// The answer, or why there isn't one.
+    pub outcome: Result<String, String>,
+    pub finish_reason: Option<FinishReason>,
+    /// Why the answer may not be the whole one, for filtering results and
+    /// trying them again.
+    pub issue: Option<ReplyIssue>,
+    pub usage: Option<TurnUsage>,
+    /// From sending the request to the reply, retries included.
+    pub latency: Duration,
+    /// Whether the answer came from the response cache.
+    pub cached: bool,
+    /// How the answer was received, unless it was cached.
+    pub metrics: Option<RequestMetrics>,
+}
+
+impl BatchResult {
+    /// The output line: `content` on success, `error` on failure, never both.
+    pub fn to_json(&self) -> Value {
+        let usage = self.usage.as_ref().map(|turn| {
+            json!({
+                "prompt_tokens": turn.usage.prompt_tokens,
+                "completion_tokens": turn.usage.completion_tokens,
+                "total_tokens": turn.usage.total_tokens(),
+                "cost": turn.cost,
+            })
+        });
+        let (content, error) = match &self.outcome {
+            Ok(content) => (Some(content.as_str()), None),
+            Err(error) => (None, Some(error.as_str())),
+        };
+        json!({
+            "id": self.id,
+            "model": self.model,
+            "content": content,
+            "finish_reason": self.finish_reason,
+            "issue": self.issue.as_ref().map(ReplyIssue::as_str),
+            "usage": usage,
+            "latency_ms": self.latency.as_millis() as u64,
+            "cached": self.cached,
+            "metrics": self.metrics.as_ref().map(RequestMetrics::to_json),
+            "error": error,
+        })
+    }
+}
+
+/// What the items of a batch have spent between them, for its budget.
+#[derive(Debug, Default)]
+struct Spending {
+    spent: Mutex<f64>,
+    /// Set once the budget is reached, to the estimate of the request it
+    /// stopped, if one did.
+    stopped: Mutex<Option<f64>>,
+}
+
+impl Spending {
+    /// What is left of `budget` for the next item, or `None` if it has run out.
+    fn remaining(&self, budget: Budget) -> Option<Budget> {
+        let mut stopped = self.stopped.lock().expect("lock poisoned");
+        let left = budget.limit - *self.spent.lock().expect("lock poisoned");
+        if stopped.is_none() && left <= 0.0 {
+            *stopped = Some(0.0);
+        }
+        stopped.is_none().then(|| Budget::new(left))
+    }
+
+    fn add(&self, cost: f64) {
+        *self.spent.lock().expect("lock poisoned") += cost;
+    }
+
+    fn stop(&self, estimate: f64) {
+        self.stopped
+            .lock()
+            .expect("lock poisoned")
+            .get_or_insert(estimate);
+    }
+}
+
+/// Answers batch items with at most `concurrency` requests in flight.
+///
+/// Each item is sent with its own agent, which retries transient failures as
+/// the config's retry policy allows. A budget in the config is shared by all
+/// of them.
+pub struct BatchRunner {
+    config: AgentConfig,
+    http_client: reqwest::Client,
+    concurrency: usize,
+}
+
+impl BatchRunner {
+    pub fn new(config: AgentConfig, concurrency: usize) -> Result<Self, AgentError> {
+        let http_client = config.network.client()?;
+        Ok(Self::with_http_client(config, concurrency, http_client))
+    }
+
+    /// Like [`new`](Self::new), with every request going through `http_client`.
+    pub fn with_http_client(
+        config: AgentConfig,
+        concurrency: usize,
+        http_client: reqwest::Client,
+    ) -> Self {
+        Self {
+            config,
+            http_client,
+            concurrency: concurrency.max(1),
+        }
+    }
+
+    /// Answer every item, calling `on_result` as each one completes.
+    ///
+    /// Once the budget is reached no more items are started, though those in
+    /// flight finish; that ends in [`AgentError::BudgetExceeded`] with what
+    /// the batch spent, and the items never started have no result.
+    pub async fn run(
+        &self,
+        items: Vec<BatchItem>,
+        mut on_result: impl FnMut(&BatchResult),
+    ) -> Result<(), AgentError> {
+        let semaphore = Semaphore::new(self.concurrency);
+        let spending = Spending::default();
+        let mut pending: FuturesUnordered<_> = items
+            .into_iter()
+            .map(|item| async {
+                // never closed, so acquiring can't fail
+                let _permit = semaphore.acquire().await.expect("semaphore closed");
+                let budget = match self.config.budget {
+                    Some(budget) => Some(spending.remaining(budget)?),
+                    None => None,
+                };
+                Some(self.answer(item, budget, &spending).await)
+            })
+            .collect();
+        let mut skipped = 0;
+        while let Some(result) = pending.next().await {
+            match result {
+                Some(result) => on_result(&result),
+                None => skipped += 1,
+            }
+        }
+        let stopped = *spending.stopped.lock().expect("lock poisoned");
+        match (self.config.budget, stopped) {
+            (Some(budget), Some(estimate)) => {
+                warn!(skipped, "batch stopped at the budget");
+                Err(AgentError::BudgetExceeded {
+                    spent: *spending.spent.lock().expect("lock poisoned"),
+                    estimate,
+                    limit: budget.limit,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The request that would be sent for `item`, without sending it.
+    pub async fn dry_run(&self, item: &BatchItem) -> Result<DryRun, AgentError> {
+        let agent = DeepSeekAgent::with_http_client(
+            self.item_config(item, None),
+            self.http_client.clone(),
+        )?;
+        agent.dry_run(&item.prompt, false, None).await
+    }
+
+    /// The config `item` is answered with, allowed to spend `budget`.
+    fn item_config(&self, item: &BatchItem, budget: Option<Budget>) -> AgentConfig {
+        AgentConfig {
+            budget,
+            ..item.config(&self.config)
+        }
+    }
+
+    async fn answer(
+        &self,
+        item: BatchItem,
+        budget: Option<Budget>,
+        spending: &Spending,
+    ) -> BatchResult {
+        let config = self.item_config(&item, budget);
+        let model = config.model.clone();
+        let started = Instant::now();
+        let mut reply = match DeepSeekAgent::with_http_client(config, self.http_client.clone()) {
+            Ok(mut agent) => {
+                let reply = agent.ask(&item.prompt).await;
+                spending.add(agent.usage().cost());
+                reply.map(|content| (content, agent))
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(AgentError::BudgetExceeded {
+            spent, estimate, ..
+        }) = &mut reply
+            && let (Some(batch), Some(item)) = (self.config.budget, budget)
+        {
+            // in terms of the whole batch's budget, not what was left of it
+            spending.stop(*estimate);
+            reply = Err(AgentError::BudgetExceeded {
+                spent: *spent + batch.limit - item.limit,
+                estimate: *estimate,
+                limit: batch.limit,
+            });
+        }
+        let latency = started.elapsed();
+        let mut result = BatchResult {
+            id: item.id,
+            model,
+            outcome: Err(String::new()),
+            finish_reason: None,
+            issue: None,
+            usage: None,
+            latency,
+            cached: false,
+            metrics: None,
+        };
+        match reply {
+            Ok((content, agent)) => {
+                debug!(id = %result.id, latency_ms = latency.as_millis() as u64, "batch item answered");
+                result.outcome = Ok(content);
+                result.finish_reason = agent.last_finish_reason();
+                result.issue = agent.last_issue();
+                result.usage = agent.last_usage().cloned();
+                result.cached = agent.last_reply_cached();
+                result.metrics = agent.last_metrics().copied();
+            }
+            Err(e) => {
+                warn!(id = %result.id, error = %e, "batch item failed");
+                result.outcome = Err(e.to_string());
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage::Usage;
+
+    #[test]
+    fn items_parse_with_optional_overrides() {
+        let items = parse_items(
+            r#"{"id": "a", "prompt": "What is ownership?"}
+
+{"id": 2, "prompt": "Is 1013 prime?", "model": "deepseek-reasoner", "temperature": 0.1}
+"#,
+        )
+        .unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].id, json!("a"));
+        assert_eq!(items[0].model, None);
+        assert_eq!(items[1].id, json!(2));
+        assert_eq!(items[1].model.as_deref(), Some("deepseek-reasoner"));
+
+        let base = AgentConfig {
+            params: RequestParams {
+                max_tokens: Some(100),
+                temperature: Some(0.7),
+                ..RequestParams::default()
+            },
+            ..AgentConfig::new("sk-test")
+        };
+        let config = items[1].config(&base);
+        assert_eq!(config.model, "deepseek-reasoner");
+        assert_eq!(config.params.temperature, Some(0.1));
+        assert_eq!(config.params.max_tokens, Some(100));
+        assert_eq!(items[0].config(&base), base);
+    }
+
+    #[test]
+    fn bad_lines_are_named() {
+        for (text, expected) in [
+            ("{\"id\": 1}", "line 1: missing field `prompt`"),
+            (
+                "\n{\"id\": 1, \"prompt\": \"p\", \"colour\": 1}",
+                "line 2: unknown field",
+            ),
+            ("{\"id\": [1], \"prompt\": \"p\"}", "line 1: id must be"),
+            (
+                "{\"id\": 1, \"prompt\": \"p\"}\n{\"id\": 1, \"prompt\": \"q\"}",
+                "line 2: duplicate id 1",
+            ),
+            (
+                "{\"id\": 1, \"prompt\": \"p\", \"top_p\": 3}",
+                "line 1: top_p",
+            ),
+            ("not json", "line 1: expected"),
+        ] {
+            let err = parse_items(text).unwrap_err().to_string();
+            assert!(err.starts_with(expected), "{text:?}: {err}");
+        }
+    }
+
+    #[test]
+    fn results_carry_content_or_error() {
+        let ok = BatchResult {
+            id: json!("a"),
+            model: "deepseek-chat".to_string(),
+            outcome: Ok("Yes.".to_string()),
+            finish_reason: Some(FinishReason::Stop),
+            issue: None,
+            usage: Some(TurnUsage {
+                model: "deepseek-chat".to_string(),
+                usage: Usage {
+                    prompt_tokens: 10,
+                    completion_tokens: 2,
+                },
+                cost: Some(0.5),
+            }),
+            latency: Duration::from_millis(1500),
+            cached: false,
+            metrics: Some(RequestMetrics {
+                latency: Duration::from_millis(1400),
+                completion_tokens: Some(2),
+                requests: 1,
+                ..RequestMetrics::default()
+            }),
+        };
+        let json = ok.to_json();
+        assert_eq!(json["content"], "Yes.");
+        assert_eq!(json["error"], Value::Null);
+        assert_eq!(json["usage"]["total_tokens"], 12);
+        assert_eq!(json["latency_ms"], 1500);
+        assert_eq!(json["finish_reason"], "stop");
+        assert_eq!(json["cached"], false);
+        assert_eq!(json["issue"], Value::Null);
+        assert_eq!(json["metrics"]["latency_ms"], 1400);
+        assert_eq!(json["metrics"]["retries"], 0);
+
+        let failed = BatchResult {
+            outcome: Err("API request failed: boom".to_string()),
+            usage: None,
+            finish_reason: None,
+            metrics: None,
+            ..ok.clone()
+        };
+        let json = failed.to_json();
+        assert_eq!(json["content"], Value::Null);
+        assert_eq!(json["error"], "API request failed: boom");
+        assert_eq!(json["id"], "a");
+        assert_eq!(json["metrics"], Value::Null);
+
+        let truncated = BatchResult {
+            finish_reason: Some(FinishReason::Length),
+            issue: Some(ReplyIssue::Truncated),
+            ..ok
+        };
+        assert_eq!(truncated.to_json()["issue"], "truncated");
+    }
+
+    #[test]
+    fn only_successes_count_as_completed() {
+        let output = r#"{"id": "a", "content": "x", "error": null, "issue": null}
+{"id": 2, "content": null, "error": "API request failed"}
+{"id": "long", "content": "Owner", "issue": "truncated"}
+{"id": 3, "content": "y"}
+{"id": "cut off", "cont"#;
+        assert_eq!(completed_ids(output), [json!("a"), json!(3)]);
+    }
+}
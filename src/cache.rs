@@ -0,0 +1,313 @@
+//! Replies kept on disk, so sending the same request again costs nothing.
+//!
+//! Entries are keyed by a hash of the request body, which covers the model,
+//! messages, sampling parameters and tools; whether it was streamed doesn't
+//! count. Each entry is one JSON file holding the completion as the API sent
+//! it, or as it was put together from a stream.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use crate::chat::{self, Reply};
+use crate::error::AgentError;
+use crate::settings;
+
+/// How long a reply is reused unless configured otherwise.
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The cache directory, relative to the user's cache directory.
+pub const CACHE_DIR: &str = "deepseek_agent/responses";
+
+/// Bumped when the key or entry format changes, so old entries are missed
+/// rather than misread.
+const KEY_VERSION: &str = "1";
+
+/// Where entries go unless configured otherwise: under `$XDG_CACHE_HOME`, or `~/.cache`.
+pub fn default_cache_dir(xdg_cache_home: Option<&str>, home: Option<&str>) -> Option<PathBuf> {
+    Some(settings::cache_home(xdg_cache_home, home)?.join(CACHE_DIR))
+}
+
+/// A directory of cached replies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseCache {
+    pub dir: PathBuf,
+    /// Entries older than this are fetched again.
+    pub ttl: Duration,
+    /// Never read entries, only write them, so every request is sent again
+    /// and its reply replaces the cached one.
+    pub refresh: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    created_at: DateTime<Utc>,
+    response: Value,
+}
+
+impl ResponseCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            ttl: DEFAULT_CACHE_TTL,
+            refresh: false,
+        }
+    }
+
+    /// The key of a request body: a hex SHA-256 of it without the fields
+    /// that only say how the reply is delivered.
+    pub fn key(request: &Value) -> String {
+        let mut request = request.clone();
+        if let Some(fields) = request.as_object_mut() {
+            fields.remove("stream");
+            fields.remove("stream_options");
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(KEY_VERSION.as_bytes());
+        // serde_json sorts object keys, so equal bodies serialize the same
+        hasher.update(request.to_string().as_bytes());
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// The cached reply for `key`, marked as cached and without usage, as it
+    /// cost nothing. Missing, expired and unreadable entries are misses;
+    /// unreadable ones are removed.
+    pub fn get(&self, key: &str) -> Option<Reply> {
+        self.get_at(key, Utc::now())
+    }
+
+    fn get_at(&self, key: &str, now: DateTime<Utc>) -> Option<Reply> {
+        if self.refresh {
+            return None;
+        }
+        let path = self.path(key);
+        let text = std::fs::read_to_string(&path).ok()?;
+        let entry = serde_json::from_str::<Entry>(&text)
+            .map_err(|e| e.to_string())
+            .and_then(|entry| {
+                let reply = chat::parse_response(entry.response).map_err(|e| e.to_string())?;
+                Ok((entry.created_at, reply))
+            });
+        let (created_at, mut reply) = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "removing unreadable cache entry");
+                let _ = std::fs::remove_file(&path);
+                return None;
+            }
+        };
+        let fresh = (now - created_at).to_std().is_ok_and(|age| age < self.ttl);
+        if !fresh {
+            debug!(key, "cache entry expired");
+            return None;
+        }
+        debug!(key, "cache hit");
+        reply.cached = true;
+        reply.usage = None;
+        Some(reply)
+    }
+
+    /// Store `response`, a chat completion, under `key`.
+    ///
+    /// The entry is written to a file of its own and renamed into place, so
+    /// concurrent writers, like a batch, never leave a half-written entry; the
+    /// last one wins.
+    pub fn put(&self, key: &str, response: &Value) -> Result<(), AgentError> {
+        static WRITES: AtomicUsize = AtomicUsize::new(0);
+        std::fs::create_dir_all(&self.dir)?;
+        let entry = Entry {
+            created_at: Utc::now(),
+            response: response.clone(),
+        };
+        let partial = self.dir.join(format!(
+            ".{}.{}.{}.partial",
+            key,
+            std::process::id(),
+            WRITES.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(
+            &partial,
+            serde_json::to_string(&entry).expect("entries always serialize"),
+        )?;
+        std::fs::rename(&partial, self.path(key)).inspect_err(|_| {
+            let _ = std::fs::remove_file(&partial);
+        })?;
+        Ok(())
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+/// A chat completion carrying `reply`, for caching a streamed one.
+pub fn completion_json(reply: &Reply) -> Value {
+    let mut message = json!({ "role": "assistant", "content": reply.content });
+    if let Some(reasoning) = &reply.reasoning {
+        message["reasoning_content"] = reasoning.as_str().into();
+    }
+    if let Some(refusal) = &reply.refusal {
+        message["refusal"] = refusal.as_str().into();
+    }
+    if !reply.tool_calls.is_empty() {
+        message["tool_calls"] = json!(reply.tool_calls);
+    }
+    let usage = reply.usage.map(|usage| {
+        json!({
+            "prompt_tokens": usage.prompt_tokens,
+            "completion_tokens": usage.completion_tokens,
+            "total_tokens": usage.total_tokens(),
+        })
+    });
+    json!({
+        "object": "chat.completion",
+        "choices": [{ "index": 0, "message": message, "finish_reason": reply.finish_reason }],
+        "usage": usage,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage::Usage;
+    use async_openai::types::FinishReason;
+
+    fn temp_cache(name: &str) -> ResponseCache {
+        let dir =
+            std::env::temp_dir().join(format!("deepseek_cache_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        ResponseCache::new(dir)
+    }
+
+    fn request(prompt: &str) -> Value {
+        json!({
+            "model": "deepseek-chat",
+            "messages": [{ "role": "user", "content": prompt }],
+            "temperature": 0.2,
+        })
+    }
+
+    fn reply(content: &str) -> Reply {
+        Reply {
+            content: content.to_string(),
+            reasoning: Some("thought".to_string()),
+            finish_reason: Some(FinishReason::Stop),
+            usage: Some(Usage {
+                prompt_tokens: 10,
+                completion_tokens: 3,
+            }),
+            ..Reply::default()
+        }
+    }
+
+    #[test]
+    fn keys_are_stable_and_ignore_streaming() {
+        let key = ResponseCache::key(&request("hi"));
+        // pinned, so a change to the key misses old entries on purpose, via KEY_VERSION
+        assert_eq!(
+            key,
+            "48103450f41888cb8f30c82b32056576680e21a4e43f16289ba4d18f8792ef82"
+        );
+
+        // field order doesn't matter, and neither does streaming
+        let reordered: Value = serde_json::from_str(
+            r#"{"stream": true, "stream_options": {"include_usage": true}, "temperature": 0.2,
+                "messages": [{"content": "hi", "role": "user"}], "model": "deepseek-chat"}"#,
+        )
+        .unwrap();
+        assert_eq!(ResponseCache::key(&reordered), key);
+
+        assert_ne!(ResponseCache::key(&request("hello")), key);
+        let mut hotter = request("hi");
+        hotter["temperature"] = json!(0.9);
+        assert_ne!(ResponseCache::key(&hotter), key);
+        let mut with_tools = request("hi");
+        with_tools["tools"] = json!([{ "type": "function", "function": { "name": "t" } }]);
+        assert_ne!(ResponseCache::key(&with_tools), key);
+    }
+
+    #[test]
+    fn hits_are_marked_and_cost_nothing() {
+        let cache = temp_cache("hits");
+        let key = ResponseCache::key(&request("hi"));
+        assert_eq!(cache.get(&key), None);
+
+        cache.put(&key, &completion_json(&reply("Hello!"))).unwrap();
+        let hit = cache.get(&key).unwrap();
+        assert_eq!(hit.content, "Hello!");
+        assert_eq!(hit.reasoning.as_deref(), Some("thought"));
+        assert_eq!(hit.finish_reason, Some(FinishReason::Stop));
+        assert!(hit.cached);
+        assert_eq!(hit.usage, None);
+
+        let refreshing = ResponseCache {
+            refresh: true,
+            ..cache.clone()
+        };
+        assert_eq!(refreshing.get(&key), None);
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn entries_expire_after_the_ttl() {
+        let cache = ResponseCache {
+            ttl: Duration::from_secs(60),
+            ..temp_cache("ttl")
+        };
+        let key = ResponseCache::key(&request("hi"));
+        cache.put(&key, &completion_json(&reply("Hello!"))).unwrap();
+
+        let soon = Utc::now() + chrono::Duration::seconds(30);
+        assert!(cache.get_at(&key, soon).is_some());
+        let later = Utc::now() + chrono::Duration::seconds(61);
+        assert!(cache.get_at(&key, later).is_none());
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_entries_are_misses_and_removed() {
+        let cache = temp_cache("corrupt");
+        let key = ResponseCache::key(&request("hi"));
+        std::fs::create_dir_all(&cache.dir).unwrap();
+        for garbage in [
+            "{ \"created_at\": \"2024-01-0",
+            r#"{"created_at": "2024-01-01T00:00:00Z", "response": {"choices": "nope"}}"#,
+        ] {
+            std::fs::write(cache.path(&key), garbage).unwrap();
+            assert_eq!(cache.get(&key), None);
+            assert!(!cache.path(&key).exists());
+        }
+
+        // and the next reply is cached as usual
+        cache.put(&key, &completion_json(&reply("Hello!"))).unwrap();
+        assert!(cache.get(&key).is_some());
+        let entries: Vec<_> = std::fs::read_dir(&cache.dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(entries, [cache.path(&key)], "no partial files left");
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn cache_dir_follows_xdg() {
+        assert_eq!(
+            default_cache_dir(Some("/xdg"), None),
+            Some(PathBuf::from("/xdg/deepseek_agent/responses"))
+        );
+        assert_eq!(
+            default_cache_dir(None, Some("/home/me")),
+            Some(PathBuf::from("/home/me/.cache/deepseek_agent/responses"))
+        );
+    }
+}
@@ -0,0 +1,654 @@
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessage,
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
+    ChatCompletionRequestToolMessage, ChatCompletionRequestUserMessage,
+    ChatCompletionStreamOptions, ChatCompletionTool, CreateChatCompletionRequest,
+    CreateChatCompletionRequestArgs, CreateChatCompletionResponse, FinishReason, ResponseFormat,
+    Stop,
+};
+use serde_json::{Map, Value};
+use tracing::{debug, trace, warn};
+
+use crate::config::RequestParams;
+use crate::conversation::describe;
+use crate::error::AgentError;
+use crate::metrics::RequestMetrics;
+use crate::usage::Usage;
+
+/// Most stop sequences a request may carry.
+pub const MAX_STOP_SEQUENCES: usize = 4;
+
+/// Model used when none is configured.
+pub const DEFAULT_MODEL: &str = "deepseek-chat";
+
+/// System prompt used when `SYSTEM_PROMPT` is not set.
+pub const DEFAULT_SYSTEM_PROMPT: &str =
+    "You are a patient, knowledgeable tutor. Explain things clearly and concisely.";
+
+/// Pick the system prompt from an optional override.
+pub fn resolve_system_prompt(system_prompt: Option<&str>) -> String {
+    match system_prompt.map(str::trim) {
+        Some(prompt) if !prompt.is_empty() => prompt.to_string(),
+        _ => DEFAULT_SYSTEM_PROMPT.to_string(),
+    }
+}
+
+pub fn system_message(content: &str) -> ChatCompletionRequestMessage {
+    ChatCompletionRequestSystemMessage::from(content).into()
+}
+
+pub fn user_message(content: &str) -> ChatCompletionRequestMessage {
+    ChatCompletionRequestUserMessage::from(content).into()
+}
+
+/// The start of an answer for the model to continue. It goes last in the
+/// request, which [`build_request`] marks as a prefix completion.
+pub fn prefill_message(content: &str) -> ChatCompletionRequestMessage {
+    ChatCompletionRequestAssistantMessage::from(content).into()
+}
+
+/// An assistant turn that asks for tools to be run, with any text that came with it.
+pub fn tool_calls_message(
+    content: &str,
+    tool_calls: Vec<ChatCompletionMessageToolCall>,
+) -> ChatCompletionRequestMessage {
+    ChatCompletionRequestAssistantMessage {
+        content: (!content.is_empty()).then(|| content.into()),
+        tool_calls: Some(tool_calls),
+        ..Default::default()
+    }
+    .into()
+}
+
+/// The result of the tool call with id `tool_call_id`.
+pub fn tool_message(tool_call_id: &str, content: &str) -> ChatCompletionRequestMessage {
+    ChatCompletionRequestToolMessage {
+        content: content.into(),
+        tool_call_id: tool_call_id.to_string(),
+    }
+    .into()
+}
+
+/// The text of a chat reply, why and whether it was cut off by the token
+/// limit, any tools the model wants run before it answers, and the tokens it took.
+///
+/// [`finish::classify`](crate::finish::classify) tells whether it is the whole answer.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Reply {
+    pub content: String,
+    /// The reasoning a model like deepseek-reasoner did before answering. It is
+    /// shown to the user but never sent back in the history.
+    pub reasoning: Option<String>,
+    pub finish_reason: Option<FinishReason>,
+    pub truncated: bool,
+    /// Why the model declined to answer, if it says so apart from the content.
+    pub refusal: Option<String>,
+    pub tool_calls: Vec<ChatCompletionMessageToolCall>,
+    pub usage: Option<Usage>,
+    /// Whether it came from the response cache rather than the API.
+    pub cached: bool,
+    /// How long it took to arrive; `None` if it came from the cache.
+    pub metrics: Option<RequestMetrics>,
+}
+
+/// Fail unless `stop` is within [`MAX_STOP_SEQUENCES`].
+pub fn check_stop_sequences(stop: &[String]) -> Result<(), AgentError> {
+    if stop.len() > MAX_STOP_SEQUENCES {
+        return Err(AgentError::InvalidConfig(format!(
+            "at most {} stop sequences are allowed, got {}",
+            MAX_STOP_SEQUENCES,
+            stop.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Build the body of a chat completion request for `messages`, offering
+/// `tools` if there are any.
+///
+/// Messages ending in an assistant turn ask the model to continue it, which
+/// DeepSeek needs flagged with `"prefix": true`; async-openai's types have no
+/// such field, so the body is returned as JSON.
+pub fn build_request(
+    model: &str,
+    params: &RequestParams,
+    messages: Vec<ChatCompletionRequestMessage>,
+    tools: &[ChatCompletionTool],
+    stream: bool,
+) -> Result<Value, AgentError> {
+    let _span = tracing::debug_span!("build_request").entered();
+    debug!(
+        model = %model,
+        temperature = ?params.temperature,
+        top_p = ?params.top_p,
+        max_tokens = ?params.max_tokens,
+        stop = ?params.stop,
+        json_mode = params.json_mode,
+        messages = messages.len(),
+        prompt_tokens_estimate = estimate_tokens(&messages),
+        tools = tools.len(),
+        stream,
+        "building request"
+    );
+    let prefix = matches!(
+        messages.last(),
+        Some(ChatCompletionRequestMessage::Assistant(_))
+    );
+    let mut args = CreateChatCompletionRequestArgs::default();
+    args.model(model).messages(messages);
+    if !tools.is_empty() {
+        args.tools(tools.to_vec());
+    }
+    if stream {
+        // streamed replies only report usage when asked to
+        args.stream(true)
+            .stream_options(ChatCompletionStreamOptions {
+                include_usage: true,
+            });
+    }
+    if let Some(temperature) = params.temperature {
+        args.temperature(temperature);
+    }
+    if let Some(top_p) = params.top_p {
+        args.top_p(top_p);
+    }
+    if let Some(max_tokens) = params.max_tokens {
+        // DeepSeek still reads `max_tokens`, not `max_completion_tokens`
+        #[allow(deprecated)]
+        args.max_tokens(max_tokens);
+    }
+    if !params.stop.is_empty() {
+        args.stop(Stop::StringArray(params.stop.clone()));
+    }
+    if params.json_mode {
+        args.response_format(ResponseFormat::JsonObject);
+    }
+    let request: CreateChatCompletionRequest = args.build()?;
+    // plain structs and strings, which always serialize
+    let mut body = serde_json::to_value(request).expect("chat requests serialize");
+    if prefix && let Some(last) = body["messages"].as_array_mut().and_then(|m| m.last_mut()) {
+        last["prefix"] = Value::Bool(true);
+    }
+    for field in merge_extra_body(&mut body, &params.extra_body) {
+        warn!(
+            field,
+            "the --extra-body value replaces the one the request sets"
+        );
+    }
+    Ok(body)
+}
+
+/// Add the `extra` fields to the request `body`, replacing those it has, and
+/// return the ones replaced.
+pub fn merge_extra_body(body: &mut Value, extra: &Map<String, Value>) -> Vec<String> {
+    let Some(fields) = body.as_object_mut() else {
+        return Vec::new();
+    };
+    extra
+        .iter()
+        .filter_map(|(key, value)| {
+            fields
+                .insert(key.clone(), value.clone())
+                .map(|_| key.clone())
+        })
+        .collect()
+}
+
+/// Finish reasons async-openai's `FinishReason` can hold.
+const KNOWN_FINISH_REASONS: [&str; 5] = [
+    "stop",
+    "length",
+    "tool_calls",
+    "content_filter",
+    "function_call",
+];
+
+/// Smooth over the ways DeepSeek and other OpenAI-compatible servers differ
+/// from OpenAI, so a completion or stream chunk deserializes into
+/// async-openai's types. Missing `id`, `object`, `created`, `model` and choice
+/// `index` fields are filled in, and finish reasons OpenAI doesn't define, like
+/// DeepSeek's `insufficient_system_resource`, become `null`.
+pub fn normalize_response(raw: &mut Value, object: &str) {
+    let Some(fields) = raw.as_object_mut() else {
+        return;
+    };
+    for (key, default) in [
+        ("id", Value::from("")),
+        ("object", Value::from(object)),
+        ("created", Value::from(0)),
+        ("model", Value::from("")),
+    ] {
+        if fields.get(key).is_none_or(Value::is_null) {
+            fields.insert(key.to_string(), default);
+        }
+    }
+    let Some(choices) = fields.get_mut("choices").and_then(Value::as_array_mut) else {
+        return;
+    };
+    for (index, choice) in choices.iter_mut().enumerate() {
+        let Some(choice) = choice.as_object_mut() else {
+            continue;
+        };
+        choice.entry("index").or_insert_with(|| index.into());
+        if let Some(reason) = choice.get("finish_reason").and_then(Value::as_str)
+            && !KNOWN_FINISH_REASONS.contains(&reason)
+        {
+            warn!(finish_reason = reason, "ignoring unknown finish reason");
+            choice.insert("finish_reason".to_string(), Value::Null);
+        }
+    }
+}
+
+/// Parse a raw chat completion response, keeping the `reasoning_content` that
+/// DeepSeek's reasoner adds and async-openai's types drop.
+pub fn parse_response(mut raw: Value) -> Result<Reply, AgentError> {
+    normalize_response(&mut raw, "chat.completion");
+    let reasoning = reasoning_content(&raw["choices"][0]["message"]).map(str::to_string);
+    let response = serde_json::from_value(raw).map_err(OpenAIError::JSONDeserialize)?;
+    let mut reply = extract_reply(response)?;
+    reply.reasoning = reasoning;
+    Ok(reply)
+}
+
+/// The `reasoning_content` of a message or stream delta, if it has any.
+pub fn reasoning_content(message: &Value) -> Option<&str> {
+    message["reasoning_content"]
+        .as_str()
+        .filter(|reasoning| !reasoning.is_empty())
+}
+
+/// Pull the assistant text, or the tool calls, out of a chat completion response.
+pub fn extract_reply(response: CreateChatCompletionResponse) -> Result<Reply, AgentError> {
+    let _span = tracing::debug_span!("parse_response").entered();
+    trace!(
+        id = %response.id,
+        model = %response.model,
+        created = response.created,
+        system_fingerprint = ?response.system_fingerprint,
+        choices = response.choices.len(),
+        finish_reason = ?response.choices.first().and_then(|c| c.finish_reason),
+        usage = ?response.usage,
+        "response received"
+    );
+    let usage = response.usage.as_ref().map(Usage::from);
+    let Some(choice) = response.choices.into_iter().next() else {
+        return Err(AgentError::EmptyResponse("no choices"));
+    };
+    let tool_calls = choice.message.tool_calls.unwrap_or_default();
+    let refusal = choice.message.refusal.filter(|refusal| !refusal.is_empty());
+    // a filtered or refused reply has no content, and is classified instead
+    let withheld = refusal.is_some() || choice.finish_reason == Some(FinishReason::ContentFilter);
+    let content = match choice.message.content {
+        Some(content) => content,
+        None if !tool_calls.is_empty() || withheld => String::new(),
+        None => return Err(AgentError::EmptyResponse("message without content")),
+    };
+
+    Ok(Reply {
+        content,
+        reasoning: None,
+        finish_reason: choice.finish_reason,
+        truncated: choice.finish_reason == Some(FinishReason::Length),
+        refusal,
+        tool_calls,
+        usage,
+        cached: false,
+        metrics: None,
+    })
+}
+
+/// Rough token count of `messages` at four characters a token, for logs.
+pub fn estimate_tokens(messages: &[ChatCompletionRequestMessage]) -> usize {
+    let chars: usize = messages
+        .iter()
+        .map(|message| describe(message).1.chars().count())
+        .sum();
+    chars.div_ceil(4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn response(choices: serde_json::Value) -> CreateChatCompletionResponse {
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1700000000,
+            "model": "deepseek-chat",
+            "choices": choices,
+            "usage": null
+        }))
+        .unwrap()
+    }
+
+    fn choice(content: serde_json::Value, finish_reason: &str) -> serde_json::Value {
+        json!({
+            "index": 0,
+            "message": { "role": "assistant", "content": content },
+            "finish_reason": finish_reason,
+            "logprobs": null
+        })
+    }
+
+    #[test]
+    fn extracts_content_from_first_choice() {
+        let reply = extract_reply(response(json!([choice(json!("Hi there"), "stop")]))).unwrap();
+        assert_eq!(reply.content, "Hi there");
+        assert!(!reply.truncated);
+        assert_eq!(reply.usage, None);
+    }
+
+    #[test]
+    fn usage_is_carried_over() {
+        let mut body =
+            serde_json::to_value(response(json!([choice(json!("Hi"), "stop")]))).unwrap();
+        body["usage"] = json!({ "prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15 });
+        let reply = extract_reply(serde_json::from_value(body).unwrap()).unwrap();
+        assert_eq!(
+            reply.usage,
+            Some(Usage {
+                prompt_tokens: 12,
+                completion_tokens: 3
+            })
+        );
+    }
+
+    #[test]
+    fn tool_calls_are_extracted_without_content() {
+        let reply = extract_reply(response(json!([{
+            "index": 0,
+            "message": {
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "calculator", "arguments": "{\"expression\":\"2+2\"}" }
+                }]
+            },
+            "finish_reason": "tool_calls",
+            "logprobs": null
+        }])))
+        .unwrap();
+        assert_eq!(reply.content, "");
+        assert_eq!(reply.tool_calls.len(), 1);
+        assert_eq!(reply.tool_calls[0].function.name, "calculator");
+    }
+
+    #[test]
+    fn length_finish_reason_marks_truncated() {
+        let reply = extract_reply(response(json!([choice(json!("Once upon"), "length")]))).unwrap();
+        assert_eq!(reply.content, "Once upon");
+        assert_eq!(reply.finish_reason, Some(FinishReason::Length));
+        assert!(reply.truncated);
+    }
+
+    #[test]
+    fn reasoning_is_kept_apart_from_the_answer() {
+        let raw =
+            serde_json::from_str(include_str!("../tests/fixtures/reasoner_response.json")).unwrap();
+        let reply = parse_response(raw).unwrap();
+        assert_eq!(reply.content, "1013 is prime.");
+        assert!(
+            reply
+                .reasoning
+                .as_deref()
+                .unwrap()
+                .starts_with("Check divisibility")
+        );
+        assert_eq!(
+            reply.usage,
+            Some(Usage {
+                prompt_tokens: 14,
+                completion_tokens: 96
+            })
+        );
+    }
+
+    #[test]
+    fn replies_without_reasoning_parse_the_same() {
+        let raw = json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1700000000,
+            "model": "deepseek-chat",
+            "choices": [choice(json!("Hi"), "stop")]
+        });
+        let reply = parse_response(raw).unwrap();
+        assert_eq!(reply.content, "Hi");
+        assert_eq!(reply.reasoning, None);
+
+        let err = parse_response(json!({ "choices": "nope" })).unwrap_err();
+        assert!(err.to_string().contains("deserialize"), "{err}");
+    }
+
+    fn fixture(json: &str) -> serde_json::Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn deepseek_extras_are_ignored() {
+        let reply = parse_response(fixture(include_str!(
+            "../tests/fixtures/deepseek_response.json"
+        )))
+        .unwrap();
+        assert!(reply.content.starts_with("Ownership"));
+        assert_eq!(reply.finish_reason, Some(FinishReason::Stop));
+        assert_eq!(reply.usage.unwrap().prompt_tokens, 24);
+    }
+
+    #[test]
+    fn unknown_finish_reasons_are_dropped() {
+        let reply = parse_response(fixture(include_str!(
+            "../tests/fixtures/insufficient_resource.json"
+        )))
+        .unwrap();
+        assert_eq!(reply.content, "The borrow checker enforces");
+        assert_eq!(reply.finish_reason, None);
+        assert!(!reply.truncated);
+    }
+
+    #[test]
+    fn missing_metadata_is_filled_in() {
+        let reply = parse_response(fixture(include_str!(
+            "../tests/fixtures/sparse_response.json"
+        )))
+        .unwrap();
+        assert!(reply.content.starts_with("Lifetimes"));
+        assert_eq!(reply.usage, None);
+
+        let mut chunk = json!({ "choices": [{ "delta": { "content": "x" } }] });
+        normalize_response(&mut chunk, "chat.completion.chunk");
+        assert_eq!(chunk["object"], "chat.completion.chunk");
+        assert_eq!(chunk["choices"][0]["index"], 0);
+
+        let mut not_an_object = json!("oops");
+        normalize_response(&mut not_an_object, "chat.completion");
+        assert_eq!(not_an_object, "oops");
+    }
+
+    #[test]
+    fn token_estimate_counts_every_message() {
+        let messages = [system_message("abcd"), user_message("efghi")];
+        assert_eq!(estimate_tokens(&messages), 3);
+        assert_eq!(estimate_tokens(&[]), 0);
+    }
+
+    #[test]
+    fn empty_choices_is_an_error() {
+        let err = extract_reply(response(json!([]))).unwrap_err();
+        assert!(err.to_string().contains("no choices"));
+    }
+
+    #[test]
+    fn missing_content_is_an_error() {
+        let err = extract_reply(response(json!([choice(json!(null), "stop")]))).unwrap_err();
+        assert!(err.to_string().contains("without content"));
+    }
+
+    #[test]
+    fn system_prompt_falls_back_to_default() {
+        assert_eq!(resolve_system_prompt(None), DEFAULT_SYSTEM_PROMPT);
+        assert_eq!(resolve_system_prompt(Some("")), DEFAULT_SYSTEM_PROMPT);
+        assert_eq!(resolve_system_prompt(Some("Be terse.")), "Be terse.");
+    }
+
+    #[test]
+    fn request_carries_params() {
+        let params = RequestParams {
+            temperature: Some(0.2),
+            top_p: Some(0.9),
+            max_tokens: Some(64),
+            stop: vec!["\n\n".to_string()],
+            json_mode: true,
+            extra_body: Map::new(),
+        };
+        let request = build_request(
+            "deepseek-chat",
+            &params,
+            vec![user_message("hi")],
+            &[],
+            false,
+        )
+        .unwrap();
+        let body = serde_json::to_value(&request).unwrap();
+        assert_eq!(body["model"], "deepseek-chat");
+        assert_eq!(body["max_tokens"], 64);
+        assert!((body["temperature"].as_f64().unwrap() - 0.2).abs() < 1e-6);
+        assert!((body["top_p"].as_f64().unwrap() - 0.9).abs() < 1e-6);
+        assert_eq!(body["stop"], json!(["\n\n"]));
+        assert_eq!(body["response_format"], json!({ "type": "json_object" }));
+        assert!(body.get("stream").is_none());
+    }
+
+    #[test]
+    fn default_params_are_omitted() {
+        let request = build_request(
+            "deepseek-chat",
+            &RequestParams::default(),
+            vec![],
+            &[],
+            true,
+        )
+        .unwrap();
+        let body = serde_json::to_value(&request).unwrap();
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["stream_options"]["include_usage"], true);
+        assert!(body.get("tools").is_none());
+        assert!(body.get("temperature").is_none());
+        assert!(body.get("top_p").is_none());
+        assert!(body.get("max_tokens").is_none());
+        assert!(body.get("stop").is_none());
+        assert!(body.get("response_format").is_none());
+    }
+
+    #[test]
+    fn extra_body_fields_are_merged_in_and_win() {
+        let params = RequestParams {
+            temperature: Some(0.2),
+            max_tokens: Some(64),
+            extra_body: json!({ "logprobs": true, "top_logprobs": 2, "temperature": 0.7 })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ..RequestParams::default()
+        };
+        let body = build_request(
+            "deepseek-chat",
+            &params,
+            vec![user_message("hi")],
+            &[],
+            true,
+        )
+        .unwrap();
+        assert_eq!(body["logprobs"], true);
+        assert_eq!(body["top_logprobs"], 2);
+        assert_eq!(body["temperature"], 0.7);
+        // the typed fields are all still there
+        assert_eq!(body["model"], "deepseek-chat");
+        assert_eq!(body["max_tokens"], 64);
+        assert_eq!(body["messages"][0]["content"], "hi");
+        assert_eq!(body["stream_options"]["include_usage"], true);
+
+        let mut body = json!({ "model": "deepseek-chat", "stream": false });
+        let extra = json!({ "stream": true, "thinking": { "type": "enabled" } });
+        let replaced = merge_extra_body(&mut body, extra.as_object().unwrap());
+        assert_eq!(replaced, ["stream"]);
+        assert_eq!(
+            body,
+            json!({ "model": "deepseek-chat", "stream": true, "thinking": { "type": "enabled" } })
+        );
+    }
+
+    #[test]
+    fn a_trailing_assistant_turn_is_a_prefix() {
+        let body = build_request(
+            "deepseek-chat",
+            &RequestParams::default(),
+            vec![
+                user_message("List three primes"),
+                prefill_message("```json\n"),
+            ],
+            &[],
+            false,
+        )
+        .unwrap();
+        let last = &body["messages"][1];
+        assert_eq!(last["role"], "assistant");
+        assert_eq!(last["content"], "```json\n");
+        assert_eq!(last["prefix"], true);
+
+        let body = build_request(
+            "deepseek-chat",
+            &RequestParams::default(),
+            vec![user_message("hi")],
+            &[],
+            false,
+        )
+        .unwrap();
+        assert!(body["messages"][0].get("prefix").is_none());
+    }
+
+    #[test]
+    fn at_most_four_stop_sequences() {
+        let stop: Vec<String> = (0..5).map(|i| i.to_string()).collect();
+        assert!(check_stop_sequences(&stop[..4]).is_ok());
+        let err = check_stop_sequences(&stop).unwrap_err();
+        assert!(
+            err.to_string().contains("at most 4 stop sequences"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn messages_carry_their_roles() {
+        let system = serde_json::to_value(system_message("rules")).unwrap();
+        let user = serde_json::to_value(user_message("question")).unwrap();
+        assert_eq!(system["role"], "system");
+        assert_eq!(system["content"], "rules");
+        assert_eq!(user["role"], "user");
+        assert_eq!(user["content"], "question");
+    }
+
+    #[test]
+    fn tool_turns_serialize_for_the_api() {
+        let call: ChatCompletionMessageToolCall = serde_json::from_value(json!({
+            "id": "call_1",
+            "type": "function",
+            "function": { "name": "current_time", "arguments": "{}" }
+        }))
+        .unwrap();
+        let assistant = serde_json::to_value(tool_calls_message("", vec![call])).unwrap();
+        assert_eq!(assistant["role"], "assistant");
+        assert!(assistant.get("content").is_none());
+        assert_eq!(assistant["tool_calls"][0]["id"], "call_1");
+
+        let result = serde_json::to_value(tool_message("call_1", "12:00")).unwrap();
+        assert_eq!(result["role"], "tool");
+        assert_eq!(result["tool_call_id"], "call_1");
+        assert_eq!(result["content"], "12:00");
+    }
+}
@@ -0,0 +1,1897 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::parser::ValueSource;
+use clap::{ArgGroup, ArgMatches, Parser, Subcommand, ValueEnum};
+use deepseek_tutor::attach::DEFAULT_MAX_ATTACH_BYTES;
+use deepseek_tutor::batch::DEFAULT_CONCURRENCY;
+use deepseek_tutor::cache::{DEFAULT_CACHE_TTL, ResponseCache};
+use deepseek_tutor::chat::{self, DEFAULT_MODEL, resolve_system_prompt};
+use deepseek_tutor::config::{DEFAULT_TIMEOUT, RequestParams};
+use deepseek_tutor::context::{
+    ContextManager, DEFAULT_CONTEXT_BUDGET, DEFAULT_KEEP_TURNS, TrimStrategy,
+};
+use deepseek_tutor::embeddings::{
+    DEFAULT_CHUNK_OVERLAP, DEFAULT_CHUNK_SIZE, DEFAULT_EMBEDDING_MODEL, DEFAULT_TOP_K,
+};
+use deepseek_tutor::input::{self, DEFAULT_MAX_INPUT_BYTES};
+use deepseek_tutor::models::ModelInfo;
+use deepseek_tutor::network::NetworkConfig;
+use deepseek_tutor::provider::{Provider, ProviderKind};
+use deepseek_tutor::ratelimit::{RateLimiter, RateLimits};
+use deepseek_tutor::retry::RetryPolicy;
+use deepseek_tutor::settings::{ConfigFile, Settings, Source};
+use deepseek_tutor::templates::Template;
+use deepseek_tutor::tools::{DEFAULT_MAX_TOOL_ITERATIONS, FetchConfig, ShellConfig};
+use deepseek_tutor::transcript::TranscriptFormat;
+use deepseek_tutor::usage::{Budget, ModelPrice};
+use deepseek_tutor::{AgentConfig, AgentError, SecretString};
+use serde_json::Value;
+
+use crate::repl::FanOut;
+
+/// Chat with DeepSeek (or any OpenAI-compatible API) from the terminal.
+///
+/// With a prompt the answer is printed and the program exits; without one an
+/// interactive session starts. Flags take precedence over environment
+/// variables, then the config file, then the built-in defaults.
+#[derive(Debug, Parser)]
+#[command(name = "deepseek_agent", version, about, long_about)]
+#[command(group(ArgGroup::new("approvable").args(["shell", "files"]).multiple(true)))]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Prompt to send; starts the interactive session when omitted
+    #[arg(value_name = "PROMPT", conflicts_with = "prompt")]
+    pub positional_prompt: Option<String>,
+
+    /// Prompt to send (same as the positional argument)
+    #[arg(short, long)]
+    pub prompt: Option<String>,
+
+    /// Read the prompt from a UTF-8 text file
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["prompt", "positional_prompt"])]
+    pub prompt_file: Option<PathBuf>,
+
+    /// Largest prompt accepted, counting piped stdin, in bytes
+    #[arg(long, env = "MAX_INPUT_BYTES", default_value_t = DEFAULT_MAX_INPUT_BYTES)]
+    pub max_input_bytes: usize,
+
+    /// Cut oversized input down to --max-input-bytes instead of refusing it
+    #[arg(long)]
+    pub truncate_input: bool,
+
+    /// Send a text file ahead of the prompt, fenced and tagged with its language; repeatable
+    #[arg(long, value_name = "PATH")]
+    pub attach: Vec<PathBuf>,
+
+    /// Largest combined size of the files attached to a message, in bytes; bigger files are cut
+    #[arg(long, env = "MAX_ATTACH_BYTES", default_value_t = DEFAULT_MAX_ATTACH_BYTES)]
+    pub max_attach_bytes: usize,
+
+    /// Config file [default: ~/.config/deepseek_agent/config.toml]
+    #[arg(long, value_name = "PATH", env = "DEEPSEEK_AGENT_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Config file profile to use [default: the file's default_profile]
+    #[arg(long, env = "DEEPSEEK_AGENT_PROFILE")]
+    pub profile: Option<String>,
+
+    /// Directory of prompt templates [default: ~/.config/deepseek_agent/templates]
+    #[arg(long, value_name = "PATH", env = "DEEPSEEK_AGENT_TEMPLATES")]
+    pub templates_dir: Option<PathBuf>,
+
+    /// Log requests and where settings came from to stderr (debug level)
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// Log everything, including full response metadata (trace level)
+    #[arg(long)]
+    pub debug: bool,
+
+    /// Also write logs to this file, one JSON object per line
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    /// Print the reply token by token as it arrives
+    #[arg(long, env = "STREAM")]
+    pub stream: bool,
+
+    /// How to print the answer to a prompt; json and jsonl keep stdout pure JSON
+    #[arg(long, value_enum, env = "OUTPUT", default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+
+    /// Also write every answer to this file as it arrives, replacing it once complete
+    #[arg(long, value_name = "PATH", env = "OUTPUT_FILE")]
+    pub output_file: Option<PathBuf>,
+
+    /// Add answers to the end of --output-file, under a header, instead of replacing it
+    #[arg(long, requires = "output_file")]
+    pub append: bool,
+
+    /// Print the request a prompt would make, as JSON, with its estimated tokens
+    /// and cost, and exit without sending it
+    #[arg(long, conflicts_with = "models")]
+    pub dry_run: bool,
+
+    /// Don't print the reasoning that models like deepseek-reasoner show before answering
+    #[arg(long, env = "HIDE_REASONING")]
+    pub hide_reasoning: bool,
+
+    /// Print replies as the raw markdown instead of styling them; the default when piping
+    #[arg(long, env = "PLAIN")]
+    pub plain: bool,
+
+    /// Save the conversation to this JSON file after every reply
+    #[arg(long, value_name = "PATH")]
+    pub save_session: Option<PathBuf>,
+
+    /// Continue a conversation saved with --save-session or /save
+    #[arg(long, value_name = "PATH")]
+    pub resume: Option<PathBuf>,
+
+    /// Print tokens used and estimated cost after every reply
+    #[arg(long, env = "SHOW_USAGE")]
+    pub show_usage: bool,
+
+    /// Print latency, time to first token and tokens/s after every reply
+    #[arg(long, env = "SHOW_TIMING")]
+    pub show_timing: bool,
+
+    /// Print latency percentiles and average tokens/s when the session ends
+    #[arg(long, env = "STATS")]
+    pub stats: bool,
+
+    /// Model name [default: deepseek-chat]
+    #[arg(short, long, env = "MODEL", help_heading = "Request")]
+    pub model: Option<String>,
+
+    /// System prompt sent before the conversation
+    #[arg(long = "system", env = "SYSTEM_PROMPT", help_heading = "Request")]
+    pub system_prompt: Option<String>,
+
+    /// Read the system prompt from a UTF-8 text file, instead of --system;
+    /// /reload-system reads it again
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "SYSTEM_FILE",
+        help_heading = "Request"
+    )]
+    pub system_file: Option<PathBuf>,
+
+    /// Sampling temperature, 0.0 to 2.0
+    #[arg(long, env = "TEMPERATURE", value_parser = parse_temperature, help_heading = "Request")]
+    pub temperature: Option<f32>,
+
+    /// Nucleus sampling probability mass, 0.0 to 1.0
+    #[arg(long, env = "TOP_P", value_parser = parse_top_p, help_heading = "Request")]
+    pub top_p: Option<f32>,
+
+    /// Upper bound on tokens in the reply
+    #[arg(
+        long,
+        env = "MAX_TOKENS",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help_heading = "Request"
+    )]
+    pub max_tokens: Option<u32>,
+
+    /// End the reply where the model would generate this text; repeat for up to 4
+    #[arg(long, value_name = "TEXT", help_heading = "Request")]
+    pub stop: Vec<String>,
+
+    /// Add a field to every chat request body, the value as JSON, e.g. logprobs=true; repeatable
+    #[arg(
+        long,
+        value_name = "KEY=JSON",
+        value_parser = parse_extra_body,
+        help_heading = "Request"
+    )]
+    pub extra_body: Vec<(String, Value)>,
+
+    /// Start the answer with this text and have the model continue it, e.g. "```json"
+    #[arg(
+        long,
+        value_name = "TEXT",
+        conflicts_with = "tools",
+        help_heading = "Request"
+    )]
+    pub prefill: Option<String>,
+
+    /// Ask each of these models at once and show every answer, e.g. deepseek-chat,deepseek-reasoner
+    #[arg(
+        long,
+        value_name = "MODEL,...",
+        value_delimiter = ',',
+        conflicts_with_all = ["tools", "shell", "files", "fetch"],
+        help_heading = "Request"
+    )]
+    pub models: Vec<String>,
+
+    /// The one of --models whose answers are kept in the history [default: the first]
+    #[arg(
+        long,
+        value_name = "MODEL",
+        requires = "models",
+        help_heading = "Request"
+    )]
+    pub history_model: Option<String>,
+
+    /// Answer in JSON matching the JSON Schema in this file, repairing replies that don't
+    #[arg(
+        long,
+        env = "JSON_SCHEMA",
+        value_name = "FILE",
+        conflicts_with = "models",
+        help_heading = "Request"
+    )]
+    pub json_schema: Option<PathBuf>,
+
+    /// Times a reply that doesn't match --json-schema is sent back to be fixed [default: 2]
+    #[arg(
+        long,
+        env = "SCHEMA_REPAIRS",
+        value_name = "N",
+        requires = "json_schema",
+        help_heading = "Request"
+    )]
+    pub schema_repairs: Option<usize>,
+
+    /// Let the model use the built-in calculator and current-time tools
+    #[arg(long, env = "TOOLS", help_heading = "Tools")]
+    pub tools: bool,
+
+    /// Tool-call rounds allowed per question [default: 5]
+    #[arg(long, env = "MAX_TOOL_ITERATIONS", help_heading = "Tools")]
+    pub max_tool_iterations: Option<usize>,
+
+    /// Let the model run shell commands, asking before each one
+    #[arg(long, help_heading = "Tools")]
+    pub shell: bool,
+
+    /// Run shell commands and write files without asking; destructive commands
+    /// are still refused
+    #[arg(long, requires = "approvable", help_heading = "Tools")]
+    pub auto_approve: bool,
+
+    /// Directory shell commands run in [default: the current directory]
+    #[arg(long, value_name = "PATH", env = "SHELL_DIR", help_heading = "Tools")]
+    pub shell_dir: Option<PathBuf>,
+
+    /// Seconds a shell command may run before it is killed [default: 30]
+    #[arg(
+        long,
+        env = "SHELL_TIMEOUT_SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help_heading = "Tools"
+    )]
+    pub shell_timeout_secs: Option<u64>,
+
+    /// Bytes of a command's stdout, and of its stderr, sent back [default: 16384]
+    #[arg(long, env = "SHELL_MAX_OUTPUT_BYTES", help_heading = "Tools")]
+    pub shell_max_output_bytes: Option<usize>,
+
+    /// Let the model read, list and (asking first) write files in the workspace
+    #[arg(long, help_heading = "Tools")]
+    pub files: bool,
+
+    /// Directory the file tools are confined to [default: the current directory]
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "WORKSPACE_DIR",
+        help_heading = "Tools"
+    )]
+    pub workspace: Option<PathBuf>,
+
+    /// Copy a file to <name>.bak before the model overwrites it
+    #[arg(long, env = "BACKUP", help_heading = "Tools")]
+    pub backup: bool,
+
+    /// Bytes of a file read_file returns at most [default: 65536]
+    #[arg(long, env = "MAX_READ_BYTES", help_heading = "Tools")]
+    pub max_read_bytes: Option<usize>,
+
+    /// Let the model fetch web pages over http and https, read as plain text
+    #[arg(long, help_heading = "Tools")]
+    pub fetch: bool,
+
+    /// Let fetch_url reach localhost and private or link-local addresses
+    #[arg(long, requires = "fetch", help_heading = "Tools")]
+    pub allow_local_fetch: bool,
+
+    /// Bytes of a page's text fetch_url returns at most [default: 16384]
+    #[arg(long, env = "FETCH_MAX_BYTES", help_heading = "Tools")]
+    pub fetch_max_bytes: Option<usize>,
+
+    /// Price of a model in USD per million input and output tokens, e.g.
+    /// deepseek-chat=0.28,0.42; repeat for several models
+    #[arg(long = "price", value_name = "MODEL=IN,OUT", value_parser = parse_price, help_heading = "Request")]
+    pub prices: Vec<(String, ModelPrice)>,
+
+    /// Most to spend in USD this session, estimated from --price; requests that would go over are refused
+    #[arg(long, env = "BUDGET_USD", value_name = "USD", value_parser = parse_budget, help_heading = "Request")]
+    pub budget_usd: Option<f64>,
+
+    /// Estimated tokens of history allowed before the oldest turns are trimmed [default: 60000]
+    #[arg(
+        long,
+        env = "CONTEXT_BUDGET",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help_heading = "Context"
+    )]
+    pub context_budget: Option<u64>,
+
+    /// How to trim a history over budget: drop-oldest or summarize [default: drop-oldest]
+    #[arg(
+        long,
+        value_name = "STRATEGY",
+        env = "TRIM_STRATEGY",
+        help_heading = "Context"
+    )]
+    pub trim_strategy: Option<TrimStrategy>,
+
+    /// Most recent turns that are never trimmed [default: 4]
+    #[arg(long, env = "KEEP_TURNS", help_heading = "Context")]
+    pub keep_turns: Option<usize>,
+
+    /// Keep identical tool results in full instead of just the latest
+    #[arg(long, help_heading = "Context")]
+    pub no_dedupe_tool_results: bool,
+
+    /// Cut tool results longer than this, outside the kept turns, to their start and end
+    #[arg(
+        long,
+        value_name = "CHARS",
+        env = "EXCERPT_TOOL_RESULTS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help_heading = "Context"
+    )]
+    pub excerpt_tool_results: Option<u64>,
+
+    /// Add excerpts from this index, built with `embed`, to the system prompt
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "CONTEXT_INDEX",
+        help_heading = "Context"
+    )]
+    pub context_index: Option<PathBuf>,
+
+    /// Excerpts from --context-index added per question
+    #[arg(long, env = "TOP_K", default_value_t = DEFAULT_TOP_K, help_heading = "Context")]
+    pub top_k: usize,
+
+    /// Who serves the API: deepseek, openai, azure or custom (with --base-url) [default: deepseek]
+    #[arg(
+        long,
+        value_name = "PROVIDER",
+        env = "PROVIDER",
+        help_heading = "Connection"
+    )]
+    pub provider: Option<ProviderKind>,
+
+    /// API base URL [default: the provider's, https://api.deepseek.com/v1 for DeepSeek]
+    #[arg(long, env = "BASE_URL", help_heading = "Connection")]
+    pub base_url: Option<String>,
+
+    /// The api-version of requests to Azure OpenAI [default: 2024-10-21]
+    #[arg(
+        long,
+        value_name = "VERSION",
+        env = "AZURE_API_VERSION",
+        help_heading = "Connection"
+    )]
+    pub azure_api_version: Option<String>,
+
+    /// Retries for rate limits, server errors and dropped connections [default: 3]
+    #[arg(long, env = "MAX_RETRIES", help_heading = "Connection")]
+    pub max_retries: Option<u32>,
+
+    /// Delay before the first retry in milliseconds, doubled after each [default: 500]
+    #[arg(long, env = "RETRY_BASE_DELAY_MS", help_heading = "Connection")]
+    pub retry_base_delay_ms: Option<u64>,
+
+    /// Seconds to wait for a reply, or between streamed chunks [default: 120]
+    #[arg(
+        long,
+        env = "TIMEOUT_SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help_heading = "Connection"
+    )]
+    pub timeout_secs: Option<u64>,
+
+    /// Requests a minute at most; more wait their turn, batch prompts included
+    #[arg(
+        long,
+        value_name = "N",
+        env = "RPM",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help_heading = "Connection"
+    )]
+    pub rpm: Option<u32>,
+
+    /// Tokens a minute at most, prompts estimated before sending and replies counted after
+    #[arg(
+        long,
+        value_name = "N",
+        env = "TPM",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help_heading = "Connection"
+    )]
+    pub tpm: Option<u32>,
+
+    /// HTTP(S) proxy for every request, e.g. http://proxy.internal:3128; NO_PROXY hosts skip it
+    #[arg(
+        long,
+        value_name = "URL",
+        env = "HTTPS_PROXY",
+        help_heading = "Connection"
+    )]
+    pub proxy: Option<String>,
+
+    /// PEM file of root certificates to trust on top of the system ones, e.g. a company CA
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "CA_BUNDLE",
+        help_heading = "Connection"
+    )]
+    pub ca_bundle: Option<PathBuf>,
+
+    /// Accept any TLS certificate, e.g. a self-signed dev endpoint's; never use it over the internet
+    #[arg(long, help_heading = "Connection")]
+    pub insecure: bool,
+
+    /// Reuse the cached reply to a request sent before, and cache new ones
+    #[arg(long, env = "CACHE", help_heading = "Cache")]
+    pub cache: bool,
+
+    /// Send every request even if caching is on, e.g. from CACHE
+    #[arg(long, conflicts_with = "cache_refresh", help_heading = "Cache")]
+    pub no_cache: bool,
+
+    /// Send every request and replace its cached reply; turns caching on
+    #[arg(long, help_heading = "Cache")]
+    pub cache_refresh: bool,
+
+    /// Directory for cached replies [default: ~/.cache/deepseek_agent/responses]
+    #[arg(long, value_name = "PATH", env = "CACHE_DIR", help_heading = "Cache")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Seconds a cached reply is reused for [default: 86400]
+    #[arg(
+        long,
+        env = "CACHE_TTL_SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help_heading = "Cache"
+    )]
+    pub cache_ttl_secs: Option<u64>,
+
+    /// Don't check the model against the endpoint's model list before chatting
+    #[arg(long, env = "NO_VALIDATE", help_heading = "Connection")]
+    pub no_validate: bool,
+
+    /// Fetch the model list again instead of using the one cached for an hour
+    #[arg(long, help_heading = "Connection")]
+    pub refresh_models: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// The reply as plain text, with status lines
+    Text,
+    /// One JSON object with the reply, finish reason, usage, timing and tool calls
+    Json,
+    /// One JSON object per streamed delta, then the json summary; implies --stream
+    Jsonl,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Ask for an API key, base URL and model, check them and save them to the config file
+    Init,
+    /// Manage config file profiles
+    Profiles {
+        #[command(subcommand)]
+        action: ProfilesCommand,
+    },
+    /// Fill in a prompt template and send it
+    Run {
+        /// Template name: a file in the templates directory without its .md, or a [templates] entry
+        name: String,
+        /// Value for a {{NAME}} placeholder; repeat for each one
+        #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_var)]
+        vars: Vec<(String, String)>,
+    },
+    /// Manage prompt templates
+    Templates {
+        #[command(subcommand)]
+        action: TemplatesCommand,
+    },
+    /// Embed text files into an index for --context-index
+    Embed {
+        /// Text files to embed; files already in the index are replaced
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Index to create or add to
+        #[arg(long, value_name = "PATH")]
+        index: PathBuf,
+        /// Embedding model; the index remembers it for embedding questions
+        #[arg(long, env = "EMBEDDING_MODEL", default_value = DEFAULT_EMBEDDING_MODEL)]
+        embedding_model: String,
+        /// Characters per chunk
+        #[arg(long, default_value_t = DEFAULT_CHUNK_SIZE as u64, value_parser = clap::value_parser!(u64).range(1..))]
+        chunk_size: u64,
+        /// Characters each chunk repeats from the one before; less than --chunk-size
+        #[arg(long, default_value_t = DEFAULT_CHUNK_OVERLAP)]
+        chunk_overlap: usize,
+    },
+    /// List the models the endpoint serves
+    Models,
+    /// Answer a JSONL file of {"id", "prompt"} lines, several at a time
+    Batch {
+        /// Input file, one JSON object per line; "model", "system_prompt",
+        /// "temperature", "top_p" and "max_tokens" override the defaults per line
+        input: PathBuf,
+        /// Write results here instead of stdout, one JSON object per line
+        #[arg(long, value_name = "PATH")]
+        out: Option<PathBuf>,
+        /// Requests in flight at once
+        #[arg(long, default_value_t = DEFAULT_CONCURRENCY as u64, value_parser = clap::value_parser!(u64).range(1..))]
+        concurrency: u64,
+        /// Append to --out, skipping ids it already has an answer for
+        #[arg(long, requires = "out")]
+        resume: bool,
+    },
+    /// Turn a saved session into a readable Markdown or HTML transcript
+    Export {
+        /// Session file, as written by --save-session or /save
+        session: PathBuf,
+        /// markdown, or html for a standalone page
+        #[arg(long, default_value = "markdown")]
+        format: TranscriptFormat,
+        /// Write the transcript here instead of stdout
+        #[arg(long, value_name = "PATH")]
+        out: Option<PathBuf>,
+        /// Show the model's reasoning before its answers, where it was saved
+        #[arg(long)]
+        include_reasoning: bool,
+    },
+    /// Review the working tree's git diff and report the findings as Markdown;
+    /// exits with 7 if any is critical
+    Review {
+        /// Review what is staged instead of what isn't
+        #[arg(long, conflicts_with = "range")]
+        staged: bool,
+        /// Review the commits in this range instead, e.g. main..HEAD
+        #[arg(long, value_name = "RANGE")]
+        range: Option<String>,
+        /// Leave out files matching this glob, like "*.snap" or "docs/**"; repeatable
+        #[arg(long = "ignore", value_name = "GLOB")]
+        ignores: Vec<String>,
+        /// Review lockfiles too, which are left out by default
+        #[arg(long)]
+        no_default_ignores: bool,
+        /// Write the report here instead of stdout
+        #[arg(long, value_name = "PATH")]
+        out: Option<PathBuf>,
+    },
+    /// Check the config, connection, key and model, and say what to fix;
+    /// exits with 8 if a critical check fails
+    Doctor,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ProfilesCommand {
+    /// Print every profile with its base URL, model and (redacted) key
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TemplatesCommand {
+    /// Print every template with its placeholders and the settings it overrides
+    List,
+}
+
+impl Cli {
+    /// Whether replies are streamed, which `--output jsonl` needs.
+    pub fn streaming(&self) -> bool {
+        self.stream || self.output == OutputFormat::Jsonl
+    }
+
+    /// The prompt given either positionally or with `--prompt`.
+    pub fn prompt(&self) -> Option<&str> {
+        self.prompt.as_deref().or(self.positional_prompt.as_deref())
+    }
+
+    /// The request settings given as flags and those read from environment
+    /// variables, as separate layers so the config file can go between them
+    /// and the defaults.
+    pub fn layers(&self, matches: &ArgMatches) -> [(Source, Settings); 2] {
+        let mut flags = Settings::default();
+        let mut env = Settings::default();
+        macro_rules! split {
+            ($($key:ident),*) => {$(
+                if let Some(value) = self.$key.clone() {
+                    let layer = match matches.value_source(stringify!($key)) {
+                        Some(ValueSource::EnvVariable) => &mut env,
+                        _ => &mut flags,
+                    };
+                    layer.$key = Some(value);
+                }
+            )*};
+        }
+        split!(
+            provider,
+            azure_api_version,
+            base_url,
+            model,
+            system_prompt,
+            temperature,
+            top_p,
+            max_tokens,
+            budget_usd
+        );
+        [(Source::Flag, flags), (Source::Env, env)]
+    }
+
+    /// Resolve the agent config from merged `settings` and the flags that only
+    /// exist on the command line, filling anything unset with defaults.
+    pub fn agent_config(&self, settings: Settings) -> Result<AgentConfig, AgentError> {
+        let api_key: SecretString = match settings.api_key {
+            Some(key) => key,
+            None => return Err(AgentError::MissingEnv(settings.api_key_var().to_string())),
+        };
+        chat::check_stop_sequences(&self.stop)?;
+        let default_retry = RetryPolicy::default();
+        let provider = Provider::resolve(
+            settings.provider.unwrap_or_default(),
+            settings.base_url.as_deref(),
+            settings.azure_api_version.as_deref(),
+        )?;
+        let mut prices = provider.prices();
+        for (model, price) in &self.prices {
+            prices.set(model, *price);
+        }
+        Ok(AgentConfig {
+            base_url: provider.base_url(settings.base_url.as_deref())?,
+            provider,
+            model: settings.model.unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+            system_prompt: match &self.system_file {
+                Some(path) => input::read_system_file(path)?,
+                None => resolve_system_prompt(settings.system_prompt.as_deref()),
+            },
+            params: RequestParams {
+                temperature: settings.temperature,
+                top_p: settings.top_p,
+                max_tokens: settings.max_tokens,
+                stop: self.stop.clone(),
+                json_mode: self.json_schema.is_some(),
+                extra_body: self.extra_body.iter().cloned().collect(),
+            },
+            retry: RetryPolicy {
+                max_retries: self.max_retries.unwrap_or(default_retry.max_retries),
+                base_delay: self
+                    .retry_base_delay_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(default_retry.base_delay),
+                ..default_retry
+            },
+            timeout: self
+                .timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_TIMEOUT),
+            max_tool_iterations: self
+                .max_tool_iterations
+                .unwrap_or(DEFAULT_MAX_TOOL_ITERATIONS),
+            prices,
+            context: ContextManager {
+                budget: self
+                    .context_budget
+                    .map_or(DEFAULT_CONTEXT_BUDGET, |budget| budget as usize),
+                keep_turns: self.keep_turns.unwrap_or(DEFAULT_KEEP_TURNS),
+                strategy: self.trim_strategy.unwrap_or_default(),
+                dedupe_tool_results: !self.no_dedupe_tool_results,
+                excerpt_tool_results: self.excerpt_tool_results.map(|chars| chars as usize),
+                ..ContextManager::default()
+            },
+            prefill: self.prefill.clone(),
+            budget: settings.budget_usd.map(Budget::new),
+            network: self.network(),
+            rate_limiter: (self.rpm.is_some() || self.tpm.is_some()).then(|| {
+                RateLimiter::new(RateLimits {
+                    requests_per_minute: self.rpm,
+                    tokens_per_minute: self.tpm,
+                })
+            }),
+            ..AgentConfig::new(api_key)
+        })
+    }
+
+    /// The proxy and TLS flags.
+    pub fn network(&self) -> NetworkConfig {
+        NetworkConfig {
+            proxy: self.proxy.clone(),
+            ca_bundle: self.ca_bundle.clone(),
+            insecure: self.insecure,
+        }
+    }
+
+    /// The response cache, if caching is on. `default_dir` is used unless
+    /// `--cache-dir` is given.
+    pub fn response_cache(
+        &self,
+        default_dir: Option<PathBuf>,
+    ) -> Result<Option<ResponseCache>, AgentError> {
+        if !(self.cache || self.cache_refresh) || self.no_cache {
+            return Ok(None);
+        }
+        let Some(dir) = self.cache_dir.clone().or(default_dir) else {
+            return Err(AgentError::InvalidConfig(
+                "--cache needs --cache-dir when HOME is not set".into(),
+            ));
+        };
+        Ok(Some(ResponseCache {
+            ttl: self
+                .cache_ttl_secs
+                .map_or(DEFAULT_CACHE_TTL, Duration::from_secs),
+            refresh: self.cache_refresh,
+            ..ResponseCache::new(dir)
+        }))
+    }
+
+    /// The models of `--models` and the one whose answers are kept, if
+    /// several are to be asked.
+    pub fn fan_out(&self) -> Result<Option<FanOut>, AgentError> {
+        let mut models: Vec<String> = Vec::new();
+        for model in self.models.iter().map(|model| model.trim()) {
+            if model.is_empty() {
+                return Err(AgentError::InvalidConfig(
+                    "--models has an empty model name".into(),
+                ));
+            }
+            if !models.iter().any(|seen| seen == model) {
+                models.push(model.to_string());
+            }
+        }
+        let Some(first) = models.first() else {
+            return Ok(None);
+        };
+        let history_model = self.history_model.clone().unwrap_or_else(|| first.clone());
+        if !models.contains(&history_model) {
+            return Err(AgentError::InvalidConfig(format!(
+                "--history-model {} is not one of --models {}",
+                history_model,
+                models.join(",")
+            )));
+        }
+        Ok(Some(FanOut {
+            models,
+            history_model,
+        }))
+    }
+
+    /// Limits for the shell tool, defaults filled in.
+    pub fn shell_config(&self) -> ShellConfig {
+        let default = ShellConfig::default();
+        ShellConfig {
+            working_dir: self.shell_dir.clone().unwrap_or(default.working_dir),
+            timeout: self
+                .shell_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(default.timeout),
+            max_output_bytes: self
+                .shell_max_output_bytes
+                .unwrap_or(default.max_output_bytes),
+        }
+    }
+
+    /// Limits for the fetch tool, defaults filled in.
+    pub fn fetch_config(&self) -> FetchConfig {
+        let default = FetchConfig::default();
+        FetchConfig {
+            max_bytes: self.fetch_max_bytes.unwrap_or(default.max_bytes),
+            allow_local: self.allow_local_fetch,
+            ..default
+        }
+    }
+}
+
+/// One block per profile for `profiles list`, with values the profile doesn't
+/// set taken from the top of the file. `env` looks up the key variables.
+pub fn describe_profiles(file: &ConfigFile, env: impl Fn(&str) -> Option<String>) -> String {
+    let top = file.settings();
+    let mut out = String::new();
+    for (name, profile) in &file.profiles {
+        let is_default = file.default_profile.as_deref() == Some(name.as_str());
+        let provider = profile.provider.or(top.provider).unwrap_or_default();
+        let base_url = profile
+            .base_url
+            .as_deref()
+            .or(top.base_url.as_deref())
+            .map(str::to_string)
+            .or_else(|| {
+                Provider::resolve(provider, None, None)
+                    .ok()?
+                    .base_url(None)
+                    .ok()
+            })
+            .unwrap_or_else(|| "(not set)".to_string());
+        let model = profile
+            .model
+            .as_deref()
+            .or(top.model.as_deref())
+            .unwrap_or(DEFAULT_MODEL);
+        let key = match profile.api_key.as_ref().or(top.api_key.as_ref()) {
+            Some(key) => format!("{} (in config file)", key),
+            None => {
+                let var = profile
+                    .api_key_env
+                    .as_deref()
+                    .unwrap_or_else(|| top.api_key_var());
+                match env(var) {
+                    Some(key) => format!("${} = {}", var, SecretString::new(key)),
+                    None => format!("${} (not set)", var),
+                }
+            }
+        };
+        out.push_str(&format!(
+            "{}{}\n  base URL: {}\n  model:    {}\n  API key:  {}\n",
+            name,
+            if is_default { " (default)" } else { "" },
+            base_url,
+            model,
+            key
+        ));
+    }
+    out
+}
+
+/// One line per model: its id, owner and creation date, `-` where the
+/// server didn't say.
+pub fn describe_models(models: &[ModelInfo]) -> String {
+    let width = models.iter().map(|info| info.id.len()).max().unwrap_or(0);
+    let owner_width = models
+        .iter()
+        .map(|info| info.owned_by.as_deref().unwrap_or("-").len())
+        .max()
+        .unwrap_or(0);
+    let mut out = String::new();
+    for info in models {
+        let created = info.created_date().map_or_else(
+            || "-".to_string(),
+            |date| date.format("%Y-%m-%d").to_string(),
+        );
+        let line = format!(
+            "{:width$}  {:owner_width$}  {}",
+            info.id,
+            info.owned_by.as_deref().unwrap_or("-"),
+            created
+        );
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// One block per template for `templates list`.
+pub fn describe_templates<'a>(templates: impl IntoIterator<Item = &'a Template>) -> String {
+    let mut out = String::new();
+    for template in templates {
+        out.push_str(&template.name);
+        if let Some(description) = &template.description {
+            out.push_str(&format!(" - {}", description));
+        }
+        out.push('\n');
+        let placeholders = template.placeholders();
+        if !placeholders.is_empty() {
+            out.push_str(&format!("  variables: {}\n", placeholders.join(", ")));
+        }
+        let settings = &template.settings;
+        let mut overrides = Vec::new();
+        if let Some(model) = &settings.model {
+            overrides.push(format!("model {}", model));
+        }
+        if let Some(temperature) = settings.temperature {
+            overrides.push(format!("temperature {}", temperature));
+        }
+        if let Some(top_p) = settings.top_p {
+            overrides.push(format!("top_p {}", top_p));
+        }
+        if let Some(max_tokens) = settings.max_tokens {
+            overrides.push(format!("max_tokens {}", max_tokens));
+        }
+        if settings.system_prompt.is_some() {
+            overrides.push("system prompt".to_string());
+        }
+        if !overrides.is_empty() {
+            out.push_str(&format!("  sets:      {}\n", overrides.join(", ")));
+        }
+        match &template.path {
+            Some(path) => out.push_str(&format!("  from:      {}\n", path.display())),
+            None => out.push_str("  from:      config file\n"),
+        }
+    }
+    out
+}
+
+fn parse_var(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected NAME=VALUE, got '{}'", value)),
+    }
+}
+
+fn parse_temperature(value: &str) -> Result<f32, String> {
+    parse_in_range(value, 0.0, 2.0)
+}
+
+fn parse_top_p(value: &str) -> Result<f32, String> {
+    parse_in_range(value, 0.0, 1.0)
+}
+
+fn parse_price(value: &str) -> Result<(String, ModelPrice), String> {
+    let (model, prices) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected MODEL=IN,OUT, got '{}'", value))?;
+    let (input, output) = prices
+        .split_once(',')
+        .ok_or_else(|| format!("expected MODEL=IN,OUT, got '{}'", value))?;
+    let price = |text: &str| -> Result<f64, String> {
+        match text.trim().parse::<f64>() {
+            Ok(price) if price >= 0.0 && price.is_finite() => Ok(price),
+            _ => Err(format!("'{}' is not a valid price", text.trim())),
+        }
+    };
+    let model = model.trim();
+    if model.is_empty() {
+        return Err(format!("missing model name in '{}'", value));
+    }
+    Ok((
+        model.to_string(),
+        ModelPrice {
+            input_per_million: price(input)?,
+            output_per_million: price(output)?,
+        },
+    ))
+}
+
+fn parse_extra_body(value: &str) -> Result<(String, Value), String> {
+    let (key, json) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=JSON, got '{}'", value))?;
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(format!("missing field name in '{}'", value));
+    }
+    let json = serde_json::from_str(json).map_err(|e| {
+        format!(
+            "the value of '{}' is not JSON ({}); quote strings, like {}='\"text\"'",
+            key, e, key
+        )
+    })?;
+    Ok((key.to_string(), json))
+}
+
+fn parse_budget(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(budget) if budget > 0.0 && budget.is_finite() => Ok(budget),
+        _ => Err(format!("'{}' is not an amount above 0", value.trim())),
+    }
+}
+
+fn parse_in_range(value: &str, min: f32, max: f32) -> Result<f32, String> {
+    let number: f32 = value
+        .parse()
+        .map_err(|_| format!("'{}' is not a number", value))?;
+    if !(min..=max).contains(&number) {
+        return Err(format!(
+            "must be between {} and {}, got {}",
+            min, max, number
+        ));
+    }
+    Ok(number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+    use deepseek_tutor::chat::DEFAULT_SYSTEM_PROMPT;
+    use deepseek_tutor::config::DEFAULT_BASE_URL;
+    use deepseek_tutor::settings::merge;
+    use deepseek_tutor::usage::PriceTable;
+
+    fn argv<'a>(args: &'a [&'a str]) -> impl Iterator<Item = &'a str> {
+        std::iter::once("deepseek_agent").chain(args.iter().copied())
+    }
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(argv(args))
+    }
+
+    /// Resolve `args` over `file`, with the key coming from the environment.
+    fn resolve_with_file(args: &[&str], file: Settings) -> Result<AgentConfig, AgentError> {
+        let matches = Cli::command().try_get_matches_from(argv(args)).unwrap();
+        let cli = Cli::from_arg_matches(&matches).unwrap();
+        let [flags, mut env] = cli.layers(&matches);
+        env.1.api_key = Some("sk-test".into());
+        cli.agent_config(merge([flags, env, (Source::File, file)]).settings)
+    }
+
+    fn resolve(args: &[&str]) -> AgentConfig {
+        resolve_with_file(args, Settings::default()).unwrap()
+    }
+
+    #[test]
+    fn no_flags_resolves_to_defaults() {
+        let config = resolve(&[]);
+        assert_eq!(config.model, DEFAULT_MODEL);
+        assert_eq!(config.base_url, DEFAULT_BASE_URL);
+        assert_eq!(config.system_prompt, DEFAULT_SYSTEM_PROMPT);
+        assert_eq!(config.params, RequestParams::default());
+        assert_eq!(config.retry, RetryPolicy::default());
+        assert_eq!(config.max_tool_iterations, DEFAULT_MAX_TOOL_ITERATIONS);
+        assert_eq!(config.api_key.expose(), "sk-test");
+        assert!(!parse(&[]).unwrap().tools);
+    }
+
+    #[test]
+    fn flags_override_defaults() {
+        let config = resolve(&[
+            "--model",
+            "deepseek-reasoner",
+            "--temperature",
+            "1.3",
+            "--top-p",
+            "0.5",
+            "--max-tokens",
+            "2048",
+            "--system",
+            "Be terse.",
+            "--base-url",
+            "http://localhost:11434/v1/",
+        ]);
+        assert_eq!(config.model, "deepseek-reasoner");
+        assert_eq!(config.system_prompt, "Be terse.");
+        assert_eq!(config.base_url, "http://localhost:11434/v1");
+        assert_eq!(
+            config.params,
+            RequestParams {
+                temperature: Some(1.3),
+                top_p: Some(0.5),
+                max_tokens: Some(2048),
+                stop: Vec::new(),
+                json_mode: false,
+                extra_body: serde_json::Map::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn system_file_wins_over_the_system_flag() {
+        let path = std::env::temp_dir().join(format!("system-file-{}.md", std::process::id()));
+        std::fs::write(&path, "Review strictly.\n").unwrap();
+        let file = path.to_str().unwrap();
+        let config = resolve(&["--system", "Be terse.", "--system-file", file]);
+        assert_eq!(config.system_prompt, "Review strictly.");
+        std::fs::remove_file(&path).unwrap();
+
+        let missing = resolve_with_file(&["--system-file", file], Settings::default());
+        assert!(missing.unwrap_err().to_string().ends_with("does not exist"));
+    }
+
+    #[test]
+    fn config_file_fills_in_below_flags() {
+        let file = Settings {
+            api_key: Some("sk-file".into()),
+            model: Some("file-model".to_string()),
+            base_url: Some("http://localhost:11434/v1".to_string()),
+            temperature: Some(0.1),
+            system_prompt: Some("From the file.".to_string()),
+            ..Settings::default()
+        };
+        let config = resolve_with_file(&["--model", "flag-model"], file).unwrap();
+        assert_eq!(config.model, "flag-model");
+        assert_eq!(config.base_url, "http://localhost:11434/v1");
+        assert_eq!(config.params.temperature, Some(0.1));
+        assert_eq!(config.system_prompt, "From the file.");
+        // the environment's key wins over the file's
+        assert_eq!(config.api_key.expose(), "sk-test");
+    }
+
+    #[test]
+    fn flags_are_split_from_env_values() {
+        let matches = Cli::command()
+            .try_get_matches_from(argv(&["--model", "m", "--temperature", "0.5"]))
+            .unwrap();
+        let cli = Cli::from_arg_matches(&matches).unwrap();
+        let [(flag_source, flags), (env_source, _)] = cli.layers(&matches);
+        assert_eq!(flag_source, Source::Flag);
+        assert_eq!(env_source, Source::Env);
+        assert_eq!(flags.model.as_deref(), Some("m"));
+        assert_eq!(flags.temperature, Some(0.5));
+        assert_eq!(flags.api_key, None);
+    }
+
+    #[test]
+    fn missing_api_key_is_reported() {
+        let cli = parse(&[]).unwrap();
+        assert!(matches!(
+            cli.agent_config(Settings::default()),
+            Err(AgentError::MissingEnv(_))
+        ));
+    }
+
+    #[test]
+    fn retry_flags() {
+        let config = resolve(&["--max-retries", "0", "--retry-base-delay-ms", "50"]);
+        assert_eq!(config.retry.max_retries, 0);
+        assert_eq!(config.retry.base_delay, Duration::from_millis(50));
+        assert_eq!(config.retry.max_delay, RetryPolicy::default().max_delay);
+    }
+
+    #[test]
+    fn extra_body_fields_are_json() {
+        let config = resolve(&[
+            "--extra-body",
+            "logprobs=true",
+            "--extra-body",
+            r#"thinking={"type": "enabled"}"#,
+            "--extra-body",
+            "user=\"me\"",
+            "--extra-body",
+            "logprobs=false",
+        ]);
+        let extra = &config.params.extra_body;
+        assert_eq!(extra.len(), 3);
+        // the last one given wins
+        assert_eq!(extra["logprobs"], false);
+        assert_eq!(extra["thinking"]["type"], "enabled");
+        assert_eq!(extra["user"], "me");
+        assert!(resolve(&[]).params.extra_body.is_empty());
+
+        let err = parse(&["--extra-body", "user=me"]).unwrap_err().to_string();
+        assert!(err.contains("user='\"text\"'"), "{err}");
+        assert!(parse(&["--extra-body", "logprobs"]).is_err());
+        assert!(parse(&["--extra-body", "=1"]).is_err());
+    }
+
+    #[test]
+    fn prefill_and_stop_flags() {
+        let config = resolve(&["--prefill", "```json", "--stop", "```", "--stop", "END"]);
+        assert_eq!(config.prefill.as_deref(), Some("```json"));
+        assert_eq!(config.params.stop, ["```", "END"]);
+        assert_eq!(resolve(&[]).prefill, None);
+
+        let five = [
+            "--stop", "a", "--stop", "b", "--stop", "c", "--stop", "d", "--stop", "e",
+        ];
+        let err = resolve_with_file(&five, Settings::default()).unwrap_err();
+        assert!(err.to_string().contains("at most 4"), "{err}");
+        assert!(parse(&["--prefill", "{", "--tools", "hi"]).is_err());
+    }
+
+    #[test]
+    fn cache_flags() {
+        let default = || Some(PathBuf::from("/home/me/.cache/responses"));
+        assert_eq!(
+            parse(&["hi"]).unwrap().response_cache(default()).unwrap(),
+            None
+        );
+
+        let cache = parse(&["--cache", "hi"])
+            .unwrap()
+            .response_cache(default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(cache, ResponseCache::new("/home/me/.cache/responses"));
+
+        let cache = parse(&[
+            "--cache-refresh",
+            "--cache-dir",
+            "/c",
+            "--cache-ttl-secs",
+            "60",
+            "hi",
+        ])
+        .unwrap()
+        .response_cache(None)
+        .unwrap()
+        .unwrap();
+        assert_eq!(cache.dir, PathBuf::from("/c"));
+        assert_eq!(cache.ttl, Duration::from_secs(60));
+        assert!(cache.refresh);
+
+        let off = parse(&["--cache", "--no-cache", "hi"]).unwrap();
+        assert_eq!(off.response_cache(default()).unwrap(), None);
+        assert!(parse(&["--no-cache", "--cache-refresh", "hi"]).is_err());
+        assert!(
+            parse(&["--cache", "hi"])
+                .unwrap()
+                .response_cache(None)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn model_list_flags() {
+        let cli = parse(&["models"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Models)));
+        assert!(!cli.no_validate && !cli.refresh_models);
+        let cli = parse(&["--no-validate", "--refresh-models", "hi"]).unwrap();
+        assert!(cli.no_validate && cli.refresh_models);
+    }
+
+    #[test]
+    fn init_is_a_subcommand() {
+        let cli = parse(&["init"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Init)));
+        assert!(parse(&["init", "extra"]).is_err());
+    }
+
+    #[test]
+    fn models_fan_out_to_each_once() {
+        assert_eq!(parse(&[]).unwrap().fan_out().unwrap(), None);
+
+        let cli = parse(&["--models", "deepseek-chat, deepseek-reasoner,deepseek-chat"]).unwrap();
+        let fan_out = cli.fan_out().unwrap().unwrap();
+        assert_eq!(fan_out.models, ["deepseek-chat", "deepseek-reasoner"]);
+        assert_eq!(fan_out.history_model, "deepseek-chat");
+
+        let cli = parse(&["--models", "a,b", "--history-model", "b"]).unwrap();
+        assert_eq!(cli.fan_out().unwrap().unwrap().history_model, "b");
+
+        let cli = parse(&["--models", "a,b", "--history-model", "c"]).unwrap();
+        assert!(cli.fan_out().is_err());
+        assert!(parse(&["--models", "a,,b"]).unwrap().fan_out().is_err());
+        assert!(parse(&["--history-model", "a"]).is_err());
+        assert!(parse(&["--models", "a,b", "--tools"]).is_err());
+    }
+
+    #[test]
+    fn json_schema_turns_on_json_mode() {
+        assert!(!resolve(&[]).params.json_mode);
+        let cli = parse(&["--json-schema", "order.json", "--schema-repairs", "0"]).unwrap();
+        assert_eq!(cli.schema_repairs, Some(0));
+        assert!(resolve(&["--json-schema", "order.json"]).params.json_mode);
+        assert!(parse(&["--schema-repairs", "1"]).is_err());
+        assert!(parse(&["--json-schema", "order.json", "--models", "a,b"]).is_err());
+    }
+
+    #[test]
+    fn timeout_flag() {
+        assert_eq!(resolve(&[]).timeout, DEFAULT_TIMEOUT);
+        let config = resolve(&["--timeout-secs", "5"]);
+        assert_eq!(config.timeout, Duration::from_secs(5));
+        assert!(parse(&["--timeout-secs", "0"]).is_err());
+    }
+
+    #[test]
+    fn context_flags() {
+        assert_eq!(resolve(&[]).context, ContextManager::default());
+
+        let config = resolve(&[
+            "--context-budget",
+            "8000",
+            "--trim-strategy",
+            "summarize",
+            "--keep-turns",
+            "1",
+        ]);
+        assert_eq!(config.context.budget, 8000);
+        assert_eq!(config.context.strategy, TrimStrategy::Summarize);
+        assert_eq!(config.context.keep_turns, 1);
+        assert!(config.context.dedupe_tool_results);
+        assert_eq!(config.context.excerpt_tool_results, None);
+
+        let config = resolve(&["--no-dedupe-tool-results", "--excerpt-tool-results", "2000"]);
+        assert!(!config.context.dedupe_tool_results);
+        assert_eq!(config.context.excerpt_tool_results, Some(2000));
+        assert!(parse(&["--excerpt-tool-results", "0"]).is_err());
+
+        assert!(parse(&["--trim-strategy", "truncate"]).is_err());
+        assert!(parse(&["--context-budget", "0"]).is_err());
+    }
+
+    #[test]
+    fn network_flags() {
+        let config = resolve(&[
+            "--proxy",
+            "http://proxy.internal:3128",
+            "--ca-bundle",
+            "/etc/ssl/company.pem",
+            "--insecure",
+        ]);
+        assert_eq!(
+            config.network,
+            NetworkConfig {
+                proxy: Some("http://proxy.internal:3128".to_string()),
+                ca_bundle: Some(PathBuf::from("/etc/ssl/company.pem")),
+                insecure: true,
+            }
+        );
+    }
+
+    #[test]
+    fn rate_limit_flags() {
+        assert_eq!(resolve(&[]).rate_limiter, None);
+        let limiter = resolve(&["--rpm", "60", "--tpm", "100000"])
+            .rate_limiter
+            .unwrap();
+        assert_eq!(
+            limiter.limits(),
+            RateLimits {
+                requests_per_minute: Some(60),
+                tokens_per_minute: Some(100_000),
+            }
+        );
+        let limiter = resolve(&["--tpm", "100000"]).rate_limiter.unwrap();
+        assert_eq!(limiter.limits().requests_per_minute, None);
+
+        assert!(parse(&["--rpm", "0"]).is_err());
+    }
+
+    #[test]
+    fn budget_flag() {
+        assert_eq!(resolve(&[]).budget, None);
+        assert_eq!(
+            resolve(&["--budget-usd", "0.50"]).budget,
+            Some(Budget::new(0.5))
+        );
+        assert!(parse(&["--budget-usd", "0"]).is_err());
+        assert!(parse(&["--budget-usd", "lots"]).is_err());
+    }
+
+    #[test]
+    fn tool_flags() {
+        let cli = parse(&["--tools", "--max-tool-iterations", "2"]).unwrap();
+        assert!(cli.tools);
+        assert_eq!(
+            resolve(&["--max-tool-iterations", "2"]).max_tool_iterations,
+            2
+        );
+    }
+
+    #[test]
+    fn shell_flags() {
+        let cli = parse(&[]).unwrap();
+        assert!(!cli.shell);
+        assert_eq!(cli.shell_config(), ShellConfig::default());
+
+        let cli = parse(&[
+            "--shell",
+            "--auto-approve",
+            "--shell-dir",
+            "/tmp",
+            "--shell-timeout-secs",
+            "3",
+            "--shell-max-output-bytes",
+            "100",
+        ])
+        .unwrap();
+        assert!(cli.shell && cli.auto_approve);
+        assert_eq!(
+            cli.shell_config(),
+            ShellConfig {
+                working_dir: PathBuf::from("/tmp"),
+                timeout: Duration::from_secs(3),
+                max_output_bytes: 100,
+            }
+        );
+
+        // auto-approval only makes sense for a tool that is enabled
+        assert!(parse(&["--auto-approve"]).is_err());
+    }
+
+    #[test]
+    fn dry_run_flag() {
+        assert!(!parse(&[]).unwrap().dry_run);
+        assert!(parse(&["--dry-run", "hi"]).unwrap().dry_run);
+        assert!(parse(&["--dry-run", "batch", "in.jsonl"]).unwrap().dry_run);
+        // one request per model isn't a dry run of the one that is kept
+        assert!(parse(&["--dry-run", "--models", "a,b", "hi"]).is_err());
+    }
+
+    #[test]
+    fn fetch_flags() {
+        let cli = parse(&[]).unwrap();
+        assert!(!cli.fetch);
+        assert_eq!(cli.fetch_config(), FetchConfig::default());
+
+        let cli = parse(&["--fetch", "--allow-local-fetch", "--fetch-max-bytes", "512"]).unwrap();
+        assert!(cli.fetch);
+        assert_eq!(
+            cli.fetch_config(),
+            FetchConfig {
+                max_bytes: 512,
+                allow_local: true,
+                ..FetchConfig::default()
+            }
+        );
+
+        assert!(parse(&["--allow-local-fetch"]).is_err());
+    }
+
+    #[test]
+    fn file_tool_flags() {
+        let cli = parse(&[
+            "--files",
+            "--auto-approve",
+            "--workspace",
+            "/tmp",
+            "--backup",
+        ])
+        .unwrap();
+        assert!(cli.files && cli.auto_approve && cli.backup);
+        assert_eq!(cli.workspace.as_deref(), Some(std::path::Path::new("/tmp")));
+        assert_eq!(cli.max_read_bytes, None);
+    }
+
+    #[test]
+    fn providers_bring_their_base_url_and_prices() {
+        let openai = resolve(&["--provider", "openai", "--model", "gpt-4o"]);
+        assert_eq!(openai.provider, Provider::OpenAI);
+        assert_eq!(openai.base_url, "https://api.openai.com/v1");
+        assert!(openai.prices.get("gpt-4o").is_some());
+        assert!(openai.prices.get("deepseek-chat").is_none());
+
+        let file = Settings {
+            provider: Some(ProviderKind::Azure),
+            base_url: Some("https://me.openai.azure.com".to_string()),
+            ..Settings::default()
+        };
+        let azure = resolve_with_file(&["--azure-api-version", "2025-01-01"], file).unwrap();
+        assert_eq!(
+            azure.provider,
+            Provider::AzureOpenAI {
+                api_version: "2025-01-01".to_string()
+            }
+        );
+        assert_eq!(azure.base_url, "https://me.openai.azure.com");
+
+        let custom = resolve(&[
+            "--provider",
+            "custom",
+            "--base-url",
+            "http://localhost:11434/v1",
+        ]);
+        assert_eq!(custom.base_url, "http://localhost:11434/v1");
+        assert_eq!(custom.prices, PriceTable::empty());
+        // a base URL alone still means DeepSeek's prices
+        let local = resolve(&["--base-url", "http://localhost:11434/v1"]);
+        assert_eq!(local.provider, Provider::DeepSeek);
+        assert!(local.prices.get("deepseek-chat").is_some());
+
+        for args in [&["--provider", "custom"][..], &["--provider", "azure"]] {
+            let err = resolve_with_file(args, Settings::default()).unwrap_err();
+            assert!(err.to_string().contains("--base-url"), "{err}");
+        }
+        assert!(parse(&["--provider", "anthropic"]).is_err());
+    }
+
+    #[test]
+    fn price_overrides() {
+        let config = resolve(&["--price", "deepseek-chat=1,2", "--price", "llama3 = 0, 0.5"]);
+        assert_eq!(
+            config.prices.get("deepseek-chat"),
+            Some(&ModelPrice {
+                input_per_million: 1.0,
+                output_per_million: 2.0
+            })
+        );
+        assert_eq!(config.prices.get("llama3").unwrap().output_per_million, 0.5);
+        assert!(config.prices.get("deepseek-reasoner").is_some());
+
+        for bad in [
+            "deepseek-chat",
+            "deepseek-chat=1",
+            "=1,2",
+            "x=1,-2",
+            "x=a,b",
+        ] {
+            let err = parse(&["--price", bad]).unwrap_err();
+            assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation, "{bad}");
+        }
+    }
+
+    #[test]
+    fn session_flags() {
+        let cli = parse(&["--resume", "old.json", "--save-session", "new.json"]).unwrap();
+        assert_eq!(cli.resume, Some(PathBuf::from("old.json")));
+        assert_eq!(cli.save_session, Some(PathBuf::from("new.json")));
+    }
+
+    #[test]
+    fn prompt_positional_or_flag() {
+        assert_eq!(
+            parse(&["hello there"]).unwrap().prompt(),
+            Some("hello there")
+        );
+        assert_eq!(parse(&["-p", "hi"]).unwrap().prompt(), Some("hi"));
+        assert_eq!(parse(&[]).unwrap().prompt(), None);
+        assert!(parse(&["a", "--prompt", "b"]).is_err());
+    }
+
+    #[test]
+    fn attach_is_repeatable() {
+        let cli = parse(&[
+            "--attach",
+            "src/main.rs",
+            "--attach",
+            "Cargo.toml",
+            "explain this",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.attach,
+            [PathBuf::from("src/main.rs"), PathBuf::from("Cargo.toml")]
+        );
+        assert_eq!(cli.max_attach_bytes, DEFAULT_MAX_ATTACH_BYTES);
+        let cli = parse(&["--max-attach-bytes", "2048"]).unwrap();
+        assert!(cli.attach.is_empty());
+        assert_eq!(cli.max_attach_bytes, 2048);
+    }
+
+    #[test]
+    fn prompt_file_conflicts_with_inline_prompt() {
+        let cli = parse(&["--prompt-file", "question.txt"]).unwrap();
+        assert_eq!(cli.prompt_file, Some(PathBuf::from("question.txt")));
+        assert!(parse(&["--prompt-file", "q.txt", "--prompt", "hi"]).is_err());
+        assert!(parse(&["--prompt-file", "q.txt", "hi"]).is_err());
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected_by_clap() {
+        for args in [
+            &["--temperature", "2.5"][..],
+            &["--temperature=-0.1"],
+            &["--temperature", "warm"],
+            &["--top-p", "1.5"],
+            &["--max-tokens", "0"],
+        ] {
+            let err = parse(args).unwrap_err();
+            assert_eq!(
+                err.kind(),
+                clap::error::ErrorKind::ValueValidation,
+                "{args:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_base_url_fails_resolution() {
+        assert!(matches!(
+            resolve_with_file(&["--base-url", "ftp://example.com"], Settings::default()),
+            Err(AgentError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn profile_flag_and_subcommand() {
+        let cli = parse(&["--profile", "local", "hello"]).unwrap();
+        assert_eq!(cli.profile.as_deref(), Some("local"));
+        assert_eq!(cli.prompt(), Some("hello"));
+        assert!(cli.command.is_none());
+
+        let cli = parse(&["profiles", "list"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Profiles {
+                action: ProfilesCommand::List
+            })
+        ));
+    }
+
+    #[test]
+    fn run_takes_a_template_and_variables() {
+        let cli = parse(&[
+            "run",
+            "review",
+            "--var",
+            "file=src/main.rs",
+            "--var",
+            "note=a=b",
+        ])
+        .unwrap();
+        match cli.command {
+            Some(Command::Run { name, vars }) => {
+                assert_eq!(name, "review");
+                assert_eq!(
+                    vars,
+                    [
+                        ("file".to_string(), "src/main.rs".to_string()),
+                        ("note".to_string(), "a=b".to_string())
+                    ]
+                );
+            }
+            other => panic!("expected run, got {other:?}"),
+        }
+
+        for bad in ["file", "=x"] {
+            let err = parse(&["run", "review", "--var", bad]).unwrap_err();
+            assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation, "{bad}");
+        }
+        assert!(matches!(
+            parse(&["templates", "list"]).unwrap().command,
+            Some(Command::Templates {
+                action: TemplatesCommand::List
+            })
+        ));
+    }
+
+    #[test]
+    fn embed_takes_files_and_chunking() {
+        let cli = parse(&["embed", "a.md", "b.rs", "--index", "docs.json"]).unwrap();
+        match cli.command {
+            Some(Command::Embed {
+                paths,
+                index,
+                embedding_model,
+                chunk_size,
+                chunk_overlap,
+            }) => {
+                assert_eq!(paths, [PathBuf::from("a.md"), PathBuf::from("b.rs")]);
+                assert_eq!(index, PathBuf::from("docs.json"));
+                assert_eq!(embedding_model, DEFAULT_EMBEDDING_MODEL);
+                assert_eq!(chunk_size, DEFAULT_CHUNK_SIZE as u64);
+                assert_eq!(chunk_overlap, DEFAULT_CHUNK_OVERLAP);
+            }
+            other => panic!("expected embed, got {other:?}"),
+        }
+        assert!(parse(&["embed", "--index", "docs.json"]).is_err());
+        assert!(parse(&["embed", "a.md"]).is_err());
+        assert!(parse(&["embed", "a.md", "--index", "i", "--chunk-size", "0"]).is_err());
+
+        let cli = parse(&["--context-index", "docs.json", "--top-k", "2", "hi"]).unwrap();
+        assert_eq!(cli.context_index, Some(PathBuf::from("docs.json")));
+        assert_eq!(cli.top_k, 2);
+        assert_eq!(parse(&["hi"]).unwrap().top_k, DEFAULT_TOP_K);
+    }
+
+    #[test]
+    fn batch_takes_an_input_and_limits() {
+        let cli = parse(&["batch", "prompts.jsonl"]).unwrap();
+        match cli.command {
+            Some(Command::Batch {
+                input,
+                out,
+                concurrency,
+                resume,
+            }) => {
+                assert_eq!(input, PathBuf::from("prompts.jsonl"));
+                assert_eq!(out, None);
+                assert_eq!(concurrency, DEFAULT_CONCURRENCY as u64);
+                assert!(!resume);
+            }
+            other => panic!("expected batch, got {other:?}"),
+        }
+
+        let cli = parse(&[
+            "batch",
+            "in.jsonl",
+            "--out",
+            "out.jsonl",
+            "--concurrency",
+            "16",
+            "--resume",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Batch {
+                concurrency: 16,
+                resume: true,
+                ..
+            })
+        ));
+
+        assert!(parse(&["batch", "in.jsonl", "--concurrency", "0"]).is_err());
+        let err = parse(&["batch", "in.jsonl", "--resume"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn export_takes_a_session_and_format() {
+        let cli = parse(&["export", "chat.json"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Export {
+                format: TranscriptFormat::Markdown,
+                out: None,
+                include_reasoning: false,
+                ..
+            })
+        ));
+
+        let cli = parse(&[
+            "export",
+            "chat.json",
+            "--format",
+            "html",
+            "--out",
+            "chat.html",
+            "--include-reasoning",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Export {
+                format: TranscriptFormat::Html,
+                include_reasoning: true,
+                ..
+            })
+        ));
+        assert!(parse(&["export", "chat.json", "--format", "pdf"]).is_err());
+    }
+
+    #[test]
+    fn review_takes_a_diff_source_and_ignores() {
+        let cli = parse(&["review"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Review {
+                staged: false,
+                range: None,
+                no_default_ignores: false,
+                out: None,
+                ..
+            })
+        ));
+
+        let cli = parse(&[
+            "review",
+            "--range",
+            "main..HEAD",
+            "--ignore",
+            "*.snap",
+            "--ignore",
+            "docs/**",
+        ])
+        .unwrap();
+        match cli.command {
+            Some(Command::Review { range, ignores, .. }) => {
+                assert_eq!(range.as_deref(), Some("main..HEAD"));
+                assert_eq!(ignores, ["*.snap", "docs/**"]);
+            }
+            other => panic!("expected review, got {other:?}"),
+        }
+        assert!(parse(&["review", "--staged", "--range", "main..HEAD"]).is_err());
+    }
+
+    #[test]
+    fn models_are_listed_with_owner_and_date() {
+        let models = [
+            ModelInfo {
+                id: "deepseek-chat".to_string(),
+                owned_by: Some("deepseek".to_string()),
+                created: Some(1_700_000_000),
+            },
+            ModelInfo {
+                id: "local".to_string(),
+                owned_by: None,
+                created: None,
+            },
+        ];
+        assert_eq!(
+            describe_models(&models),
+            "deepseek-chat  deepseek  2023-11-14\nlocal          -         -\n"
+        );
+    }
+
+    #[test]
+    fn templates_are_listed_with_their_overrides() {
+        let review = Template::parse(
+            "review",
+            "+++\ndescription = \"Review a file\"\nmodel = \"deepseek-reasoner\"\n\
+             temperature = 0.2\nsystem_prompt = \"Be strict.\"\n+++\nReview {{file}} in {{lang}}.",
+            Some(PathBuf::from("/t/review.md")),
+        )
+        .unwrap();
+        let plain = Template::parse("plain", "Just ask.", None).unwrap();
+        assert_eq!(
+            describe_templates([&review, &plain]),
+            "review - Review a file\n  variables: file, lang\n  \
+             sets:      model deepseek-reasoner, temperature 0.2, system prompt\n  \
+             from:      /t/review.md\n\
+             plain\n  from:      config file\n"
+        );
+    }
+
+    #[test]
+    fn missing_key_names_the_profile_variable() {
+        let cli = parse(&[]).unwrap();
+        let settings = Settings {
+            api_key_env: Some("OLLAMA_API_KEY".to_string()),
+            ..Settings::default()
+        };
+        match cli.agent_config(settings) {
+            Err(AgentError::MissingEnv(var)) => assert_eq!(var, "OLLAMA_API_KEY"),
+            other => panic!("expected MissingEnv, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn profiles_are_listed_with_redacted_keys() {
+        let file = deepseek_tutor::settings::parse(
+            r#"
+            default_profile = "deepseek"
+
+            [profiles.deepseek]
+            model = "deepseek-reasoner"
+
+            [profiles.local]
+            base_url = "http://localhost:11434/v1"
+            api_key_env = "OLLAMA_API_KEY"
+            "#,
+        )
+        .unwrap();
+        let env = |var: &str| (var == "OPENAI_API_KEY").then(|| "sk-0123456789abcdef1234".into());
+        let listing = describe_profiles(&file, env);
+        assert_eq!(
+            listing,
+            "deepseek (default)\n  base URL: https://api.deepseek.com/v1\n  model:    deepseek-reasoner\n  API key:  $OPENAI_API_KEY = sk-****1234\n\
+             local\n  base URL: http://localhost:11434/v1\n  model:    deepseek-chat\n  API key:  $OLLAMA_API_KEY (not set)\n"
+        );
+        assert!(!listing.contains("0123456789abcdef"));
+    }
+
+    #[test]
+    fn logging_flags() {
+        let cli = parse(&["-v", "--debug", "--log-file", "agent.log"]).unwrap();
+        assert!(cli.verbose);
+        assert!(cli.debug);
+        assert_eq!(
+            cli.log_file.as_deref(),
+            Some(std::path::Path::new("agent.log"))
+        );
+    }
+
+    #[test]
+    fn output_files_can_be_appended_to() {
+        let cli = parse(&["--output-file", "answer.md", "hi"]).unwrap();
+        assert_eq!(cli.output_file, Some(PathBuf::from("answer.md")));
+        assert!(!cli.append);
+        let cli = parse(&["--output-file", "answers.md", "--append"]).unwrap();
+        assert!(cli.append);
+        assert!(parse(&["--append", "hi"]).is_err());
+    }
+
+    #[test]
+    fn output_formats() {
+        let cli = parse(&["hi"]).unwrap();
+        assert_eq!(cli.output, OutputFormat::Text);
+        assert!(!cli.streaming());
+
+        let cli = parse(&["--output", "json", "hi"]).unwrap();
+        assert_eq!(cli.output, OutputFormat::Json);
+        assert!(!cli.streaming());
+
+        let cli = parse(&["--output", "jsonl", "hi"]).unwrap();
+        assert!(cli.streaming());
+
+        assert!(parse(&["--output", "yaml", "hi"]).is_err());
+    }
+
+    #[test]
+    fn replies_are_styled_unless_plain() {
+        assert!(!parse(&["hi"]).unwrap().plain);
+        assert!(parse(&["--plain", "hi"]).unwrap().plain);
+    }
+
+    #[test]
+    fn reasoning_is_shown_unless_hidden() {
+        assert!(!parse(&["hi"]).unwrap().hide_reasoning);
+        assert!(parse(&["--hide-reasoning", "hi"]).unwrap().hide_reasoning);
+    }
+
+    #[test]
+    fn command_definition_is_valid() {
+        Cli::command().debug_assert();
+    }
+}
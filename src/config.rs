@@ -0,0 +1,210 @@
+use std::time::Duration;
+
+use async_openai::config::OpenAIConfig;
+use serde_json::{Map, Value};
+use url::Url;
+
+use crate::cache::ResponseCache;
+use crate::chat::{DEFAULT_MODEL, DEFAULT_SYSTEM_PROMPT};
+use crate::context::ContextManager;
+use crate::error::AgentError;
+use crate::network::NetworkConfig;
+use crate::provider::Provider;
+use crate::ratelimit::RateLimiter;
+use crate::retry::RetryPolicy;
+use crate::secret::SecretString;
+use crate::tools::DEFAULT_MAX_TOOL_ITERATIONS;
+use crate::usage::{Budget, PriceTable};
+
+type Result<T> = std::result::Result<T, AgentError>;
+
+/// Endpoint used when `BASE_URL` is not set.
+pub const DEFAULT_BASE_URL: &str = "https://api.deepseek.com/v1";
+
+/// How long a request may go without a reply before it is abandoned.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Everything needed to construct a [`DeepSeekAgent`](crate::DeepSeekAgent).
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgentConfig {
+    pub api_key: SecretString,
+    /// Who serves `base_url`, for how the key is sent and models are named.
+    pub provider: Provider,
+    pub base_url: String,
+    pub model: String,
+    pub system_prompt: String,
+    pub params: RequestParams,
+    pub retry: RetryPolicy,
+    /// Longest wait for a reply, or for the next chunk of a streamed one.
+    pub timeout: Duration,
+    /// Tool-call rounds allowed per question.
+    pub max_tool_iterations: usize,
+    /// Prices used to estimate what each turn cost.
+    pub prices: PriceTable,
+    /// How the history is kept within the context window.
+    pub context: ContextManager,
+    /// The start of every answer, for the model to continue from. DeepSeek
+    /// only accepts this on its `/beta` endpoint.
+    pub prefill: Option<String>,
+    /// Where replies are cached, if they are.
+    pub cache: Option<ResponseCache>,
+    /// Requests that could take estimated spending past this are refused.
+    pub budget: Option<Budget>,
+    /// The proxy and certificates requests go out with.
+    pub network: NetworkConfig,
+    /// Requests wait for this before they are sent, retries included.
+    pub rate_limiter: Option<RateLimiter>,
+}
+
+impl AgentConfig {
+    /// Config for the DeepSeek endpoint with default model and prompt.
+    pub fn new(api_key: impl Into<SecretString>) -> Self {
+        Self {
+            api_key: api_key.into(),
+            provider: Provider::DeepSeek,
+            base_url: DEFAULT_BASE_URL.to_string(),
+            model: DEFAULT_MODEL.to_string(),
+            system_prompt: DEFAULT_SYSTEM_PROMPT.to_string(),
+            params: RequestParams::default(),
+            retry: RetryPolicy::default(),
+            timeout: DEFAULT_TIMEOUT,
+            max_tool_iterations: DEFAULT_MAX_TOOL_ITERATIONS,
+            prices: PriceTable::default(),
+            context: ContextManager::default(),
+            prefill: None,
+            cache: None,
+            budget: None,
+            network: NetworkConfig::default(),
+            rate_limiter: None,
+        }
+    }
+}
+
+/// Sampling parameters applied to every request. `None` leaves the provider default.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RequestParams {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    /// Text that ends the reply when generated; at most
+    /// [`MAX_STOP_SEQUENCES`](crate::chat::MAX_STOP_SEQUENCES).
+    pub stop: Vec<String>,
+    /// Ask for a reply that is a JSON object (`response_format`), as
+    /// `--json-schema` does.
+    pub json_mode: bool,
+    /// Fields added to the request body as they are, for parameters the
+    /// typed request has no field for; they replace any it sets.
+    pub extra_body: Map<String, Value>,
+}
+
+/// Pick the base URL from an optional override, falling back to DeepSeek.
+pub fn resolve_base_url(base_url: Option<&str>) -> Result<String> {
+    match base_url.map(str::trim) {
+        Some(url) if !url.is_empty() => normalize_base_url(url),
+        _ => Ok(DEFAULT_BASE_URL.to_string()),
+    }
+}
+
+/// Validate a base URL and strip trailing slashes.
+///
+/// async-openai joins the base and the request path with a plain
+/// `format!("{base}{path}")`, so `https://host/v1/` would turn into
+/// `https://host/v1//chat/completions`.
+pub fn normalize_base_url(base_url: &str) -> Result<String> {
+    let parsed = match Url::parse(base_url) {
+        Ok(url) => url,
+        Err(e) => {
+            return Err(AgentError::InvalidConfig(format!(
+                "invalid base URL '{}': {}",
+                base_url, e
+            )));
+        }
+    };
+
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return Err(AgentError::InvalidConfig(format!(
+            "invalid base URL '{}': scheme must be http or https, got '{}'",
+            base_url,
+            parsed.scheme()
+        )));
+    }
+    if parsed.host_str().is_none_or(str::is_empty) {
+        return Err(AgentError::InvalidConfig(format!(
+            "invalid base URL '{}': missing host",
+            base_url
+        )));
+    }
+    if parsed.query().is_some() || parsed.fragment().is_some() {
+        return Err(AgentError::InvalidConfig(format!(
+            "invalid base URL '{}': query strings and fragments are not allowed",
+            base_url
+        )));
+    }
+
+    Ok(parsed.as_str().trim_end_matches('/').to_string())
+}
+
+/// Build the client config pointing at `base_url`.
+pub fn build_config(api_key: &str, base_url: &str) -> Result<OpenAIConfig> {
+    let base_url = normalize_base_url(base_url)?;
+    Ok(OpenAIConfig::new()
+        .with_api_key(api_key)
+        .with_api_base(base_url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::config::Config;
+
+    fn host_of(config: &OpenAIConfig) -> String {
+        Url::parse(config.api_base())
+            .unwrap()
+            .host_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn config_points_at_given_host() {
+        let config = build_config("sk-test", "https://api.deepseek.com/v1").unwrap();
+        assert_eq!(config.api_base(), "https://api.deepseek.com/v1");
+        assert_eq!(host_of(&config), "api.deepseek.com");
+        assert_eq!(
+            config.url("/chat/completions"),
+            "https://api.deepseek.com/v1/chat/completions"
+        );
+    }
+
+    #[test]
+    fn trailing_slashes_are_stripped() {
+        let config = build_config("sk-test", "http://localhost:11434/v1//").unwrap();
+        assert_eq!(config.api_base(), "http://localhost:11434/v1");
+        assert_eq!(config.url("/models"), "http://localhost:11434/v1/models");
+    }
+
+    #[test]
+    fn bare_host_has_no_trailing_slash() {
+        let url = normalize_base_url("https://api.deepseek.com").unwrap();
+        assert_eq!(url, "https://api.deepseek.com");
+    }
+
+    #[test]
+    fn unset_or_blank_falls_back_to_deepseek() {
+        assert_eq!(resolve_base_url(None).unwrap(), DEFAULT_BASE_URL);
+        assert_eq!(resolve_base_url(Some("  ")).unwrap(), DEFAULT_BASE_URL);
+    }
+
+    #[test]
+    fn malformed_urls_are_rejected() {
+        for bad in [
+            "api.deepseek.com",
+            "ftp://api.deepseek.com",
+            "https://",
+            "https://api.deepseek.com/v1?key=1",
+            "not a url",
+        ] {
+            assert!(normalize_base_url(bad).is_err(), "accepted {bad}");
+        }
+    }
+}
@@ -0,0 +1,552 @@
+//! Keeping the history inside the model's context window.
+//!
+//! Before each question the [`ContextManager`] estimates how many tokens the
+//! history and the new prompt come to. Over budget, the oldest turns are
+//! dropped or folded into a summary; the system prompt and the last few turns
+//! are always kept.
+//!
+//! Before that, tool results are compressed: a file read again and again
+//! would otherwise be in the history once for every read. All but the latest
+//! of identical results become a stub pointing at it, and, if asked for,
+//! long results before the kept turns are cut to their start and end.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use async_openai::types::ChatCompletionRequestMessage;
+use sha2::{Digest, Sha256};
+
+use crate::chat::{estimate_tokens, system_message, user_message};
+use crate::conversation::describe;
+
+/// Tokens of history allowed before trimming, comfortably under deepseek-chat's 64K window.
+pub const DEFAULT_CONTEXT_BUDGET: usize = 60_000;
+
+/// Turns, each a question and everything answering it, never trimmed.
+pub const DEFAULT_KEEP_TURNS: usize = 4;
+
+/// Instructions for the request that summarizes trimmed turns.
+const SUMMARY_PROMPT: &str = "Summarize the conversation below for your own later reference. \
+Keep every fact, decision, name, number and open question the rest of the conversation may \
+rely on; leave out pleasantries. Answer with the summary only.";
+
+/// What to do with the oldest turns once the history is over budget.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrimStrategy {
+    /// Forget them.
+    #[default]
+    DropOldest,
+    /// Ask the model to summarize them into a single system message.
+    Summarize,
+}
+
+impl std::str::FromStr for TrimStrategy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "drop-oldest" => Ok(Self::DropOldest),
+            "summarize" => Ok(Self::Summarize),
+            _ => Err(format!(
+                "unknown trim strategy '{}', expected drop-oldest or summarize",
+                name
+            )),
+        }
+    }
+}
+
+/// How tokens are counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TokenEstimator {
+    /// Four characters a token, which is close enough for English and code.
+    #[default]
+    CharsPerToken,
+}
+
+impl TokenEstimator {
+    pub fn estimate(&self, messages: &[ChatCompletionRequestMessage]) -> usize {
+        match self {
+            Self::CharsPerToken => estimate_tokens(messages),
+        }
+    }
+}
+
+/// When and how to trim the history.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContextManager {
+    /// Estimated tokens the history and the next prompt may come to.
+    pub budget: usize,
+    /// Most recent turns that are never trimmed.
+    pub keep_turns: usize,
+    pub strategy: TrimStrategy,
+    pub estimator: TokenEstimator,
+    /// Replace all but the latest of identical tool results with a stub.
+    pub dedupe_tool_results: bool,
+    /// Cut tool results longer than this many characters, before the kept
+    /// turns, to an excerpt of their start and end.
+    pub excerpt_tool_results: Option<usize>,
+}
+
+impl Default for ContextManager {
+    fn default() -> Self {
+        Self {
+            budget: DEFAULT_CONTEXT_BUDGET,
+            keep_turns: DEFAULT_KEEP_TURNS,
+            strategy: TrimStrategy::default(),
+            estimator: TokenEstimator::default(),
+            dedupe_tool_results: true,
+            excerpt_tool_results: None,
+        }
+    }
+}
+
+impl ContextManager {
+    /// How many messages after the system prompt to trim before asking
+    /// `prompt`, or `None` if everything fits or nothing may be trimmed.
+    ///
+    /// Only whole turns are trimmed, so a tool call is never separated from
+    /// its result. Dropping takes the fewest turns that bring the estimate
+    /// under budget, or all it may if that isn't enough; summarizing takes
+    /// every turn but the kept ones, so the next summary is a while off.
+    pub fn plan(&self, messages: &[ChatCompletionRequestMessage], prompt: &str) -> Option<usize> {
+        let prompt_tokens = self.estimator.estimate(&[user_message(prompt)]);
+        if self.estimator.estimate(messages) + prompt_tokens <= self.budget {
+            return None;
+        }
+
+        let starts: Vec<usize> = messages
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(_, message)| matches!(message, ChatCompletionRequestMessage::User(_)))
+            .map(|(index, _)| index)
+            .collect();
+        let removable = starts.len().checked_sub(self.keep_turns)?;
+        if removable == 0 {
+            return None;
+        }
+        // the index the kept history starts at once `turns` turns are gone
+        let cut = |turns: usize| starts.get(turns).copied().unwrap_or(messages.len());
+
+        let turns = match self.strategy {
+            TrimStrategy::Summarize => removable,
+            TrimStrategy::DropOldest => (1..=removable)
+                .find(|&turns| {
+                    let kept = self.estimator.estimate(&messages[..1])
+                        + self.estimator.estimate(&messages[cut(turns)..]);
+                    kept + prompt_tokens <= self.budget
+                })
+                .unwrap_or(removable),
+        };
+        Some(cut(turns) - 1)
+    }
+
+    /// The tool results in `messages` to shorten, by index, with their new
+    /// content; nothing but tool results is ever changed.
+    ///
+    /// A result is a duplicate if an identical call, the same tool with the
+    /// same arguments, got the same content later on, and the latest of them
+    /// is always left whole for the model to read, never excerpted, as the
+    /// stubs point at it. Results already shortened are left as they are, so
+    /// compressing again changes nothing.
+    pub fn compress(&self, messages: &[ChatCompletionRequestMessage]) -> Vec<(usize, String)> {
+        let mut calls = HashMap::new();
+        let mut results = Vec::new();
+        // the calls whose results stubs point at
+        let mut referenced = HashSet::new();
+        for (index, message) in messages.iter().enumerate() {
+            match message {
+                ChatCompletionRequestMessage::Assistant(assistant) => {
+                    for call in assistant.tool_calls.iter().flatten() {
+                        calls.insert(call.id.as_str(), &call.function);
+                    }
+                }
+                ChatCompletionRequestMessage::Tool(tool) => {
+                    let content = describe(message).1;
+                    if let Some(kept) = stub_target(&content) {
+                        referenced.insert(kept.to_string());
+                    } else if !is_excerpt(&content) {
+                        results.push((index, tool.tool_call_id.as_str(), content));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut changes = BTreeMap::new();
+        if self.dedupe_tool_results {
+            let mut latest: HashMap<_, &str> = HashMap::new();
+            for (index, id, content) in results.iter().rev() {
+                let (name, arguments) = calls
+                    .get(id)
+                    .map_or(("", ""), |f| (f.name.as_str(), f.arguments.as_str()));
+                let kept = *latest
+                    .entry(payload_hash(name, arguments, content))
+                    .or_insert(id);
+                if kept != *id {
+                    changes.insert(*index, stub(kept));
+                    referenced.insert(kept.to_string());
+                }
+            }
+        }
+        if let Some(limit) = self.excerpt_tool_results {
+            // where the kept turns start, or the start if there are fewer
+            let kept_from = match self.keep_turns {
+                0 => messages.len(),
+                turns => messages
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, message)| matches!(message, ChatCompletionRequestMessage::User(_)))
+                    .map(|(index, _)| index)
+                    .rev()
+                    .nth(turns - 1)
+                    .unwrap_or(0),
+            };
+            for (index, id, content) in &results {
+                if *index < kept_from
+                    && !changes.contains_key(index)
+                    && !referenced.contains(*id)
+                    && content.chars().count() > limit
+                {
+                    changes.insert(*index, excerpt(content, limit));
+                }
+            }
+        }
+        changes.into_iter().collect()
+    }
+}
+
+/// What compressing tool results did before the last question.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Compressed {
+    /// Results replaced with a stub or an excerpt.
+    pub results: usize,
+    pub tokens_saved: usize,
+}
+
+/// A hash of a tool call and what it returned, to find repeats by.
+fn payload_hash(name: &str, arguments: &str, content: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in [name, arguments, content] {
+        // lengths first, so the parts can't run into each other
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+    hasher.finalize().into()
+}
+
+const STUB_START: &str = "[content identical to tool result ";
+
+/// What an older copy of the result of call `kept` is replaced with.
+fn stub(kept: &str) -> String {
+    format!("{}{}, omitted]", STUB_START, kept)
+}
+
+/// The call a stub points at, if `content` is one.
+fn stub_target(content: &str) -> Option<&str> {
+    content.strip_prefix(STUB_START)?.strip_suffix(", omitted]")
+}
+
+const EXCERPT_MARK: &str = " characters omitted ...]";
+
+/// The start and end of `content`, `limit` characters of it in all.
+fn excerpt(content: &str, limit: usize) -> String {
+    let chars: Vec<char> = content.chars().collect();
+    let head = limit.div_ceil(2);
+    let tail = limit - head;
+    format!(
+        "{}\n[... {}{}\n{}",
+        chars[..head].iter().collect::<String>(),
+        chars.len() - head - tail,
+        EXCERPT_MARK,
+        chars[chars.len() - tail..].iter().collect::<String>()
+    )
+}
+
+fn is_excerpt(content: &str) -> bool {
+    content
+        .lines()
+        .any(|line| line.starts_with("[... ") && line.ends_with(EXCERPT_MARK))
+}
+
+/// What trimming did to the history before the last question.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trimmed {
+    /// Messages removed after the system prompt.
+    pub messages: usize,
+    /// Whether they were replaced with a summary rather than dropped.
+    pub summarized: bool,
+    pub tokens_before: usize,
+    pub tokens_after: usize,
+}
+
+/// The request asking the model to summarize `messages`.
+///
+/// They go as a plain transcript: sent as they are, tool calls would need the
+/// tool definitions alongside.
+pub fn summary_request(
+    messages: &[ChatCompletionRequestMessage],
+) -> Vec<ChatCompletionRequestMessage> {
+    let transcript = messages
+        .iter()
+        .map(|message| {
+            let (role, text) = describe(message);
+            format!("{}: {}", role, text)
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    vec![system_message(SUMMARY_PROMPT), user_message(&transcript)]
+}
+
+/// The system message a summary is kept in.
+pub fn summary_message(summary: &str) -> String {
+    format!("Summary of the earlier conversation:\n{}", summary.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::Conversation;
+    use async_openai::types::ChatCompletionMessageToolCall;
+
+    /// `turns` question-and-answer pairs of about 100 tokens each.
+    fn long_history(turns: usize) -> Conversation {
+        let mut conversation = Conversation::new("sys");
+        for turn in 0..turns {
+            conversation.push_user(&format!("question {turn} {}", "q".repeat(200)));
+            conversation.push_assistant(&format!("answer {turn} {}", "a".repeat(200)));
+        }
+        conversation
+    }
+
+    fn manager(budget: usize, strategy: TrimStrategy) -> ContextManager {
+        ContextManager {
+            budget,
+            keep_turns: 2,
+            strategy,
+            ..ContextManager::default()
+        }
+    }
+
+    #[test]
+    fn histories_under_budget_are_left_alone() {
+        let conversation = long_history(3);
+        let manager = manager(10_000, TrimStrategy::DropOldest);
+        assert_eq!(manager.plan(conversation.messages(), "next"), None);
+    }
+
+    #[test]
+    fn dropping_takes_the_fewest_turns_that_fit() {
+        let mut conversation = long_history(10);
+        let manager = manager(620, TrimStrategy::DropOldest);
+
+        let count = manager.plan(conversation.messages(), "next").unwrap();
+        assert_eq!(count % 2, 0, "only whole turns are trimmed");
+        conversation.drop_oldest(count);
+
+        let messages = conversation.messages();
+        assert_eq!(describe(&messages[0]).1, "sys");
+        assert!(describe(&messages[1]).1.starts_with("question 5 "));
+        assert!(manager.estimator.estimate(messages) <= 620);
+        assert_eq!(manager.plan(messages, "next"), None);
+    }
+
+    #[test]
+    fn the_last_turns_are_kept_even_over_budget() {
+        let mut conversation = long_history(10);
+        let manager = manager(10, TrimStrategy::DropOldest);
+
+        conversation.drop_oldest(manager.plan(conversation.messages(), "next").unwrap());
+        let texts: Vec<_> = conversation
+            .messages()
+            .iter()
+            .map(|m| describe(m).1)
+            .collect();
+        assert_eq!(texts.len(), 5);
+        assert_eq!(texts[0], "sys");
+        assert!(texts[1].starts_with("question 8 "));
+        assert!(texts[3].starts_with("question 9 "));
+        assert_eq!(manager.plan(conversation.messages(), "next"), None);
+    }
+
+    #[test]
+    fn summarizing_takes_every_turn_but_the_kept_ones() {
+        let mut conversation = long_history(10);
+        let manager = manager(620, TrimStrategy::Summarize);
+
+        let count = manager.plan(conversation.messages(), "next").unwrap();
+        assert_eq!(count, 16);
+        let request = summary_request(&conversation.messages()[1..=count]);
+        assert_eq!(request.len(), 2);
+        let transcript = describe(&request[1]).1;
+        assert!(transcript.starts_with("user: question 0 "), "{transcript}");
+        assert!(transcript.contains("assistant: answer 7 "));
+        assert!(!transcript.contains("question 8 "));
+
+        conversation.summarize_oldest(count, &summary_message("they asked ten questions"));
+        let messages = conversation.messages();
+        assert_eq!(messages.len(), 6);
+        assert_eq!(
+            describe(&messages[1]),
+            (
+                "system",
+                "Summary of the earlier conversation:\nthey asked ten questions".to_string()
+            )
+        );
+        assert!(describe(&messages[2]).1.starts_with("question 8 "));
+    }
+
+    #[test]
+    fn a_second_summary_folds_in_the_first() {
+        let mut conversation = long_history(0);
+        conversation.summarize_oldest(0, &summary_message("earlier"));
+        for turn in 0..6 {
+            conversation.push_user(&format!("question {turn} {}", "q".repeat(200)));
+            conversation.push_assistant("ok");
+        }
+        let manager = manager(200, TrimStrategy::Summarize);
+
+        let count = manager.plan(conversation.messages(), "next").unwrap();
+        let transcript = describe(&summary_request(&conversation.messages()[1..=count])[1]).1;
+        assert!(transcript.starts_with("system: Summary of the earlier conversation:\nearlier"));
+        assert!(transcript.contains("question 3 "));
+    }
+
+    fn tool_call(id: &str, name: &str, arguments: &str) -> ChatCompletionMessageToolCall {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "type": "function",
+            "function": { "name": name, "arguments": arguments }
+        }))
+        .unwrap()
+    }
+
+    /// A question answered after one tool call returning `content`.
+    fn ask_tool(conversation: &mut Conversation, id: &str, arguments: &str, content: &str) {
+        conversation.push_user(&format!("what does call {id} say?"));
+        conversation.push_tool_calls("", vec![tool_call(id, "read_file", arguments)]);
+        conversation.push_tool_result(id, content);
+        conversation.push_assistant(content);
+    }
+
+    fn apply(conversation: &mut Conversation, changes: &[(usize, String)]) {
+        for (index, content) in changes {
+            assert!(conversation.replace_tool_result(*index, content));
+        }
+    }
+
+    #[test]
+    fn repeated_tool_results_keep_only_the_latest() {
+        let mut conversation = Conversation::new("sys");
+        let file = "fn main() {}\n".repeat(50);
+        ask_tool(&mut conversation, "call_1", r#"{"path":"main.rs"}"#, &file);
+        ask_tool(&mut conversation, "call_2", r#"{"path":"lib.rs"}"#, &file);
+        ask_tool(
+            &mut conversation,
+            "call_3",
+            r#"{"path":"main.rs"}"#,
+            "changed",
+        );
+        ask_tool(&mut conversation, "call_4", r#"{"path":"main.rs"}"#, &file);
+        let manager = ContextManager::default();
+
+        let changes = manager.compress(conversation.messages());
+        // the same file read with other arguments, and other content, are kept
+        assert_eq!(
+            changes,
+            [(
+                3,
+                "[content identical to tool result call_4, omitted]".to_string()
+            )]
+        );
+        let before = manager.estimator.estimate(conversation.messages());
+        apply(&mut conversation, &changes);
+        assert!(manager.estimator.estimate(conversation.messages()) < before);
+
+        let messages = conversation.messages();
+        assert_eq!(describe(&messages[15]).1, file, "the latest copy is whole");
+        assert_eq!(describe(&messages[16]).1, file, "answers are never changed");
+        assert_eq!(describe(&messages[7]).1, file);
+        assert!(manager.compress(messages).is_empty());
+
+        let keep_all = ContextManager {
+            dedupe_tool_results: false,
+            ..ContextManager::default()
+        };
+        let mut conversation = Conversation::new("sys");
+        ask_tool(&mut conversation, "call_1", "{}", &file);
+        ask_tool(&mut conversation, "call_2", "{}", &file);
+        assert!(keep_all.compress(conversation.messages()).is_empty());
+    }
+
+    #[test]
+    fn long_results_are_excerpted_outside_the_kept_turns() {
+        let mut conversation = Conversation::new("sys");
+        let long = format!("{}{}", "a".repeat(500), "z".repeat(500));
+        conversation.push_user(&"question ".repeat(200));
+        conversation.push_assistant(&"answer ".repeat(200));
+        ask_tool(&mut conversation, "call_1", "{}", &long);
+        ask_tool(&mut conversation, "call_2", r#"{"n":2}"#, &long);
+        ask_tool(&mut conversation, "call_3", r#"{"n":3}"#, &long);
+        let manager = ContextManager {
+            keep_turns: 2,
+            excerpt_tool_results: Some(10),
+            ..ContextManager::default()
+        };
+
+        let changes = manager.compress(conversation.messages());
+        assert_eq!(
+            changes,
+            [(
+                5,
+                "aaaaa\n[... 990 characters omitted ...]\nzzzzz".to_string()
+            )]
+        );
+        apply(&mut conversation, &changes);
+        assert!(manager.compress(conversation.messages()).is_empty());
+
+        let messages = conversation.messages();
+        assert_eq!(describe(&messages[1]).1, "question ".repeat(200));
+        assert_eq!(describe(&messages[2]).1, "answer ".repeat(200));
+        assert_eq!(describe(&messages[9]).1, long);
+        assert_eq!(describe(&messages[13]).1, long);
+    }
+
+    #[test]
+    fn duplicates_are_stubbed_and_their_kept_copy_left_whole() {
+        let mut conversation = Conversation::new("sys");
+        let long = "x".repeat(1000);
+        ask_tool(&mut conversation, "call_1", "{}", &long);
+        ask_tool(&mut conversation, "call_2", "{}", &long);
+        ask_tool(&mut conversation, "call_3", r#"{"path":"other"}"#, &long);
+        let manager = ContextManager {
+            keep_turns: 0,
+            excerpt_tool_results: Some(100),
+            ..ContextManager::default()
+        };
+
+        let changes = manager.compress(conversation.messages());
+        assert_eq!(changes.len(), 2);
+        assert_eq!(
+            changes[0],
+            (
+                3,
+                "[content identical to tool result call_2, omitted]".to_string()
+            )
+        );
+        // call_2's copy is the one the stub points at; call_3 has none
+        assert_eq!(changes[1].0, 11);
+        assert!(changes[1].1.contains("[... 900 characters omitted ...]"));
+
+        // nor is it excerpted once the stub is in the history
+        apply(&mut conversation, &changes);
+        assert!(manager.compress(conversation.messages()).is_empty());
+        assert_eq!(describe(&conversation.messages()[7]).1, long);
+    }
+
+    #[test]
+    fn strategies_parse_from_their_flag_names() {
+        assert_eq!("drop-oldest".parse(), Ok(TrimStrategy::DropOldest));
+        assert_eq!("summarize".parse(), Ok(TrimStrategy::Summarize));
+        assert!("truncate".parse::<TrimStrategy>().is_err());
+    }
+}
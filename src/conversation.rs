@@ -0,0 +1,599 @@
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessage,
+    ChatCompletionRequestAssistantMessageContent, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageContent, ChatCompletionRequestToolMessageContent,
+    ChatCompletionRequestUserMessageContent,
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::chat::{system_message, tool_calls_message, tool_message, user_message};
+
+/// The running message history sent with every request.
+///
+/// The system prompt is always the first message and survives `clear()`.
+/// Every message carries the time it was added, and answers the reasoning
+/// behind them, if the model showed any; that is kept for transcripts and
+/// never sent back.
+#[derive(Debug, Clone)]
+pub struct Conversation {
+    messages: Vec<ChatCompletionRequestMessage>,
+    timestamps: Vec<DateTime<Utc>>,
+    reasoning: Vec<Option<String>>,
+}
+
+/// A message with the time it was added and the reasoning behind it.
+pub type Entry<M> = (DateTime<Utc>, M, Option<String>);
+
+impl Conversation {
+    pub fn new(system_prompt: &str) -> Self {
+        Self {
+            messages: vec![system_message(system_prompt)],
+            timestamps: vec![Utc::now()],
+            reasoning: vec![None],
+        }
+    }
+
+    /// Rebuild a conversation from timestamped messages, e.g. a saved session.
+    ///
+    /// `None` unless the first message is a system prompt.
+    pub fn from_entries(
+        entries: impl IntoIterator<Item = Entry<ChatCompletionRequestMessage>>,
+    ) -> Option<Self> {
+        let mut conversation = Self {
+            messages: Vec::new(),
+            timestamps: Vec::new(),
+            reasoning: Vec::new(),
+        };
+        for (timestamp, message, reasoning) in entries {
+            conversation.messages.push(message);
+            conversation.timestamps.push(timestamp);
+            conversation.reasoning.push(reasoning);
+        }
+        match conversation.messages.first() {
+            Some(ChatCompletionRequestMessage::System(_)) => Some(conversation),
+            _ => None,
+        }
+    }
+
+    pub fn push_user(&mut self, content: &str) {
+        self.push(user_message(content));
+    }
+
+    pub fn push_assistant(&mut self, content: &str) {
+        self.push(ChatCompletionRequestAssistantMessage::from(content).into());
+    }
+
+    /// Add an answer with the reasoning the model showed before it.
+    pub fn push_answer(&mut self, content: &str, reasoning: Option<&str>) {
+        self.push_assistant(content);
+        *self.reasoning.last_mut().expect("never empty") = reasoning.map(str::to_string);
+    }
+
+    /// Record that the assistant asked for `tool_calls` to be run.
+    pub fn push_tool_calls(
+        &mut self,
+        content: &str,
+        tool_calls: Vec<ChatCompletionMessageToolCall>,
+    ) {
+        self.push(tool_calls_message(content, tool_calls));
+    }
+
+    pub fn push_tool_result(&mut self, tool_call_id: &str, content: &str) {
+        self.push(tool_message(tool_call_id, content));
+    }
+
+    fn push(&mut self, message: ChatCompletionRequestMessage) {
+        self.messages.push(message);
+        self.timestamps.push(Utc::now());
+        self.reasoning.push(None);
+    }
+
+    /// Remove the most recent message, never the system prompt.
+    pub fn pop(&mut self) -> Option<ChatCompletionRequestMessage> {
+        if self.messages.len() > 1 {
+            self.timestamps.pop();
+            self.reasoning.pop();
+            self.messages.pop()
+        } else {
+            None
+        }
+    }
+
+    /// The text of the last question asked, if any is left.
+    pub fn last_user(&self) -> Option<String> {
+        self.last_user_index()
+            .map(|index| describe(&self.messages[index]).1)
+    }
+
+    /// Remove the last question and everything after it: its answer, and any
+    /// tool calls and their results in between. Returns the question; `None`,
+    /// changing nothing, if there isn't one.
+    pub fn pop_last_exchange(&mut self) -> Option<String> {
+        let index = self.last_user_index()?;
+        let question = describe(&self.messages[index]).1;
+        self.truncate(index - 1);
+        Some(question)
+    }
+
+    /// Replace the text of the last question, removing everything after it,
+    /// so the history ends with it, ready to be answered again. Returns the
+    /// text it had; `None`, changing nothing, if there isn't one.
+    pub fn replace_last_user(&mut self, content: &str) -> Option<String> {
+        let index = self.last_user_index()?;
+        let question = describe(&self.messages[index]).1;
+        self.truncate(index);
+        self.messages[index] = user_message(content);
+        self.timestamps[index] = Utc::now();
+        Some(question)
+    }
+
+    /// Where the last user message is; never the system prompt.
+    fn last_user_index(&self) -> Option<usize> {
+        self.messages
+            .iter()
+            .rposition(|message| matches!(message, ChatCompletionRequestMessage::User(_)))
+    }
+
+    /// Keep only the first `len` messages after the system prompt.
+    pub fn truncate(&mut self, len: usize) {
+        self.messages.truncate(len + 1);
+        self.timestamps.truncate(len + 1);
+        self.reasoning.truncate(len + 1);
+    }
+
+    /// Remove the first `count` messages after the system prompt.
+    pub fn drop_oldest(&mut self, count: usize) {
+        self.messages.drain(1..=count);
+        self.timestamps.drain(1..=count);
+        self.reasoning.drain(1..=count);
+    }
+
+    /// Replace the first `count` messages after the system prompt with
+    /// `summary`, as a system message.
+    pub fn summarize_oldest(&mut self, count: usize, summary: &str) {
+        self.drop_oldest(count);
+        self.messages.insert(1, system_message(summary));
+        self.timestamps.insert(1, Utc::now());
+        self.reasoning.insert(1, None);
+    }
+
+    /// Replace the content of the tool result at `index`, keeping the call
+    /// it answers. Returns whether there was one there; other messages are
+    /// never changed.
+    pub fn replace_tool_result(&mut self, index: usize, content: &str) -> bool {
+        match self.messages.get_mut(index) {
+            Some(ChatCompletionRequestMessage::Tool(tool)) => {
+                tool.content = ChatCompletionRequestToolMessageContent::Text(content.to_string());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Replace the system prompt, keeping every turn.
+    pub fn set_system_prompt(&mut self, system_prompt: &str) {
+        self.messages[0] = system_message(system_prompt);
+    }
+
+    /// Replace the system prompt with a new version of it, as when its file
+    /// is reloaded, keeping every turn. Returns how many characters changed;
+    /// a conversation started without a prompt has all of `system_prompt` new.
+    pub fn replace_system_prompt(&mut self, system_prompt: &str) -> usize {
+        let changed = changed_chars(&describe(&self.messages[0]).1, system_prompt);
+        self.messages[0] = system_message(system_prompt);
+        self.timestamps[0] = Utc::now();
+        changed
+    }
+
+    /// Drop every turn but keep the system prompt.
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    pub fn messages(&self) -> &[ChatCompletionRequestMessage] {
+        &self.messages
+    }
+
+    /// Every message with the time it was added and any reasoning behind
+    /// it, system prompt first.
+    pub fn entries(&self) -> impl Iterator<Item = Entry<&ChatCompletionRequestMessage>> {
+        self.timestamps
+            .iter()
+            .zip(&self.messages)
+            .zip(&self.reasoning)
+            .map(|((timestamp, message), reasoning)| (*timestamp, message, reasoning.clone()))
+    }
+
+    /// Number of messages, excluding the system prompt.
+    pub fn len(&self) -> usize {
+        self.messages.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// How many characters differ between `old` and `new`: what is left of the
+/// longer once the text they start and end with is set aside.
+fn changed_chars(old: &str, new: &str) -> usize {
+    let old: Vec<char> = old.chars().collect();
+    let new: Vec<char> = new.chars().collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    (old.len() - prefix - suffix).max(new.len() - prefix - suffix)
+}
+
+/// Role name and plain text of a message, for display.
+pub fn describe(message: &ChatCompletionRequestMessage) -> (&'static str, String) {
+    match message {
+        ChatCompletionRequestMessage::Developer(m) => ("developer", format!("{:?}", m.content)),
+        ChatCompletionRequestMessage::System(m) => (
+            "system",
+            match &m.content {
+                ChatCompletionRequestSystemMessageContent::Text(text) => text.clone(),
+                other => format!("{:?}", other),
+            },
+        ),
+        ChatCompletionRequestMessage::User(m) => (
+            "user",
+            match &m.content {
+                ChatCompletionRequestUserMessageContent::Text(text) => text.clone(),
+                other => format!("{:?}", other),
+            },
+        ),
+        ChatCompletionRequestMessage::Assistant(m) => (
+            "assistant",
+            match (&m.content, &m.tool_calls) {
+                (Some(ChatCompletionRequestAssistantMessageContent::Text(text)), _)
+                    if !text.is_empty() =>
+                {
+                    text.clone()
+                }
+                (_, Some(calls)) if !calls.is_empty() => calls
+                    .iter()
+                    .map(|call| {
+                        format!("calls {}({})", call.function.name, call.function.arguments)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                (Some(other), _) => format!("{:?}", other),
+                (None, _) => String::new(),
+            },
+        ),
+        ChatCompletionRequestMessage::Tool(m) => (
+            "tool",
+            match &m.content {
+                ChatCompletionRequestToolMessageContent::Text(text) => text.clone(),
+                other => format!("{:?}", other),
+            },
+        ),
+        ChatCompletionRequestMessage::Function(m) => {
+            ("function", m.content.clone().unwrap_or_default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roles(conversation: &Conversation) -> Vec<&'static str> {
+        conversation
+            .messages()
+            .iter()
+            .map(|m| describe(m).0)
+            .collect()
+    }
+
+    #[test]
+    fn starts_with_system_prompt_only() {
+        let conversation = Conversation::new("Be helpful.");
+        assert_eq!(roles(&conversation), ["system"]);
+        assert_eq!(describe(&conversation.messages()[0]).1, "Be helpful.");
+        assert!(conversation.is_empty());
+    }
+
+    #[test]
+    fn turns_are_appended_in_order() {
+        let mut conversation = Conversation::new("sys");
+        conversation.push_user("hi");
+        conversation.push_assistant("hello");
+        conversation.push_user("how are you?");
+
+        assert_eq!(
+            roles(&conversation),
+            ["system", "user", "assistant", "user"]
+        );
+        assert_eq!(describe(&conversation.messages()[2]).1, "hello");
+        assert_eq!(conversation.len(), 3);
+    }
+
+    #[test]
+    fn clear_keeps_system_prompt() {
+        let mut conversation = Conversation::new("sys");
+        conversation.push_user("hi");
+        conversation.push_assistant("hello");
+        conversation.clear();
+
+        assert_eq!(roles(&conversation), ["system"]);
+        assert_eq!(describe(&conversation.messages()[0]).1, "sys");
+    }
+
+    #[test]
+    fn tool_turns_are_recorded_and_described() {
+        let call: ChatCompletionMessageToolCall = serde_json::from_value(serde_json::json!({
+            "id": "call_1",
+            "type": "function",
+            "function": { "name": "calculator", "arguments": "{\"expression\":\"2+2\"}" }
+        }))
+        .unwrap();
+        let mut conversation = Conversation::new("sys");
+        conversation.push_user("what is 2+2?");
+        conversation.push_tool_calls("", vec![call]);
+        conversation.push_tool_result("call_1", "4");
+
+        assert_eq!(
+            roles(&conversation),
+            ["system", "user", "assistant", "tool"]
+        );
+        assert_eq!(
+            describe(&conversation.messages()[2]).1,
+            r#"calls calculator({"expression":"2+2"})"#
+        );
+        assert_eq!(describe(&conversation.messages()[3]).1, "4");
+    }
+
+    #[test]
+    fn truncate_rolls_back_to_a_length() {
+        let mut conversation = Conversation::new("sys");
+        conversation.push_user("hi");
+        conversation.push_assistant("hello");
+        conversation.push_user("again");
+        conversation.truncate(1);
+        assert_eq!(roles(&conversation), ["system", "user"]);
+
+        conversation.truncate(0);
+        assert_eq!(roles(&conversation), ["system"]);
+    }
+
+    #[test]
+    fn system_prompt_can_be_replaced() {
+        let mut conversation = Conversation::new("sys");
+        conversation.push_user("hi");
+        conversation.set_system_prompt("new");
+        assert_eq!(describe(&conversation.messages()[0]).1, "new");
+        assert_eq!(roles(&conversation), ["system", "user"]);
+    }
+
+    #[test]
+    fn reloaded_system_prompts_keep_the_history() {
+        let mut conversation = Conversation::new("Be brief.");
+        conversation.push_user("hi");
+        conversation.push_answer("hello", Some("greet back"));
+        conversation.summarize_oldest(1, "they said hi");
+        conversation.push_user("again");
+        let before: Vec<_> = conversation.messages()[1..].iter().map(describe).collect();
+
+        assert_eq!(conversation.replace_system_prompt("Be very brief."), 5);
+        assert_eq!(describe(&conversation.messages()[0]).1, "Be very brief.");
+        let after: Vec<_> = conversation.messages()[1..].iter().map(describe).collect();
+        assert_eq!(after, before);
+        assert_eq!(
+            roles(&conversation),
+            ["system", "system", "assistant", "user"]
+        );
+        let reasoning: Vec<_> = conversation.entries().map(|(_, _, r)| r).collect();
+        assert_eq!(
+            reasoning,
+            [None, None, Some("greet back".to_string()), None]
+        );
+
+        // the same prompt again changes nothing
+        assert_eq!(conversation.replace_system_prompt("Be very brief."), 0);
+    }
+
+    #[test]
+    fn reloading_into_a_conversation_without_a_prompt() {
+        let mut conversation = Conversation::new("");
+        conversation.push_user("hi");
+        assert_eq!(conversation.replace_system_prompt("Be brief."), 9);
+        assert_eq!(describe(&conversation.messages()[0]).1, "Be brief.");
+        assert_eq!(roles(&conversation), ["system", "user"]);
+    }
+
+    #[test]
+    fn changed_characters_skip_the_common_ends() {
+        assert_eq!(changed_chars("abc", "abc"), 0);
+        assert_eq!(changed_chars("Be brief.", "Be terse."), 5);
+        assert_eq!(changed_chars("Be brief.", "Be brief. Use Rust."), 10);
+        assert_eq!(changed_chars("Be brief. Use Rust.", "Use Rust."), 10);
+        assert_eq!(changed_chars("", "héllo"), 5);
+        assert_eq!(changed_chars("aaa", "aa"), 1);
+    }
+
+    fn tool_call(id: &str, name: &str, arguments: &str) -> ChatCompletionMessageToolCall {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "type": "function",
+            "function": { "name": name, "arguments": arguments }
+        }))
+        .unwrap()
+    }
+
+    /// A question answered after a round of tool calls, then a plain one.
+    fn with_tool_calls() -> Conversation {
+        let mut conversation = Conversation::new("sys");
+        conversation.push_user("what is 6*7?");
+        conversation.push_tool_calls("", vec![tool_call("call_1", "calculator", "{}")]);
+        conversation.push_tool_result("call_1", "42");
+        conversation.push_answer("42", Some("multiplied"));
+        conversation.push_user("and 6*8?");
+        conversation.push_tool_calls("", vec![tool_call("call_2", "calculator", "{}")]);
+        conversation.push_tool_result("call_2", "48");
+        conversation.push_assistant("48");
+        conversation
+    }
+
+    #[test]
+    fn exchanges_are_popped_with_their_tool_calls() {
+        let mut conversation = with_tool_calls();
+        assert_eq!(conversation.last_user().as_deref(), Some("and 6*8?"));
+
+        assert_eq!(
+            conversation.pop_last_exchange().as_deref(),
+            Some("and 6*8?")
+        );
+        assert_eq!(
+            roles(&conversation),
+            ["system", "user", "assistant", "tool", "assistant"]
+        );
+        let reasoning: Vec<_> = conversation.entries().map(|(_, _, r)| r).collect();
+        assert_eq!(reasoning.last().unwrap().as_deref(), Some("multiplied"));
+
+        assert_eq!(
+            conversation.pop_last_exchange().as_deref(),
+            Some("what is 6*7?")
+        );
+        assert_eq!(roles(&conversation), ["system"]);
+        assert_eq!(conversation.entries().count(), 1);
+        assert_eq!(conversation.pop_last_exchange(), None);
+        assert_eq!(roles(&conversation), ["system"]);
+    }
+
+    #[test]
+    fn unanswered_questions_are_popped_too() {
+        let mut conversation = Conversation::new("sys");
+        conversation.push_user("one");
+        conversation.push_assistant("1");
+        conversation.push_user("two");
+        assert_eq!(conversation.pop_last_exchange().as_deref(), Some("two"));
+        assert_eq!(roles(&conversation), ["system", "user", "assistant"]);
+    }
+
+    #[test]
+    fn summaries_are_not_questions() {
+        let mut conversation = Conversation::new("sys");
+        conversation.push_user("one");
+        conversation.push_assistant("1");
+        conversation.summarize_oldest(2, "they counted");
+        assert_eq!(conversation.last_user(), None);
+        assert_eq!(conversation.pop_last_exchange(), None);
+        assert_eq!(conversation.replace_last_user("two"), None);
+        assert_eq!(roles(&conversation), ["system", "system"]);
+    }
+
+    #[test]
+    fn replacing_the_last_question_drops_its_answer() {
+        let mut conversation = with_tool_calls();
+        assert_eq!(
+            conversation.replace_last_user("and 7*8?").as_deref(),
+            Some("and 6*8?")
+        );
+        assert_eq!(
+            roles(&conversation),
+            ["system", "user", "assistant", "tool", "assistant", "user"]
+        );
+        assert_eq!(conversation.last_user().as_deref(), Some("and 7*8?"));
+        assert_eq!(conversation.entries().count(), 6);
+
+        // asked again, it reads as if it had been asked that way
+        conversation.push_assistant("56");
+        assert_eq!(conversation.len(), 6);
+        assert_eq!(
+            conversation.pop_last_exchange().as_deref(),
+            Some("and 7*8?")
+        );
+        assert_eq!(conversation.len(), 4);
+    }
+
+    #[test]
+    fn oldest_messages_are_dropped_or_summarized() {
+        let mut conversation = Conversation::new("sys");
+        for text in ["one", "two", "three", "four"] {
+            conversation.push_user(text);
+        }
+        conversation.drop_oldest(1);
+        assert_eq!(describe(&conversation.messages()[1]).1, "two");
+
+        conversation.summarize_oldest(2, "one to three");
+        assert_eq!(roles(&conversation), ["system", "system", "user"]);
+        assert_eq!(describe(&conversation.messages()[1]).1, "one to three");
+        assert_eq!(conversation.entries().count(), 3);
+    }
+
+    #[test]
+    fn entries_carry_timestamps_in_order() {
+        let mut conversation = Conversation::new("sys");
+        conversation.push_user("hi");
+        conversation.push_assistant("hello");
+        let times: Vec<_> = conversation.entries().map(|(time, _, _)| time).collect();
+        assert_eq!(times.len(), 3);
+        assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+
+        conversation.pop();
+        assert_eq!(conversation.entries().count(), 2);
+    }
+
+    #[test]
+    fn from_entries_requires_a_system_prompt() {
+        let now = Utc::now();
+        let restored = Conversation::from_entries([
+            (now, system_message("sys"), None),
+            (now, user_message("hi"), None),
+        ])
+        .unwrap();
+        assert_eq!(roles(&restored), ["system", "user"]);
+        assert!(Conversation::from_entries([(now, user_message("hi"), None)]).is_none());
+        assert!(Conversation::from_entries([]).is_none());
+    }
+
+    #[test]
+    fn reasoning_stays_with_its_answer() {
+        let mut conversation = Conversation::new("sys");
+        conversation.push_user("Is 1013 prime?");
+        conversation.push_answer("Yes.", Some("No divisor up to 31."));
+        conversation.push_user("And 1015?");
+        conversation.push_answer("No, 5 divides it.", None);
+
+        let reasoning: Vec<_> = conversation.entries().map(|(_, _, r)| r).collect();
+        assert_eq!(
+            reasoning,
+            [
+                None,
+                None,
+                Some("No divisor up to 31.".to_string()),
+                None,
+                None
+            ]
+        );
+        // the message itself is a plain answer, as sent back
+        assert_eq!(
+            describe(&conversation.messages()[2]),
+            ("assistant", "Yes.".to_string())
+        );
+
+        conversation.drop_oldest(1);
+        assert_eq!(
+            conversation.entries().nth(1).unwrap().2.as_deref(),
+            Some("No divisor up to 31.")
+        );
+        conversation.truncate(1);
+        assert_eq!(conversation.entries().count(), 2);
+    }
+
+    #[test]
+    fn pop_never_removes_system_prompt() {
+        let mut conversation = Conversation::new("sys");
+        conversation.push_user("hi");
+        assert!(conversation.pop().is_some());
+        assert!(conversation.pop().is_none());
+        assert_eq!(roles(&conversation), ["system"]);
+    }
+}
@@ -0,0 +1,656 @@
+//! The checks `doctor` runs to find what stands between this machine and a
+//! working reply: the config, the proxy, DNS, TLS, the key, the model and
+//! the clock.
+//!
+//! Each check is its own function returning a [`CheckResult`], so a failure
+//! in one doesn't hide the others and each can be tried alone against a mock
+//! server. A failed check says what went wrong and what to try; the ones
+//! nothing works without are critical.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use async_openai::error::OpenAIError;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use url::{Host, Url};
+
+use crate::agent::DeepSeekAgent;
+use crate::error::AgentError;
+use crate::models;
+use crate::network::NetworkConfig;
+
+/// Longest wait for a DNS answer, or for the endpoint to answer a plain
+/// request.
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Clock skew past which the clock check warns, and past which it fails.
+const SKEW_WARN: Duration = Duration::from_secs(60);
+const SKEW_FAIL: Duration = Duration::from_secs(5 * 60);
+
+/// The question the chat check asks; one token of any answer will do.
+const CHAT_PROMPT: &str = "Reply with the single word OK.";
+
+/// How a check went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    /// It works, but something may trip it up.
+    Warn,
+    Fail,
+    /// Not tried, as it doesn't apply or an earlier problem rules it out.
+    Skip,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Pass => "pass",
+            Self::Warn => "warn",
+            Self::Fail => "fail",
+            Self::Skip => "skip",
+        })
+    }
+}
+
+/// One check's outcome.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub status: Status,
+    /// Whether requests can't work while this check fails.
+    pub critical: bool,
+    /// What was found, or what went wrong.
+    pub detail: String,
+    /// What to try, for a check that didn't pass.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl CheckResult {
+    fn pass(name: &'static str, detail: String) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            critical: false,
+            detail,
+            hint: None,
+        }
+    }
+
+    /// A check not tried, and why.
+    pub fn skip(name: &'static str, detail: String) -> Self {
+        Self {
+            status: Status::Skip,
+            ..Self::pass(name, detail)
+        }
+    }
+
+    fn warn(name: &'static str, detail: String, hint: &str) -> Self {
+        Self {
+            status: Status::Warn,
+            hint: Some(hint.to_string()),
+            ..Self::pass(name, detail)
+        }
+    }
+
+    /// A critical failure.
+    fn fail(name: &'static str, detail: String, hint: &str) -> Self {
+        Self {
+            status: Status::Fail,
+            critical: true,
+            hint: Some(hint.to_string()),
+            ..Self::pass(name, detail)
+        }
+    }
+
+    /// Whether this is a failure requests can't work with.
+    pub fn is_critical_failure(&self) -> bool {
+        self.status == Status::Fail && self.critical
+    }
+
+    /// The check as `doctor` prints it: a status line, then its hint.
+    pub fn to_text(&self) -> String {
+        let mut text = format!("[{}] {:<7} {}", self.status, self.name, self.detail);
+        if let Some(hint) = &self.hint {
+            text.push_str(&format!("\n       {:<7} {}", "", hint));
+        }
+        text
+    }
+}
+
+/// How many of `results` are critical failures.
+pub fn critical_failures(results: &[CheckResult]) -> usize {
+    results.iter().filter(|r| r.is_critical_failure()).count()
+}
+
+/// Where the settings were found.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Discovery {
+    /// The `.env` file read, if there was one.
+    pub env_file: Option<PathBuf>,
+    /// The config file looked for, and whether it was there.
+    pub config_file: Option<PathBuf>,
+    pub config_found: bool,
+    /// The config file profile in use.
+    pub profile: Option<String>,
+    /// Where the API key came from, like `$OPENAI_API_KEY`; `None` for no key.
+    pub api_key_from: Option<String>,
+    /// The environment variable the key is read from.
+    pub api_key_var: String,
+}
+
+/// Whether there is an API key, and which files the settings came from.
+pub fn config(discovery: &Discovery) -> CheckResult {
+    const NAME: &str = "config";
+    let mut found = Vec::new();
+    match (&discovery.config_file, discovery.config_found) {
+        (Some(path), true) => found.push(match &discovery.profile {
+            Some(profile) => format!("config file {} (profile {})", path.display(), profile),
+            None => format!("config file {}", path.display()),
+        }),
+        (Some(path), false) => found.push(format!("no config file at {}", path.display())),
+        (None, _) => found.push("no config file (HOME is not set)".to_string()),
+    }
+    found.push(match &discovery.env_file {
+        Some(path) => format!(".env at {}", path.display()),
+        None => "no .env file".to_string(),
+    });
+    match &discovery.api_key_from {
+        Some(from) => {
+            CheckResult::pass(NAME, format!("API key from {}; {}", from, found.join("; ")))
+        }
+        None => CheckResult::fail(
+            NAME,
+            format!("no API key; {}", found.join("; ")),
+            &format!(
+                "set {} in the environment or a .env file, or run `deepseek_agent init`",
+                discovery.api_key_var
+            ),
+        ),
+    }
+}
+
+/// Whether the proxy, if there is one, takes connections.
+pub async fn proxy(network: &NetworkConfig) -> CheckResult {
+    const NAME: &str = "proxy";
+    let Some(proxy) = &network.proxy else {
+        return CheckResult::skip(NAME, "no proxy is set".to_string());
+    };
+    match network.check_proxy().await {
+        Ok(()) => CheckResult::pass(NAME, format!("{} takes connections", proxy)),
+        Err(e) => CheckResult::fail(
+            NAME,
+            e.to_string(),
+            "check --proxy or HTTPS_PROXY, or unset it if this network needs none",
+        ),
+    }
+}
+
+/// Whether the endpoint's host name resolves. Through a proxy, the proxy
+/// resolves it instead.
+pub async fn dns(base_url: &str, network: &NetworkConfig) -> CheckResult {
+    const NAME: &str = "dns";
+    let (host, port) = match host_and_port(base_url) {
+        Ok(host) => host,
+        Err(e) => return CheckResult::fail(NAME, e, "check --base-url"),
+    };
+    if network.proxy.is_some() {
+        return CheckResult::skip(NAME, format!("the proxy resolves {}", host));
+    }
+    if host.parse::<std::net::IpAddr>().is_ok() {
+        return CheckResult::pass(
+            NAME,
+            format!("{} is an address, with nothing to resolve", host),
+        );
+    }
+    let lookup = tokio::net::lookup_host((host.as_str(), port));
+    match tokio::time::timeout(CHECK_TIMEOUT, lookup).await {
+        Ok(Ok(addresses)) => {
+            let addresses: Vec<String> = addresses.map(|a| a.ip().to_string()).collect();
+            match addresses.is_empty() {
+                true => CheckResult::fail(
+                    NAME,
+                    format!("{} has no addresses", host),
+                    "check the host in --base-url",
+                ),
+                false => CheckResult::pass(
+                    NAME,
+                    format!("{} resolves to {}", host, addresses.join(", ")),
+                ),
+            }
+        }
+        Ok(Err(e)) => CheckResult::fail(
+            NAME,
+            format!("could not resolve {}: {}", host, e),
+            "check the host in --base-url for typos, and that DNS works on this network \
+             (some endpoints need a VPN)",
+        ),
+        Err(_) => CheckResult::fail(
+            NAME,
+            format!("no DNS answer for {} in {}s", host, CHECK_TIMEOUT.as_secs()),
+            "check this network's DNS, or set --proxy if it only reaches the internet through one",
+        ),
+    }
+}
+
+/// Whether the endpoint takes a connection and, over HTTPS, completes a TLS
+/// handshake. Any HTTP answer will do; `client` is set up like requests'.
+pub async fn tls(base_url: &str, client: &reqwest::Client) -> CheckResult {
+    const NAME: &str = "tls";
+    let (host, port) = match host_and_port(base_url) {
+        Ok(host) => host,
+        Err(e) => return CheckResult::fail(NAME, e, "check --base-url"),
+    };
+    let started = Instant::now();
+    let response = match fetch(client, base_url).await {
+        Ok(response) => response,
+        Err(failure) => return failure.into_result(NAME, &host, port),
+    };
+    let elapsed = started.elapsed().as_millis();
+    if base_url.starts_with("https://") {
+        CheckResult::pass(
+            NAME,
+            format!(
+                "TLS handshake with {}:{} done; HTTP {} in {} ms",
+                host,
+                port,
+                response.status().as_u16(),
+                elapsed
+            ),
+        )
+    } else if is_local(base_url) {
+        CheckResult::pass(
+            NAME,
+            format!(
+                "{}:{} answered over plain HTTP, which is fine for a local server",
+                host, port
+            ),
+        )
+    } else {
+        CheckResult::warn(
+            NAME,
+            format!(
+                "{}:{} answered over plain HTTP, so the API key is sent unencrypted",
+                host, port
+            ),
+            "use an https:// --base-url unless the server is on a network you trust",
+        )
+    }
+}
+
+/// Whether the key is accepted, by listing the endpoint's models, and
+/// whether the configured model is one of them. Without a key there is
+/// nothing to check.
+pub async fn auth(agent: &DeepSeekAgent, has_key: bool) -> CheckResult {
+    const NAME: &str = "auth";
+    if !has_key {
+        return CheckResult::skip(NAME, "no API key to check".to_string());
+    }
+    match agent.list_models().await {
+        Ok(listed) => {
+            match models::unknown_model_warning(&listed, agent.model(), agent.base_url()) {
+                None => CheckResult::pass(
+                    NAME,
+                    format!(
+                        "the key works; {} models listed, {} among them",
+                        listed.len(),
+                        agent.model()
+                    ),
+                ),
+                Some(warning) => CheckResult::warn(
+                    NAME,
+                    format!("the key works, but {}", warning),
+                    "pick a listed model with --model; `deepseek_agent models` lists them",
+                ),
+            }
+        }
+        Err(e @ (AgentError::Auth(_) | AgentError::Quota(_) | AgentError::Timeout(_))) => {
+            request_failure(NAME, &e, agent)
+        }
+        // some OpenAI-compatible servers don't list models at all
+        Err(e) => CheckResult::warn(
+            NAME,
+            format!("could not list models: {}", first_line(&e)),
+            "the server may not serve /models; the chat check tells whether the key works",
+        ),
+    }
+}
+
+/// Whether the configured model answers a question of one token. The
+/// question joins no history: `agent`'s is cleared after.
+pub async fn chat(agent: &mut DeepSeekAgent, has_key: bool) -> CheckResult {
+    const NAME: &str = "chat";
+    if !has_key {
+        return CheckResult::skip(NAME, "no API key to ask with".to_string());
+    }
+    let params = agent.params().clone();
+    let one_token = crate::config::RequestParams {
+        max_tokens: Some(1),
+        ..params.clone()
+    };
+    if let Err(e) = agent.set_params(one_token) {
+        return CheckResult::fail(NAME, e.to_string(), "check the request settings");
+    }
+    let started = Instant::now();
+    let asked = agent.ask(CHAT_PROMPT).await;
+    let elapsed = started.elapsed().as_millis();
+    agent.reset();
+    let _ = agent.set_params(params);
+    match asked {
+        // a reasoning model may spend its one token thinking
+        Ok(_) | Err(AgentError::EmptyResponse(_)) => CheckResult::pass(
+            NAME,
+            format!("{} answered in {} ms", agent.model(), elapsed),
+        ),
+        Err(e) => request_failure(NAME, &e, agent),
+    }
+}
+
+/// Whether this machine's clock agrees with the endpoint's, going by the
+/// `Date` header of a plain request.
+pub async fn clock(base_url: &str, client: &reqwest::Client) -> CheckResult {
+    const NAME: &str = "clock";
+    let response = match fetch(client, base_url).await {
+        Ok(response) => response,
+        Err(_) => {
+            return CheckResult::skip(NAME, "the endpoint didn't answer; see tls".to_string());
+        }
+    };
+    let date = response
+        .headers()
+        .get(reqwest::header::DATE)
+        .and_then(|date| date.to_str().ok())
+        .and_then(|date| DateTime::parse_from_rfc2822(date).ok());
+    match date {
+        Some(date) => skew(date.with_timezone(&Utc), Utc::now()),
+        None => CheckResult::skip(NAME, "the endpoint sent no Date header".to_string()),
+    }
+}
+
+/// How far `local` is from the endpoint's `server` time, and whether that
+/// matters. It only warns: requests work, but TLS certificates and signed
+/// URLs can start to fail.
+pub fn skew(server: DateTime<Utc>, local: DateTime<Utc>) -> CheckResult {
+    const NAME: &str = "clock";
+    let seconds = (local - server).num_seconds();
+    let off = Duration::from_secs(seconds.unsigned_abs());
+    let detail = match seconds {
+        0 => "this clock agrees with the endpoint's".to_string(),
+        _ => format!(
+            "this clock is {}s {} the endpoint's",
+            seconds.abs(),
+            if seconds > 0 { "ahead of" } else { "behind" }
+        ),
+    };
+    let hint =
+        "set the clock from the network (NTP); a clock far off breaks TLS certificate checks";
+    if off > SKEW_FAIL {
+        CheckResult {
+            critical: false,
+            ..CheckResult::fail(NAME, detail, hint)
+        }
+    } else if off > SKEW_WARN {
+        CheckResult::warn(NAME, detail, hint)
+    } else {
+        CheckResult::pass(NAME, detail)
+    }
+}
+
+/// Why a plain request to the endpoint got no answer.
+enum FetchFailure {
+    Timeout,
+    Failed(String),
+}
+
+impl FetchFailure {
+    fn into_result(self, name: &'static str, host: &str, port: u16) -> CheckResult {
+        match self {
+            Self::Timeout => CheckResult::fail(
+                name,
+                format!(
+                    "{}:{} didn't answer in {}s",
+                    host,
+                    port,
+                    CHECK_TIMEOUT.as_secs()
+                ),
+                "check that a firewall isn't dropping the connection, or set --proxy",
+            ),
+            Self::Failed(reason) if is_certificate_problem(&reason) => CheckResult::fail(
+                name,
+                format!("TLS handshake with {}:{} failed: {}", host, port, reason),
+                "a proxy or private CA may sign the endpoint's certificate: pass its \
+                 certificate with --ca-bundle",
+            ),
+            Self::Failed(reason) => CheckResult::fail(
+                name,
+                format!("could not connect to {}:{}: {}", host, port, reason),
+                "check the scheme and port in --base-url, and that the server is up",
+            ),
+        }
+    }
+}
+
+async fn fetch(client: &reqwest::Client, url: &str) -> Result<reqwest::Response, FetchFailure> {
+    match tokio::time::timeout(CHECK_TIMEOUT, client.get(url).send()).await {
+        Ok(Ok(response)) => Ok(response),
+        Ok(Err(e)) if e.is_timeout() => Err(FetchFailure::Timeout),
+        Ok(Err(e)) => Err(FetchFailure::Failed(error_chain(&e))),
+        Err(_) => Err(FetchFailure::Timeout),
+    }
+}
+
+/// The failure of a request the agent made, with what to try.
+fn request_failure(name: &'static str, error: &AgentError, agent: &DeepSeekAgent) -> CheckResult {
+    let hint = match error {
+        AgentError::Auth(_) => {
+            "check that the API key is current and made for this endpoint, or run \
+             `deepseek_agent init` to set a new one"
+                .to_string()
+        }
+        AgentError::Quota(_) => "top up the account, or use a key with quota left".to_string(),
+        AgentError::Timeout(_) => {
+            "the endpoint may be overloaded or unreachable: try again, raise --timeout-secs, \
+             or check the proxy"
+                .to_string()
+        }
+        _ if is_missing_model(error) => format!(
+            "{} isn't served at {}; `deepseek_agent models` lists those that are, for --model",
+            agent.model(),
+            agent.base_url()
+        ),
+        _ => "run with --debug to see the request and response".to_string(),
+    };
+    CheckResult::fail(name, first_line(error), &hint)
+}
+
+/// The first line of `error`, as a server's error page would fill the screen.
+fn first_line(error: &AgentError) -> String {
+    let message = error.to_string();
+    match message.split_once('\n') {
+        Some((line, _)) => format!("{} [...]", line.trim_end()),
+        None => message,
+    }
+}
+
+/// Whether the API said the model doesn't exist: OpenAI's `model_not_found`,
+/// or DeepSeek's "Model Not Exist".
+fn is_missing_model(error: &AgentError) -> bool {
+    let AgentError::Api(OpenAIError::ApiError(api_error)) = error else {
+        return false;
+    };
+    let message = api_error.message.to_ascii_lowercase();
+    api_error.code.as_deref() == Some("model_not_found")
+        || message.contains("model not exist")
+        || (message.contains("model") && message.contains("does not exist"))
+}
+
+fn is_certificate_problem(reason: &str) -> bool {
+    let reason = reason.to_ascii_lowercase();
+    ["certificate", "tls", "handshake", "unknownissuer"]
+        .iter()
+        .any(|needle| reason.contains(needle))
+}
+
+/// `error` with every error behind it, as reqwest's own message is often
+/// just "error sending request".
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut chain = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        chain.push_str(": ");
+        chain.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    chain
+}
+
+fn host_and_port(base_url: &str) -> Result<(String, u16), String> {
+    let url = Url::parse(base_url).map_err(|e| format!("'{}' is not a URL: {}", base_url, e))?;
+    match (url.host_str(), url.port_or_known_default()) {
+        (Some(host), Some(port)) => Ok((host.trim_matches(['[', ']']).to_string(), port)),
+        _ => Err(format!("'{}' has no host", base_url)),
+    }
+}
+
+fn is_local(base_url: &str) -> bool {
+    match Url::parse(base_url)
+        .ok()
+        .and_then(|url| url.host().map(|h| h.to_owned()))
+    {
+        Some(Host::Domain(domain)) => domain == "localhost",
+        Some(Host::Ipv4(ip)) => ip.is_loopback() || ip.is_private(),
+        Some(Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn discovery() -> Discovery {
+        Discovery {
+            env_file: Some(PathBuf::from("/work/.env")),
+            config_file: Some(PathBuf::from("/home/me/.config/deepseek_agent/config.toml")),
+            config_found: true,
+            profile: Some("work".into()),
+            api_key_from: Some("$OPENAI_API_KEY".into()),
+            api_key_var: "OPENAI_API_KEY".into(),
+        }
+    }
+
+    #[test]
+    fn config_names_where_the_settings_came_from() {
+        let result = config(&discovery());
+        assert_eq!(result.status, Status::Pass);
+        assert_eq!(
+            result.detail,
+            "API key from $OPENAI_API_KEY; config file \
+             /home/me/.config/deepseek_agent/config.toml (profile work); .env at /work/.env"
+        );
+
+        let missing = config(&Discovery {
+            env_file: None,
+            config_found: false,
+            api_key_from: None,
+            ..discovery()
+        });
+        assert!(missing.is_critical_failure());
+        assert!(
+            missing.detail.starts_with("no API key; no config file at"),
+            "{}",
+            missing.detail
+        );
+        assert!(missing.hint.unwrap().contains("set OPENAI_API_KEY"));
+    }
+
+    #[test]
+    fn clock_skew_warns_then_fails_without_being_critical() {
+        let server = Utc.with_ymd_and_hms(2026, 10, 14, 9, 30, 0).unwrap();
+        let close = skew(server, server + chrono::Duration::seconds(3));
+        assert_eq!(close.status, Status::Pass);
+        assert_eq!(close.detail, "this clock is 3s ahead of the endpoint's");
+
+        let off = skew(server, server - chrono::Duration::seconds(90));
+        assert_eq!(off.status, Status::Warn);
+        assert_eq!(off.detail, "this clock is 90s behind the endpoint's");
+
+        let far = skew(server, server + chrono::Duration::hours(2));
+        assert_eq!(far.status, Status::Fail);
+        assert!(!far.is_critical_failure());
+        assert_eq!(
+            skew(server, server).detail,
+            "this clock agrees with the endpoint's"
+        );
+    }
+
+    #[test]
+    fn results_print_with_their_hint_and_serialize_for_ci() {
+        let failed = CheckResult::fail("auth", "the key was refused".into(), "set a new key");
+        assert_eq!(
+            failed.to_text(),
+            "[fail] auth    the key was refused\n               set a new key"
+        );
+        assert_eq!(
+            serde_json::to_value(&failed).unwrap(),
+            serde_json::json!({
+                "name": "auth",
+                "status": "fail",
+                "critical": true,
+                "detail": "the key was refused",
+                "hint": "set a new key"
+            })
+        );
+        let skipped = CheckResult::skip("proxy", "no proxy is set".into());
+        assert_eq!(skipped.to_text(), "[skip] proxy   no proxy is set");
+        assert!(
+            serde_json::to_value(&skipped)
+                .unwrap()
+                .get("hint")
+                .is_none()
+        );
+        assert_eq!(critical_failures(&[failed, skipped]), 1);
+    }
+
+    #[test]
+    fn local_servers_may_use_plain_http() {
+        assert!(is_local("http://localhost:11434/v1"));
+        assert!(is_local("http://127.0.0.1:8080/v1"));
+        assert!(is_local("http://192.168.1.20/v1"));
+        assert!(!is_local("http://api.example.com/v1"));
+        assert_eq!(
+            host_and_port("https://api.deepseek.com/v1").unwrap(),
+            ("api.deepseek.com".to_string(), 443)
+        );
+        assert_eq!(
+            host_and_port("http://[::1]:8080").unwrap(),
+            ("::1".to_string(), 8080)
+        );
+    }
+
+    #[tokio::test]
+    async fn misspelled_hosts_fail_to_resolve() {
+        let result = dns("https://api.deepseek.invalid/v1", &NetworkConfig::default()).await;
+        assert!(result.is_critical_failure(), "{result:?}");
+        assert!(
+            result
+                .detail
+                .starts_with("could not resolve api.deepseek.invalid"),
+            "{}",
+            result.detail
+        );
+
+        let proxied = NetworkConfig {
+            proxy: Some("http://proxy.internal:3128".into()),
+            ..NetworkConfig::default()
+        };
+        let result = dns("https://api.deepseek.com/v1", &proxied).await;
+        assert_eq!(result.status, Status::Skip);
+        let result = dns("http://127.0.0.1:8080/v1", &NetworkConfig::default()).await;
+        assert_eq!(result.status, Status::Pass);
+    }
+}
@@ -0,0 +1,420 @@
+//! A local index of embedded text chunks, for answering with excerpts of
+//! your own files.
+//!
+//! `embed` splits files into overlapping chunks and stores each chunk's
+//! embedding with where it came from. With an index set, the agent embeds
+//! every question, picks the chunks closest to it by cosine similarity and
+//! adds them to the system prompt.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::agent::DeepSeekAgent;
+use crate::error::AgentError;
+use crate::input;
+
+/// Model used to embed when none is given.
+pub const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+/// Characters per chunk.
+pub const DEFAULT_CHUNK_SIZE: usize = 1000;
+
+/// Characters each chunk repeats from the end of the one before.
+pub const DEFAULT_CHUNK_OVERLAP: usize = 200;
+
+/// Chunks added to the system prompt per question.
+pub const DEFAULT_TOP_K: usize = 4;
+
+/// Inputs sent per embeddings request.
+pub const EMBEDDING_BATCH_SIZE: usize = 64;
+
+/// Format version written and read by this build.
+pub const INDEX_VERSION: u32 = 1;
+
+/// Separates the configured system prompt from the excerpts added to it.
+const EXCERPTS_HEADING: &str =
+    "\n\nExcerpts from the user's files that may help answer the next question:";
+
+/// A piece of a file, with the lines it spans.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub text: String,
+    /// 1-based, inclusive.
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+/// Split `text` into chunks of at most `size` characters, each starting
+/// `overlap` characters before the last one ended.
+///
+/// A chunk that would be cut mid-line ends at the last newline in its second
+/// half instead, or failing that the last whitespace. Chunks of nothing but
+/// whitespace are left out.
+pub fn chunk_text(text: &str, size: usize, overlap: usize) -> Vec<Chunk> {
+    let size = size.max(1);
+    let overlap = overlap.min(size - 1);
+    // byte offset of every char, plus the end
+    let offsets: Vec<usize> = text
+        .char_indices()
+        .map(|(offset, _)| offset)
+        .chain([text.len()])
+        .collect();
+    let chars = offsets.len() - 1;
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < chars {
+        let mut end = (start + size).min(chars);
+        if end < chars {
+            let window = &text[offsets[start + size / 2]..offsets[end]];
+            let cut = window
+                .rfind('\n')
+                .or_else(|| window.rfind(char::is_whitespace));
+            if let Some(cut) = cut {
+                let cut_at = offsets[start + size / 2] + cut;
+                // keep the separator with the chunk it ends
+                end = offsets.partition_point(|&offset| offset <= cut_at);
+            }
+        }
+        let piece = &text[offsets[start]..offsets[end]];
+        if !piece.trim().is_empty() {
+            let start_line = text[..offsets[start]].matches('\n').count() + 1;
+            chunks.push(Chunk {
+                text: piece.to_string(),
+                start_line,
+                end_line: start_line + piece.trim_end().matches('\n').count(),
+            });
+        }
+        if end == chars {
+            break;
+        }
+        start = end.saturating_sub(overlap).max(start + 1);
+    }
+    chunks
+}
+
+/// Cosine of the angle between `a` and `b`: 1 for the same direction, 0 for
+/// unrelated. Vectors of different lengths, or all zeros, score 0.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let (mut dot, mut norm_a, mut norm_b) = (0.0f32, 0.0f32, 0.0f32);
+    for (x, y) in a.iter().zip(b) {
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+/// A chunk of a source file and its embedding.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexedChunk {
+    /// The file, as given to `embed`.
+    pub source: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub text: String,
+    pub embedding: Vec<f32>,
+}
+
+impl IndexedChunk {
+    /// `source:start-end`, for showing where an excerpt came from.
+    pub fn location(&self) -> String {
+        format!("{}:{}-{}", self.source, self.start_line, self.end_line)
+    }
+}
+
+/// The `k` chunks most similar to `query`, best first.
+pub fn top_k<'a>(
+    chunks: &'a [IndexedChunk],
+    query: &[f32],
+    k: usize,
+) -> Vec<(f32, &'a IndexedChunk)> {
+    let mut scored: Vec<_> = chunks
+        .iter()
+        .map(|chunk| (cosine_similarity(&chunk.embedding, query), chunk))
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.truncate(k);
+    scored
+}
+
+/// `system_prompt` followed by `excerpts`, each headed with where it came from.
+pub fn context_prompt(system_prompt: &str, excerpts: &[(f32, &IndexedChunk)]) -> String {
+    let mut prompt = base_prompt(system_prompt).to_string();
+    if excerpts.is_empty() {
+        return prompt;
+    }
+    prompt.push_str(EXCERPTS_HEADING);
+    for (_, chunk) in excerpts {
+        prompt.push_str(&format!(
+            "\n\n--- {} ---\n{}",
+            chunk.location(),
+            chunk.text.trim()
+        ));
+    }
+    prompt
+}
+
+/// `system_prompt` without the excerpts [`context_prompt`] added to it.
+pub fn base_prompt(system_prompt: &str) -> &str {
+    system_prompt
+        .split_once(EXCERPTS_HEADING)
+        .map_or(system_prompt, |(base, _)| base)
+}
+
+/// Embedded chunks of some files, as saved by `embed`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Index {
+    pub version: u32,
+    /// Model the chunks were embedded with; questions must use the same one.
+    pub model: String,
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    pub chunks: Vec<IndexedChunk>,
+}
+
+impl Index {
+    pub fn new(model: &str, chunk_size: usize, chunk_overlap: usize) -> Self {
+        Self {
+            version: INDEX_VERSION,
+            model: model.to_string(),
+            chunk_size,
+            chunk_overlap,
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Forget the chunks of `source`, e.g. before embedding it again.
+    pub fn remove_source(&mut self, source: &str) {
+        self.chunks.retain(|chunk| chunk.source != source);
+    }
+
+    /// Number of files with chunks in the index.
+    pub fn sources(&self) -> usize {
+        let mut sources: Vec<_> = self.chunks.iter().map(|chunk| &chunk.source).collect();
+        sources.dedup();
+        sources.len()
+    }
+
+    /// The index at `path` to add to, or a new one if there is none yet. An
+    /// existing index must have been embedded with `model`.
+    pub fn open(
+        path: &Path,
+        model: &str,
+        chunk_size: usize,
+        chunk_overlap: usize,
+    ) -> Result<Self, AgentError> {
+        let mut index = if path.exists() {
+            let index = Self::load(path)?;
+            if index.model != model {
+                return Err(AgentError::InvalidConfig(format!(
+                    "'{}' was embedded with {}; pass --embedding-model {} or use a new index",
+                    path.display(),
+                    index.model,
+                    index.model
+                )));
+            }
+            index
+        } else {
+            Self::new(model, chunk_size, chunk_overlap)
+        };
+        index.chunk_size = chunk_size;
+        index.chunk_overlap = chunk_overlap;
+        Ok(index)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, AgentError> {
+        let invalid = |message: String| {
+            AgentError::InvalidConfig(format!("context index '{}': {}", path.display(), message))
+        };
+        let json = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let index: Self = serde_json::from_str(&json).map_err(|e| invalid(e.to_string()))?;
+        if index.version != INDEX_VERSION {
+            return Err(invalid(format!(
+                "unsupported version {} (this build reads version {})",
+                index.version, INDEX_VERSION
+            )));
+        }
+        Ok(index)
+    }
+
+    /// Write the index to `path`, replacing it only once the new file is complete.
+    pub fn save(&self, path: &Path) -> Result<(), AgentError> {
+        let file_name = path.file_name().map_or_else(
+            || "index".into(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        let partial = path.with_file_name(format!(".{}.partial", file_name));
+        std::fs::write(
+            &partial,
+            serde_json::to_string(self).expect("indexes always serialize"),
+        )?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+}
+
+/// Chunk and embed each of `paths` into `index` with `agent`, replacing what
+/// it held of them, and save the index to `index_path` after each file.
+/// `embedded` is told each file's name and number of chunks once it is saved.
+pub async fn embed_files(
+    agent: &mut DeepSeekAgent,
+    index: &mut Index,
+    paths: &[PathBuf],
+    index_path: &Path,
+    mut embedded: impl FnMut(&str, usize),
+) -> Result<(), AgentError> {
+    for path in paths {
+        let text = input::read_prompt_file(path)?;
+        let chunks = chunk_text(&text, index.chunk_size, index.chunk_overlap);
+        let texts: Vec<String> = chunks.iter().map(|chunk| chunk.text.clone()).collect();
+        let vectors = agent.embed(&index.model, &texts).await?;
+        let source = path.display().to_string();
+        index.remove_source(&source);
+        index
+            .chunks
+            .extend(
+                chunks
+                    .into_iter()
+                    .zip(vectors)
+                    .map(|(chunk, embedding)| IndexedChunk {
+                        source: source.clone(),
+                        start_line: chunk.start_line,
+                        end_line: chunk.end_line,
+                        text: chunk.text,
+                        embedding,
+                    }),
+            );
+        index.save(index_path)?;
+        embedded(&source, texts.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn indexed(source: &str, embedding: &[f32]) -> IndexedChunk {
+        IndexedChunk {
+            source: source.to_string(),
+            start_line: 1,
+            end_line: 2,
+            text: format!("text of {source}"),
+            embedding: embedding.to_vec(),
+        }
+    }
+
+    #[test]
+    fn short_text_is_one_chunk() {
+        let chunks = chunk_text("fn main() {}\n", 100, 10);
+        assert_eq!(
+            chunks,
+            [Chunk {
+                text: "fn main() {}\n".to_string(),
+                start_line: 1,
+                end_line: 1
+            }]
+        );
+        assert!(chunk_text("", 100, 10).is_empty());
+        assert!(chunk_text(" \n\n ", 100, 10).is_empty());
+    }
+
+    #[test]
+    fn chunks_end_at_line_breaks_and_overlap() {
+        let text = (1..=10)
+            .map(|n| format!("line {n:02}\n"))
+            .collect::<String>();
+        // each line is 8 characters
+        let chunks = chunk_text(&text, 20, 8);
+
+        assert_eq!(chunks[0].text, "line 01\nline 02\n");
+        assert_eq!((chunks[0].start_line, chunks[0].end_line), (1, 2));
+        assert!(chunks[1].text.starts_with("line 02\n"), "{:?}", chunks[1]);
+        assert_eq!(chunks[1].start_line, 2);
+        assert!(chunks.iter().all(|chunk| chunk.text.chars().count() <= 20));
+        assert!(chunks.last().unwrap().text.ends_with("line 10\n"));
+        assert_eq!(chunks.last().unwrap().end_line, 10);
+    }
+
+    #[test]
+    fn unbroken_text_is_cut_at_the_size() {
+        let chunks = chunk_text(&"é".repeat(25), 10, 0);
+        let lengths: Vec<_> = chunks.iter().map(|c| c.text.chars().count()).collect();
+        assert_eq!(lengths, [10, 10, 5]);
+    }
+
+    #[test]
+    fn overlap_never_stalls() {
+        let chunks = chunk_text("abcdef", 2, 5);
+        assert_eq!(chunks.len(), 5);
+        assert_eq!(chunks[0].text, "ab");
+        assert_eq!(chunks[4].text, "ef");
+    }
+
+    #[test]
+    fn cosine_similarity_measures_direction() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 1.0], &[-1.0, -1.0]) + 1.0).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0], &[1.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn top_k_picks_the_closest_chunks_best_first() {
+        let chunks = [
+            indexed("far", &[0.0, 1.0]),
+            indexed("near", &[1.0, 0.1]),
+            indexed("middle", &[1.0, 1.0]),
+        ];
+        let best = top_k(&chunks, &[1.0, 0.0], 2);
+        let sources: Vec<_> = best
+            .iter()
+            .map(|(_, chunk)| chunk.source.as_str())
+            .collect();
+        assert_eq!(sources, ["near", "middle"]);
+        assert!(best[0].0 > best[1].0);
+        assert_eq!(top_k(&chunks, &[1.0, 0.0], 10).len(), 3);
+        assert!(top_k(&[], &[1.0], 3).is_empty());
+    }
+
+    #[test]
+    fn excerpts_are_added_once_to_the_system_prompt() {
+        let chunk = indexed("notes.md", &[1.0]);
+        let prompt = context_prompt("Be terse.", &[(0.9, &chunk)]);
+        assert_eq!(
+            prompt,
+            format!("Be terse.{EXCERPTS_HEADING}\n\n--- notes.md:1-2 ---\ntext of notes.md")
+        );
+        assert_eq!(base_prompt(&prompt), "Be terse.");
+
+        let again = context_prompt(&prompt, &[]);
+        assert_eq!(again, "Be terse.");
+    }
+
+    #[test]
+    fn indexes_round_trip_through_a_file() {
+        let mut index = Index::new("embedder", 100, 10);
+        index.chunks.push(indexed("a.rs", &[0.5, -0.25]));
+        index.chunks.push(indexed("b.rs", &[1.0, 0.0]));
+        index.remove_source("b.rs");
+        assert_eq!(index.sources(), 1);
+
+        let path = std::env::temp_dir().join(format!("deepseek_index_{}.json", std::process::id()));
+        index.save(&path).unwrap();
+        assert_eq!(Index::load(&path).unwrap(), index);
+
+        std::fs::write(&path, "{\"version\": 9}").unwrap();
+        let err = Index::load(&path).unwrap_err().to_string();
+        assert!(err.contains("context index"), "{err}");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,455 @@
+use std::fmt;
+use std::time::Duration;
+
+use async_openai::error::OpenAIError;
+
+/// Errors returned by the agent library.
+#[derive(Debug, thiserror::Error)]
+pub enum AgentError {
+    #[error("{0} is not set; set {0} in your environment or .env file")]
+    MissingEnv(String),
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("{0}")]
+    Input(String),
+    #[error("could not load session: {0}")]
+    Session(String),
+    #[error("API request failed: {0}")]
+    Api(OpenAIError),
+    #[error("{0}")]
+    Auth(ApiFailure),
+    #[error("{0}")]
+    Quota(ApiFailure),
+    #[error("{0}")]
+    ContextLength(ApiFailure),
+    #[error("{0}")]
+    ContentFilter(ApiFailure),
+    #[error(
+        "no reply from the API within {}s; try --stream, which only times out between chunks, or a longer --timeout-secs",
+        .0.as_secs_f64()
+    )]
+    Timeout(Duration),
+    #[error("the API returned an empty response ({0}); try rephrasing or retrying")]
+    EmptyResponse(&'static str),
+    #[error(
+        "the model was still calling tools after {0} rounds; raise --max-tool-iterations or simplify the request"
+    )]
+    ToolLoopLimit(usize),
+    #[error(
+        "the reply still didn't match the JSON schema after {attempts} attempts: {}",
+        .errors.join("; ")
+    )]
+    SchemaMismatch {
+        attempts: usize,
+        errors: Vec<String>,
+        /// The last reply, as the model sent it.
+        content: String,
+    },
+    #[error(
+        "stopping at the ${limit} budget: ~${spent:.6} spent, and the next request was estimated at ~${estimate:.6}; raise --budget-usd to go on"
+    )]
+    BudgetExceeded {
+        spent: f64,
+        estimate: f64,
+        limit: f64,
+    },
+    #[error("stream interrupted after {} characters: {source}", partial.chars().count())]
+    StreamInterrupted {
+        partial: String,
+        source: OpenAIError,
+    },
+    #[error("the review found {0} critical issue(s)")]
+    CriticalFindings(usize),
+    #[error("{0} critical check(s) failed")]
+    ChecksFailed(usize),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("interrupted")]
+    Interrupted,
+}
+
+impl AgentError {
+    /// Process exit code for this error, so scripts can tell failures apart.
+    ///
+    /// 2 is a configuration or input problem, 3 an API failure, 4 no usable reply,
+    /// 5 I/O, 6 the spending budget reached, 7 a review that found something
+    /// critical, 8 a critical `doctor` check that failed, and 130 a Ctrl-C, as
+    /// shells report for SIGINT.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            AgentError::MissingEnv(_)
+            | AgentError::InvalidConfig(_)
+            | AgentError::Input(_)
+            | AgentError::Session(_) => 2,
+            AgentError::Api(_)
+            | AgentError::Auth(_)
+            | AgentError::Quota(_)
+            | AgentError::ContextLength(_)
+            | AgentError::ContentFilter(_)
+            | AgentError::Timeout(_)
+            | AgentError::StreamInterrupted { .. } => 3,
+            AgentError::EmptyResponse(_)
+            | AgentError::ToolLoopLimit(_)
+            | AgentError::SchemaMismatch { .. } => 4,
+            AgentError::Io(_) => 5,
+            AgentError::BudgetExceeded { .. } => 6,
+            AgentError::CriticalFindings(_) => 7,
+            AgentError::ChecksFailed(_) => 8,
+            AgentError::Interrupted => 130,
+        }
+    }
+
+    /// A stable name for the kind of error, for scripts reading JSON output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AgentError::MissingEnv(_) => "missing_env",
+            AgentError::InvalidConfig(_) => "invalid_config",
+            AgentError::Input(_) => "input",
+            AgentError::Session(_) => "session",
+            AgentError::Api(_) => "api",
+            AgentError::Auth(_) => "auth",
+            AgentError::Quota(_) => "quota",
+            AgentError::ContextLength(_) => "context_length",
+            AgentError::ContentFilter(_) => "content_filter",
+            AgentError::Timeout(_) => "timeout",
+            AgentError::EmptyResponse(_) => "empty_response",
+            AgentError::ToolLoopLimit(_) => "tool_loop_limit",
+            AgentError::SchemaMismatch { .. } => "schema_mismatch",
+            AgentError::BudgetExceeded { .. } => "budget_exceeded",
+            AgentError::StreamInterrupted { .. } => "stream_interrupted",
+            AgentError::CriticalFindings(_) => "critical_findings",
+            AgentError::ChecksFailed(_) => "checks_failed",
+            AgentError::Io(_) => "io",
+            AgentError::Interrupted => "interrupted",
+        }
+    }
+
+    /// What the API said, for the failures it was recognized as.
+    pub fn api_failure(&self) -> Option<&ApiFailure> {
+        match self {
+            AgentError::Auth(failure)
+            | AgentError::Quota(failure)
+            | AgentError::ContextLength(failure)
+            | AgentError::ContentFilter(failure) => Some(failure),
+            _ => None,
+        }
+    }
+}
+
+impl From<OpenAIError> for AgentError {
+    /// Failures with known advice get a variant of their own; the rest keep
+    /// the API's own message.
+    fn from(error: OpenAIError) -> Self {
+        let Some(failure) = ApiFailure::classify(&error) else {
+            return AgentError::Api(error);
+        };
+        match failure.kind {
+            FailureKind::Auth => AgentError::Auth(failure),
+            FailureKind::Quota => AgentError::Quota(failure),
+            FailureKind::ContextLength => AgentError::ContextLength(failure),
+            FailureKind::ContentFilter => AgentError::ContentFilter(failure),
+        }
+    }
+}
+
+/// The API failures that come with advice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The key was missing, wrong or revoked.
+    Auth,
+    /// The account has no balance or quota left.
+    Quota,
+    /// The prompt and reply don't fit in the model's context window.
+    ContextLength,
+    /// The provider refused the content.
+    ContentFilter,
+}
+
+/// A recognized API failure: the fields of the error body, and what to do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiFailure {
+    pub kind: FailureKind,
+    /// The API's own message.
+    pub message: String,
+    pub code: Option<String>,
+    pub r#type: Option<String>,
+}
+
+impl ApiFailure {
+    /// Recognize `error` as one of the [`FailureKind`]s.
+    ///
+    /// DeepSeek sends most failures with the `invalid_request_error` code and
+    /// tells them apart in the type or message, while OpenAI-compatible
+    /// servers use codes like `insufficient_quota`, so all three are looked
+    /// at. A failed stream only keeps the status line, which is enough for
+    /// 401 and 402.
+    pub fn classify(error: &OpenAIError) -> Option<Self> {
+        match error {
+            OpenAIError::ApiError(api_error) => {
+                let fields = [&api_error.code, &api_error.r#type];
+                let mentions = |needle: &str| {
+                    fields
+                        .iter()
+                        .any(|field| field.as_deref().is_some_and(|f| f.contains(needle)))
+                };
+                let message = api_error.message.to_ascii_lowercase();
+                let says = |needles: &[&str]| needles.iter().any(|n| message.contains(n));
+                let kind = if mentions("insufficient_quota")
+                    || says(&["insufficient balance", "exceeded your current quota"])
+                {
+                    FailureKind::Quota
+                } else if mentions("authentication")
+                    || mentions("invalid_api_key")
+                    || says(&[
+                        "authentication fail",
+                        "incorrect api key",
+                        "invalid api key",
+                    ])
+                {
+                    FailureKind::Auth
+                } else if mentions("context_length")
+                    || says(&["maximum context length", "context window"])
+                {
+                    FailureKind::ContextLength
+                } else if mentions("content_filter")
+                    || mentions("content_policy")
+                    || says(&["content exists risk", "content management policy"])
+                {
+                    FailureKind::ContentFilter
+                } else {
+                    return None;
+                };
+                Some(Self {
+                    kind,
+                    message: api_error.message.clone(),
+                    code: api_error.code.clone(),
+                    r#type: api_error.r#type.clone(),
+                })
+            }
+            OpenAIError::StreamError(message) => {
+                let status_line = message.strip_prefix("Invalid status code: ")?;
+                let kind = match status_line.get(..3)? {
+                    "401" | "403" => FailureKind::Auth,
+                    "402" => FailureKind::Quota,
+                    _ => return None,
+                };
+                Some(Self {
+                    kind,
+                    message: status_line.to_string(),
+                    code: None,
+                    r#type: None,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// What to do about it.
+    pub fn guidance(&self) -> String {
+        match self.kind {
+            FailureKind::Auth => {
+                "the API key was rejected; check the key you configured, or --profile".to_string()
+            }
+            FailureKind::Quota => {
+                "your balance is exhausted; top up at platform.deepseek.com".to_string()
+            }
+            FailureKind::ContextLength => {
+                let window = match context_limit(&self.message) {
+                    Some(tokens) if tokens % 1024 == 0 => format!("the {}K", tokens / 1024),
+                    Some(tokens) => format!("the {}-token", tokens),
+                    None => "the model's".to_string(),
+                };
+                format!(
+                    "the prompt exceeds {} context window; try --trim-strategy summarize or a lower --context-budget",
+                    window
+                )
+            }
+            FailureKind::ContentFilter => {
+                "the provider's content filter refused the request; rephrase it".to_string()
+            }
+        }
+    }
+}
+
+impl fmt::Display for ApiFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (API said: {})", self.guidance(), self.message)
+    }
+}
+
+/// The window size in "maximum context length is 65536 tokens".
+fn context_limit(message: &str) -> Option<u64> {
+    let marker = "maximum context length is ";
+    let rest = &message[message.find(marker)? + marker.len()..];
+    let digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..digits].parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_env_tells_the_user_what_to_do() {
+        let err = AgentError::MissingEnv("OPENAI_API_KEY".to_string());
+        assert_eq!(
+            err.to_string(),
+            "OPENAI_API_KEY is not set; set OPENAI_API_KEY in your environment or .env file"
+        );
+    }
+
+    #[test]
+    fn timeout_suggests_a_way_out() {
+        let err = AgentError::Timeout(Duration::from_secs(120));
+        let message = err.to_string();
+        assert!(
+            message.starts_with("no reply from the API within 120s"),
+            "{message}"
+        );
+        assert!(message.contains("--stream"), "{message}");
+        assert!(message.contains("--timeout-secs"), "{message}");
+    }
+
+    #[test]
+    fn conversions_pick_the_right_variant() {
+        let api: AgentError = OpenAIError::InvalidArgument("bad".to_string()).into();
+        assert!(matches!(api, AgentError::Api(_)));
+
+        let io: AgentError = std::io::Error::other("disk full").into();
+        assert!(matches!(io, AgentError::Io(_)));
+        assert_eq!(io.to_string(), "I/O error: disk full");
+    }
+
+    /// The error a fixture body becomes.
+    fn api_error(body: &str) -> OpenAIError {
+        #[derive(serde::Deserialize)]
+        struct Wrapped {
+            error: async_openai::error::ApiError,
+        }
+        OpenAIError::ApiError(serde_json::from_str::<Wrapped>(body).unwrap().error)
+    }
+
+    #[test]
+    fn error_bodies_are_classified() {
+        let cases = [
+            (
+                include_str!("../tests/fixtures/errors/authentication.json"),
+                "auth",
+            ),
+            (
+                include_str!("../tests/fixtures/errors/insufficient_balance.json"),
+                "quota",
+            ),
+            (
+                include_str!("../tests/fixtures/errors/insufficient_quota.json"),
+                "quota",
+            ),
+            (
+                include_str!("../tests/fixtures/errors/context_length.json"),
+                "context_length",
+            ),
+            (
+                include_str!("../tests/fixtures/errors/content_risk.json"),
+                "content_filter",
+            ),
+            (
+                include_str!("../tests/fixtures/errors/unknown_parameter.json"),
+                "api",
+            ),
+        ];
+        for (body, kind) in cases {
+            let err = AgentError::from(api_error(body));
+            assert_eq!(err.kind(), kind, "{body}");
+            assert_eq!(err.exit_code(), 3);
+        }
+    }
+
+    #[test]
+    fn known_failures_say_what_to_do_and_keep_the_message() {
+        let err = AgentError::from(api_error(include_str!(
+            "../tests/fixtures/errors/insufficient_balance.json"
+        )));
+        assert_eq!(
+            err.to_string(),
+            "your balance is exhausted; top up at platform.deepseek.com (API said: Insufficient Balance)"
+        );
+        let failure = err.api_failure().unwrap();
+        assert_eq!(failure.code.as_deref(), Some("invalid_request_error"));
+        assert_eq!(failure.r#type.as_deref(), Some("unknown_error"));
+
+        let err = AgentError::from(api_error(include_str!(
+            "../tests/fixtures/errors/context_length.json"
+        )));
+        let message = err.to_string();
+        assert!(
+            message.starts_with("the prompt exceeds the 64K context window; try --trim-strategy"),
+            "{message}"
+        );
+        assert!(
+            message.contains("maximum context length is 65536"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn unknown_failures_keep_the_raw_message() {
+        let err = AgentError::from(api_error(include_str!(
+            "../tests/fixtures/errors/unknown_parameter.json"
+        )));
+        assert!(err.api_failure().is_none());
+        assert_eq!(
+            err.to_string(),
+            "API request failed: invalid_request_error: Unknown parameter: 'response_format.schema' (code: invalid_request_error)"
+        );
+    }
+
+    #[test]
+    fn failed_streams_are_classified_by_status() {
+        let stream_error = |status: &str| {
+            AgentError::from(OpenAIError::StreamError(format!(
+                "Invalid status code: {}",
+                status
+            )))
+        };
+        assert_eq!(stream_error("401 Unauthorized").kind(), "auth");
+        assert_eq!(stream_error("402 Payment Required").kind(), "quota");
+        assert_eq!(stream_error("400 Bad Request").kind(), "api");
+        assert_eq!(
+            AgentError::from(OpenAIError::StreamError("connection reset".to_string())).kind(),
+            "api"
+        );
+    }
+
+    #[test]
+    fn exit_codes_by_category() {
+        let cases = [
+            (AgentError::MissingEnv("X".to_string()), 2),
+            (AgentError::InvalidConfig("bad url".to_string()), 2),
+            (AgentError::Input("file missing".to_string()), 2),
+            (AgentError::Session("bad version".to_string()), 2),
+            (
+                AgentError::Api(OpenAIError::StreamError("boom".to_string())),
+                3,
+            ),
+            (AgentError::Timeout(Duration::from_secs(120)), 3),
+            (
+                AgentError::StreamInterrupted {
+                    partial: "half".to_string(),
+                    source: OpenAIError::StreamError("boom".to_string()),
+                },
+                3,
+            ),
+            (AgentError::EmptyResponse("no choices"), 4),
+            (AgentError::ToolLoopLimit(5), 4),
+            (AgentError::Io(std::io::Error::other("x")), 5),
+            (AgentError::CriticalFindings(2), 7),
+            (AgentError::ChecksFailed(1), 8),
+            (AgentError::Interrupted, 130),
+        ];
+        for (err, code) in cases {
+            assert_eq!(err.exit_code(), code, "{err}");
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Noticing replies that aren't the whole answer: cut off at the token
+//! limit, held back by a content filter, refused, or empty.
+//!
+//! Servers report these through the finish reason or not at all, and a
+//! reply that is otherwise well formed would be printed as if nothing were
+//! wrong. [`classify`] looks at a finished reply and only that, so it can be
+//! run on replies from the API, the cache or a test alike.
+
+use std::fmt;
+
+use async_openai::types::FinishReason;
+
+use crate::chat::Reply;
+
+/// Why a reply may not answer the question.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplyIssue {
+    /// It stopped at `max_tokens`, or the model's own limit.
+    Truncated,
+    /// The provider's content filter withheld it or cut it short.
+    ContentFilter,
+    /// The model stopped to call tools, but no answer followed.
+    UnansweredToolCalls,
+    /// The model declined, in the `refusal` field OpenAI's API has for it.
+    Refusal,
+    /// It has no text.
+    Empty,
+}
+
+/// What, if anything, is wrong with the finished `reply`.
+pub fn classify(reply: &Reply) -> Option<ReplyIssue> {
+    match reply.finish_reason {
+        Some(FinishReason::Length) => Some(ReplyIssue::Truncated),
+        Some(FinishReason::ContentFilter) => Some(ReplyIssue::ContentFilter),
+        Some(FinishReason::ToolCalls | FinishReason::FunctionCall) => {
+            Some(ReplyIssue::UnansweredToolCalls)
+        }
+        _ if reply.refusal.is_some() => Some(ReplyIssue::Refusal),
+        _ if reply.content.trim().is_empty() => Some(ReplyIssue::Empty),
+        _ => None,
+    }
+}
+
+impl ReplyIssue {
+    /// The name in JSON output, for filtering results by.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Truncated => "truncated",
+            Self::ContentFilter => "content_filter",
+            Self::UnansweredToolCalls => "unanswered_tool_calls",
+            Self::Refusal => "refusal",
+            Self::Empty => "empty",
+        }
+    }
+
+    /// A sentence for people, suggesting what to do if anything helps. A
+    /// truncated reply was sent with `max_tokens`.
+    pub fn note(&self, max_tokens: Option<u32>) -> String {
+        match self {
+            Self::Truncated => match max_tokens {
+                Some(limit) => format!(
+                    "the response was truncated at max_tokens \u{2014} rerun with --max-tokens {}",
+                    limit.saturating_mul(2)
+                ),
+                None => "the response was truncated at the model's token limit".to_string(),
+            },
+            Self::ContentFilter => {
+                "the provider's content filter withheld the response or cut it short".to_string()
+            }
+            Self::UnansweredToolCalls => {
+                "the model stopped to call tools and gave no answer after them".to_string()
+            }
+            Self::Refusal => "the model refused to answer".to_string(),
+            Self::Empty => "the response was empty".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ReplyIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::parse_response;
+    use serde_json::{Value, json};
+
+    /// A response as the API sends it, with `message` and `finish_reason`.
+    fn response(message: Value, finish_reason: Value) -> Reply {
+        parse_response(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1700000000,
+            "model": "deepseek-chat",
+            "choices": [{ "index": 0, "message": message, "finish_reason": finish_reason }],
+            "usage": { "prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15 }
+        }))
+        .unwrap()
+    }
+
+    fn answer(content: &str, finish_reason: &str) -> Reply {
+        response(
+            json!({ "role": "assistant", "content": content }),
+            finish_reason.into(),
+        )
+    }
+
+    #[test]
+    fn complete_answers_have_no_issue() {
+        assert_eq!(classify(&answer("1013 is prime.", "stop")), None);
+        // servers that leave the reason out, or give one OpenAI doesn't define
+        assert_eq!(
+            classify(&response(
+                json!({ "role": "assistant", "content": "Yes." }),
+                Value::Null
+            )),
+            None
+        );
+        assert_eq!(
+            classify(&answer("Yes.", "insufficient_system_resource")),
+            None
+        );
+    }
+
+    #[test]
+    fn each_finish_reason_is_classified() {
+        assert_eq!(
+            classify(&answer("Ownership means", "length")),
+            Some(ReplyIssue::Truncated)
+        );
+        assert_eq!(
+            classify(&answer("", "content_filter")),
+            Some(ReplyIssue::ContentFilter)
+        );
+        // Azure leaves the content out altogether
+        let filtered = response(json!({ "role": "assistant" }), "content_filter".into());
+        assert_eq!(classify(&filtered), Some(ReplyIssue::ContentFilter));
+        assert_eq!(
+            classify(&answer("Let me check.", "tool_calls")),
+            Some(ReplyIssue::UnansweredToolCalls)
+        );
+    }
+
+    #[test]
+    fn refusals_and_empty_replies_are_noticed() {
+        let refused = response(
+            json!({ "role": "assistant", "content": null, "refusal": "I can't help with that." }),
+            "stop".into(),
+        );
+        assert_eq!(refused.refusal.as_deref(), Some("I can't help with that."));
+        assert_eq!(classify(&refused), Some(ReplyIssue::Refusal));
+        assert_eq!(classify(&answer(" \n", "stop")), Some(ReplyIssue::Empty));
+    }
+
+    #[test]
+    fn notes_say_what_to_do() {
+        assert_eq!(
+            ReplyIssue::Truncated.note(Some(1024)),
+            "the response was truncated at max_tokens \u{2014} rerun with --max-tokens 2048"
+        );
+        assert_eq!(
+            ReplyIssue::Truncated.note(None),
+            "the response was truncated at the model's token limit"
+        );
+        assert_eq!(ReplyIssue::ContentFilter.to_string(), "content_filter");
+    }
+}
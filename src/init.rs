@@ -0,0 +1,149 @@
+//! The first-run setup: asking for the key, endpoint and model, checking
+//! them against the endpoint, and saving them to the config file.
+
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::Path;
+
+use deepseek_tutor::chat::DEFAULT_MODEL;
+use deepseek_tutor::config::{DEFAULT_BASE_URL, resolve_base_url};
+use deepseek_tutor::models;
+use deepseek_tutor::network::NetworkConfig;
+use deepseek_tutor::settings::{self, Credentials, DEFAULT_API_KEY_ENV};
+use deepseek_tutor::{AgentConfig, AgentError, DeepSeekAgent};
+
+use crate::approval::is_yes;
+
+/// The models offered to pick from, the default first.
+const MODELS: [&str; 2] = [DEFAULT_MODEL, "deepseek-reasoner"];
+
+/// Whether there is someone at a terminal to ask.
+pub fn can_prompt() -> bool {
+    std::io::stdin().is_terminal()
+}
+
+/// Ask for credentials, check them with a model list request, and save them
+/// to the config file at `config_path`. The check goes out over `network`.
+/// The questions go to stderr, so stdout stays clean for whatever runs after.
+pub async fn run(
+    config_path: Option<&Path>,
+    network: &NetworkConfig,
+) -> Result<Credentials, AgentError> {
+    let Some(config_path) = config_path else {
+        return Err(AgentError::InvalidConfig(
+            "init needs --config when HOME is not set".into(),
+        ));
+    };
+    if !can_prompt() {
+        return Err(AgentError::InvalidConfig(
+            "init asks questions, so it needs a terminal; edit the config file instead".into(),
+        ));
+    }
+    eprintln!("Setting up {}.", config_path.display());
+
+    let api_key = rpassword::prompt_password("API key (input hidden): ")?;
+    let api_key = api_key.trim();
+    if api_key.is_empty() {
+        return Err(AgentError::Input("no API key given".into()));
+    }
+    let base_url = resolve_base_url(Some(&ask(&format!("Base URL [{}]: ", DEFAULT_BASE_URL))?))?;
+    eprintln!("Models:");
+    for (number, model) in MODELS.iter().enumerate() {
+        eprintln!("  {}) {}", number + 1, model);
+    }
+    let model = loop {
+        match choose_model(&ask("Model [1]: ")?) {
+            Some(model) => break model,
+            None => eprintln!("Pick 1 to {}, or type a model name.", MODELS.len()),
+        }
+    };
+    let credentials = Credentials {
+        api_key: api_key.into(),
+        base_url,
+        model,
+    };
+
+    eprint!("Checking the key with {}... ", credentials.base_url);
+    match verify(&credentials, network).await {
+        Ok(None) => eprintln!("ok."),
+        Ok(Some(warning)) => eprintln!("ok, but {}.", warning),
+        Err(e) => {
+            eprintln!("failed: {}", e);
+            if !is_yes(&ask("Save anyway? [y/N] ")?) {
+                return Err(e);
+            }
+        }
+    }
+
+    settings::save_credentials(config_path, &credentials)?;
+    eprintln!("Saved to {}, readable only by you.", config_path.display());
+    if is_yes(&ask(&format!(
+        "Also set {} in ./.env? [y/N] ",
+        DEFAULT_API_KEY_ENV
+    ))?) {
+        settings::save_env_key(Path::new(".env"), DEFAULT_API_KEY_ENV, &credentials.api_key)?;
+        eprintln!("Saved to .env.");
+    }
+    Ok(credentials)
+}
+
+/// The model for an answer to "Model [1]:": the default for none, one from
+/// the list by number, or any name typed out.
+fn choose_model(answer: &str) -> Option<String> {
+    let answer = answer.trim();
+    if answer.is_empty() {
+        return Some(MODELS[0].to_string());
+    }
+    if let Ok(number) = answer.parse::<usize>() {
+        return MODELS.get(number.checked_sub(1)?).map(|m| m.to_string());
+    }
+    (!answer.contains(char::is_whitespace)).then(|| answer.to_string())
+}
+
+/// List the endpoint's models with the new key, which fails if the key
+/// doesn't work. A warning if the chosen model isn't among them.
+async fn verify(
+    credentials: &Credentials,
+    network: &NetworkConfig,
+) -> Result<Option<String>, AgentError> {
+    network.check_proxy().await?;
+    let agent = DeepSeekAgent::new(AgentConfig {
+        base_url: credentials.base_url.clone(),
+        model: credentials.model.clone(),
+        network: network.clone(),
+        ..AgentConfig::new(credentials.api_key.clone())
+    })?;
+    let listed = agent.list_models().await?;
+    Ok(models::unknown_model_warning(
+        &listed,
+        &credentials.model,
+        agent.base_url(),
+    ))
+}
+
+fn ask(question: &str) -> Result<String, AgentError> {
+    eprint!("{}", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer)? == 0 {
+        return Err(AgentError::Input("setup cancelled".into()));
+    }
+    Ok(answer.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn models_are_picked_by_number_or_name() {
+        assert_eq!(choose_model("").as_deref(), Some("deepseek-chat"));
+        assert_eq!(choose_model(" 2\n").as_deref(), Some("deepseek-reasoner"));
+        assert_eq!(
+            choose_model("qwen2.5-coder").as_deref(),
+            Some("qwen2.5-coder")
+        );
+        assert_eq!(choose_model("0"), None);
+        assert_eq!(choose_model("3"), None);
+        assert_eq!(choose_model("two words"), None);
+    }
+}
@@ -0,0 +1,246 @@
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::error::AgentError;
+
+/// Default cap on the size of a prompt assembled from flags, files and stdin.
+pub const DEFAULT_MAX_INPUT_BYTES: usize = 100 * 1024;
+
+/// Read all of `reader` if it's a pipe or file; `None` for a terminal or empty input.
+pub fn read_piped(mut reader: impl Read, is_terminal: bool) -> Result<Option<String>, AgentError> {
+    if is_terminal {
+        return Ok(None);
+    }
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let text = String::from_utf8(bytes).map_err(|e| {
+        AgentError::Input(format!(
+            "stdin is not valid UTF-8 (invalid byte at offset {})",
+            e.utf8_error().valid_up_to()
+        ))
+    })?;
+    Ok(if text.trim().is_empty() {
+        None
+    } else {
+        Some(text)
+    })
+}
+
+/// Read a prompt from a UTF-8 text file.
+pub fn read_prompt_file(path: &Path) -> Result<String, AgentError> {
+    read_text_file(path, "prompt file")
+}
+
+/// Read a system prompt from a UTF-8 text file, which must have some text in
+/// it. Surrounding whitespace is trimmed.
+pub fn read_system_file(path: &Path) -> Result<String, AgentError> {
+    let text = read_text_file(path, "system prompt file")?;
+    if text.trim().is_empty() {
+        return Err(AgentError::Input(format!(
+            "system prompt file '{}' is empty",
+            path.display()
+        )));
+    }
+    Ok(text.trim().to_string())
+}
+
+/// Read a UTF-8 text file, naming it as `what` in errors.
+fn read_text_file(path: &Path, what: &str) -> Result<String, AgentError> {
+    let bytes = std::fs::read(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => {
+            AgentError::Input(format!("{} '{}' does not exist", what, path.display()))
+        }
+        _ => AgentError::Input(format!(
+            "could not read {} '{}': {}",
+            what,
+            path.display(),
+            e
+        )),
+    })?;
+    String::from_utf8(bytes).map_err(|e| {
+        AgentError::Input(format!(
+            "{} '{}' is not valid UTF-8 (invalid byte at offset {})",
+            what,
+            path.display(),
+            e.utf8_error().valid_up_to()
+        ))
+    })
+}
+
+/// Join the prompt with piped input, which is fenced off so the model can tell
+/// the instruction from the material it applies to.
+pub fn combine(prompt: Option<&str>, piped: Option<&str>) -> Option<String> {
+    match (prompt, piped) {
+        (Some(prompt), Some(piped)) => Some(format!(
+            "{}\n\n--- stdin ---\n{}\n--- end stdin ---",
+            prompt.trim_end(),
+            piped.trim_end()
+        )),
+        (Some(prompt), None) => Some(prompt.to_string()),
+        (None, Some(piped)) => Some(piped.to_string()),
+        (None, None) => None,
+    }
+}
+
+/// Text that fits the input cap, and its original size if it had to be cut.
+#[derive(Debug, PartialEq)]
+pub struct CappedInput {
+    pub text: String,
+    pub truncated_from: Option<usize>,
+}
+
+/// Enforce `max_bytes` on `text`: an error by default, or with `truncate` the
+/// first `max_bytes` (cut on a character boundary).
+pub fn cap_input(
+    text: String,
+    max_bytes: usize,
+    truncate: bool,
+) -> Result<CappedInput, AgentError> {
+    let len = text.len();
+    if len <= max_bytes {
+        return Ok(CappedInput {
+            text,
+            truncated_from: None,
+        });
+    }
+    if !truncate {
+        return Err(AgentError::Input(format!(
+            "input is {} bytes, over the {} byte limit; raise --max-input-bytes or pass \
+             --truncate-input to send only the first {} bytes",
+            len, max_bytes, max_bytes
+        )));
+    }
+
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut text = text;
+    text.truncate(end);
+    Ok(CappedInput {
+        text,
+        truncated_from: Some(len),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn temp_file(name: &str, contents: &[u8]) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("deepseek_input_{}_{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn terminal_stdin_is_not_read() {
+        let piped = read_piped(Cursor::new("ignored"), true).unwrap();
+        assert_eq!(piped, None);
+    }
+
+    #[test]
+    fn piped_stdin_is_read_fully() {
+        let piped = read_piped(Cursor::new("diff --git a b\n+line\n"), false).unwrap();
+        assert_eq!(piped.as_deref(), Some("diff --git a b\n+line\n"));
+    }
+
+    #[test]
+    fn empty_or_blank_stdin_counts_as_none() {
+        assert_eq!(read_piped(Cursor::new(""), false).unwrap(), None);
+        assert_eq!(read_piped(Cursor::new("\n  \n"), false).unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_utf8_stdin_is_an_error() {
+        let err = read_piped(Cursor::new(vec![b'o', b'k', 0xff]), false).unwrap_err();
+        assert!(err.to_string().contains("offset 2"), "{err}");
+    }
+
+    #[test]
+    fn reads_prompt_file() {
+        let path = temp_file("ok.txt", "What is a borrow checker?\n".as_bytes());
+        assert_eq!(
+            read_prompt_file(&path).unwrap(),
+            "What is a borrow checker?\n"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn missing_and_binary_prompt_files() {
+        let missing = read_prompt_file(Path::new("/definitely/not/here.txt")).unwrap_err();
+        assert!(missing.to_string().contains("does not exist"), "{missing}");
+
+        let path = temp_file("bin.dat", &[0xc3, 0x28]);
+        let binary = read_prompt_file(&path).unwrap_err();
+        assert!(binary.to_string().contains("not valid UTF-8"), "{binary}");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn system_files_must_exist_have_text_and_be_utf8() {
+        let path = temp_file("system.md", "  Be a strict reviewer.\n\n".as_bytes());
+        assert_eq!(read_system_file(&path).unwrap(), "Be a strict reviewer.");
+        std::fs::remove_file(path).unwrap();
+
+        let missing = read_system_file(Path::new("/definitely/not/system.md")).unwrap_err();
+        assert_eq!(
+            missing.to_string(),
+            "system prompt file '/definitely/not/system.md' does not exist"
+        );
+
+        let path = temp_file("blank.md", " \n\t\n".as_bytes());
+        let empty = read_system_file(&path).unwrap_err();
+        assert!(empty.to_string().ends_with("is empty"), "{empty}");
+        std::fs::remove_file(path).unwrap();
+
+        let path = temp_file("system.bin", &[b'o', b'k', 0xff]);
+        let binary = read_system_file(&path).unwrap_err();
+        assert!(
+            binary
+                .to_string()
+                .contains("is not valid UTF-8 (invalid byte at offset 2)"),
+            "{binary}"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn combine_delimits_piped_input() {
+        assert_eq!(
+            combine(Some("review this diff"), Some("+ added\n")).unwrap(),
+            "review this diff\n\n--- stdin ---\n+ added\n--- end stdin ---"
+        );
+        assert_eq!(combine(None, Some("just stdin")).unwrap(), "just stdin");
+        assert_eq!(combine(Some("just prompt"), None).unwrap(), "just prompt");
+        assert_eq!(combine(None, None), None);
+    }
+
+    #[test]
+    fn input_under_cap_is_untouched() {
+        let capped = cap_input("short".to_string(), 10, false).unwrap();
+        assert_eq!(capped.text, "short");
+        assert_eq!(capped.truncated_from, None);
+    }
+
+    #[test]
+    fn oversized_input_errors_unless_truncating() {
+        let err = cap_input("x".repeat(20), 10, false).unwrap_err();
+        assert!(err.to_string().contains("--truncate-input"), "{err}");
+
+        let capped = cap_input("x".repeat(20), 10, true).unwrap();
+        assert_eq!(capped.text, "x".repeat(10));
+        assert_eq!(capped.truncated_from, Some(20));
+    }
+
+    #[test]
+    fn truncation_respects_char_boundaries() {
+        // each 'é' is two bytes, so a 5 byte cap has to stop after two of them
+        let capped = cap_input("ééé".to_string(), 5, true).unwrap();
+        assert_eq!(capped.text, "éé");
+        assert_eq!(capped.truncated_from, Some(6));
+    }
+}
@@ -0,0 +1,37 @@
+//! A small chat agent for DeepSeek's OpenAI-compatible API.
+
+pub mod agent;
+pub mod attach;
+pub mod batch;
+pub mod cache;
+pub mod chat;
+pub mod config;
+pub mod context;
+pub mod conversation;
+pub mod doctor;
+pub mod embeddings;
+pub mod error;
+pub mod finish;
+pub mod input;
+pub mod metrics;
+pub mod models;
+pub mod network;
+pub mod provider;
+pub mod ratelimit;
+pub mod retry;
+pub mod review;
+pub mod schema;
+pub mod secret;
+pub mod session;
+pub mod settings;
+pub mod stream;
+pub mod templates;
+pub mod tools;
+pub mod transcript;
+pub mod usage;
+
+pub use agent::DeepSeekAgent;
+pub use config::AgentConfig;
+pub use error::AgentError;
+pub use secret::SecretString;
+pub use tools::{Tool, ToolRegistry};
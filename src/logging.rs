@@ -0,0 +1,122 @@
+//! Diagnostic logs: human-readable on stderr, and JSON in `--log-file` if given.
+
+use std::fs::File;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::Mutex;
+
+use deepseek_tutor::AgentError;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, fmt};
+
+/// Filter directives for the requested verbosity.
+///
+/// `--verbose` and `--debug` raise this program's own logs to debug and trace;
+/// everything else stays at warn. `RUST_LOG` comes last so it can refine or
+/// override any of it.
+pub fn directives(verbose: bool, debug: bool, rust_log: Option<&str>) -> String {
+    let level = if debug {
+        Some("trace")
+    } else if verbose {
+        Some("debug")
+    } else {
+        None
+    };
+    let mut directives = match level {
+        Some(level) => format!("warn,deepseek_tutor={level},deepseek_agent={level}"),
+        None => "warn".to_string(),
+    };
+    if let Some(rust_log) = rust_log.map(str::trim).filter(|r| !r.is_empty()) {
+        directives.push(',');
+        directives.push_str(rust_log);
+    }
+    directives
+}
+
+/// Install the global subscriber.
+pub fn init(directives: &str, log_file: Option<&Path>) -> Result<(), AgentError> {
+    let filter = EnvFilter::try_new(directives)
+        .map_err(|e| AgentError::InvalidConfig(format!("invalid RUST_LOG: {}", e)))?;
+    let file = match log_file {
+        Some(path) => Some(File::create(path).map_err(|e| {
+            AgentError::Input(format!(
+                "could not create log file '{}': {}",
+                path.display(),
+                e
+            ))
+        })?),
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(
+            fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_ansi(std::io::stderr().is_terminal()),
+        )
+        .with(file.map(|file| fmt::layer().json().with_writer(Mutex::new(file))))
+        .init();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deepseek_tutor::{AgentConfig, DeepSeekAgent};
+    use std::io::Write;
+    use std::sync::Arc;
+
+    #[test]
+    fn flags_pick_the_level_and_rust_log_refines_it() {
+        assert_eq!(directives(false, false, None), "warn");
+        assert_eq!(
+            directives(true, false, None),
+            "warn,deepseek_tutor=debug,deepseek_agent=debug"
+        );
+        assert_eq!(
+            directives(true, true, Some("hyper=trace")),
+            "warn,deepseek_tutor=trace,deepseek_agent=trace,hyper=trace"
+        );
+        assert_eq!(directives(false, false, Some(" ")), "warn");
+    }
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_logs_redact_the_api_key() {
+        let key = "sk-verysecretkey00001234";
+        let buffer = Buffer::default();
+        let sink = buffer.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(EnvFilter::new(directives(false, true, None)))
+            .with(fmt::layer().json().with_writer(move || sink.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            DeepSeekAgent::new(AgentConfig::new(key)).unwrap();
+        });
+
+        let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(!logs.contains(key), "{logs}");
+        let events: Vec<serde_json::Value> = logs
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let configured = events
+            .iter()
+            .find(|event| event["fields"]["message"] == "configuring agent")
+            .expect("the agent logs its configuration");
+        assert_eq!(configured["fields"]["api_key"], "sk-****1234");
+        assert_eq!(configured["fields"]["model"], "deepseek-chat");
+    }
+}
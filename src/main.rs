@@ -1,44 +1,1155 @@
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
 use dotenv::dotenv;
 use std::env;
-use async_openai::{Client, config::OpenAIConfig};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Instant;
+
+use deepseek_tutor::attach::Attachment;
+use deepseek_tutor::batch::{self, BatchRunner};
+use deepseek_tutor::cache;
+use deepseek_tutor::doctor::{self, CheckResult, Discovery};
+use deepseek_tutor::embeddings::{self, Index};
+use deepseek_tutor::metrics::RequestStats;
+use deepseek_tutor::models;
+use deepseek_tutor::provider::Provider;
+use deepseek_tutor::retry::RetryPolicy;
+use deepseek_tutor::review;
+use deepseek_tutor::schema::{self, JsonSchema};
+use deepseek_tutor::session::Session;
+use deepseek_tutor::settings::{self, ConfigFile, Merged, Settings, Source};
+use deepseek_tutor::stream::Delta;
+use deepseek_tutor::templates::{self, Template};
+use deepseek_tutor::tools::{
+    AutoApprove, DEFAULT_MAX_READ_BYTES, FetchUrl, ListDirectory, ReadFile, ShellTool, Workspace,
+    WriteFile,
+};
+use deepseek_tutor::transcript::TranscriptFormat;
+use deepseek_tutor::{AgentConfig, AgentError, DeepSeekAgent, ToolRegistry, input};
+use tracing::debug;
+
+mod approval;
+mod cli;
+mod init;
+mod logging;
+mod output;
+mod render;
+mod repl;
+mod tee;
+
+use cli::OutputFormat;
+
 #[tokio::main]
-async fn main() {
-dotenv().ok();
+async fn main() -> ExitCode {
+    dotenv().ok();
+    let matches = cli::Cli::command().get_matches();
+    let cli = cli::Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
-//Get API key from enviroment variables
-let api_key = env::var("OPENAI_API_KEY")
-    .expect("OPENAI_API_KEY must be set");
-let base_url=env::var("BASE_URL")
-    .expect("BASE_URL must be set");
+    match run(cli, matches).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
 
-println!("API Key: {}", api_key);
-println!("Base URL: {}", base_url);
+async fn run(cli: cli::Cli, matches: ArgMatches) -> Result<(), AgentError> {
+    logging::init(
+        &logging::directives(cli.verbose, cli.debug, env::var("RUST_LOG").ok().as_deref()),
+        cli.log_file.as_deref(),
+    )?;
+    let (path, file) = load_config_file(&cli)?;
+    let template = match &cli.command {
+        Some(cli::Command::Init) => {
+            return init::run(path.as_deref(), &cli.network()).await.map(|_| ());
+        }
+        Some(cli::Command::Profiles {
+            action: cli::ProfilesCommand::List,
+        }) => return list_profiles(path, &file.unwrap_or_default()),
+        Some(cli::Command::Templates {
+            action: cli::TemplatesCommand::List,
+        }) => return list_templates(&cli, &file.unwrap_or_default()),
+        Some(cli::Command::Export {
+            session,
+            format,
+            out,
+            include_reasoning,
+        }) => return export(session, *format, out.as_deref(), *include_reasoning),
+        Some(cli::Command::Run { name, vars }) => {
+            let templates = load_templates(&cli, file.as_ref())?;
+            let template = templates::find(&templates, name)?;
+            let rendered = template.render(vars)?;
+            for var in &rendered.unused {
+                eprintln!(
+                    "Warning: --var {} is not used by template '{}'.",
+                    var, template.name
+                );
+            }
+            Some((template.settings.clone(), rendered.prompt))
+        }
+        Some(
+            cli::Command::Batch { .. }
+            | cli::Command::Embed { .. }
+            | cli::Command::Models
+            | cli::Command::Review { .. }
+            | cli::Command::Doctor,
+        )
+        | None => None,
+    };
+    let (template_settings, template_prompt) = template.unzip();
+    let config_found = file.is_some();
+    let profile = file
+        .as_ref()
+        .and_then(|file| file.profile(cli.profile.as_deref()).ok().flatten())
+        .map(|(name, _)| name.to_string());
+    let mut merged = load_settings(&cli, &matches, path.clone(), file, template_settings)?;
+    if let Some(cli::Command::Doctor) = &cli.command {
+        if cli.dry_run {
+            return Err(AgentError::InvalidConfig(
+                "--dry-run does not apply to doctor, which sends its checks".into(),
+            ));
+        }
+        let discovery = Discovery {
+            // already loaded in main; this finds the file again
+            env_file: dotenv().ok(),
+            config_file: path,
+            config_found,
+            profile,
+            api_key_from: merged.source("api_key").map(|source| match source {
+                Source::Env => format!("${}", merged.settings.api_key_var()),
+                source => source.to_string(),
+            }),
+            api_key_var: merged.settings.api_key_var().to_string(),
+        };
+        return run_doctor(&cli, merged.settings, &discovery).await;
+    }
+    if cli.dry_run && merged.settings.api_key.is_none() {
+        // nothing is sent, so no key is needed
+        merged.settings.api_key = Some("".into());
+    }
+    if merged.settings.api_key.is_none() && init::can_prompt() {
+        eprintln!(
+            "No API key in ${} or the config file; setting one up.",
+            merged.settings.api_key_var()
+        );
+        let credentials = init::run(path.as_deref(), &cli.network()).await?;
+        merged.settings.api_key = Some(credentials.api_key);
+        // what was just saved replaces the file's old values, not the layers above it
+        if matches!(merged.source("base_url"), None | Some(Source::File)) {
+            merged.settings.base_url = Some(credentials.base_url);
+        }
+        if matches!(merged.source("model"), None | Some(Source::File)) {
+            merged.settings.model = Some(credentials.model);
+        }
+    }
+    let default_cache_dir = cache::default_cache_dir(
+        env::var("XDG_CACHE_HOME").ok().as_deref(),
+        env::var("HOME").ok().as_deref(),
+    );
+    let config = AgentConfig {
+        cache: cli.response_cache(default_cache_dir)?,
+        ..cli.agent_config(merged.settings)?
+    };
+    if config.network.insecure {
+        eprintln!(
+            "Warning: --insecure turns off TLS certificate checks, so anyone between you and {} \
+             can read your API key and replies. Only use it for a dev endpoint you trust.",
+            config.base_url
+        );
+    }
+    if !cli.dry_run {
+        config.network.check_proxy().await?;
+    }
+    match &cli.command {
+        Some(
+            cli::Command::Batch { .. }
+            | cli::Command::Embed { .. }
+            | cli::Command::Models
+            | cli::Command::Review { .. }
+            | cli::Command::Doctor,
+        ) if !cli.attach.is_empty() => {
+            return Err(AgentError::InvalidConfig(
+                "--attach only applies to prompts, run and the interactive session".into(),
+            ));
+        }
+        Some(cli::Command::Batch { input, .. }) if cli.dry_run => {
+            return dry_run_batch(config, input).await;
+        }
+        Some(cli::Command::Embed { .. } | cli::Command::Models | cli::Command::Review { .. })
+            if cli.dry_run =>
+        {
+            return Err(AgentError::InvalidConfig(
+                "--dry-run only applies to prompts and batch".into(),
+            ));
+        }
+        Some(cli::Command::Batch {
+            input,
+            out,
+            concurrency,
+            resume,
+        }) => {
+            let out = out.as_deref();
+            let report = BatchReport {
+                show_timing: cli.show_timing,
+                show_stats: cli.stats,
+            };
+            return run_batch(config, input, out, *concurrency as usize, *resume, report).await;
+        }
+        Some(cli::Command::Embed {
+            paths,
+            index,
+            embedding_model,
+            chunk_size,
+            chunk_overlap,
+        }) => {
+            let chunking = (*chunk_size as usize, *chunk_overlap);
+            return run_embed(config, paths, index, embedding_model, chunking).await;
+        }
+        Some(cli::Command::Models) => return list_models(config, cli.refresh_models).await,
+        Some(cli::Command::Review {
+            staged,
+            range,
+            ignores,
+            no_default_ignores,
+            out,
+        }) => {
+            let diff = review::git_diff(*staged, range.as_deref())?;
+            let mut ignores = ignores.clone();
+            if !no_default_ignores {
+                ignores.extend(review::DEFAULT_IGNORES.map(str::to_string));
+            }
+            return run_review(config, &diff, &ignores, out.as_deref()).await;
+        }
+        _ => {}
+    }
+    let mut attachments = read_attachments(&cli.attach)?;
+    let prompt = resolve_prompt(&cli, template_prompt)?.map(|prompt| {
+        let attached = std::mem::take(&mut attachments);
+        repl::attach_files(&prompt, attached, cli.max_attach_bytes)
+    });
+    if prompt.is_none() && cli.output != OutputFormat::Text {
+        return Err(AgentError::InvalidConfig(
+            "--output json and jsonl need a prompt; the interactive session is text only".into(),
+        ));
+    }
+    if cli.dry_run && prompt.is_none() {
+        return Err(AgentError::InvalidConfig(
+            "--dry-run needs a prompt; the interactive session sends every question".into(),
+        ));
+    }
+    // with JSON output, or a dry run's, stdout is for JSON alone
+    let status = |line: String| match cli.output {
+        OutputFormat::Text if !cli.dry_run => println!("{}", line),
+        _ => eprintln!("{}", line),
+    };
 
-//create config with explicit values
-let config = OpenAIConfig::new()
-    .with_api_key(api_key);
+    // SecretString prints redacted, e.g. sk-****1234
+    status(format!("API Key: {}", config.api_key));
+    status(format!("Base URL: {}", config.base_url));
+    if config.provider != Provider::DeepSeek {
+        status(format!("Provider: {}", config.provider));
+    }
 
-//Initialize the OpenAi client with config
-let client = Client::with_config(config);
+    let mut agent = DeepSeekAgent::new(config)?;
+    log_retries(&mut agent);
 
-println!("Client initialized successfully!");
+    let fan_out = cli.fan_out()?;
+    if !cli.no_validate && !cli.dry_run {
+        let asked = match &fan_out {
+            Some(fan_out) => fan_out.models.clone(),
+            None => vec![agent.model().to_string()],
+        };
+        check_models(&agent, &asked, cli.refresh_models).await;
+    }
 
-// Make a simple completion request (not chat completion)
-let request = async_openai::types::CreateCompletionRequestArgs::default()
-    .model("gpt-4o-mini")
-    .prompt("Hello! Can you tell me a short joke?")
-    .max_tokens(100u32)
-    .build()
-    .unwrap();
+    if cli.tools {
+        agent.set_tools(ToolRegistry::builtin());
+    }
+    if cli.shell {
+        let config = cli.shell_config();
+        if cli.auto_approve {
+            agent.register_tool(ShellTool::new(config, AutoApprove));
+        } else {
+            agent.register_tool(ShellTool::new(config, approval::TerminalApprover));
+        }
+    }
+    if cli.files {
+        let root = cli.workspace.clone().unwrap_or_else(|| PathBuf::from("."));
+        let workspace = Workspace::new(&root).map_err(|e| {
+            AgentError::InvalidConfig(format!("workspace '{}': {}", root.display(), e))
+        })?;
+        let max_bytes = cli.max_read_bytes.unwrap_or(DEFAULT_MAX_READ_BYTES);
+        agent.register_tool(ReadFile::new(workspace.clone(), max_bytes));
+        agent.register_tool(ListDirectory::new(workspace.clone()));
+        if cli.auto_approve {
+            agent.register_tool(WriteFile::new(workspace, AutoApprove, cli.backup));
+        } else {
+            agent.register_tool(WriteFile::new(
+                workspace,
+                approval::TerminalApprover,
+                cli.backup,
+            ));
+        }
+    }
+    if cli.fetch {
+        agent.register_tool(FetchUrl::new(cli.fetch_config(), &cli.network())?);
+    }
+    if let Some(path) = &cli.context_index {
+        let index = Index::load(path)?;
+        status(format!(
+            "Context index: {} chunks from {} files, embedded with {}",
+            index.chunks.len(),
+            index.sources(),
+            index.model
+        ));
+        agent.set_context_index(index, cli.top_k);
+    }
+    if !agent.tools().is_empty() {
+        agent.on_tool_call(|call| {
+            eprintln!(
+                "[tool] {}({}) -> {}",
+                call.name, call.arguments, call.output
+            );
+        });
+    }
 
-match client.completions().create(request).await {
-    Ok(response) => {
-        if let Some(choice) = response.choices.first() {
-            println!("DeepSeek Response: {}", choice.text);
+    if let Some(path) = &cli.resume {
+        let session = Session::load(path)?;
+        agent.resume(&session)?;
+        status(format!(
+            "Resumed {} messages from {}",
+            agent.conversation().len(),
+            path.display()
+        ));
+        if session.model != agent.model() {
+            eprintln!(
+                "Note: the session was held with {}; continuing with {}.",
+                session.model,
+                agent.model()
+            );
         }
     }
-    Err(e) => {
-        eprintln!("Error calling DeepSeek API: {}", e);
+
+    status("Client initialized successfully!".to_string());
+
+    // A prompt from the command line, a file or a pipe gets a single answer,
+    // otherwise chat until the user exits
+    let options = repl::Options {
+        streaming: cli.streaming(),
+        show_usage: cli.show_usage,
+        show_timing: cli.show_timing,
+        show_stats: cli.stats,
+        show_reasoning: !cli.hide_reasoning,
+        render: render::RenderMode::detect(
+            cli.plain,
+            std::io::stdout().is_terminal(),
+            env::var("NO_COLOR").ok().as_deref(),
+            env::var("TERM").ok().as_deref(),
+        ),
+        save_session: cli.save_session.clone(),
+        session_path: cli.save_session.clone().or(cli.resume.clone()),
+        fan_out,
+        json_schema: cli
+            .json_schema
+            .as_deref()
+            .map(JsonSchema::load)
+            .transpose()?,
+        schema_repairs: cli.schema_repairs.unwrap_or(schema::DEFAULT_MAX_REPAIRS),
+        system_file: cli.system_file.clone(),
+        output_file: cli.output_file.clone(),
+        append: cli.append,
+        attachments,
+        max_attach_bytes: cli.max_attach_bytes,
+    };
+    match prompt {
+        Some(prompt) if cli.dry_run => {
+            // JSON answers are never streamed, as a reply may need repairing
+            let stream = options.streaming && options.json_schema.is_none();
+            let dry_run = agent
+                .dry_run(&prompt, stream, options.json_schema.as_ref())
+                .await?;
+            let json = serde_json::to_string_pretty(&dry_run.to_json())
+                .expect("JSON values always serialize");
+            writeln!(std::io::stdout(), "{}", json)?;
+            Ok(())
+        }
+        Some(prompt) => {
+            let asked = ask_once(&mut agent, &prompt, &options, cli.output).await;
+            repl::report_stats(&agent, &options);
+            asked
+        }
+        None => repl::run(&mut agent, &options).await,
+    }
+}
+
+/// The config file named by `--config`, or the default one if it exists.
+fn load_config_file(cli: &cli::Cli) -> Result<(Option<PathBuf>, Option<ConfigFile>), AgentError> {
+    let path = cli.config.clone().or_else(|| {
+        settings::default_config_path(
+            env::var("XDG_CONFIG_HOME").ok().as_deref(),
+            env::var("HOME").ok().as_deref(),
+        )
+    });
+    let file = match &path {
+        Some(path) => settings::load(path, cli.config.is_some())?,
+        None => None,
+    };
+    Ok((path, file))
+}
+
+/// Run each `doctor` check in turn and print how it went, as text lines or
+/// for `--output json` one object CI can read. Critical failures make it an
+/// error, after every check has run.
+async fn run_doctor(
+    cli: &cli::Cli,
+    mut settings: Settings,
+    discovery: &Discovery,
+) -> Result<(), AgentError> {
+    let has_key = discovery.api_key_from.is_some();
+    if !has_key {
+        // the checks that need a key say so; the others still run
+        settings.api_key = Some("".into());
+    }
+    let config = AgentConfig {
+        // a check reports the first failure rather than retrying it
+        retry: RetryPolicy::none(),
+        cache: None,
+        ..cli.agent_config(settings)?
+    };
+    let client = config.network.client()?;
+    let (base_url, network) = (config.base_url.clone(), config.network.clone());
+    let mut agent = DeepSeekAgent::new(config)?;
+
+    let mut results = Vec::new();
+    let mut report = |result: CheckResult| {
+        match cli.output {
+            OutputFormat::Text => println!("{}", result.to_text()),
+            OutputFormat::Jsonl => println!("{}", serde_json::json!(result)),
+            OutputFormat::Json => {}
+        }
+        results.push(result);
+    };
+    report(doctor::config(discovery));
+    report(doctor::proxy(&network).await);
+    let dns = doctor::dns(&base_url, &network).await;
+    let tls = doctor::tls(&base_url, &client).await;
+    let reachable = !dns.is_critical_failure() && !tls.is_critical_failure();
+    report(dns);
+    report(tls);
+    if reachable {
+        report(doctor::auth(&agent, has_key).await);
+        report(doctor::chat(&mut agent, has_key).await);
+    } else {
+        // both would only fail to connect again
+        for name in ["auth", "chat"] {
+            report(CheckResult::skip(
+                name,
+                "the endpoint can't be reached".into(),
+            ));
+        }
+    }
+    report(doctor::clock(&base_url, &client).await);
+
+    let failed = doctor::critical_failures(&results);
+    match cli.output {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::json!({ "checks": results, "ok": failed == 0 })
+        ),
+        OutputFormat::Text if failed == 0 => println!("All critical checks passed."),
+        _ => {}
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(AgentError::ChecksFailed(failed)),
+    }
+}
+
+/// Write the transcript of the session in `path` to `out`, or stdout.
+fn export(
+    path: &Path,
+    format: TranscriptFormat,
+    out: Option<&Path>,
+    include_reasoning: bool,
+) -> Result<(), AgentError> {
+    let transcript = format.render(&Session::load(path)?, include_reasoning);
+    match out {
+        Some(out) => {
+            std::fs::write(out, transcript)?;
+            eprintln!("Wrote the transcript to {}.", out.display());
+        }
+        None => print!("{}", transcript),
+    }
+    Ok(())
+}
+
+fn list_profiles(path: Option<PathBuf>, file: &ConfigFile) -> Result<(), AgentError> {
+    if file.profiles.is_empty() {
+        match path {
+            Some(path) => println!("No profiles in {}.", path.display()),
+            None => println!("No config file (HOME is not set)."),
+        }
+        return Ok(());
+    }
+    print!("{}", cli::describe_profiles(file, |var| env::var(var).ok()));
+    Ok(())
+}
+
+/// The templates in `--templates-dir` (or the default directory) and the
+/// config file.
+fn load_templates(
+    cli: &cli::Cli,
+    file: Option<&ConfigFile>,
+) -> Result<std::collections::BTreeMap<String, Template>, AgentError> {
+    let dir = cli.templates_dir.clone().or_else(|| {
+        templates::default_templates_dir(
+            env::var("XDG_CONFIG_HOME").ok().as_deref(),
+            env::var("HOME").ok().as_deref(),
+        )
+    });
+    let inline = file.map(|file| file.templates.clone()).unwrap_or_default();
+    templates::load(dir.as_deref(), &inline)
+}
+
+/// Where the model list is cached, if there is a home directory.
+fn models_cache_path() -> Option<PathBuf> {
+    models::default_cache_path(
+        env::var("XDG_CACHE_HOME").ok().as_deref(),
+        env::var("HOME").ok().as_deref(),
+    )
+}
+
+async fn list_models(config: AgentConfig, refresh: bool) -> Result<(), AgentError> {
+    let agent = DeepSeekAgent::new(config)?;
+    let (models, cached) = models::list(&agent, models_cache_path().as_deref(), refresh).await?;
+    if models.is_empty() {
+        println!("{} lists no models.", agent.base_url());
+        return Ok(());
+    }
+    print!("{}", cli::describe_models(&models));
+    if cached {
+        eprintln!("(cached; --refresh-models fetches the list again)");
     }
+    Ok(())
 }
+
+/// Warn about each of `asked` the endpoint doesn't list. Not every server
+/// has a model list, so failing to get one is only logged.
+async fn check_models(agent: &DeepSeekAgent, asked: &[String], refresh: bool) {
+    match models::list(agent, models_cache_path().as_deref(), refresh).await {
+        Ok((models, _)) => {
+            for model in asked {
+                if let Some(warning) =
+                    models::unknown_model_warning(&models, model, agent.base_url())
+                {
+                    eprintln!("Warning: {} (--no-validate skips this check)", warning);
+                }
+            }
+        }
+        Err(e) => debug!(error = %e, "could not list models; not checking the model name"),
+    }
+}
+
+fn list_templates(cli: &cli::Cli, file: &ConfigFile) -> Result<(), AgentError> {
+    let templates = load_templates(cli, Some(file))?;
+    if templates.is_empty() {
+        println!("No templates defined.");
+        return Ok(());
+    }
+    print!("{}", cli::describe_templates(templates.values()));
+    Ok(())
+}
+
+/// Merge flags, the template being run, environment variables, the selected
+/// profile and the rest of the config file, in that order.
+fn load_settings(
+    cli: &cli::Cli,
+    matches: &ArgMatches,
+    path: Option<PathBuf>,
+    file: Option<ConfigFile>,
+    template: Option<Settings>,
+) -> Result<Merged, AgentError> {
+    let _span = tracing::debug_span!("load_settings").entered();
+    match (&path, &file) {
+        (Some(path), Some(_)) => debug!(path = %path.display(), "read config file"),
+        (Some(path), None) => debug!(path = %path.display(), "no config file"),
+        (None, _) => debug!("no config file path (HOME is not set)"),
+    }
+    let file = file.unwrap_or_default();
+    let profile = file.profile(cli.profile.as_deref())?;
+    let top = file.settings();
+    let profile = match profile {
+        Some((name, profile)) => {
+            debug!(profile = name, "using profile");
+            profile.clone()
+        }
+        None => Default::default(),
+    };
+
+    let [flags, mut env_layer] = cli.layers(matches);
+    // the key is only ever read from the environment or the file, never a flag
+    let key_var = profile
+        .api_key_env
+        .clone()
+        .unwrap_or_else(|| top.api_key_var().to_string());
+    env_layer.1.api_key = env::var(&key_var)
+        .ok()
+        .filter(|key| !key.is_empty())
+        .map(Into::into);
+
+    let merged = settings::merge([
+        flags,
+        (Source::Template, template.unwrap_or_default()),
+        env_layer,
+        (Source::Profile, profile),
+        (Source::File, top),
+    ]);
+    for overridden in &merged.overrides {
+        debug!("{}", overridden);
+    }
+    Ok(merged)
+}
+
+/// Assemble the prompt from `--prompt`/`--prompt-file`, or a filled-in
+/// template, plus anything piped on stdin.
+fn resolve_prompt(cli: &cli::Cli, template: Option<String>) -> Result<Option<String>, AgentError> {
+    let given = cli.prompt_file.is_some() || cli.prompt().is_some();
+    let prompt = match (&cli.prompt_file, template) {
+        (_, Some(_)) if given => {
+            return Err(AgentError::InvalidConfig(
+                "run takes its prompt from the template; pipe extra input on stdin instead".into(),
+            ));
+        }
+        (_, Some(template)) => Some(template),
+        (Some(path), None) => Some(input::read_prompt_file(path)?),
+        (None, None) => cli.prompt().map(str::to_string),
+    };
+    let stdin = std::io::stdin();
+    let piped = input::read_piped(stdin.lock(), stdin.is_terminal())?;
+
+    let Some(prompt) = input::combine(prompt.as_deref(), piped.as_deref()) else {
+        return Ok(None);
+    };
+    let capped = input::cap_input(prompt, cli.max_input_bytes, cli.truncate_input)?;
+    if let Some(original) = capped.truncated_from {
+        eprintln!(
+            "Warning: input truncated from {} to {} bytes.",
+            original,
+            capped.text.len()
+        );
+    }
+    Ok(Some(capped.text))
+}
+
+/// The files `--attach` names, read before anything is sent so a binary or
+/// missing one stops the run.
+fn read_attachments(paths: &[PathBuf]) -> Result<Vec<Attachment>, AgentError> {
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+    let base = env::current_dir()?;
+    paths
+        .iter()
+        .map(|path| Attachment::read(path, &base))
+        .collect()
+}
+
+/// Report each retry of `agent`'s requests on stderr.
+fn log_retries(agent: &mut DeepSeekAgent) {
+    agent.on_retry(|retry| {
+        eprintln!(
+            "Request failed ({}), retry {}/{} in {:.1}s",
+            retry.error,
+            retry.attempt,
+            retry.max_retries,
+            retry.delay.as_secs_f64()
+        );
+    });
+}
+
+/// Chunk and embed `paths` into the index at `index_path`, adding to it if
+/// it exists. The index is saved after each file.
+async fn run_embed(
+    config: AgentConfig,
+    paths: &[PathBuf],
+    index_path: &Path,
+    model: &str,
+    (chunk_size, chunk_overlap): (usize, usize),
+) -> Result<(), AgentError> {
+    if chunk_overlap >= chunk_size {
+        return Err(AgentError::InvalidConfig(format!(
+            "--chunk-overlap ({}) must be less than --chunk-size ({})",
+            chunk_overlap, chunk_size
+        )));
+    }
+    let mut index = Index::open(index_path, model, chunk_size, chunk_overlap)?;
+    let mut agent = DeepSeekAgent::new(config)?;
+    log_retries(&mut agent);
+    embeddings::embed_files(
+        &mut agent,
+        &mut index,
+        paths,
+        index_path,
+        |source, chunks| eprintln!("Embedded {} ({} chunks)", source, chunks),
+    )
+    .await?;
+    let totals = agent.usage().totals();
+    eprintln!(
+        "Index {}: {} chunks from {} files; {} tokens, ~${:.6}.",
+        index_path.display(),
+        index.chunks.len(),
+        index.sources(),
+        totals.usage.prompt_tokens,
+        totals.cost
+    );
+    Ok(())
+}
+
+/// What a batch reports on stderr besides its progress.
+#[derive(Debug, Clone, Copy)]
+struct BatchReport {
+    /// The timing of every answer.
+    show_timing: bool,
+    /// Latency and throughput over the batch, at the end.
+    show_stats: bool,
+}
+
+/// Answer every prompt in `input`, writing each result as it completes and
+/// reporting progress on stderr.
+async fn run_batch(
+    config: AgentConfig,
+    input: &Path,
+    out: Option<&Path>,
+    concurrency: usize,
+    resume: bool,
+    report: BatchReport,
+) -> Result<(), AgentError> {
+    let text = std::fs::read_to_string(input)
+        .map_err(|e| AgentError::Input(format!("could not read '{}': {}", input.display(), e)))?;
+    let mut items = batch::parse_items(&text)
+        .map_err(|e| AgentError::Input(format!("{}: {}", input.display(), e)))?;
+
+    let mut writer: Box<dyn Write> = match out {
+        Some(path) => {
+            if resume {
+                let done = match std::fs::read_to_string(path) {
+                    Ok(text) => batch::completed_ids(&text),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                    Err(e) => return Err(e.into()),
+                };
+                let before = items.len();
+                items.retain(|item| !done.contains(&item.id));
+                if before > items.len() {
+                    eprintln!(
+                        "Skipping {} prompts already answered in {}.",
+                        before - items.len(),
+                        path.display()
+                    );
+                }
+            }
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(resume)
+                .truncate(!resume)
+                .open(path)?;
+            Box::new(file)
+        }
+        None => Box::new(std::io::stdout()),
+    };
+
+    let total = items.len();
+    let (mut finished, mut failed, mut flagged, mut cost) = (0, 0, 0, 0.0);
+    let mut stats = RequestStats::default();
+    let mut write_error = None;
+    let runner = BatchRunner::new(config, concurrency)?;
+    let outcome = repl::interruptible(async {
+        runner
+            .run(items, |result| {
+                finished += 1;
+                if let Some(turn_cost) = result.usage.as_ref().and_then(|usage| usage.cost) {
+                    cost += turn_cost;
+                }
+                match &result.outcome {
+                    Ok(_) if result.cached => eprintln!(
+                        "[{}/{}] {} from the cache, ~${:.6} so far",
+                        finished, total, result.id, cost
+                    ),
+                    Ok(_) => eprintln!(
+                        "[{}/{}] {} done in {:.1}s, ~${:.6} so far",
+                        finished,
+                        total,
+                        result.id,
+                        result.latency.as_secs_f64(),
+                        cost
+                    ),
+                    Err(error) => {
+                        failed += 1;
+                        eprintln!("[{}/{}] {} failed: {}", finished, total, result.id, error);
+                    }
+                }
+                if let Some(issue) = result.issue {
+                    flagged += 1;
+                    eprintln!(
+                        "[{}/{}] {} flagged: {}",
+                        finished,
+                        total,
+                        result.id,
+                        issue.note(None)
+                    );
+                }
+                if let Some(metrics) = result.metrics {
+                    if report.show_timing {
+                        eprintln!("[{}/{}] {} {}", finished, total, result.id, metrics);
+                    }
+                    stats.record(metrics);
+                }
+                // flushed line by line, so a crash loses nothing finished
+                if write_error.is_none()
+                    && let Err(e) = output::write_line(&mut writer, &result.to_json())
+                {
+                    write_error = Some(e);
+                }
+            })
+            .await
+    })
+    .await;
+    if let Some(e) = write_error {
+        return Err(e.into());
+    }
+    if report.show_stats
+        && let Some(summary) = stats.summary()
+    {
+        eprintln!("{}", summary);
+    }
+    if let Err(AgentError::Interrupted) = outcome {
+        eprintln!(
+            "Interrupted after {} of {} prompts; rerun with --resume to answer the rest.",
+            finished, total
+        );
+        return Err(AgentError::Interrupted);
+    }
+    if let Err(e @ AgentError::BudgetExceeded { .. }) = outcome {
+        eprintln!(
+            "Stopped at the budget with {} of {} prompts answered; rerun with --resume and a \
+             higher --budget-usd to answer the rest.",
+            finished - failed,
+            total
+        );
+        return Err(e);
+    }
+    outcome?;
+    eprintln!(
+        "Answered {} of {} prompts ({} failed), ~${:.6}.",
+        finished - failed,
+        total,
+        failed,
+        cost
+    );
+    if flagged > 0 {
+        eprintln!(
+            "{} answers were flagged with an \"issue\"; --resume tries them again with the \
+             failed ones.",
+            flagged
+        );
+    }
+    Ok(())
+}
+
+/// Print the request each line of `input` would make, one JSON object a
+/// line tagged with its id, without sending any.
+async fn dry_run_batch(config: AgentConfig, input: &Path) -> Result<(), AgentError> {
+    let text = std::fs::read_to_string(input)
+        .map_err(|e| AgentError::Input(format!("could not read '{}': {}", input.display(), e)))?;
+    let items = batch::parse_items(&text)
+        .map_err(|e| AgentError::Input(format!("{}: {}", input.display(), e)))?;
+    let runner = BatchRunner::new(config, 1)?;
+    let mut stdout = std::io::stdout();
+    for item in &items {
+        let mut line = runner.dry_run(item).await?.to_json();
+        line["id"] = item.id.clone();
+        output::write_line(&mut stdout, &line)?;
+    }
+    Ok(())
+}
+
+/// Review `diff` a chunk at a time and write the findings as one Markdown
+/// report, then fail as [`review::Review::verdict`] says.
+async fn run_review(
+    config: AgentConfig,
+    diff: &str,
+    ignores: &[String],
+    out: Option<&Path>,
+) -> Result<(), AgentError> {
+    let (files, skipped) = review::select(review::parse_diff(diff), ignores);
+    for (file, reason) in &skipped {
+        eprintln!("Skipping {} ({}).", file, reason);
+    }
+    if files.is_empty() {
+        eprintln!("Nothing to review: the diff has no reviewable changes.");
+        return Ok(());
+    }
+    // half the budget, leaving room for the prompt, the answer and repairs
+    let chunks = review::chunk(&files, config.context.budget / 2);
+    let mut agent = DeepSeekAgent::new(review::reviewer_config(config))?;
+    log_retries(&mut agent);
+
+    let total = chunks.len();
+    let review = repl::interruptible(review::review_chunks(
+        &mut agent,
+        &chunks,
+        skipped,
+        |progress| match progress {
+            review::Progress::Sending(i, chunk) => {
+                eprintln!("[{}/{}] reviewing {}", i + 1, total, chunk.files.join(", "))
+            }
+            review::Progress::Failed(i, e) => eprintln!("[{}/{}] failed: {}", i + 1, total, e),
+        },
+    ))
+    .await?;
+
+    let markdown = review.report.to_markdown();
+    match out {
+        Some(path) => {
+            std::fs::write(path, &markdown)?;
+            eprintln!("Wrote the review to {}", path.display());
+        }
+        None => print!("{}", markdown),
+    }
+    let totals = agent.usage().totals();
+    eprintln!(
+        "Reviewed {} of {} chunks ({} files); {} tokens, ~${:.6}.",
+        review.reviewed,
+        total,
+        files.len(),
+        totals.usage.total_tokens(),
+        totals.cost
+    );
+    review.verdict()
+}
+
+async fn ask_once(
+    agent: &mut DeepSeekAgent,
+    prompt: &str,
+    options: &repl::Options,
+    format: OutputFormat,
+) -> Result<(), AgentError> {
+    if let Some(fan_out) = &options.fan_out {
+        return ask_each(agent, prompt, fan_out, options, format).await;
+    }
+    if let Some(schema) = &options.json_schema {
+        return ask_json(agent, prompt, schema, options, format).await;
+    }
+    let started = Instant::now();
+    let mut stdout = std::io::stdout();
+    let mut tee = begin_tee(agent, options);
+    let mut copy = |delta: &Delta| {
+        if let Some(tee) = tee.as_mut() {
+            tee.delta(delta);
+        }
+    };
+    let result = match (format, options.streaming) {
+        (OutputFormat::Text, true) => {
+            let mut printer = repl::DeltaPrinter::new(options.show_reasoning, options.render);
+            let result = repl::interruptible(agent.ask_streaming(prompt, |delta| {
+                printer.print(delta);
+                copy(delta);
+            }))
+            .await;
+            printer.finish();
+            result
+        }
+        (OutputFormat::Text, false) => {
+            repl::interruptible(agent.ask(prompt))
+                .await
+                .inspect(|content| {
+                    repl::print_answer(
+                        agent.last_reasoning(),
+                        content,
+                        options.show_reasoning,
+                        options.render,
+                    );
+                    copy(&Delta::Content(content.clone()));
+                })
+        }
+        (OutputFormat::Jsonl, _) => {
+            repl::interruptible(agent.ask_streaming(prompt, |delta| {
+                if options.show_reasoning || matches!(delta, Delta::Content(_)) {
+                    let _ = output::write_line(&mut stdout, &output::delta(delta));
+                }
+                copy(delta);
+            }))
+            .await
+        }
+        (OutputFormat::Json, true) => repl::interruptible(agent.ask_streaming(prompt, copy)).await,
+        (OutputFormat::Json, false) => repl::interruptible(agent.ask(prompt))
+            .await
+            .inspect(|content| copy(&Delta::Content(content.clone()))),
+    };
+    // an answer cut short doesn't replace the last one
+    if let Some(tee) = tee.as_mut() {
+        match result {
+            Ok(_) => tee.finish(),
+            Err(_) => tee.cancel(),
+        }
+    }
+    let content = match result {
+        Ok(content) => content,
+        Err(AgentError::Interrupted) => {
+            repl::interrupted(agent, options);
+            return Err(AgentError::Interrupted);
+        }
+        Err(e) => {
+            write_error(&mut stdout, format, &e)?;
+            return Err(e);
+        }
+    };
+
+    let mut summary = output::Summary::of(agent, &content, started.elapsed());
+    if !options.show_reasoning {
+        summary.reasoning = None;
+    }
+    match format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => output::write_line(&mut stdout, &summary.to_json())?,
+        OutputFormat::Jsonl => output::write_line(&mut stdout, &output::done(&summary))?,
+    }
+    repl::report_reply(agent, options.show_usage, options.show_timing);
+    if let Some(path) = &options.save_session {
+        agent.session().save(path)?;
+    }
+    Ok(())
+}
+
+/// Ask for an answer held to `schema`, which in JSON output is the JSON
+/// document itself. Never streamed, as a reply may need repairing.
+async fn ask_json(
+    agent: &mut DeepSeekAgent,
+    prompt: &str,
+    schema: &JsonSchema,
+    options: &repl::Options,
+    format: OutputFormat,
+) -> Result<(), AgentError> {
+    let started = Instant::now();
+    let mut stdout = std::io::stdout();
+    let mut tee = begin_tee(agent, options);
+    let value =
+        match repl::interruptible(agent.ask_json(prompt, schema, options.schema_repairs)).await {
+            Ok(value) => value,
+            Err(e) => {
+                if let Some(tee) = tee.as_mut() {
+                    tee.cancel();
+                }
+                if let AgentError::Interrupted = e {
+                    repl::interrupted(agent, options);
+                    return Err(e);
+                }
+                write_error(&mut stdout, format, &e)?;
+                return Err(e);
+            }
+        };
+    if let Some(mut tee) = tee {
+        tee.write(&repl::pretty_json(&value));
+        tee.finish();
+    }
+    match format {
+        OutputFormat::Text => repl::print_json(&value),
+        OutputFormat::Json => output::write_line(&mut stdout, &value)?,
+        OutputFormat::Jsonl => {
+            let content = value.to_string();
+            let mut summary = output::Summary::of(agent, &content, started.elapsed());
+            if !options.show_reasoning {
+                summary.reasoning = None;
+            }
+            let mut line = output::done(&summary);
+            line["json"] = value;
+            output::write_line(&mut stdout, &line)?
+        }
+    }
+    repl::report_reply(agent, options.show_usage, options.show_timing);
+    if let Some(path) = &options.save_session {
+        agent.session().save(path)?;
+    }
+    Ok(())
+}
+
+/// Where `options` say to copy the answer to a prompt, ready for it. Answers
+/// appended to a file get a header, so one can be told from the next.
+fn begin_tee(agent: &DeepSeekAgent, options: &repl::Options) -> Option<tee::Tee> {
+    let mut tee = options.tee()?;
+    tee.begin(
+        options
+            .append
+            .then(|| tee::header(chrono::Utc::now(), agent.model())),
+    );
+    Some(tee)
+}
+
+/// Write `error` to stdout for JSON output; text output leaves it to main.
+fn write_error(
+    stdout: &mut std::io::Stdout,
+    format: OutputFormat,
+    error: &AgentError,
+) -> Result<(), AgentError> {
+    match format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => output::write_line(stdout, &output::error(error))?,
+        OutputFormat::Jsonl => {
+            let mut line = output::error(error);
+            line["type"] = "error".into();
+            output::write_line(stdout, &line)?
+        }
+    }
+    Ok(())
+}
+
+/// Ask every model of `fan_out` at once and print all the answers. Fails
+/// only if every model did.
+async fn ask_each(
+    agent: &mut DeepSeekAgent,
+    prompt: &str,
+    fan_out: &repl::FanOut,
+    options: &repl::Options,
+    format: OutputFormat,
+) -> Result<(), AgentError> {
+    let asked =
+        repl::interruptible(agent.ask_models(prompt, &fan_out.models, &fan_out.history_model))
+            .await;
+    let mut answers = match asked {
+        Ok(answers) => answers,
+        Err(AgentError::Interrupted) => {
+            repl::interrupted(agent, options);
+            return Err(AgentError::Interrupted);
+        }
+        Err(e) => return Err(e),
+    };
+    if let Some(mut tee) = begin_tee(agent, options) {
+        repl::tee_answers(&mut tee, &answers);
+        tee.finish();
+    }
+    let mut stdout = std::io::stdout();
+    match format {
+        OutputFormat::Text => repl::print_answers(&answers, options),
+        OutputFormat::Json => output::write_line(
+            &mut stdout,
+            &output::answers(&answers, options.show_reasoning).into(),
+        )?,
+        OutputFormat::Jsonl => {
+            for mut line in output::answers(&answers, options.show_reasoning) {
+                let failed = line.get("error").is_some();
+                line["type"] = if failed { "error" } else { "done" }.into();
+                output::write_line(&mut stdout, &line)?;
+            }
+        }
+    }
+    repl::report_reply(agent, false, false);
+    if let Some(path) = &options.save_session {
+        agent.session().save(path)?;
+    }
+    if answers.iter().any(|answer| answer.reply.is_ok()) {
+        return Ok(());
+    }
+    answers.remove(0).reply.map(|_| ())
 }
@@ -0,0 +1,336 @@
+//! How long requests take, for `--show-timing` and `--stats`: latency, time
+//! to the first streamed token, tokens a second and retries.
+//!
+//! Timers are handed the time rather than reading the clock, so what they
+//! measure can be tested with made-up instants.
+
+use std::fmt;
+use std::ops::AddAssign;
+use std::time::{Duration, Instant};
+
+use serde_json::{Value, json};
+
+/// How a reply was received, over every request made for it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RequestMetrics {
+    /// From sending the first request to the end of the last reply, retries
+    /// included.
+    pub latency: Duration,
+    /// From sending to the first piece of text, if the reply was streamed.
+    pub time_to_first_token: Option<Duration>,
+    /// Tokens the model generated, if the API reported them.
+    pub completion_tokens: Option<u64>,
+    pub retries: u32,
+    /// Requests made, with one for every round of tool calls.
+    pub requests: u32,
+}
+
+impl RequestMetrics {
+    /// Completion tokens over the time spent generating them: after the first
+    /// token if streamed, as the prompt was being read before it, or over the
+    /// whole latency if not.
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        let tokens = self.completion_tokens?;
+        let generating = self
+            .latency
+            .saturating_sub(self.time_to_first_token.unwrap_or_default());
+        (tokens > 0 && !generating.is_zero()).then(|| tokens as f64 / generating.as_secs_f64())
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "latency_ms": self.latency.as_millis() as u64,
+            "time_to_first_token_ms": self
+                .time_to_first_token
+                .map(|ttft| ttft.as_millis() as u64),
+            "completion_tokens": self.completion_tokens,
+            "tokens_per_second": self.tokens_per_second(),
+            "retries": self.retries,
+            "requests": self.requests,
+        })
+    }
+}
+
+impl AddAssign for RequestMetrics {
+    /// Count `later`, a request made after these, as part of the same reply.
+    fn add_assign(&mut self, later: Self) {
+        self.time_to_first_token = self
+            .time_to_first_token
+            .or(later.time_to_first_token.map(|ttft| self.latency + ttft));
+        self.latency += later.latency;
+        self.completion_tokens = match (self.completion_tokens, later.completion_tokens) {
+            (None, None) => None,
+            (tokens, more) => Some(tokens.unwrap_or(0) + more.unwrap_or(0)),
+        };
+        self.retries += later.retries;
+        self.requests += later.requests;
+    }
+}
+
+impl fmt::Display for RequestMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timing: ")?;
+        if let Some(ttft) = self.time_to_first_token {
+            write!(f, "first token {:.2}s, ", ttft.as_secs_f64())?;
+        }
+        write!(f, "{:.2}s total", self.latency.as_secs_f64())?;
+        if let Some(rate) = self.tokens_per_second() {
+            write!(f, ", {:.1} tokens/s", rate)?;
+        }
+        if self.requests > 1 {
+            write!(f, ", {} requests", self.requests)?;
+        }
+        if self.retries > 0 {
+            write!(f, ", {}", count(self.retries as usize, "retry", "retries"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Times one request as it goes.
+#[derive(Debug, Clone)]
+pub struct RequestTimer {
+    started: Instant,
+    first_token: Option<Instant>,
+    retries: u32,
+}
+
+impl RequestTimer {
+    /// Start timing a request sent `at`.
+    pub fn start(at: Instant) -> Self {
+        Self {
+            started: at,
+            first_token: None,
+            retries: 0,
+        }
+    }
+
+    /// Note text arriving `at`; only the first time counts.
+    pub fn first_token(&mut self, at: Instant) {
+        self.first_token.get_or_insert(at);
+    }
+
+    pub fn retried(&mut self) {
+        self.retries += 1;
+    }
+
+    /// The metrics of a request whose reply ended `at`, having generated
+    /// `completion_tokens`.
+    pub fn finish(self, at: Instant, completion_tokens: Option<u64>) -> RequestMetrics {
+        RequestMetrics {
+            latency: at.saturating_duration_since(self.started),
+            time_to_first_token: self
+                .first_token
+                .map(|first| first.saturating_duration_since(self.started)),
+            completion_tokens,
+            retries: self.retries,
+            requests: 1,
+        }
+    }
+}
+
+/// The metrics of every reply in a session or batch.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RequestStats {
+    replies: Vec<RequestMetrics>,
+}
+
+impl RequestStats {
+    pub fn record(&mut self, metrics: RequestMetrics) {
+        self.replies.push(metrics);
+    }
+
+    /// Percentiles and averages, or `None` before the first reply.
+    pub fn summary(&self) -> Option<StatsSummary> {
+        if self.replies.is_empty() {
+            return None;
+        }
+        let mut latencies: Vec<Duration> = self.replies.iter().map(|m| m.latency).collect();
+        latencies.sort();
+        let rates: Vec<f64> = self
+            .replies
+            .iter()
+            .filter_map(RequestMetrics::tokens_per_second)
+            .collect();
+        Some(StatsSummary {
+            replies: self.replies.len(),
+            requests: self.replies.iter().map(|m| m.requests).sum(),
+            retries: self.replies.iter().map(|m| m.retries).sum(),
+            p50_latency: percentile(&latencies, 50),
+            p95_latency: percentile(&latencies, 95),
+            tokens_per_second: (!rates.is_empty())
+                .then(|| rates.iter().sum::<f64>() / rates.len() as f64),
+        })
+    }
+}
+
+/// The nearest-rank `p`th percentile of `sorted`, which isn't empty.
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+/// What `--stats` prints at the end of a session.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatsSummary {
+    pub replies: usize,
+    pub requests: u32,
+    pub retries: u32,
+    pub p50_latency: Duration,
+    pub p95_latency: Duration,
+    /// The mean of each reply's tokens a second, of those that have one.
+    pub tokens_per_second: Option<f64>,
+}
+
+impl fmt::Display for StatsSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "stats: {} for {}, latency p50 {:.2}s, p95 {:.2}s",
+            count(self.requests as usize, "request", "requests"),
+            count(self.replies, "reply", "replies"),
+            self.p50_latency.as_secs_f64(),
+            self.p95_latency.as_secs_f64()
+        )?;
+        if let Some(rate) = self.tokens_per_second {
+            write!(f, ", {:.1} tokens/s on average", rate)?;
+        }
+        write!(f, ", {}", count(self.retries as usize, "retry", "retries"))
+    }
+}
+
+fn count(n: usize, one: &str, many: &str) -> String {
+    format!("{} {}", n, if n == 1 { one } else { many })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn timers_measure_from_the_instants_given() {
+        let sent = Instant::now();
+        let mut timer = RequestTimer::start(sent);
+        timer.retried();
+        timer.first_token(sent + ms(400));
+        timer.first_token(sent + ms(900));
+        let metrics = timer.finish(sent + ms(2400), Some(100));
+        assert_eq!(
+            metrics,
+            RequestMetrics {
+                latency: ms(2400),
+                time_to_first_token: Some(ms(400)),
+                completion_tokens: Some(100),
+                retries: 1,
+                requests: 1,
+            }
+        );
+        // 100 tokens in the 2 seconds after the first
+        assert_eq!(metrics.tokens_per_second(), Some(50.0));
+        assert_eq!(
+            metrics.to_string(),
+            "timing: first token 0.40s, 2.40s total, 50.0 tokens/s, 1 retry"
+        );
+    }
+
+    #[test]
+    fn unstreamed_replies_are_rated_over_their_latency() {
+        let sent = Instant::now();
+        let metrics = RequestTimer::start(sent).finish(sent + ms(500), Some(20));
+        assert_eq!(metrics.time_to_first_token, None);
+        assert_eq!(metrics.tokens_per_second(), Some(40.0));
+        assert_eq!(metrics.to_string(), "timing: 0.50s total, 40.0 tokens/s");
+
+        let unreported = RequestTimer::start(sent).finish(sent + ms(500), None);
+        assert_eq!(unreported.tokens_per_second(), None);
+        let json = unreported.to_json();
+        assert_eq!(json["latency_ms"], 500);
+        assert!(json["tokens_per_second"].is_null());
+        assert!(json["time_to_first_token_ms"].is_null());
+    }
+
+    #[test]
+    fn later_requests_add_to_the_reply() {
+        let mut metrics = RequestMetrics::default();
+        metrics += RequestMetrics {
+            latency: ms(1000),
+            time_to_first_token: None,
+            completion_tokens: Some(10),
+            retries: 1,
+            requests: 1,
+        };
+        metrics += RequestMetrics {
+            latency: ms(2000),
+            time_to_first_token: Some(ms(300)),
+            completion_tokens: None,
+            retries: 0,
+            requests: 1,
+        };
+        assert_eq!(
+            metrics,
+            RequestMetrics {
+                latency: ms(3000),
+                // the first token came in the second request
+                time_to_first_token: Some(ms(1300)),
+                completion_tokens: Some(10),
+                retries: 1,
+                requests: 2,
+            }
+        );
+        assert!(metrics.to_string().ends_with(", 2 requests, 1 retry"));
+    }
+
+    #[test]
+    fn stats_take_percentiles_and_average_rates() {
+        let mut stats = RequestStats::default();
+        assert_eq!(stats.summary(), None);
+        stats.record(RequestTimer::start(Instant::now()).finish(Instant::now(), None));
+        assert!(
+            stats
+                .summary()
+                .unwrap()
+                .to_string()
+                .starts_with("stats: 1 request for 1 reply, ")
+        );
+        stats = RequestStats::default();
+        for (latency, tokens) in [(400, Some(40)), (100, None), (300, Some(30)), (200, None)] {
+            stats.record(RequestMetrics {
+                latency: ms(latency),
+                completion_tokens: tokens,
+                requests: 1,
+                ..RequestMetrics::default()
+            });
+        }
+        stats.record(RequestMetrics {
+            latency: ms(5000),
+            completion_tokens: Some(500),
+            retries: 2,
+            requests: 3,
+            ..RequestMetrics::default()
+        });
+        let summary = stats.summary().unwrap();
+        assert_eq!(summary.replies, 5);
+        assert_eq!(summary.requests, 7);
+        assert_eq!(summary.retries, 2);
+        assert_eq!(summary.p50_latency, ms(300));
+        assert_eq!(summary.p95_latency, ms(5000));
+        assert_eq!(summary.tokens_per_second, Some(100.0));
+        assert_eq!(
+            summary.to_string(),
+            "stats: 7 requests for 5 replies, latency p50 0.30s, p95 5.00s, \
+             100.0 tokens/s on average, 2 retries"
+        );
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let sorted: Vec<_> = (1..=20).map(ms).collect();
+        assert_eq!(percentile(&sorted, 50), ms(10));
+        assert_eq!(percentile(&sorted, 95), ms(19));
+        assert_eq!(percentile(&sorted[..1], 95), ms(1));
+    }
+}
@@ -0,0 +1,242 @@
+//! The models an endpoint serves, kept on disk for a while so checking the
+//! configured model doesn't cost a request every run.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use async_openai::error::OpenAIError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::debug;
+
+use crate::agent::DeepSeekAgent;
+use crate::error::AgentError;
+use crate::settings;
+
+/// How long a fetched list is used before it is fetched again.
+pub const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The cache file, relative to the user's cache directory.
+pub const CACHE_FILE: &str = "deepseek_agent/models.json";
+
+/// One entry of `GET /models`. DeepSeek leaves out `created`, and other
+/// servers sometimes the owner, so both are optional.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub id: String,
+    #[serde(default)]
+    pub owned_by: Option<String>,
+    /// Unix seconds.
+    #[serde(default)]
+    pub created: Option<i64>,
+}
+
+impl ModelInfo {
+    /// `created` as a UTC date, if the server sent a valid one.
+    pub fn created_date(&self) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp(self.created?, 0)
+    }
+}
+
+/// The models in a `GET /models` response, sorted by id.
+pub fn parse_list(raw: Value) -> Result<Vec<ModelInfo>, AgentError> {
+    #[derive(Deserialize)]
+    struct List {
+        data: Vec<ModelInfo>,
+    }
+    let list: List = serde_json::from_value(raw).map_err(OpenAIError::JSONDeserialize)?;
+    let mut models = list.data;
+    models.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(models)
+}
+
+/// Where the cache goes: under `$XDG_CACHE_HOME`, or `~/.cache`.
+pub fn default_cache_path(xdg_cache_home: Option<&str>, home: Option<&str>) -> Option<PathBuf> {
+    Some(settings::cache_home(xdg_cache_home, home)?.join(CACHE_FILE))
+}
+
+/// Model lists by base URL, with when each was fetched.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelCache {
+    pub lists: BTreeMap<String, CachedList>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedList {
+    pub fetched_at: DateTime<Utc>,
+    pub models: Vec<ModelInfo>,
+}
+
+impl ModelCache {
+    /// The cache at `path`. One that is missing or can't be read is empty; it
+    /// only saves a request.
+    pub fn load(path: &Path) -> Self {
+        let cache = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()));
+        cache.unwrap_or_else(|e| {
+            debug!(path = %path.display(), error = %e, "no usable model cache");
+            Self::default()
+        })
+    }
+
+    /// Write the cache to `path`, making its directory if need be.
+    pub fn save(&self, path: &Path) -> Result<(), AgentError> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(
+            path,
+            serde_json::to_string_pretty(self).expect("caches always serialize"),
+        )?;
+        Ok(())
+    }
+
+    /// The models of `base_url`, unless there are none younger than [`CACHE_TTL`] at `now`.
+    pub fn fresh(&self, base_url: &str, now: DateTime<Utc>) -> Option<&[ModelInfo]> {
+        let list = self.lists.get(base_url)?;
+        let age = (now - list.fetched_at).to_std().ok()?;
+        (age < CACHE_TTL).then_some(list.models.as_slice())
+    }
+
+    pub fn insert(&mut self, base_url: &str, fetched_at: DateTime<Utc>, models: Vec<ModelInfo>) {
+        self.lists
+            .insert(base_url.to_string(), CachedList { fetched_at, models });
+    }
+}
+
+/// The agent's endpoint's models, from the cache at `cache_path` if it has a
+/// fresh list and `refresh` isn't set, otherwise fetched and cached. Returns
+/// whether they came from the cache.
+pub async fn list(
+    agent: &DeepSeekAgent,
+    cache_path: Option<&Path>,
+    refresh: bool,
+) -> Result<(Vec<ModelInfo>, bool), AgentError> {
+    let now = Utc::now();
+    let mut cache = cache_path.map(ModelCache::load).unwrap_or_default();
+    if !refresh && let Some(models) = cache.fresh(agent.base_url(), now) {
+        return Ok((models.to_vec(), true));
+    }
+    let models = agent.list_models().await?;
+    if let Some(path) = cache_path {
+        cache.insert(agent.base_url(), now, models.clone());
+        if let Err(e) = cache.save(path) {
+            debug!(path = %path.display(), error = %e, "could not save the model cache");
+        }
+    }
+    Ok((models, false))
+}
+
+/// A warning if `model` isn't among `models`, naming those that are.
+pub fn unknown_model_warning(models: &[ModelInfo], model: &str, base_url: &str) -> Option<String> {
+    if models.is_empty() || models.iter().any(|info| info.id == model) {
+        return None;
+    }
+    let ids: Vec<&str> = models.iter().map(|info| info.id.as_str()).collect();
+    Some(format!(
+        "model '{}' is not listed by {}; available: {}",
+        model,
+        base_url,
+        ids.join(", ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn model(id: &str) -> ModelInfo {
+        ModelInfo {
+            id: id.to_string(),
+            owned_by: Some("deepseek".to_string()),
+            created: None,
+        }
+    }
+
+    #[test]
+    fn lists_parse_with_or_without_dates() {
+        let models = parse_list(json!({
+            "object": "list",
+            "data": [
+                { "id": "deepseek-reasoner", "object": "model", "owned_by": "deepseek" },
+                { "id": "deepseek-chat", "object": "model", "owned_by": "deepseek", "created": 1700000000 }
+            ]
+        }))
+        .unwrap();
+        assert_eq!(models[0].id, "deepseek-chat");
+        assert_eq!(
+            models[0].created_date().unwrap().date_naive().to_string(),
+            "2023-11-14"
+        );
+        assert_eq!(models[1].created_date(), None);
+        assert!(parse_list(json!({ "models": [] })).is_err());
+    }
+
+    #[test]
+    fn cached_lists_expire_after_an_hour() {
+        let url = "https://api.deepseek.com/v1";
+        let fetched = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut cache = ModelCache::default();
+        cache.insert(url, fetched, vec![model("deepseek-chat")]);
+
+        let later = |secs| fetched + chrono::Duration::seconds(secs);
+        assert_eq!(cache.fresh(url, later(59 * 60)).unwrap().len(), 1);
+        assert!(cache.fresh(url, later(60 * 60)).is_none());
+        assert!(cache.fresh("http://localhost:11434/v1", later(0)).is_none());
+        // a clock that went backwards doesn't make a list fresh forever
+        assert!(cache.fresh(url, later(-10)).is_none());
+    }
+
+    #[test]
+    fn caches_round_trip_and_bad_ones_are_empty() {
+        let dir = std::env::temp_dir().join(format!("deepseek_models_{}", std::process::id()));
+        let path = dir.join("nested").join("models.json");
+        let mut cache = ModelCache::default();
+        cache.insert(
+            "https://api.deepseek.com/v1",
+            Utc::now(),
+            vec![model("deepseek-chat")],
+        );
+        cache.save(&path).unwrap();
+        assert_eq!(ModelCache::load(&path), cache);
+
+        std::fs::write(&path, "{ not json").unwrap();
+        assert_eq!(ModelCache::load(&path), ModelCache::default());
+        assert_eq!(
+            ModelCache::load(&dir.join("missing.json")),
+            ModelCache::default()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_models_are_warned_about() {
+        let models = [model("deepseek-chat"), model("deepseek-reasoner")];
+        let url = "https://api.deepseek.com/v1";
+        assert_eq!(unknown_model_warning(&models, "deepseek-chat", url), None);
+        let warning = unknown_model_warning(&models, "deepseek-chatt", url).unwrap();
+        assert_eq!(
+            warning,
+            "model 'deepseek-chatt' is not listed by https://api.deepseek.com/v1; available: deepseek-chat, deepseek-reasoner"
+        );
+        // nothing to compare against
+        assert_eq!(unknown_model_warning(&[], "anything", url), None);
+    }
+
+    #[test]
+    fn cache_path_follows_xdg() {
+        assert_eq!(
+            default_cache_path(Some("/xdg"), Some("/home/me")),
+            Some(PathBuf::from("/xdg/deepseek_agent/models.json"))
+        );
+        assert_eq!(
+            default_cache_path(Some("relative"), Some("/home/me")),
+            Some(PathBuf::from("/home/me/.cache/deepseek_agent/models.json"))
+        );
+        assert_eq!(default_cache_path(None, None), None);
+    }
+}
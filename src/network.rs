@@ -0,0 +1,225 @@
+//! How requests get to the endpoint: through a proxy, trusting extra root
+//! certificates, or, for self-signed dev endpoints, not checking them at all.
+//!
+//! Everything here is checked before the first request, so a typo in a proxy
+//! URL or a CA bundle that isn't PEM is a config error naming the flag rather
+//! than a connection failure later on.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use reqwest::{Certificate, NoProxy, Proxy};
+use tokio::net::TcpStream;
+use tracing::debug;
+use url::Url;
+
+use crate::error::AgentError;
+
+/// Longest wait for the proxy to accept a connection when checking it.
+const PROXY_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Proxy and TLS settings for the HTTP client.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetworkConfig {
+    /// An `http://` or `https://` proxy every request goes through, except to
+    /// hosts in `NO_PROXY`.
+    pub proxy: Option<String>,
+    /// A PEM file of root certificates trusted on top of the system ones.
+    pub ca_bundle: Option<PathBuf>,
+    /// Accept any certificate, including self-signed and expired ones.
+    pub insecure: bool,
+}
+
+impl NetworkConfig {
+    /// An HTTP client that sends requests the way this config says.
+    pub fn client(&self) -> Result<reqwest::Client, AgentError> {
+        self.client_with(reqwest::Client::builder())
+    }
+
+    /// `builder`'s client, sending requests the way this config says.
+    pub fn client_with(
+        &self,
+        mut builder: reqwest::ClientBuilder,
+    ) -> Result<reqwest::Client, AgentError> {
+        if let Some(proxy) = &self.proxy {
+            let url = proxy_url(proxy)?;
+            let proxy = Proxy::all(url.as_str())
+                .map_err(|e| invalid_proxy(proxy, e))?
+                .no_proxy(NoProxy::from_env());
+            builder = builder.proxy(proxy);
+        }
+        if let Some(path) = &self.ca_bundle {
+            for certificate in load_certificates(path)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if self.insecure {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        builder.build().map_err(|e| match &self.ca_bundle {
+            // certificates are only parsed once the client is built
+            Some(path) => AgentError::InvalidConfig(format!(
+                "{} has a certificate that can't be used: {}",
+                path.display(),
+                e
+            )),
+            None => AgentError::InvalidConfig(format!("could not set up the HTTP client: {}", e)),
+        })
+    }
+
+    /// Fail unless the proxy, if there is one, accepts a connection.
+    pub async fn check_proxy(&self) -> Result<(), AgentError> {
+        let Some(proxy) = &self.proxy else {
+            return Ok(());
+        };
+        let url = proxy_url(proxy)?;
+        let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+            return Err(invalid_proxy(proxy, "no host"));
+        };
+        debug!(host, port, "checking the proxy");
+        match tokio::time::timeout(PROXY_CONNECT_TIMEOUT, TcpStream::connect((host, port))).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(AgentError::InvalidConfig(format!(
+                "could not reach the proxy at {}:{}: {}",
+                host, port, e
+            ))),
+            Err(_) => Err(AgentError::InvalidConfig(format!(
+                "could not reach the proxy at {}:{}: no answer in {}s",
+                host,
+                port,
+                PROXY_CONNECT_TIMEOUT.as_secs()
+            ))),
+        }
+    }
+}
+
+/// The root certificates in the PEM file at `path`, which must have at least one.
+pub fn load_certificates(path: &Path) -> Result<Vec<Certificate>, AgentError> {
+    let pem = std::fs::read(path).map_err(|e| {
+        AgentError::InvalidConfig(format!("could not read {}: {}", path.display(), e))
+    })?;
+    let certificates = Certificate::from_pem_bundle(&pem).map_err(|e| {
+        AgentError::InvalidConfig(format!("{} is not a PEM file: {}", path.display(), e))
+    })?;
+    if certificates.is_empty() {
+        return Err(AgentError::InvalidConfig(format!(
+            "{} has no PEM certificates in it",
+            path.display()
+        )));
+    }
+    debug!(path = %path.display(), count = certificates.len(), "loaded root certificates");
+    Ok(certificates)
+}
+
+fn proxy_url(proxy: &str) -> Result<Url, AgentError> {
+    let url = Url::parse(proxy).map_err(|e| invalid_proxy(proxy, e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid_proxy(
+            proxy,
+            "it must start with http:// or https://",
+        ));
+    }
+    Ok(url)
+}
+
+fn invalid_proxy(proxy: &str, reason: impl std::fmt::Display) -> AgentError {
+    AgentError::InvalidConfig(format!("invalid proxy '{}': {}", proxy, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CA: &str = include_str!("../tests/fixtures/ca.pem");
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("deepseek_network_{}_{}", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn clients_are_built_with_a_proxy_and_extra_roots() {
+        assert!(NetworkConfig::default().client().is_ok());
+
+        let path = temp_file("ca", CA);
+        assert_eq!(load_certificates(&path).unwrap().len(), 1);
+        let config = NetworkConfig {
+            proxy: Some("http://proxy.internal:3128".to_string()),
+            ca_bundle: Some(path.clone()),
+            insecure: true,
+        };
+        assert!(config.client().is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn bad_ca_bundles_are_config_errors() {
+        let missing = Path::new("/no/such/ca.pem");
+        let err = load_certificates(missing).unwrap_err().to_string();
+        assert!(err.contains("could not read /no/such/ca.pem"), "{err}");
+
+        let empty = temp_file("empty", "just some text\n");
+        let err = load_certificates(&empty).unwrap_err().to_string();
+        assert!(err.contains("has no PEM certificates"), "{err}");
+
+        // the right markers around something that isn't base64
+        let broken = temp_file(
+            "broken",
+            "-----BEGIN CERTIFICATE-----\nnot*base64\n-----END CERTIFICATE-----\n",
+        );
+        let err = load_certificates(&broken).unwrap_err().to_string();
+        assert!(err.contains("is not a PEM file"), "{err}");
+
+        // valid PEM, but not a certificate inside
+        let garbage = temp_file(
+            "garbage",
+            "-----BEGIN CERTIFICATE-----\naGVsbG8=\n-----END CERTIFICATE-----\n",
+        );
+        let config = NetworkConfig {
+            ca_bundle: Some(garbage.clone()),
+            ..NetworkConfig::default()
+        };
+        let err = config.client().unwrap_err();
+        assert!(matches!(err, AgentError::InvalidConfig(_)), "{err:?}");
+        assert!(err.to_string().contains("can't be used"), "{err}");
+
+        for path in [empty, broken, garbage] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn bad_proxy_urls_are_config_errors() {
+        for proxy in [
+            "proxy.internal:3128",
+            "socks5://proxy.internal:1080",
+            "http://",
+        ] {
+            let config = NetworkConfig {
+                proxy: Some(proxy.to_string()),
+                ..NetworkConfig::default()
+            };
+            let err = config.client().unwrap_err().to_string();
+            assert!(err.contains("invalid proxy"), "{proxy}: {err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn proxies_are_checked_for_a_listener() {
+        assert!(NetworkConfig::default().check_proxy().await.is_ok());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = NetworkConfig {
+            proxy: Some(format!("http://127.0.0.1:{}", port)),
+            ..NetworkConfig::default()
+        };
+        assert!(config.check_proxy().await.is_ok());
+
+        drop(listener);
+        let err = config.check_proxy().await.unwrap_err().to_string();
+        assert!(err.contains("could not reach the proxy"), "{err}");
+    }
+}
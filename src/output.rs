@@ -0,0 +1,344 @@
+//! Machine-readable replies for `--output json` and `--output jsonl`.
+//!
+//! In these modes stdout carries nothing but JSON, one object per line;
+//! everything meant for people goes to stderr.
+
+use std::io::{self, Write};
+use std::time::Duration;
+
+use async_openai::types::FinishReason;
+use deepseek_tutor::agent::ModelAnswer;
+use deepseek_tutor::finish::{self, ReplyIssue};
+use deepseek_tutor::metrics::RequestMetrics;
+use deepseek_tutor::stream::Delta;
+use deepseek_tutor::tools::ToolExecution;
+use deepseek_tutor::usage::TurnUsage;
+use deepseek_tutor::{AgentError, DeepSeekAgent};
+use serde_json::{Value, json};
+
+/// Everything a script needs to know about one answered prompt.
+#[derive(Debug)]
+pub struct Summary<'a> {
+    pub model: &'a str,
+    pub content: &'a str,
+    /// What the model reasoned before answering, unless hidden.
+    pub reasoning: Option<&'a str>,
+    pub finish_reason: Option<FinishReason>,
+    /// Why the reply may not be the whole answer, if it may not.
+    pub issue: Option<ReplyIssue>,
+    pub usage: Option<&'a TurnUsage>,
+    pub elapsed: Duration,
+    pub tool_calls: &'a [ToolExecution],
+    /// Whether the reply came from the response cache.
+    pub cached: bool,
+    /// How the reply was received, unless it was cached.
+    pub metrics: Option<&'a RequestMetrics>,
+}
+
+impl<'a> Summary<'a> {
+    /// The last reply `agent` gave, which read `content`.
+    pub fn of(agent: &'a DeepSeekAgent, content: &'a str, elapsed: Duration) -> Self {
+        Self {
+            model: agent.model(),
+            content,
+            reasoning: agent.last_reasoning(),
+            finish_reason: agent.last_finish_reason(),
+            issue: agent.last_issue(),
+            usage: agent.last_usage(),
+            elapsed,
+            tool_calls: agent.last_tool_calls(),
+            cached: agent.last_reply_cached(),
+            metrics: agent.last_metrics(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let usage = self.usage.map(usage_json);
+        let tool_calls: Vec<Value> = self
+            .tool_calls
+            .iter()
+            .map(|call| {
+                json!({
+                    "name": call.name,
+                    "arguments": call.arguments,
+                    "output": call.output,
+                })
+            })
+            .collect();
+        json!({
+            "model": self.model,
+            "content": self.content,
+            "reasoning": self.reasoning,
+            "finish_reason": self.finish_reason,
+            "issue": self.issue.as_ref().map(ReplyIssue::as_str),
+            "usage": usage,
+            "elapsed_ms": self.elapsed.as_millis() as u64,
+            "tool_calls": tool_calls,
+            "cached": self.cached,
+            "metrics": self.metrics.map(RequestMetrics::to_json),
+        })
+    }
+}
+
+fn usage_json(turn: &TurnUsage) -> Value {
+    json!({
+        "prompt_tokens": turn.usage.prompt_tokens,
+        "completion_tokens": turn.usage.completion_tokens,
+        "total_tokens": turn.usage.total_tokens(),
+        "cost": turn.cost,
+    })
+}
+
+/// One object per model asked with `--models`, in the order given: its
+/// reply like a [`Summary`], or the error it failed with.
+pub fn answers(answers: &[ModelAnswer], show_reasoning: bool) -> Vec<Value> {
+    answers
+        .iter()
+        .map(|answer| {
+            let mut value = match &answer.reply {
+                Ok(reply) => json!({
+                    "content": reply.content,
+                    "reasoning": reply.reasoning.as_ref().filter(|_| show_reasoning),
+                    "finish_reason": reply.finish_reason,
+                    "issue": finish::classify(reply).as_ref().map(ReplyIssue::as_str),
+                    "usage": answer.usage.as_ref().map(usage_json),
+                    "cached": reply.cached,
+                    "metrics": reply.metrics.as_ref().map(RequestMetrics::to_json),
+                }),
+                Err(e) => error(e),
+            };
+            value["model"] = answer.model.as_str().into();
+            value["elapsed_ms"] = (answer.latency.as_millis() as u64).into();
+            value
+        })
+        .collect()
+}
+
+/// A `jsonl` line for a piece of streamed text, tagged `reasoning` or `delta`.
+pub fn delta(delta: &Delta) -> Value {
+    match delta {
+        Delta::Reasoning(text) => json!({ "type": "reasoning", "content": text }),
+        Delta::Content(text) => json!({ "type": "delta", "content": text }),
+    }
+}
+
+/// The `jsonl` line that ends a streamed reply: the summary, tagged.
+pub fn done(summary: &Summary) -> Value {
+    let mut value = summary.to_json();
+    value["type"] = "done".into();
+    value
+}
+
+/// The line for a prompt that failed: what kind of failure it was, the
+/// message shown to people, and the API's own fields when it sent them.
+pub fn error(error: &AgentError) -> Value {
+    let mut value = json!({
+        "error": {
+            "kind": error.kind(),
+            "message": error.to_string(),
+        }
+    });
+    if let Some(failure) = error.api_failure() {
+        value["error"]["api_message"] = failure.message.as_str().into();
+        value["error"]["code"] = json!(failure.code);
+        value["error"]["type"] = json!(failure.r#type);
+    }
+    value
+}
+
+/// Write `value` as a single line and flush, so readers see it at once.
+pub fn write_line(out: &mut impl Write, value: &Value) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, value)?;
+    writeln!(out)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deepseek_tutor::error::{ApiFailure, FailureKind};
+    use deepseek_tutor::usage::Usage;
+
+    fn usage() -> TurnUsage {
+        TurnUsage {
+            model: "deepseek-chat".to_string(),
+            usage: Usage {
+                prompt_tokens: 100,
+                completion_tokens: 50,
+            },
+            cost: Some(0.000049),
+        }
+    }
+
+    fn summary<'a>(usage: Option<&'a TurnUsage>, tool_calls: &'a [ToolExecution]) -> Summary<'a> {
+        Summary {
+            model: "deepseek-chat",
+            content: "42, \"quoted\"\nand a newline",
+            reasoning: None,
+            finish_reason: Some(FinishReason::Stop),
+            issue: None,
+            usage,
+            elapsed: Duration::from_millis(1234),
+            tool_calls,
+            cached: false,
+            metrics: None,
+        }
+    }
+
+    #[test]
+    fn json_output_parses_back() {
+        let usage = usage();
+        let calls = [ToolExecution {
+            name: "calculator".to_string(),
+            arguments: r#"{"expression":"6*7"}"#.to_string(),
+            output: "42".to_string(),
+        }];
+        let metrics = RequestMetrics {
+            latency: Duration::from_millis(1200),
+            time_to_first_token: Some(Duration::from_millis(200)),
+            completion_tokens: Some(50),
+            retries: 1,
+            requests: 2,
+        };
+        let mut out = Vec::new();
+        let summary = Summary {
+            metrics: Some(&metrics),
+            ..summary(Some(&usage), &calls)
+        };
+        write_line(&mut out, &summary.to_json()).unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text.lines().count(), 1);
+        let parsed: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(parsed["model"], "deepseek-chat");
+        assert_eq!(parsed["content"], "42, \"quoted\"\nand a newline");
+        assert_eq!(parsed["finish_reason"], "stop");
+        assert_eq!(parsed["usage"]["total_tokens"], 150);
+        assert_eq!(parsed["usage"]["cost"], 0.000049);
+        assert_eq!(parsed["elapsed_ms"], 1234);
+        assert_eq!(parsed["tool_calls"][0]["name"], "calculator");
+        assert_eq!(parsed["tool_calls"][0]["output"], "42");
+        assert_eq!(parsed["metrics"]["time_to_first_token_ms"], 200);
+        assert_eq!(parsed["metrics"]["tokens_per_second"], 50.0);
+        assert_eq!(parsed["metrics"]["retries"], 1);
+        assert_eq!(parsed["metrics"]["requests"], 2);
+    }
+
+    #[test]
+    fn unreported_usage_is_null() {
+        let parsed = summary(None, &[]).to_json();
+        assert!(parsed["usage"].is_null());
+        assert!(parsed["reasoning"].is_null());
+        assert!(parsed["metrics"].is_null());
+        assert!(parsed["issue"].is_null());
+        assert_eq!(parsed["tool_calls"], json!([]));
+
+        let truncated = Summary {
+            finish_reason: Some(FinishReason::Length),
+            issue: Some(ReplyIssue::Truncated),
+            ..summary(None, &[])
+        };
+        assert_eq!(truncated.to_json()["issue"], "truncated");
+    }
+
+    #[test]
+    fn errors_name_their_kind() {
+        let quota = AgentError::Quota(ApiFailure {
+            kind: FailureKind::Quota,
+            message: "Insufficient Balance".to_string(),
+            code: Some("invalid_request_error".to_string()),
+            r#type: None,
+        });
+        assert_eq!(
+            error(&quota),
+            json!({
+                "error": {
+                    "kind": "quota",
+                    "message": quota.to_string(),
+                    "api_message": "Insufficient Balance",
+                    "code": "invalid_request_error",
+                    "type": null,
+                }
+            })
+        );
+
+        let timeout = AgentError::Timeout(Duration::from_secs(120));
+        let parsed = error(&timeout);
+        assert_eq!(parsed["error"]["kind"], "timeout");
+        assert!(parsed["error"].get("api_message").is_none());
+    }
+
+    #[test]
+    fn answers_keep_each_model_and_its_failure() {
+        use deepseek_tutor::chat::Reply;
+        let answers = [
+            ModelAnswer {
+                model: "deepseek-chat".to_string(),
+                reply: Ok(Reply {
+                    content: "Hi.".to_string(),
+                    reasoning: Some("hidden".to_string()),
+                    finish_reason: Some(FinishReason::Stop),
+                    ..Reply::default()
+                }),
+                usage: Some(usage()),
+                latency: Duration::from_millis(800),
+            },
+            ModelAnswer {
+                model: "deepseek-chat-filtered".to_string(),
+                reply: Ok(Reply {
+                    finish_reason: Some(FinishReason::ContentFilter),
+                    ..Reply::default()
+                }),
+                usage: None,
+                latency: Duration::from_millis(300),
+            },
+            ModelAnswer {
+                model: "deepseek-reasoner".to_string(),
+                reply: Err(AgentError::Timeout(Duration::from_secs(120))),
+                usage: None,
+                latency: Duration::from_secs(120),
+            },
+        ];
+        let values = self::answers(&answers, false);
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[0]["model"], "deepseek-chat");
+        assert_eq!(values[0]["content"], "Hi.");
+        assert!(values[0]["reasoning"].is_null());
+        assert_eq!(values[0]["usage"]["total_tokens"], 150);
+        assert_eq!(values[0]["elapsed_ms"], 800);
+        assert!(values[0].get("error").is_none());
+        assert!(values[0]["issue"].is_null());
+        assert_eq!(values[1]["issue"], "content_filter");
+        assert_eq!(values[2]["model"], "deepseek-reasoner");
+        assert_eq!(values[2]["error"]["kind"], "timeout");
+        assert_eq!(values[2]["elapsed_ms"], 120_000);
+        assert!(values[2].get("content").is_none());
+
+        assert_eq!(self::answers(&answers, true)[0]["reasoning"], "hidden");
+    }
+
+    #[test]
+    fn jsonl_output_is_one_object_per_line() {
+        let mut out = Vec::new();
+        for piece in [
+            Delta::Reasoning("6*7".into()),
+            Delta::Content("4".into()),
+            Delta::Content("2\n".into()),
+        ] {
+            write_line(&mut out, &delta(&piece)).unwrap();
+        }
+        write_line(&mut out, &done(&summary(None, &[]))).unwrap();
+
+        let lines: Vec<Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], json!({ "type": "reasoning", "content": "6*7" }));
+        assert_eq!(lines[1], json!({ "type": "delta", "content": "4" }));
+        assert_eq!(lines[2]["content"], "2\n");
+        assert_eq!(lines[3]["type"], "done");
+        assert_eq!(lines[3]["finish_reason"], "stop");
+    }
+}
@@ -0,0 +1,335 @@
+//! Who serves the API: DeepSeek, OpenAI, an Azure OpenAI resource or any
+//! other OpenAI-compatible server.
+//!
+//! They all take the same chat requests but differ in where they live, how
+//! the key is sent and what they charge. Azure also names deployments rather
+//! than models in the URL and wants an `api-version` on every request, so
+//! there a request's model is taken as the name of its deployment.
+
+use std::fmt;
+
+use async_openai::config::{AzureConfig, Config, OPENAI_API_BASE, OpenAIConfig};
+use reqwest::header::HeaderMap;
+use secrecy::SecretString;
+use serde::Deserialize;
+
+use crate::config::{DEFAULT_BASE_URL, normalize_base_url};
+use crate::embeddings::DEFAULT_EMBEDDING_MODEL;
+use crate::error::AgentError;
+use crate::usage::{ModelPrice, PriceTable};
+
+/// The `api-version` sent to Azure unless another is given.
+pub const DEFAULT_AZURE_API_VERSION: &str = "2024-10-21";
+
+/// A provider as named in flags and the config file, before the settings it
+/// needs are filled in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    #[default]
+    DeepSeek,
+    OpenAI,
+    Azure,
+    Custom,
+}
+
+impl std::str::FromStr for ProviderKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "deepseek" => Ok(Self::DeepSeek),
+            "openai" => Ok(Self::OpenAI),
+            "azure" => Ok(Self::Azure),
+            "custom" => Ok(Self::Custom),
+            _ => Err(format!(
+                "unknown provider '{}', expected deepseek, openai, azure or custom",
+                name
+            )),
+        }
+    }
+}
+
+/// Where requests go, and how.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Provider {
+    #[default]
+    DeepSeek,
+    OpenAI,
+    /// An Azure OpenAI resource, whose deployments are named by the model.
+    AzureOpenAI {
+        api_version: String,
+    },
+    /// Any other server speaking the OpenAI API. Its prices are unknown.
+    Custom {
+        base_url: String,
+    },
+}
+
+impl Provider {
+    /// The provider `kind` names. Custom servers need `base_url`; Azure
+    /// takes `api_version`, defaulting to [`DEFAULT_AZURE_API_VERSION`].
+    pub fn resolve(
+        kind: ProviderKind,
+        base_url: Option<&str>,
+        api_version: Option<&str>,
+    ) -> Result<Self, AgentError> {
+        let base_url = base_url.map(str::trim).filter(|url| !url.is_empty());
+        Ok(match kind {
+            ProviderKind::DeepSeek => Self::DeepSeek,
+            ProviderKind::OpenAI => Self::OpenAI,
+            ProviderKind::Azure => Self::AzureOpenAI {
+                api_version: api_version
+                    .map(str::trim)
+                    .filter(|version| !version.is_empty())
+                    .unwrap_or(DEFAULT_AZURE_API_VERSION)
+                    .to_string(),
+            },
+            ProviderKind::Custom => match base_url {
+                Some(url) => Self::Custom {
+                    base_url: normalize_base_url(url)?,
+                },
+                None => {
+                    return Err(AgentError::InvalidConfig(
+                        "the custom provider needs a base URL (--base-url or BASE_URL)".into(),
+                    ));
+                }
+            },
+        })
+    }
+
+    /// Where requests go when no base URL is given. Azure has none, as every
+    /// resource has its own.
+    pub fn default_base_url(&self) -> Option<&str> {
+        match self {
+            Self::DeepSeek => Some(DEFAULT_BASE_URL),
+            Self::OpenAI => Some(OPENAI_API_BASE),
+            Self::AzureOpenAI { .. } => None,
+            Self::Custom { base_url } => Some(base_url),
+        }
+    }
+
+    /// `base_url` if given, normalized, or the provider's default.
+    pub fn base_url(&self, base_url: Option<&str>) -> Result<String, AgentError> {
+        match base_url.map(str::trim) {
+            Some(url) if !url.is_empty() => normalize_base_url(url),
+            _ => self.default_base_url().map(str::to_string).ok_or_else(|| {
+                AgentError::InvalidConfig(
+                    "Azure OpenAI needs the resource's base URL, like \
+                     https://RESOURCE.openai.azure.com (--base-url or BASE_URL)"
+                        .into(),
+                )
+            }),
+        }
+    }
+
+    /// The header the API key goes in.
+    pub fn auth_header(&self) -> &'static str {
+        match self {
+            Self::AzureOpenAI { .. } => "api-key",
+            _ => "Authorization",
+        }
+    }
+
+    /// List prices, with every prompt token billed as a cache miss. Azure's
+    /// are taken to be OpenAI's, under the model names used as deployments.
+    pub fn prices(&self) -> PriceTable {
+        match self {
+            Self::DeepSeek => PriceTable::default(),
+            Self::OpenAI | Self::AzureOpenAI { .. } => openai_prices(),
+            Self::Custom { .. } => PriceTable::empty(),
+        }
+    }
+
+    /// The client config for requests to `model` at `base_url`, which is
+    /// already normalized.
+    pub fn client_config(&self, api_key: &str, base_url: &str, model: &str) -> ProviderConfig {
+        match self {
+            Self::AzureOpenAI { api_version } => ProviderConfig::Azure(
+                AzureConfig::new()
+                    .with_api_key(api_key)
+                    .with_api_base(base_url)
+                    .with_api_version(api_version)
+                    .with_deployment_id(model),
+            ),
+            _ => ProviderConfig::OpenAI(
+                OpenAIConfig::new()
+                    .with_api_key(api_key)
+                    .with_api_base(base_url),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DeepSeek => f.write_str("DeepSeek"),
+            Self::OpenAI => f.write_str("OpenAI"),
+            Self::AzureOpenAI { api_version } => {
+                write!(f, "Azure OpenAI (api-version {})", api_version)
+            }
+            Self::Custom { base_url } => write!(f, "custom ({})", base_url),
+        }
+    }
+}
+
+fn openai_prices() -> PriceTable {
+    let mut table = PriceTable::empty();
+    for (model, input, output) in [
+        ("gpt-4o", 2.50, 10.00),
+        ("gpt-4o-mini", 0.15, 0.60),
+        ("gpt-4.1", 2.00, 8.00),
+        ("gpt-4.1-mini", 0.40, 1.60),
+        ("gpt-4.1-nano", 0.10, 0.40),
+        ("o4-mini", 1.10, 4.40),
+        (DEFAULT_EMBEDDING_MODEL, 0.02, 0.0),
+    ] {
+        table.set(
+            model,
+            ModelPrice {
+                input_per_million: input,
+                output_per_million: output,
+            },
+        );
+    }
+    table
+}
+
+/// The client config of either kind, so one client type serves them all.
+#[derive(Debug, Clone)]
+pub enum ProviderConfig {
+    OpenAI(OpenAIConfig),
+    Azure(AzureConfig),
+}
+
+impl Config for ProviderConfig {
+    fn headers(&self) -> HeaderMap {
+        match self {
+            Self::OpenAI(config) => config.headers(),
+            Self::Azure(config) => config.headers(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        match self {
+            Self::OpenAI(config) => config.url(path),
+            Self::Azure(config) => config.url(path),
+        }
+    }
+
+    fn query(&self) -> Vec<(&str, &str)> {
+        match self {
+            Self::OpenAI(config) => config.query(),
+            Self::Azure(config) => config.query(),
+        }
+    }
+
+    fn api_base(&self) -> &str {
+        match self {
+            Self::OpenAI(config) => config.api_base(),
+            Self::Azure(config) => config.api_base(),
+        }
+    }
+
+    fn api_key(&self) -> &SecretString {
+        match self {
+            Self::OpenAI(config) => config.api_key(),
+            Self::Azure(config) => config.api_key(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::AUTHORIZATION;
+
+    fn azure() -> Provider {
+        Provider::resolve(ProviderKind::Azure, None, None).unwrap()
+    }
+
+    #[test]
+    fn providers_are_named_in_lowercase() {
+        assert_eq!("openai".parse(), Ok(ProviderKind::OpenAI));
+        assert_eq!("azure".parse(), Ok(ProviderKind::Azure));
+        assert!("Azure".parse::<ProviderKind>().is_err());
+        let kind: ProviderKind = serde_json::from_str("\"deepseek\"").unwrap();
+        assert_eq!(kind, ProviderKind::DeepSeek);
+    }
+
+    #[test]
+    fn each_provider_has_its_own_default_base_url() {
+        assert_eq!(Provider::DeepSeek.base_url(None).unwrap(), DEFAULT_BASE_URL);
+        assert_eq!(
+            Provider::OpenAI.base_url(None).unwrap(),
+            "https://api.openai.com/v1"
+        );
+        let err = azure().base_url(Some(" ")).unwrap_err().to_string();
+        assert!(err.contains("RESOURCE.openai.azure.com"), "{err}");
+        assert_eq!(
+            azure()
+                .base_url(Some("https://me.openai.azure.com/"))
+                .unwrap(),
+            "https://me.openai.azure.com"
+        );
+
+        let custom = Provider::resolve(
+            ProviderKind::Custom,
+            Some("http://localhost:11434/v1/"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(custom.base_url(None).unwrap(), "http://localhost:11434/v1");
+        assert!(Provider::resolve(ProviderKind::Custom, None, None).is_err());
+    }
+
+    #[test]
+    fn openai_style_providers_put_the_model_in_the_body() {
+        let config = Provider::OpenAI.client_config("sk-test", OPENAI_API_BASE, "gpt-4o");
+        assert_eq!(
+            config.url("/chat/completions"),
+            "https://api.openai.com/v1/chat/completions"
+        );
+        assert!(config.query().is_empty());
+        let headers = config.headers();
+        assert_eq!(headers[AUTHORIZATION], "Bearer sk-test");
+        assert!(headers.get("api-key").is_none());
+        assert_eq!(Provider::DeepSeek.auth_header(), "Authorization");
+    }
+
+    #[test]
+    fn azure_puts_the_deployment_in_the_url() {
+        let provider = Provider::resolve(ProviderKind::Azure, None, Some("2025-01-01")).unwrap();
+        let config = provider.client_config("key", "https://me.openai.azure.com", "gpt-4o");
+        assert_eq!(
+            config.url("/chat/completions"),
+            "https://me.openai.azure.com/openai/deployments/gpt-4o/chat/completions"
+        );
+        assert_eq!(config.query(), [("api-version", "2025-01-01")]);
+        let headers = config.headers();
+        assert_eq!(headers["api-key"], "key");
+        assert!(headers.get(AUTHORIZATION).is_none());
+        assert_eq!(provider.auth_header(), "api-key");
+
+        assert_eq!(
+            azure(),
+            Provider::AzureOpenAI {
+                api_version: DEFAULT_AZURE_API_VERSION.to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn prices_follow_the_provider() {
+        assert!(Provider::DeepSeek.prices().get("deepseek-chat").is_some());
+        assert!(Provider::DeepSeek.prices().get("gpt-4o").is_none());
+        let openai = Provider::OpenAI.prices();
+        assert_eq!(openai.get("gpt-4o-mini").unwrap().input_per_million, 0.15);
+        assert_eq!(azure().prices(), openai);
+        let custom = Provider::Custom {
+            base_url: "http://localhost:11434/v1".into(),
+        };
+        assert_eq!(custom.prices(), PriceTable::empty());
+    }
+}
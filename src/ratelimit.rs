@@ -0,0 +1,235 @@
+//! A client-side limit on requests and tokens a minute, so batches and tool
+//! loops wait their turn instead of running into the provider's 429s and
+//! spending their retries on them.
+//!
+//! Both limits are token buckets that start full and refill continuously, so
+//! a minute's worth can go at once and the rest follow at the steady rate.
+//! Every agent made from a config shares its limiter, batch items included.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::debug;
+
+/// Requests and tokens allowed a minute; `None` is no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+}
+
+/// A shared pair of buckets. Clones share them.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    state: Arc<Mutex<Buckets>>,
+}
+
+/// What is left in each bucket, which may be negative after replies that
+/// used more tokens than were taken for them.
+#[derive(Debug)]
+struct Buckets {
+    requests: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl PartialEq for RateLimiter {
+    /// Limiters are equal if they share buckets.
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            state: Arc::new(Mutex::new(Buckets {
+                requests: limits.requests_per_minute.map_or(0.0, f64::from),
+                tokens: limits.tokens_per_minute.map_or(0.0, f64::from),
+                updated: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn limits(&self) -> RateLimits {
+        self.limits
+    }
+
+    /// Wait until a request estimated at `tokens` fits under both limits,
+    /// then take it from them. Returns how long that took.
+    ///
+    /// Waiters are served in order: the lock is held while waiting, so a
+    /// later request can't take what an earlier one is waiting for.
+    pub async fn acquire(&self, tokens: u64) -> Duration {
+        let mut buckets = self.state.lock().await;
+        buckets.refill(self.limits, Instant::now());
+        let wait = buckets.wait_for(self.limits, tokens);
+        if !wait.is_zero() {
+            debug!(
+                wait_ms = wait.as_millis() as u64,
+                tokens, "waiting for the rate limit"
+            );
+            tokio::time::sleep(wait).await;
+            buckets.refill(self.limits, Instant::now());
+        }
+        buckets.requests -= 1.0;
+        buckets.tokens -= tokens as f64;
+        wait
+    }
+
+    /// Take `tokens` more, used by a request beyond what was acquired for it,
+    /// like the tokens of its reply. Later requests wait for them.
+    pub async fn spend(&self, tokens: u64) {
+        if tokens > 0 && self.limits.tokens_per_minute.is_some() {
+            self.state.lock().await.tokens -= tokens as f64;
+        }
+    }
+}
+
+impl Buckets {
+    fn refill(&mut self, limits: RateLimits, now: Instant) {
+        let minutes = (now - self.updated).as_secs_f64() / 60.0;
+        self.updated = now;
+        if let Some(rpm) = limits.requests_per_minute {
+            self.requests = (self.requests + minutes * f64::from(rpm)).min(f64::from(rpm));
+        }
+        if let Some(tpm) = limits.tokens_per_minute {
+            self.tokens = (self.tokens + minutes * f64::from(tpm)).min(f64::from(tpm));
+        }
+    }
+
+    /// How long until a request of `tokens` fits. One bigger than a whole
+    /// minute's tokens waits for a full bucket, not forever.
+    fn wait_for(&self, limits: RateLimits, tokens: u64) -> Duration {
+        let wait = |available: f64, needed: f64, per_minute: u32| {
+            let needed = needed.min(f64::from(per_minute));
+            let short = needed - available;
+            if short <= 0.0 {
+                0.0
+            } else {
+                short / f64::from(per_minute) * 60.0
+            }
+        };
+        let requests = limits
+            .requests_per_minute
+            .map_or(0.0, |rpm| wait(self.requests, 1.0, rpm));
+        let tokens = limits
+            .tokens_per_minute
+            .map_or(0.0, |tpm| wait(self.tokens, tokens as f64, tpm));
+        Duration::from_secs_f64(requests.max(tokens))
+    }
+}
+
+/// The prompt tokens of a chat request body, at four characters a token,
+/// for taking from the limit before it is sent.
+pub fn request_tokens(request: &Value) -> u64 {
+    let chars: usize = request["messages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|message| message["content"].as_str())
+        .map(|content| content.chars().count())
+        .sum();
+    chars.div_ceil(4) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn limiter(rpm: Option<u32>, tpm: Option<u32>) -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            requests_per_minute: rpm,
+            tokens_per_minute: tpm,
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_go_at_once_until_the_bucket_is_empty() {
+        let limiter = limiter(Some(2), None);
+        let started = Instant::now();
+        assert_eq!(limiter.acquire(0).await, Duration::ZERO);
+        assert_eq!(limiter.acquire(0).await, Duration::ZERO);
+        // then one every 30 seconds
+        assert_eq!(limiter.acquire(0).await, Duration::from_secs(30));
+        assert_eq!(limiter.acquire(0).await, Duration::from_secs(30));
+        assert_eq!(started.elapsed(), Duration::from_secs(60));
+
+        // a quiet minute fills it again, but no further
+        tokio::time::advance(Duration::from_secs(600)).await;
+        assert_eq!(limiter.acquire(0).await, Duration::ZERO);
+        assert_eq!(limiter.acquire(0).await, Duration::ZERO);
+        assert_eq!(limiter.acquire(0).await, Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tokens_wait_for_what_they_are_short() {
+        let limiter = limiter(None, Some(1_000));
+        assert_eq!(limiter.acquire(800).await, Duration::ZERO);
+        // 200 left, 400 wanted: 200 more at 1000 a minute
+        assert_eq!(limiter.acquire(400).await, Duration::from_secs(12));
+
+        // more than a minute's worth waits for a full bucket
+        let wait = limiter.acquire(5_000).await;
+        assert_eq!(wait, Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tokens_spent_after_the_reply_delay_the_next_request() {
+        let limiter = limiter(Some(100), Some(600));
+        limiter.acquire(100).await;
+        limiter.spend(500).await;
+        // the bucket is empty, and 60 tokens take 6 seconds
+        assert_eq!(limiter.acquire(60).await, Duration::from_secs(6));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_requests_are_paced_in_turn() {
+        let limiter = limiter(Some(2), None);
+        let started = Instant::now();
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move {
+                    limiter.acquire(0).await;
+                    started.elapsed()
+                })
+            })
+            .collect();
+        let mut finished = Vec::new();
+        for task in tasks {
+            finished.push(task.await.unwrap().as_secs());
+        }
+        finished.sort();
+        assert_eq!(finished, [0, 0, 30, 60]);
+    }
+
+    #[tokio::test]
+    async fn no_limits_never_wait() {
+        let limiter = RateLimiter::new(RateLimits::default());
+        for _ in 0..100 {
+            assert_eq!(limiter.acquire(1_000_000).await, Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn request_tokens_count_message_text() {
+        let request = json!({
+            "model": "deepseek-chat",
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "What is a lifetime?" },
+                { "role": "assistant", "content": null, "tool_calls": [] }
+            ]
+        });
+        // 9 + 19 characters
+        assert_eq!(request_tokens(&request), 7);
+        assert_eq!(request_tokens(&json!({})), 0);
+    }
+}
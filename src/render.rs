@@ -0,0 +1,622 @@
+//! Markdown replies as styled terminal text (binary only).
+//!
+//! Code blocks are highlighted, emphasis and headings styled, and lists and
+//! quotes indented. Without colour the structure is kept in plain ASCII, and
+//! when stdout isn't a terminal the markdown goes out untouched.
+
+use std::sync::LazyLock;
+
+use pulldown_cmark::{CodeBlockKind, Event, HeadingLevel, Options, Parser, Tag, TagEnd};
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::{LinesWithEndings, as_24_bit_terminal_escaped};
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+static THEME: LazyLock<Theme> = LazyLock::new(|| {
+    ThemeSet::load_defaults()
+        .themes
+        .remove("base16-ocean.dark")
+        .expect("syntect ships base16-ocean.dark")
+});
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const ITALIC: &str = "\x1b[3m";
+const UNDERLINE: &str = "\x1b[4m";
+const STRIKE: &str = "\x1b[9m";
+const CYAN: &str = "\x1b[36m";
+
+/// How replies are shown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RenderMode {
+    /// The markdown as the model wrote it.
+    #[default]
+    Raw,
+    /// Indented lists and quotes, no escape codes.
+    Plain,
+    /// Colours, highlighting and text styles.
+    Styled,
+}
+
+impl RenderMode {
+    /// Raw with `--plain` or when stdout isn't a terminal; plain when
+    /// `NO_COLOR` is set or the terminal is dumb; styled otherwise.
+    pub fn detect(
+        plain: bool,
+        stdout_is_tty: bool,
+        no_color: Option<&str>,
+        term: Option<&str>,
+    ) -> Self {
+        if plain || !stdout_is_tty {
+            RenderMode::Raw
+        } else if no_color.is_some_and(|value| !value.is_empty()) || term == Some("dumb") {
+            RenderMode::Plain
+        } else {
+            RenderMode::Styled
+        }
+    }
+}
+
+/// `markdown` rendered for the terminal, ending in a newline unless empty.
+pub fn render(markdown: &str, mode: RenderMode) -> String {
+    if mode == RenderMode::Raw {
+        return markdown.to_string();
+    }
+    let mut writer = Writer::new(mode == RenderMode::Styled);
+    let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    for event in Parser::new_ext(markdown, options) {
+        writer.event(event);
+    }
+    writer.finish()
+}
+
+/// Renders a streamed reply a block at a time.
+///
+/// Text is held back until the block it belongs to is complete: a paragraph,
+/// list or the like at the next blank line, a code block at its closing
+/// fence. Raw mode passes text straight through.
+pub struct StreamRenderer {
+    mode: RenderMode,
+    pending: String,
+    /// How far `pending` has been scanned for block ends.
+    scanned: usize,
+    in_fence: bool,
+    /// Where a blank line left `pending`, to cut there unless the next line
+    /// continues the block.
+    blank_at: Option<usize>,
+    blocks: usize,
+}
+
+impl StreamRenderer {
+    pub fn new(mode: RenderMode) -> Self {
+        Self {
+            mode,
+            pending: String::new(),
+            scanned: 0,
+            in_fence: false,
+            blank_at: None,
+            blocks: 0,
+        }
+    }
+
+    /// Add `text`, returning whatever is ready to print.
+    pub fn push(&mut self, text: &str) -> String {
+        if self.mode == RenderMode::Raw {
+            return text.to_string();
+        }
+        self.pending.push_str(text);
+        let mut out = String::new();
+        while let Some(newline) = self.pending[self.scanned..].find('\n') {
+            let start = self.scanned;
+            let end = start + newline + 1;
+            self.scanned = end;
+            let line = &self.pending[start..end];
+            let fence = is_fence(line);
+            let blank = line.trim().is_empty();
+            let indented = line.starts_with([' ', '\t']);
+
+            if self.in_fence {
+                if fence {
+                    self.in_fence = false;
+                    // a fence inside a list item waits for the item to end
+                    if !indented {
+                        out.push_str(&self.flush(end));
+                    }
+                }
+                continue;
+            }
+            if blank {
+                self.blank_at.get_or_insert(end);
+                continue;
+            }
+            // an indented line or another item after a blank one goes on
+            // with the list
+            if let Some(cut) = self.blank_at.take()
+                && !indented
+                && !is_list_item(line)
+            {
+                out.push_str(&self.flush(cut));
+            }
+            self.in_fence = fence;
+        }
+        out
+    }
+
+    /// Everything still held back, rendered.
+    pub fn finish(&mut self) -> String {
+        if self.mode == RenderMode::Raw {
+            return String::new();
+        }
+        let end = self.pending.len();
+        self.flush(end)
+    }
+
+    /// Render and drop the first `end` bytes of `pending`.
+    fn flush(&mut self, end: usize) -> String {
+        let block: String = self.pending.drain(..end).collect();
+        self.scanned -= end.min(self.scanned);
+        if let Some(blank_at) = self.blank_at {
+            self.blank_at = blank_at.checked_sub(end).filter(|&at| at > 0);
+        }
+        let rendered = render(&block, self.mode);
+        if rendered.is_empty() {
+            return rendered;
+        }
+        self.blocks += 1;
+        if self.blocks > 1 {
+            format!("\n{}", rendered)
+        } else {
+            rendered
+        }
+    }
+}
+
+fn is_list_item(line: &str) -> bool {
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    let rest = &line[digits..];
+    if digits > 0 {
+        rest.starts_with(". ") || rest.starts_with(") ")
+    } else {
+        ["- ", "* ", "+ "]
+            .iter()
+            .any(|marker| line.starts_with(marker))
+    }
+}
+
+fn is_fence(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("```") || line.starts_with("~~~")
+}
+
+/// Turns parser events into terminal text.
+struct Writer {
+    styled: bool,
+    out: String,
+    /// Escape codes in effect, re-applied after each reset.
+    styles: Vec<&'static str>,
+    /// Next number of each open list, `None` for bullets.
+    lists: Vec<Option<u64>>,
+    /// What continuation lines start with: quote bars and list indentation.
+    prefix: Vec<String>,
+    at_line_start: bool,
+    /// Right after a list marker, where the item's first block goes.
+    after_marker: bool,
+    code: Option<(String, String)>,
+    link: Option<String>,
+}
+
+impl Writer {
+    fn new(styled: bool) -> Self {
+        Self {
+            styled,
+            out: String::new(),
+            styles: Vec::new(),
+            lists: Vec::new(),
+            prefix: Vec::new(),
+            at_line_start: true,
+            after_marker: false,
+            code: None,
+            link: None,
+        }
+    }
+
+    fn event(&mut self, event: Event) {
+        if let Some((_, code)) = &mut self.code {
+            match event {
+                Event::Text(text) => code.push_str(&text),
+                Event::End(TagEnd::CodeBlock) => self.code_block(),
+                _ => {}
+            }
+            return;
+        }
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) => self.text(&text),
+            Event::Code(code) => {
+                if self.styled {
+                    self.push_style(CYAN);
+                    self.text(&code);
+                    self.pop_style();
+                } else {
+                    self.text(&format!("`{}`", code));
+                }
+            }
+            Event::SoftBreak | Event::HardBreak => self.newline(),
+            Event::Rule => {
+                self.block();
+                let rule = if self.styled {
+                    "─".repeat(40)
+                } else {
+                    "-".repeat(40)
+                };
+                self.text(&rule);
+                self.newline();
+            }
+            Event::Html(html) | Event::InlineHtml(html) => self.text(&html),
+            Event::TaskListMarker(done) => self.text(if done { "[x] " } else { "[ ] " }),
+            _ => {}
+        }
+    }
+
+    fn start(&mut self, tag: Tag) {
+        match tag {
+            Tag::Paragraph => self.block(),
+            Tag::Heading { level, .. } => {
+                self.block();
+                if self.styled {
+                    self.push_style(BOLD);
+                    if level == HeadingLevel::H1 {
+                        self.push_style(UNDERLINE);
+                    }
+                } else {
+                    self.text(&format!("{} ", "#".repeat(level as usize)));
+                }
+            }
+            Tag::BlockQuote(_) => {
+                self.block();
+                let bar = if self.styled {
+                    format!("{}│{} ", DIM, RESET)
+                } else {
+                    "> ".to_string()
+                };
+                self.prefix.push(bar);
+            }
+            Tag::CodeBlock(kind) => {
+                self.block();
+                let lang = match kind {
+                    CodeBlockKind::Fenced(info) => {
+                        info.split_whitespace().next().unwrap_or("").to_string()
+                    }
+                    CodeBlockKind::Indented => String::new(),
+                };
+                self.code = Some((lang, String::new()));
+            }
+            Tag::List(start) => {
+                if self.lists.is_empty() {
+                    self.block();
+                } else if !self.at_line_start {
+                    self.newline();
+                }
+                self.lists.push(start);
+            }
+            Tag::Item => {
+                if !self.at_line_start {
+                    self.newline();
+                }
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ if self.styled => "• ".to_string(),
+                    _ => "- ".to_string(),
+                };
+                self.text(&marker);
+                self.prefix.push(" ".repeat(marker.chars().count()));
+                self.after_marker = true;
+            }
+            Tag::Emphasis => self.emphasis(ITALIC, "*"),
+            Tag::Strong => self.emphasis(BOLD, "**"),
+            Tag::Strikethrough => self.emphasis(STRIKE, "~~"),
+            Tag::Link { dest_url, .. } => {
+                if self.styled {
+                    self.push_style(UNDERLINE);
+                }
+                self.link = Some(dest_url.to_string());
+            }
+            _ => {}
+        }
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Paragraph => self.newline(),
+            TagEnd::Heading(_) => {
+                self.styles.clear();
+                if self.styled {
+                    self.out.push_str(RESET);
+                }
+                self.newline();
+            }
+            TagEnd::BlockQuote(_) | TagEnd::Item => {
+                if !self.at_line_start {
+                    self.newline();
+                }
+                self.prefix.pop();
+            }
+            TagEnd::List(_) => {
+                self.lists.pop();
+            }
+            TagEnd::Emphasis => self.emphasis_end("*"),
+            TagEnd::Strong => self.emphasis_end("**"),
+            TagEnd::Strikethrough => self.emphasis_end("~~"),
+            TagEnd::Link => {
+                if self.styled {
+                    self.pop_style();
+                }
+                if let Some(url) = self.link.take()
+                    && !self.out.ends_with(url.as_str())
+                {
+                    if self.styled {
+                        self.push_style(DIM);
+                        self.text(&format!(" ({})", url));
+                        self.pop_style();
+                    } else {
+                        self.text(&format!(" ({})", url));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn code_block(&mut self) {
+        let Some((lang, code)) = self.code.take() else {
+            return;
+        };
+        let fence = format!("```{}", lang);
+        self.fence_line(&fence);
+        if self.styled {
+            let syntax = SYNTAXES
+                .find_syntax_by_token(&lang)
+                .unwrap_or_else(|| SYNTAXES.find_syntax_plain_text());
+            let mut highlighter = HighlightLines::new(syntax, &THEME);
+            for line in LinesWithEndings::from(&code) {
+                let highlighted = match highlighter.highlight_line(line, &SYNTAXES) {
+                    Ok(ranges) => as_24_bit_terminal_escaped(&ranges, false),
+                    Err(_) => line.to_string(),
+                };
+                self.line_prefix();
+                self.out.push_str(highlighted.trim_end_matches('\n'));
+                self.out.push_str(RESET);
+                self.newline();
+            }
+        } else {
+            for line in code.lines() {
+                self.text(line);
+                self.newline();
+            }
+        }
+        self.fence_line("```");
+    }
+
+    fn fence_line(&mut self, fence: &str) {
+        if self.styled {
+            self.line_prefix();
+            self.push_style(DIM);
+            self.text(fence);
+            self.pop_style();
+        } else {
+            self.text(fence);
+        }
+        self.newline();
+    }
+
+    fn emphasis(&mut self, style: &'static str, marker: &str) {
+        if self.styled {
+            self.push_style(style);
+        } else {
+            self.text(marker);
+        }
+    }
+
+    fn emphasis_end(&mut self, marker: &str) {
+        if self.styled {
+            self.pop_style();
+        } else {
+            self.text(marker);
+        }
+    }
+
+    fn push_style(&mut self, style: &'static str) {
+        self.styles.push(style);
+        self.out.push_str(style);
+    }
+
+    fn pop_style(&mut self) {
+        self.styles.pop();
+        self.out.push_str(RESET);
+        for style in &self.styles {
+            self.out.push_str(style);
+        }
+    }
+
+    /// Start a block: on a fresh line, after a blank one unless inside a list.
+    fn block(&mut self) {
+        if self.out.is_empty() || std::mem::take(&mut self.after_marker) {
+            return;
+        }
+        if !self.at_line_start {
+            self.newline();
+        }
+        if self.lists.is_empty() && !self.out.ends_with("\n\n") {
+            // a blank line inside a quote still shows the bar
+            self.out.push_str(self.prefix.concat().trim_end());
+            self.out.push('\n');
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                self.newline();
+            }
+            if !line.is_empty() {
+                self.line_prefix();
+                self.after_marker = false;
+                self.out.push_str(line);
+            }
+        }
+    }
+
+    fn line_prefix(&mut self) {
+        if self.at_line_start {
+            self.at_line_start = false;
+            let prefix = self.prefix.concat();
+            if !prefix.is_empty() {
+                self.out.push_str(&prefix);
+                for style in &self.styles {
+                    self.out.push_str(style);
+                }
+            }
+        }
+    }
+
+    fn newline(&mut self) {
+        if self.styled && !self.styles.is_empty() {
+            self.out.push_str(RESET);
+            self.out.push('\n');
+            for style in &self.styles {
+                self.out.push_str(style);
+            }
+        } else {
+            self.out.push('\n');
+        }
+        self.at_line_start = true;
+    }
+
+    fn finish(mut self) -> String {
+        if self.styled && !self.styles.is_empty() {
+            self.out.push_str(RESET);
+        }
+        let trimmed = self.out.trim_end_matches('\n').len();
+        self.out.truncate(trimmed);
+        if !self.out.is_empty() {
+            self.out.push('\n');
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPLY: &str = include_str!("../tests/fixtures/markdown/reply.md");
+    const NESTED: &str = include_str!("../tests/fixtures/markdown/nested.md");
+
+    /// Compare with a snapshot in tests/fixtures/markdown. Escape codes are
+    /// written as `\e` so the snapshots stay readable.
+    fn assert_snapshot(rendered: &str, snapshot: &str) {
+        let visible = rendered.replace('\x1b', "\\e");
+        assert_eq!(visible, snapshot, "rendered:\n{visible}");
+    }
+
+    #[test]
+    fn modes_follow_the_terminal() {
+        assert_eq!(
+            RenderMode::detect(false, true, None, Some("xterm")),
+            RenderMode::Styled
+        );
+        assert_eq!(RenderMode::detect(true, true, None, None), RenderMode::Raw);
+        assert_eq!(
+            RenderMode::detect(false, false, None, None),
+            RenderMode::Raw
+        );
+        assert_eq!(
+            RenderMode::detect(false, true, Some("1"), None),
+            RenderMode::Plain
+        );
+        assert_eq!(
+            RenderMode::detect(false, true, Some(""), None),
+            RenderMode::Styled
+        );
+        assert_eq!(
+            RenderMode::detect(false, true, None, Some("dumb")),
+            RenderMode::Plain
+        );
+    }
+
+    #[test]
+    fn raw_mode_is_untouched() {
+        assert_eq!(render(REPLY, RenderMode::Raw), REPLY);
+    }
+
+    #[test]
+    fn plain_snapshots() {
+        assert_snapshot(
+            &render(REPLY, RenderMode::Plain),
+            include_str!("../tests/fixtures/markdown/reply.plain.txt"),
+        );
+        assert_snapshot(
+            &render(NESTED, RenderMode::Plain),
+            include_str!("../tests/fixtures/markdown/nested.plain.txt"),
+        );
+    }
+
+    #[test]
+    fn styled_snapshots() {
+        assert_snapshot(
+            &render(REPLY, RenderMode::Styled),
+            include_str!("../tests/fixtures/markdown/reply.styled.txt"),
+        );
+        assert_snapshot(
+            &render(NESTED, RenderMode::Styled),
+            include_str!("../tests/fixtures/markdown/nested.styled.txt"),
+        );
+    }
+
+    #[test]
+    fn plain_mode_has_no_escape_codes() {
+        assert!(!render(REPLY, RenderMode::Plain).contains('\x1b'));
+    }
+
+    #[test]
+    fn streams_hold_code_blocks_until_the_fence_closes() {
+        let mut stream = StreamRenderer::new(RenderMode::Plain);
+        assert_eq!(
+            stream.push("Here you go:\n\n```rust\nfn main() {\n"),
+            "Here you go:\n"
+        );
+        assert_eq!(stream.push("    println!(\"hi\");\n}\n"), "");
+        assert_eq!(
+            stream.push("```\nThat's all."),
+            "\n```rust\nfn main() {\n    println!(\"hi\");\n}\n```\n"
+        );
+        assert_eq!(stream.finish(), "\nThat's all.\n");
+    }
+
+    #[test]
+    fn streamed_output_matches_rendering_at_once() {
+        // one character at a time is the worst case for finding block ends
+        for markdown in [REPLY, NESTED] {
+            let mut stream = StreamRenderer::new(RenderMode::Plain);
+            let mut printed = String::new();
+            for c in markdown.chars() {
+                printed.push_str(&stream.push(&c.to_string()));
+            }
+            printed.push_str(&stream.finish());
+            assert_eq!(printed, render(markdown, RenderMode::Plain));
+        }
+    }
+
+    #[test]
+    fn raw_streams_pass_through() {
+        let mut stream = StreamRenderer::new(RenderMode::Raw);
+        assert_eq!(stream.push("```ru"), "```ru");
+        assert_eq!(stream.finish(), "");
+    }
+}
@@ -0,0 +1,1326 @@
+use std::future::Future;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+use deepseek_tutor::agent::ModelAnswer;
+use deepseek_tutor::attach::{self, Attachment, Pending};
+use deepseek_tutor::config::RequestParams;
+use deepseek_tutor::conversation::{Conversation, describe};
+use deepseek_tutor::finish;
+use deepseek_tutor::input;
+use deepseek_tutor::schema::JsonSchema;
+use deepseek_tutor::session::{ConversationSummary, Session};
+use deepseek_tutor::stream::Delta;
+use deepseek_tutor::transcript::TranscriptFormat;
+use deepseek_tutor::{AgentError, DeepSeekAgent};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::render::{self, RenderMode, StreamRenderer};
+use crate::tee::{self, Tee};
+
+/// A line typed at the REPL prompt.
+#[derive(Debug, PartialEq)]
+pub enum Command<'a> {
+    Exit,
+    Clear,
+    History,
+    Usage,
+    ReloadSystem,
+    Undo,
+    Retry(Option<&'a str>),
+    Edit(Option<&'a str>),
+    Save(Option<&'a str>),
+    Load(Option<&'a str>),
+    Set(Option<&'a str>),
+    Show(Option<&'a str>),
+    Reset(Option<&'a str>),
+    Export(Option<&'a str>),
+    Tee(Option<&'a str>),
+    Attach(Option<&'a str>),
+    New(Option<&'a str>),
+    Switch(Option<&'a str>),
+    Delete(Option<&'a str>),
+    List,
+    Unknown(&'a str),
+    Empty,
+    Message(&'a str),
+}
+
+pub fn parse_command(line: &str) -> Command<'_> {
+    let line = line.trim();
+    match line {
+        "" => Command::Empty,
+        "/exit" | "/quit" => Command::Exit,
+        "/clear" => Command::Clear,
+        "/history" => Command::History,
+        "/usage" => Command::Usage,
+        "/reload-system" => Command::ReloadSystem,
+        "/undo" => Command::Undo,
+        "/list" => Command::List,
+        _ if line.starts_with('/') => {
+            let (name, argument) = match line.split_once(char::is_whitespace) {
+                Some((name, rest)) => (name, Some(rest.trim()).filter(|r| !r.is_empty())),
+                None => (line, None),
+            };
+            match name {
+                "/save" => Command::Save(argument),
+                "/load" => Command::Load(argument),
+                "/set" => Command::Set(argument),
+                "/show" => Command::Show(argument),
+                "/reset" => Command::Reset(argument),
+                "/export" => Command::Export(argument),
+                "/retry" => Command::Retry(argument),
+                "/edit" => Command::Edit(argument),
+                "/tee" => Command::Tee(argument),
+                "/attach" => Command::Attach(argument),
+                "/new" => Command::New(argument),
+                "/switch" => Command::Switch(argument),
+                "/delete" => Command::Delete(argument),
+                _ => Command::Unknown(line),
+            }
+        }
+        _ => Command::Message(line),
+    }
+}
+
+/// How `/export` is used.
+const EXPORT_USAGE: &str = "Usage: /export markdown|html <path> [--include-reasoning]";
+
+/// The format, path and reasoning choice `/export` was given, like
+/// `html notes.html`.
+pub fn parse_export(argument: &str) -> Result<(TranscriptFormat, &Path, bool), String> {
+    let mut words = argument.split_whitespace();
+    let (Some(format), Some(path)) = (words.next(), words.next()) else {
+        return Err(EXPORT_USAGE.to_string());
+    };
+    let include_reasoning = match words.next() {
+        None => false,
+        Some("--include-reasoning") if words.next().is_none() => true,
+        Some(_) => return Err(EXPORT_USAGE.to_string()),
+    };
+    Ok((format.parse()?, Path::new(path), include_reasoning))
+}
+
+/// How `/tee` is used.
+const TEE_USAGE: &str = "Usage: /tee on <path> | /tee off";
+
+/// What `/tee` was asked to do: copy answers to a path, or stop with `None`.
+pub fn parse_tee(argument: &str) -> Result<Option<&Path>, String> {
+    match argument.split_once(char::is_whitespace) {
+        Some(("on", path)) if !path.trim().is_empty() => Ok(Some(Path::new(path.trim()))),
+        None if argument == "off" => Ok(None),
+        _ => Err(TEE_USAGE.to_string()),
+    }
+}
+
+/// How `/attach` is used.
+const ATTACH_USAGE: &str = "Usage: /attach <path> | /attach clear";
+
+/// What `/set` can change, with the values it takes.
+const SETTABLE: &str = "model NAME, temperature 0.0-2.0, top_p 0.0-1.0, max_tokens 1 or more";
+
+/// The request settings `/set` changes, taken as a whole so a rejected
+/// change leaves every one of them as it was.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub model: String,
+    pub params: RequestParams,
+}
+
+impl Settings {
+    pub fn of(agent: &DeepSeekAgent) -> Self {
+        Self {
+            model: agent.model().to_string(),
+            params: agent.params().clone(),
+        }
+    }
+
+    /// Use these settings for the agent's later requests.
+    pub fn apply(&self, agent: &mut DeepSeekAgent) -> Result<(), AgentError> {
+        agent.set_params(self.params.clone())?;
+        agent.set_model(&self.model);
+        Ok(())
+    }
+}
+
+/// `settings` with the change `/set` was given, like `temperature 1.3`, or
+/// why it isn't one, naming what is allowed.
+pub fn parse_setting(settings: &Settings, change: &str) -> Result<Settings, String> {
+    let (key, value) = match change.trim().split_once(char::is_whitespace) {
+        Some((key, value)) => (key, value.trim()),
+        None => (change.trim(), ""),
+    };
+    let mut changed = settings.clone();
+    match key {
+        "model" | "temperature" | "top_p" | "max_tokens" if value.is_empty() => {
+            return Err(format!(
+                "/set {} needs a value; settable: {}",
+                key, SETTABLE
+            ));
+        }
+        "model" if value.contains(char::is_whitespace) => {
+            return Err(format!("model names have no spaces, got '{}'", value));
+        }
+        "model" => changed.model = value.to_string(),
+        "temperature" => changed.params.temperature = Some(in_range(key, value, 0.0, 2.0)?),
+        "top_p" => changed.params.top_p = Some(in_range(key, value, 0.0, 1.0)?),
+        "max_tokens" => match value.parse::<u32>() {
+            Ok(max_tokens) if max_tokens >= 1 => changed.params.max_tokens = Some(max_tokens),
+            _ => {
+                return Err(format!(
+                    "max_tokens must be a whole number of 1 or more, got '{}'",
+                    value
+                ));
+            }
+        },
+        _ => return Err(format!("unknown setting '{}'; settable: {}", key, SETTABLE)),
+    }
+    Ok(changed)
+}
+
+fn in_range(key: &str, value: &str, min: f32, max: f32) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(number) if (min..=max).contains(&number) => Ok(number),
+        _ => Err(format!(
+            "{} must be a number from {:.1} to {:.1}, got '{}'",
+            key, min, max, value
+        )),
+    }
+}
+
+/// One line per setting, as `/show settings` prints them.
+pub fn describe_settings(settings: &Settings) -> String {
+    fn or_default(value: Option<impl ToString>) -> String {
+        value.map_or_else(|| "provider default".to_string(), |v| v.to_string())
+    }
+    format!(
+        "model:       {}\ntemperature: {}\ntop_p:       {}\nmax_tokens:  {}\n",
+        settings.model,
+        or_default(settings.params.temperature),
+        or_default(settings.params.top_p),
+        or_default(settings.params.max_tokens)
+    )
+}
+
+/// One line per conversation, as `/list` prints them, the one in use marked.
+pub fn describe_conversations(summaries: &[ConversationSummary]) -> String {
+    let width = summaries.iter().map(|s| s.name.len()).max().unwrap_or(0);
+    summaries
+        .iter()
+        .map(|summary| {
+            format!(
+                "{} {:<width$}  {} {}, ~{} tokens, last active {}\n",
+                if summary.active { "*" } else { " " },
+                summary.name,
+                summary.turns,
+                if summary.turns == 1 { "turn" } else { "turns" },
+                summary.tokens,
+                summary.last_active.format("%Y-%m-%d %H:%M UTC"),
+                width = width
+            )
+        })
+        .collect()
+}
+
+/// Several models to ask at once, and the one whose answers join the history.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FanOut {
+    pub models: Vec<String>,
+    pub history_model: String,
+}
+
+/// How replies are shown and where the session is kept.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Print replies token by token as they arrive.
+    pub streaming: bool,
+    /// Print usage after every reply and the session total on exit.
+    pub show_usage: bool,
+    /// Print how long every reply took to arrive.
+    pub show_timing: bool,
+    /// Print latency and throughput over the session on exit.
+    pub show_stats: bool,
+    /// Print the model's reasoning, dimmed on stderr, before its answer.
+    pub show_reasoning: bool,
+    /// Markdown styling for replies.
+    pub render: RenderMode,
+    /// Save the session here after every reply.
+    pub save_session: Option<PathBuf>,
+    /// Where `/save` and `/load` go without an argument.
+    pub session_path: Option<PathBuf>,
+    /// Ask several models instead of one; replies then arrive whole.
+    pub fan_out: Option<FanOut>,
+    /// Hold answers to this schema; they then arrive whole, as JSON.
+    pub json_schema: Option<JsonSchema>,
+    /// Times an answer that doesn't match `json_schema` is sent back.
+    pub schema_repairs: usize,
+    /// Where the system prompt came from, for `/reload-system`.
+    pub system_file: Option<PathBuf>,
+    /// Copy every answer to this file as well.
+    pub output_file: Option<PathBuf>,
+    /// Add answers to the end of `output_file`, and of files `/tee` names,
+    /// rather than replacing it.
+    pub append: bool,
+    /// Files `--attach` queued for the first message.
+    pub attachments: Vec<Attachment>,
+    /// Largest combined size of the files attached to a message.
+    pub max_attach_bytes: usize,
+}
+
+impl Options {
+    /// Where answers are copied, if they are.
+    pub fn tee(&self) -> Option<Tee> {
+        self.output_file
+            .clone()
+            .map(|path| Tee::new(path, self.append))
+    }
+}
+
+/// Read lines from stdin and hold a multi-turn conversation until `/exit` or Ctrl-D.
+pub async fn run(agent: &mut DeepSeekAgent, options: &Options) -> Result<(), AgentError> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let startup = Settings::of(agent);
+    let mut tee = options.tee();
+    let mut pending = Pending::default();
+    for attachment in &options.attachments {
+        pending.add(attachment.clone());
+    }
+
+    println!(
+        "Type a message, or /history, /usage, /set, /show settings, /save, /load, /export, /tee, /attach, /new, /switch, /list, /delete, /retry, /edit, /undo, /reload-system, /clear, /exit. Ctrl-D quits."
+    );
+    loop {
+        // with several conversations, the prompt says which is in use
+        match agent.conversations().parked().is_empty() {
+            true => print!("> "),
+            false => print!("{}> ", agent.conversations().active()),
+        }
+        std::io::stdout().flush()?;
+
+        let Some(line) = lines.next_line().await? else {
+            // Ctrl-D
+            println!();
+            break;
+        };
+
+        match parse_command(&line) {
+            Command::Empty => continue,
+            Command::Exit => break,
+            Command::Clear => {
+                agent.reset();
+                println!("History cleared.");
+            }
+            Command::History => print_history(agent.conversation()),
+            Command::Usage => println!("{}", agent.usage()),
+            Command::ReloadSystem => match &options.system_file {
+                Some(path) => match input::read_system_file(path) {
+                    Ok(system_prompt) => {
+                        let changed = agent.replace_system_prompt(&system_prompt);
+                        println!(
+                            "Reloaded the system prompt from {}: {} characters changed.",
+                            path.display(),
+                            changed
+                        );
+                        autosave(agent, options);
+                    }
+                    Err(e) => eprintln!("{}", e),
+                },
+                None => eprintln!("No --system-file to reload the system prompt from."),
+            },
+            Command::Save(path) => match path.map(Path::new).or(options.session_path.as_deref()) {
+                Some(path) => match agent.session().save(path) {
+                    Ok(()) => println!("Saved session to {}", path.display()),
+                    Err(e) => eprintln!("Could not save session: {}", e),
+                },
+                None => eprintln!("Usage: /save <path>"),
+            },
+            Command::Load(path) => match path.map(Path::new).or(options.session_path.as_deref()) {
+                Some(path) => match Session::load(path).and_then(|s| agent.resume(&s)) {
+                    Ok(()) => println!(
+                        "Loaded {} messages from {}",
+                        agent.conversation().len(),
+                        path.display()
+                    ),
+                    Err(e) => eprintln!("{}", e),
+                },
+                None => eprintln!("Usage: /load <path>"),
+            },
+            Command::Set(None) => {
+                eprintln!("Usage: /set <setting> <value>; settable: {}", SETTABLE)
+            }
+            Command::Set(Some(change)) => {
+                let set = parse_setting(&Settings::of(agent), change)
+                    .and_then(|settings| settings.apply(agent).map_err(|e| e.to_string()));
+                match set {
+                    Ok(()) => println!("Set {}.", change),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            Command::Show(Some("settings")) => {
+                print!("{}", describe_settings(&Settings::of(agent)))
+            }
+            Command::Show(_) => eprintln!("Usage: /show settings"),
+            Command::Reset(Some("settings")) => match startup.apply(agent) {
+                Ok(()) => println!("Settings reset to their startup values."),
+                Err(e) => eprintln!("{}", e),
+            },
+            Command::Reset(_) => eprintln!("Usage: /reset settings"),
+            Command::Export(None) => eprintln!("{}", EXPORT_USAGE),
+            Command::Export(Some(argument)) => {
+                let exported = parse_export(argument).and_then(|(format, path, reasoning)| {
+                    std::fs::write(path, format.render(&agent.session(), reasoning))
+                        .map(|()| path)
+                        .map_err(|e| format!("Could not export to {}: {}", path.display(), e))
+                });
+                match exported {
+                    Ok(path) => println!("Exported the conversation to {}", path.display()),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            Command::Tee(None) => match &tee {
+                Some(tee) => println!("Copying answers to {}. {}", tee.path().display(), TEE_USAGE),
+                None => println!("Answers aren't copied to a file. {}", TEE_USAGE),
+            },
+            Command::Tee(Some(argument)) => match parse_tee(argument) {
+                Ok(Some(path)) => {
+                    tee = Some(Tee::new(path.to_path_buf(), options.append));
+                    println!("Copying answers to {}.", path.display());
+                }
+                Ok(None) => match tee.take() {
+                    Some(tee) => println!("Stopped copying answers to {}.", tee.path().display()),
+                    None => eprintln!("Answers weren't being copied to a file."),
+                },
+                Err(e) => eprintln!("{}", e),
+            },
+            Command::Attach(None) if pending.is_empty() => {
+                println!(
+                    "No files are attached to the next message. {}",
+                    ATTACH_USAGE
+                )
+            }
+            Command::Attach(None) => {
+                println!("Attached to the next message:");
+                for file in pending.files() {
+                    println!("  {} ({} bytes)", file.name, file.size);
+                }
+            }
+            Command::Attach(Some("clear")) => {
+                pending.clear();
+                println!("No files are attached to the next message.");
+            }
+            Command::Attach(Some(path)) => {
+                let read = std::env::current_dir()
+                    .map_err(AgentError::from)
+                    .and_then(|base| Attachment::read(Path::new(path), &base));
+                match read {
+                    Ok(file) => {
+                        println!(
+                            "Attached {} ({} bytes) to the next message.",
+                            file.name, file.size
+                        );
+                        pending.add(file);
+                    }
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            Command::New(None) => eprintln!("Usage: /new <name>"),
+            Command::New(Some(name)) => match agent.new_conversation(name) {
+                Ok(()) => {
+                    println!("Started the conversation '{}'.", name);
+                    autosave(agent, options);
+                }
+                Err(e) => eprintln!("{}", e),
+            },
+            Command::Switch(None) => eprintln!("Usage: /switch <name>"),
+            Command::Switch(Some(name)) => match agent.switch_conversation(name) {
+                Ok(()) => {
+                    println!(
+                        "Switched to '{}' ({} messages).",
+                        name,
+                        agent.conversation().len()
+                    );
+                    autosave(agent, options);
+                }
+                Err(e) => eprintln!("{}", e),
+            },
+            Command::Delete(None) => eprintln!("Usage: /delete <name>"),
+            Command::Delete(Some(name)) => match agent.delete_conversation(name) {
+                Ok(()) => {
+                    println!("Deleted the conversation '{}'.", name);
+                    if agent.conversations().active() != name {
+                        println!("Now in '{}'.", agent.conversations().active());
+                    }
+                    autosave(agent, options);
+                }
+                Err(e) => eprintln!("{}", e),
+            },
+            Command::List => print!("{}", describe_conversations(&agent.list_conversations())),
+            Command::Unknown(command) => {
+                eprintln!("Unknown command: {}", command);
+            }
+            Command::Undo => match agent.undo() {
+                Some(question) => {
+                    println!("Took back \"{}\" and its answer.", preview(&question));
+                    autosave(agent, options);
+                }
+                None => eprintln!("Nothing to undo yet."),
+            },
+            Command::Retry(temperature) => {
+                let Some(question) = agent.conversation().last_user() else {
+                    eprintln!("Nothing to retry yet.");
+                    continue;
+                };
+                let settings = Settings::of(agent);
+                if let Some(temperature) = temperature {
+                    let set = parse_setting(&settings, &format!("temperature {}", temperature))
+                        .and_then(|changed| changed.apply(agent).map_err(|e| e.to_string()));
+                    if let Err(e) = set {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                }
+                let asked = ask_again(agent, &question, options, &mut tee).await;
+                // a temperature given is for this retry only
+                settings.apply(agent)?;
+                if let Err(e) = asked {
+                    interrupted(agent, options);
+                    return Err(e);
+                }
+            }
+            Command::Edit(text) => {
+                let Some(question) = agent.conversation().last_user() else {
+                    eprintln!("Nothing to edit yet.");
+                    continue;
+                };
+                let edited = match (text, editor()) {
+                    (Some(text), _) => Ok(text.to_string()),
+                    (None, Some(editor)) => edit_with(&editor, &question),
+                    (None, None) => Err(EDIT_USAGE.to_string()),
+                };
+                match edited {
+                    Ok(edited) if edited.trim().is_empty() => {
+                        eprintln!("The edited message is empty; nothing was sent.")
+                    }
+                    Ok(edited) => {
+                        if let Err(e) = ask_again(agent, edited.trim(), options, &mut tee).await {
+                            interrupted(agent, options);
+                            return Err(e);
+                        }
+                    }
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            Command::Message(text) => {
+                if let Err(e) = send_with(agent, text, &mut pending, options, &mut tee).await {
+                    interrupted(agent, options);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    if options.show_usage && agent.usage().turns() > 0 {
+        eprintln!("{}", agent.usage());
+    }
+    report_stats(agent, options);
+    Ok(())
+}
+
+/// [`send`] `text` with the files `pending` holds. They stay queued until
+/// it is answered, so asking again after a failure still sends them.
+async fn send_with(
+    agent: &mut DeepSeekAgent,
+    text: &str,
+    pending: &mut Pending,
+    options: &Options,
+    tee: &mut Option<Tee>,
+) -> Result<bool, AgentError> {
+    let text = attach_files(text, pending.files().to_vec(), options.max_attach_bytes);
+    let answered = send(agent, &text, options, tee).await?;
+    if answered {
+        pending.clear();
+    }
+    Ok(answered)
+}
+
+/// `question` with `attachments` ahead of it, cut to `max_bytes` between
+/// them. Says on stderr what was attached and what had to be cut.
+pub fn attach_files(question: &str, mut attachments: Vec<Attachment>, max_bytes: usize) -> String {
+    attach::fit(&mut attachments, max_bytes);
+    for file in &attachments {
+        eprintln!(
+            "[attach] {} ({} bytes, ~{} tokens)",
+            file.name,
+            file.size,
+            file.tokens()
+        );
+        if file.truncated() {
+            eprintln!(
+                "Warning: {} was cut to {} of {} bytes to keep the attachments under \
+                 --max-attach-bytes ({}).",
+                file.name,
+                file.text.len(),
+                file.size,
+                max_bytes
+            );
+        }
+    }
+    attach::with_attachments(question, &attachments)
+}
+
+/// Ask `text` the way `options` say and show the answer, copying it to
+/// `tee` under a header. Returns whether it was answered; failures are
+/// reported here, but being interrupted is an error, so the session can end.
+async fn send(
+    agent: &mut DeepSeekAgent,
+    text: &str,
+    options: &Options,
+    tee: &mut Option<Tee>,
+) -> Result<bool, AgentError> {
+    let models = match &options.fan_out {
+        Some(fan_out) => fan_out.models.join(", "),
+        None => agent.model().to_string(),
+    };
+    if let Some(tee) = tee.as_mut() {
+        tee.begin(Some(tee::header(chrono::Utc::now(), &models)));
+    }
+    let result = if let Some(fan_out) = &options.fan_out {
+        match interruptible(agent.ask_models(text, &fan_out.models, &fan_out.history_model)).await {
+            Ok(answers) => {
+                print_answers(&answers, options);
+                if let Some(tee) = tee.as_mut() {
+                    tee_answers(tee, &answers);
+                }
+                report_reply(agent, false, false);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    } else if let Some(schema) = &options.json_schema {
+        match interruptible(agent.ask_json(text, schema, options.schema_repairs)).await {
+            Ok(value) => {
+                print_json(&value);
+                if let Some(tee) = tee.as_mut() {
+                    tee.write(&pretty_json(&value));
+                }
+                report_reply(agent, options.show_usage, options.show_timing);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    } else {
+        let result = if options.streaming {
+            let mut printer = DeltaPrinter::new(options.show_reasoning, options.render);
+            let reply = interruptible(agent.ask_streaming(text, |delta| {
+                printer.print(delta);
+                if let Some(tee) = tee.as_mut() {
+                    tee.delta(delta);
+                }
+            }))
+            .await;
+            printer.finish();
+            reply
+        } else {
+            interruptible(agent.ask(text)).await.inspect(|reply| {
+                print_answer(
+                    agent.last_reasoning(),
+                    reply,
+                    options.show_reasoning,
+                    options.render,
+                );
+                if let Some(tee) = tee.as_mut() {
+                    tee.write(reply);
+                }
+            })
+        };
+        result.map(|_| report_reply(agent, options.show_usage, options.show_timing))
+    };
+    if let Some(tee) = tee.as_mut() {
+        match result {
+            Ok(()) => tee.finish(),
+            Err(_) => tee.cancel(),
+        }
+    }
+    match result {
+        Ok(()) => {
+            autosave(agent, options);
+            Ok(true)
+        }
+        Err(AgentError::Interrupted) => Err(AgentError::Interrupted),
+        Err(e) => {
+            eprintln!("Error calling DeepSeek API: {}", e);
+            Ok(false)
+        }
+    }
+}
+
+/// Take back the last question and ask `question` in its place, for
+/// `/retry` and `/edit`. The earlier answer is put back if there is no new one.
+async fn ask_again(
+    agent: &mut DeepSeekAgent,
+    question: &str,
+    options: &Options,
+    tee: &mut Option<Tee>,
+) -> Result<(), AgentError> {
+    let before = agent.conversation().clone();
+    agent.undo();
+    let asked = send(agent, question, options, tee).await;
+    if !matches!(asked, Ok(true)) {
+        agent.restore_conversation(before);
+    }
+    if let Ok(false) = asked {
+        eprintln!("Kept the earlier answer.");
+    }
+    asked.map(|_| ())
+}
+
+/// How `/edit` is used without an editor.
+const EDIT_USAGE: &str =
+    "Usage: /edit <new message>, or set $VISUAL or $EDITOR to edit the last one there";
+
+/// The editor `/edit` opens, from `$VISUAL` or `$EDITOR`.
+fn editor() -> Option<String> {
+    ["VISUAL", "EDITOR"]
+        .into_iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|editor| !editor.trim().is_empty())
+}
+
+/// Let the user change `text` in `editor`, a command like `vim` or
+/// `code --wait` that is given a file to edit, and return what they saved.
+fn edit_with(editor: &str, text: &str) -> Result<String, String> {
+    edit_in(&std::env::temp_dir(), editor, text)
+}
+
+/// [`edit_with`], keeping the file in a fresh private directory under `base`
+/// that is removed again however the edit ends.
+fn edit_in(base: &Path, editor: &str, text: &str) -> Result<String, String> {
+    let dir = private_dir(base).map_err(|e| format!("Could not create a file to edit: {}", e))?;
+    let path = dir.join("message.md");
+    let edited = run_editor(editor, &path, text);
+    let _ = std::fs::remove_dir_all(&dir);
+    edited
+}
+
+/// A new directory under `base` only this user can enter, never one that
+/// already exists.
+fn private_dir(base: &Path) -> std::io::Result<PathBuf> {
+    let dir = base.join(format!(
+        "deepseek_agent_edit_{}_{:016x}",
+        std::process::id(),
+        rand::random::<u64>()
+    ));
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(&dir)?;
+    Ok(dir)
+}
+
+fn run_editor(editor: &str, path: &Path, text: &str) -> Result<String, String> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(text.as_bytes()))
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or(editor);
+    match std::process::Command::new(program)
+        .args(words)
+        .arg(path)
+        .status()
+    {
+        Ok(status) if status.success() => std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e)),
+        Ok(status) => Err(format!(
+            "{} exited with {}; nothing was sent.",
+            program, status
+        )),
+        Err(e) => Err(format!("Could not start {}: {}", program, e)),
+    }
+}
+
+/// The start of `text`'s first line, to name a message in a status line.
+fn preview(text: &str) -> String {
+    let line = text.lines().next().unwrap_or_default();
+    match line.char_indices().nth(60) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None if line.len() < text.trim_end().len() => format!("{}...", line),
+        None => line.to_string(),
+    }
+}
+
+/// Prints a streamed reply: reasoning on stderr as it arrives, then the answer
+/// on stdout.
+pub struct DeltaPrinter {
+    show_reasoning: bool,
+    reasoning: bool,
+    render: RenderMode,
+    renderer: StreamRenderer,
+}
+
+impl DeltaPrinter {
+    pub fn new(show_reasoning: bool, render: RenderMode) -> Self {
+        Self {
+            show_reasoning,
+            reasoning: false,
+            render,
+            renderer: StreamRenderer::new(render),
+        }
+    }
+
+    pub fn print(&mut self, delta: &Delta) {
+        match delta {
+            Delta::Reasoning(_) if !self.show_reasoning => {}
+            Delta::Reasoning(text) => {
+                if !self.reasoning {
+                    eprint!("{}", reasoning_style(true));
+                    self.reasoning = true;
+                }
+                eprint!("{}", text);
+            }
+            Delta::Content(text) => {
+                self.end_reasoning();
+                print!("{}", self.renderer.push(text));
+                let _ = std::io::stdout().flush();
+            }
+        }
+    }
+
+    /// End the reply, and the reasoning if that was all there was.
+    pub fn finish(&mut self) {
+        self.end_reasoning();
+        // rendered blocks end their own lines
+        if self.render == RenderMode::Raw {
+            println!();
+        } else {
+            print!("{}", self.renderer.finish());
+        }
+    }
+
+    fn end_reasoning(&mut self) {
+        if self.reasoning {
+            eprintln!("{}", reasoning_style(false));
+            self.reasoning = false;
+        }
+    }
+}
+
+/// Print a whole reply, after its reasoning if there is any and it is wanted.
+pub fn print_answer(reasoning: Option<&str>, answer: &str, show_reasoning: bool, mode: RenderMode) {
+    if let Some(reasoning) = reasoning.filter(|_| show_reasoning) {
+        eprintln!(
+            "{}{}{}",
+            reasoning_style(true),
+            reasoning,
+            reasoning_style(false)
+        );
+    }
+    match render::render(answer, mode) {
+        rendered if mode == RenderMode::Raw || rendered.is_empty() => println!("{}", answer),
+        rendered => print!("{}", rendered),
+    }
+}
+
+/// Print a JSON answer indented, for reading.
+pub fn print_json(value: &serde_json::Value) {
+    println!("{}", pretty_json(value));
+}
+
+pub fn pretty_json(value: &serde_json::Value) -> String {
+    serde_json::to_string_pretty(value).expect("JSON values serialize")
+}
+
+/// Print each model's answer under a header naming it, how long it took and
+/// what it cost. Failures get a header too, and their error on stderr.
+pub fn print_answers(answers: &[ModelAnswer], options: &Options) {
+    for answer in answers {
+        println!("{}", answer_header(answer));
+        match &answer.reply {
+            Ok(reply) => print_answer(
+                reply.reasoning.as_deref(),
+                &reply.content,
+                options.show_reasoning,
+                options.render,
+            ),
+            Err(e) => eprintln!("Error: {}", e),
+        }
+        if options.show_timing
+            && let Some(metrics) = answer.reply.as_ref().ok().and_then(|reply| reply.metrics)
+        {
+            eprintln!("{}", metrics);
+        }
+    }
+}
+
+/// Copy the models' answers, each under its name; failures are left out.
+pub fn tee_answers(tee: &mut Tee, answers: &[ModelAnswer]) {
+    for answer in answers {
+        if let Ok(reply) = &answer.reply {
+            tee.write(&format!(
+                "### {}\n\n{}\n\n",
+                answer.model,
+                reply.content.trim_end()
+            ));
+        }
+    }
+}
+
+fn answer_header(answer: &ModelAnswer) -> String {
+    let seconds = answer.latency.as_secs_f64();
+    let mut details = match (&answer.reply, &answer.usage) {
+        (Err(_), _) => format!("failed after {:.1}s", seconds),
+        (Ok(reply), _) if reply.cached => format!("{:.1}s, cached", seconds),
+        (Ok(_), Some(turn)) => match turn.cost {
+            Some(cost) => format!(
+                "{:.1}s, {} tokens, ~${:.6}",
+                seconds,
+                turn.usage.total_tokens(),
+                cost
+            ),
+            None => format!("{:.1}s, {} tokens", seconds, turn.usage.total_tokens()),
+        },
+        (Ok(_), None) => format!("{:.1}s, usage not reported", seconds),
+    };
+    if let Some(issue) = answer.reply.as_ref().ok().and_then(finish::classify) {
+        details.push_str(&format!(", {}", issue));
+    }
+    format!("=== {} ({}) ===", answer.model, details)
+}
+
+/// What starts or ends a block of reasoning on stderr: a label, and dimming
+/// if stderr is a terminal.
+fn reasoning_style(start: bool) -> &'static str {
+    let dim = std::io::stderr().is_terminal();
+    match (start, dim) {
+        (true, true) => "\x1b[2m[reasoning] ",
+        (true, false) => "[reasoning] ",
+        (false, true) => "\x1b[0m",
+        (false, false) => "",
+    }
+}
+
+/// Wait for `request`, giving up with [`AgentError::Interrupted`] if Ctrl-C
+/// comes first.
+pub async fn interruptible<T>(
+    request: impl Future<Output = Result<T, AgentError>>,
+) -> Result<T, AgentError> {
+    tokio::select! {
+        result = request => result,
+        Ok(()) = tokio::signal::ctrl_c() => Err(AgentError::Interrupted),
+    }
+}
+
+/// Clean up after an interrupted question: forget it, then save what came
+/// before it if `--save-session` is set.
+pub fn interrupted(agent: &mut DeepSeekAgent, options: &Options) {
+    agent.cancel_pending();
+    autosave(agent, options);
+}
+
+/// Save the session to `--save-session`, if set, warning rather than failing.
+pub fn autosave(agent: &DeepSeekAgent, options: &Options) {
+    if let Some(path) = &options.save_session
+        && let Err(e) = agent.session().save(path)
+    {
+        eprintln!(
+            "Warning: could not save session to {}: {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+/// Warn about a reply that may not be the whole answer and, if asked to,
+/// print what it cost.
+pub fn report_reply(agent: &DeepSeekAgent, show_usage: bool, show_timing: bool) {
+    if !agent.last_excerpts().is_empty() {
+        let excerpts: Vec<_> = agent
+            .last_excerpts()
+            .iter()
+            .map(|(location, score)| format!("{} ({:.2})", location, score))
+            .collect();
+        eprintln!("[context] {}", excerpts.join(", "));
+    }
+    if let Some(trimmed) = agent.last_trimmed() {
+        eprintln!(
+            "Note: {} {} earlier messages to stay within the context budget (~{} -> ~{} tokens).",
+            if trimmed.summarized {
+                "summarized"
+            } else {
+                "dropped"
+            },
+            trimmed.messages,
+            trimmed.tokens_before,
+            trimmed.tokens_after
+        );
+    }
+    if let Some(issue) = agent.last_issue() {
+        eprintln!("Warning: {}.", issue.note(agent.params().max_tokens));
+    }
+    if agent.last_reply_cached() {
+        eprintln!("[cache] reply from the cache; --cache-refresh sends it again");
+    }
+    if show_usage {
+        match agent.last_usage() {
+            Some(usage) => eprintln!("{}", usage),
+            None if agent.last_reply_cached() => eprintln!("usage: none, the reply was cached"),
+            None => eprintln!("usage: not reported by the API"),
+        }
+    }
+    if show_timing && let Some(metrics) = agent.last_metrics() {
+        eprintln!("{}", metrics);
+    }
+}
+
+/// Print the session's latency and throughput, if `--stats` asked for them.
+pub fn report_stats(agent: &DeepSeekAgent, options: &Options) {
+    if options.show_stats
+        && let Some(summary) = agent.stats().summary()
+    {
+        eprintln!("{}", summary);
+    }
+}
+
+fn print_history(conversation: &Conversation) {
+    if conversation.is_empty() {
+        println!("(no messages yet)");
+        return;
+    }
+    for message in &conversation.messages()[1..] {
+        let (role, text) = describe(message);
+        println!("[{}] {}", role, text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use deepseek_tutor::AgentConfig;
+    use deepseek_tutor::retry::RetryPolicy;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_command("/exit"), Command::Exit);
+        assert_eq!(parse_command("  /quit  "), Command::Exit);
+        assert_eq!(parse_command("/clear"), Command::Clear);
+        assert_eq!(parse_command("/history"), Command::History);
+        assert_eq!(parse_command("/usage"), Command::Usage);
+        assert_eq!(parse_command("/reload-system"), Command::ReloadSystem);
+        assert_eq!(parse_command("/nope"), Command::Unknown("/nope"));
+        assert_eq!(parse_command("/nope arg"), Command::Unknown("/nope arg"));
+    }
+
+    #[test]
+    fn retry_and_edit_take_an_optional_argument() {
+        assert_eq!(parse_command("/undo"), Command::Undo);
+        assert_eq!(parse_command("/retry"), Command::Retry(None));
+        assert_eq!(parse_command("/retry 1.2"), Command::Retry(Some("1.2")));
+        assert_eq!(parse_command("/edit"), Command::Edit(None));
+        assert_eq!(
+            parse_command("/edit  and in Python? "),
+            Command::Edit(Some("and in Python?"))
+        );
+    }
+
+    #[test]
+    fn tee_turns_on_with_a_path_and_off() {
+        assert_eq!(parse_command("/tee"), Command::Tee(None));
+        assert_eq!(
+            parse_command("/tee on notes.md"),
+            Command::Tee(Some("on notes.md"))
+        );
+        assert_eq!(
+            parse_tee("on  notes/answers.md"),
+            Ok(Some(Path::new("notes/answers.md")))
+        );
+        assert_eq!(parse_tee("off"), Ok(None));
+        assert_eq!(parse_tee("on"), Err(TEE_USAGE.to_string()));
+        assert_eq!(parse_tee("off notes.md"), Err(TEE_USAGE.to_string()));
+    }
+
+    #[test]
+    fn conversation_commands_take_a_name() {
+        assert_eq!(parse_command("/new work"), Command::New(Some("work")));
+        assert_eq!(parse_command("/new"), Command::New(None));
+        assert_eq!(
+            parse_command("/switch  work "),
+            Command::Switch(Some("work"))
+        );
+        assert_eq!(parse_command("/delete work"), Command::Delete(Some("work")));
+        assert_eq!(parse_command("/list"), Command::List);
+    }
+
+    #[test]
+    fn conversations_are_listed_with_the_active_one_marked() {
+        let at = chrono::Utc
+            .with_ymd_and_hms(2026, 10, 14, 9, 30, 0)
+            .unwrap();
+        let summary = |name: &str, active, turns| ConversationSummary {
+            name: name.to_string(),
+            active,
+            turns,
+            tokens: 120,
+            last_active: at,
+        };
+        assert_eq!(
+            describe_conversations(&[summary("default", false, 3), summary("sql", true, 1)]),
+            "  default  3 turns, ~120 tokens, last active 2026-10-14 09:30 UTC\n\
+             * sql      1 turn, ~120 tokens, last active 2026-10-14 09:30 UTC\n"
+        );
+    }
+
+    #[test]
+    fn attach_takes_a_path() {
+        assert_eq!(parse_command("/attach"), Command::Attach(None));
+        assert_eq!(
+            parse_command("/attach  src/main.rs "),
+            Command::Attach(Some("src/main.rs"))
+        );
+        assert_eq!(
+            parse_command("/attach clear"),
+            Command::Attach(Some("clear"))
+        );
+    }
+
+    #[test]
+    fn edits_come_back_from_the_editor() {
+        let edited = edit_with("sed -i s/Rust/Python/", "Explain lifetimes in Rust").unwrap();
+        assert_eq!(edited, "Explain lifetimes in Python");
+
+        let failed = edit_with("false", "text").unwrap_err();
+        assert!(failed.starts_with("false exited with"), "{failed}");
+        let missing = edit_with("/definitely/not/an/editor", "text").unwrap_err();
+        assert!(missing.starts_with("Could not start"), "{missing}");
+    }
+
+    #[test]
+    fn edit_files_are_private_and_always_removed() {
+        let base = std::env::temp_dir().join(format!("deepseek_repl_edit_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(&base).unwrap();
+
+        let dir = private_dir(&base).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+        assert_ne!(private_dir(&base).unwrap(), dir);
+        std::fs::remove_dir_all(&base).unwrap();
+        std::fs::create_dir_all(&base).unwrap();
+
+        assert!(edit_in(&base, "sed -i s/a/b/", "a").is_ok());
+        assert!(edit_in(&base, "false", "text").is_err());
+        assert!(edit_in(&base, "/definitely/not/an/editor", "text").is_err());
+        assert_eq!(std::fs::read_dir(&base).unwrap().count(), 0);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn previews_shorten_long_messages() {
+        assert_eq!(preview("short"), "short");
+        assert_eq!(preview("first line\nsecond"), "first line...");
+        let long = "x".repeat(80);
+        assert_eq!(preview(&long), format!("{}...", "x".repeat(60)));
+    }
+
+    #[test]
+    fn save_and_load_take_an_optional_path() {
+        assert_eq!(parse_command("/save"), Command::Save(None));
+        assert_eq!(
+            parse_command("/save  chats/rust.json "),
+            Command::Save(Some("chats/rust.json"))
+        );
+        assert_eq!(parse_command("/load"), Command::Load(None));
+        assert_eq!(parse_command("/load a.json"), Command::Load(Some("a.json")));
+    }
+
+    #[test]
+    fn export_takes_a_format_and_path() {
+        assert_eq!(
+            parse_command("/export markdown notes.md"),
+            Command::Export(Some("markdown notes.md"))
+        );
+        assert_eq!(
+            parse_export("markdown notes.md"),
+            Ok((TranscriptFormat::Markdown, Path::new("notes.md"), false))
+        );
+        assert_eq!(
+            parse_export("html out/chat.html --include-reasoning"),
+            Ok((TranscriptFormat::Html, Path::new("out/chat.html"), true))
+        );
+        assert_eq!(parse_export("notes.md").unwrap_err(), EXPORT_USAGE);
+        assert_eq!(parse_export("md a.md --verbose").unwrap_err(), EXPORT_USAGE);
+        assert!(
+            parse_export("pdf a.pdf")
+                .unwrap_err()
+                .contains("unknown transcript format")
+        );
+    }
+
+    #[test]
+    fn settings_commands_take_an_argument() {
+        assert_eq!(
+            parse_command("/set temperature  1.3"),
+            Command::Set(Some("temperature  1.3"))
+        );
+        assert_eq!(parse_command("/set"), Command::Set(None));
+        assert_eq!(
+            parse_command("/show settings"),
+            Command::Show(Some("settings"))
+        );
+        assert_eq!(
+            parse_command("/reset settings"),
+            Command::Reset(Some("settings"))
+        );
+    }
+
+    fn startup() -> Settings {
+        Settings {
+            model: "deepseek-chat".to_string(),
+            params: RequestParams {
+                temperature: Some(0.7),
+                stop: vec!["END".to_string()],
+                ..RequestParams::default()
+            },
+        }
+    }
+
+    #[test]
+    fn valid_settings_change_only_their_value() {
+        let changed = |change: fn(&mut Settings)| {
+            let mut settings = startup();
+            change(&mut settings);
+            settings
+        };
+        let reasoner = changed(|s| s.model = "deepseek-reasoner".to_string());
+        let cases = [
+            (
+                "temperature 1.3",
+                changed(|s| s.params.temperature = Some(1.3)),
+            ),
+            (
+                "temperature 0",
+                changed(|s| s.params.temperature = Some(0.0)),
+            ),
+            (
+                "temperature 2.0",
+                changed(|s| s.params.temperature = Some(2.0)),
+            ),
+            ("top_p 0.9", changed(|s| s.params.top_p = Some(0.9))),
+            (
+                "max_tokens 2048",
+                changed(|s| s.params.max_tokens = Some(2048)),
+            ),
+            ("model deepseek-reasoner", reasoner.clone()),
+            ("  model   deepseek-reasoner ", reasoner),
+        ];
+        for (change, expected) in cases {
+            assert_eq!(parse_setting(&startup(), change), Ok(expected), "{change}");
+        }
+    }
+
+    #[test]
+    fn invalid_settings_say_what_is_allowed() {
+        let cases = [
+            (
+                "temperature 2.5",
+                "temperature must be a number from 0.0 to 2.0, got '2.5'",
+            ),
+            (
+                "temperature -1",
+                "temperature must be a number from 0.0 to 2.0, got '-1'",
+            ),
+            (
+                "temperature hot",
+                "temperature must be a number from 0.0 to 2.0, got 'hot'",
+            ),
+            (
+                "top_p 1.5",
+                "top_p must be a number from 0.0 to 1.0, got '1.5'",
+            ),
+            (
+                "max_tokens 0",
+                "max_tokens must be a whole number of 1 or more, got '0'",
+            ),
+            (
+                "max_tokens 1.5",
+                "max_tokens must be a whole number of 1 or more, got '1.5'",
+            ),
+            (
+                "model two words",
+                "model names have no spaces, got 'two words'",
+            ),
+            ("temperature", "/set temperature needs a value; settable: "),
+            ("seed 42", "unknown setting 'seed'; settable: model NAME, "),
+        ];
+        for (change, error) in cases {
+            let result = parse_setting(&startup(), change);
+            assert!(
+                result.as_ref().is_err_and(|e| e.starts_with(error)),
+                "{change}: {result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn settings_are_listed_with_provider_defaults() {
+        assert_eq!(
+            describe_settings(&startup()),
+            "model:       deepseek-chat\ntemperature: 0.7\ntop_p:       provider default\nmax_tokens:  provider default\n"
+        );
+    }
+
+    #[test]
+    fn blank_lines_are_ignored() {
+        assert_eq!(parse_command(""), Command::Empty);
+        assert_eq!(parse_command("   \t"), Command::Empty);
+    }
+
+    #[test]
+    fn everything_else_is_a_message() {
+        assert_eq!(
+            parse_command("what is a monad?\n"),
+            Command::Message("what is a monad?")
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_sends_keep_the_attachments_queued() {
+        // nothing listens on port 1, so the request fails at once
+        let config = AgentConfig {
+            base_url: "http://127.0.0.1:1/v1".into(),
+            retry: RetryPolicy::none(),
+            ..AgentConfig::new("sk-test")
+        };
+        let mut agent = DeepSeekAgent::new(config).unwrap();
+        let mut pending = Pending::default();
+        pending.add(Attachment {
+            name: "src/main.rs".into(),
+            text: "fn main() {}\n".into(),
+            size: 13,
+        });
+        let options = Options {
+            max_attach_bytes: 1024,
+            ..Options::default()
+        };
+
+        let answered = send_with(
+            &mut agent,
+            "what does it do?",
+            &mut pending,
+            &options,
+            &mut None,
+        )
+        .await
+        .unwrap();
+        assert!(!answered);
+        let queued: Vec<_> = pending.files().iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(queued, ["src/main.rs"]);
+        assert!(agent.conversation().is_empty());
+    }
+}
@@ -0,0 +1,571 @@
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use async_openai::error::{ApiError, OpenAIError};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+/// How often and how patiently a failed request is retried.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; 0 disables retrying.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for each one after.
+    pub base_delay: Duration,
+    /// Upper bound for a single delay, including a server-provided `Retry-After`.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    /// Delay before retry number `attempt` (0-based).
+    ///
+    /// `jitter` in `0.0..=1.0` scales the delay between half and the full
+    /// exponential value so concurrent clients don't retry in lockstep.
+    pub fn backoff(&self, attempt: u32, jitter: f64) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        exponential.mul_f64(0.5 + 0.5 * jitter.clamp(0.0, 1.0))
+    }
+
+    /// How long to wait before retrying after `error` on retry number `attempt`
+    /// (0-based), or `None` if the error is permanent or retries are used up.
+    pub fn retry_delay(&self, attempt: u32, error: &impl Transient) -> Option<Duration> {
+        let Retryable::Yes { retry_after } = error.retryable() else {
+            return None;
+        };
+        if attempt >= self.max_retries {
+            return None;
+        }
+        Some(self.delay(attempt, retry_after))
+    }
+
+    fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        match retry_after {
+            Some(retry_after) => retry_after.min(self.max_delay),
+            None => self.backoff(attempt, rand::random::<f64>()),
+        }
+    }
+}
+
+/// Whether an error is worth retrying.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Retryable {
+    No,
+    Yes { retry_after: Option<Duration> },
+}
+
+/// An error the retry loop can tell transient from permanent.
+pub trait Transient: fmt::Display {
+    fn retryable(&self) -> Retryable;
+}
+
+impl Transient for OpenAIError {
+    fn retryable(&self) -> Retryable {
+        classify(self)
+    }
+}
+
+/// A request that got an HTTP response other than success: the error in its
+/// body, with the status and `Retry-After` header that async-openai would
+/// drop, or an error that came before any response.
+#[derive(Debug)]
+pub struct Failure {
+    pub error: OpenAIError,
+    pub status: Option<u16>,
+    /// The wait the `Retry-After` header asked for.
+    pub retry_after: Option<Duration>,
+}
+
+impl Failure {
+    /// The failure a response with `status`, `retry_after` and `body` is.
+    /// A body that isn't an API error object becomes the message of one.
+    pub fn response(status: u16, retry_after: Option<Duration>, body: &[u8]) -> Self {
+        #[derive(Deserialize)]
+        struct ErrorBody {
+            error: ApiError,
+        }
+        let error = match serde_json::from_slice::<ErrorBody>(body) {
+            Ok(body) => body.error,
+            Err(_) => ApiError {
+                message: String::from_utf8_lossy(body).into_owned(),
+                r#type: None,
+                param: None,
+                code: None,
+            },
+        };
+        Self {
+            error: OpenAIError::ApiError(error),
+            status: Some(status),
+            retry_after,
+        }
+    }
+}
+
+impl From<OpenAIError> for Failure {
+    fn from(error: OpenAIError) -> Self {
+        Self {
+            error,
+            status: None,
+            retry_after: None,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl Transient for Failure {
+    /// Transient for 429 and 500/502/503, except a 429 for exhausted quota;
+    /// the wait is the `Retry-After` header's, or else the message's hint.
+    /// Without a response it is up to the error alone.
+    fn retryable(&self) -> Retryable {
+        let Some(status) = self.status else {
+            return classify(&self.error);
+        };
+        let OpenAIError::ApiError(api_error) = &self.error else {
+            return Retryable::No;
+        };
+        if !is_retryable_status(status) || is_quota(api_error) {
+            return Retryable::No;
+        }
+        Retryable::Yes {
+            retry_after: self
+                .retry_after
+                .or_else(|| parse_retry_after(&api_error.message.to_ascii_lowercase())),
+        }
+    }
+}
+
+/// Classify an error that comes without its response as transient
+/// (rate limits, 429 and 500/502/503 status lines, connection failures) or
+/// permanent (everything else, including exhausted quota).
+///
+/// Rate-limit errors carry a `rate_limit` code or message, and streaming
+/// errors embed the status line in their text. The wait the server asks
+/// for is read from "try again in 20s" style messages. An API error that is
+/// neither is permanent: without the status, a minimal 400 body can't be
+/// told from a 502's. [`Failure`] keeps the status where there is one.
+pub fn classify(error: &OpenAIError) -> Retryable {
+    match error {
+        OpenAIError::Reqwest(e) => {
+            if e.is_timeout() || e.is_connect() {
+                return Retryable::Yes { retry_after: None };
+            }
+            match e.status() {
+                Some(status) if is_retryable_status(status.as_u16()) => {
+                    Retryable::Yes { retry_after: None }
+                }
+                Some(_) => Retryable::No,
+                // a request that never got a response, e.g. a reset connection
+                None if e.is_request() => Retryable::Yes { retry_after: None },
+                None => Retryable::No,
+            }
+        }
+        OpenAIError::ApiError(api_error) => {
+            if is_quota(api_error) {
+                return Retryable::No;
+            }
+            let message = api_error.message.to_ascii_lowercase();
+            if mentions(api_error, "rate_limit") || message.contains("rate limit") {
+                return Retryable::Yes {
+                    retry_after: parse_retry_after(&message),
+                };
+            }
+            Retryable::No
+        }
+        OpenAIError::StreamError(message) => {
+            if let Some(status) = message
+                .strip_prefix("Invalid status code: ")
+                .and_then(|rest| rest.get(..3))
+                .and_then(|code| code.parse::<u16>().ok())
+            {
+                return if is_retryable_status(status) {
+                    Retryable::Yes { retry_after: None }
+                } else {
+                    Retryable::No
+                };
+            }
+            let message = message.to_ascii_lowercase();
+            if message.contains("error sending request") || message.contains("connection") {
+                Retryable::Yes { retry_after: None }
+            } else {
+                Retryable::No
+            }
+        }
+        _ => Retryable::No,
+    }
+}
+
+fn is_retryable_status(status: u16) -> bool {
+    matches!(status, 429 | 500 | 502 | 503)
+}
+
+fn mentions(api_error: &ApiError, needle: &str) -> bool {
+    [&api_error.code, &api_error.r#type]
+        .iter()
+        .any(|field| field.as_deref().is_some_and(|f| f.contains(needle)))
+}
+
+/// OpenAI sends exhausted quota as a 429, which waiting won't fix.
+fn is_quota(api_error: &ApiError) -> bool {
+    mentions(api_error, "insufficient_quota")
+}
+
+/// The wait a `Retry-After` header value asks for at `now`: a number of
+/// seconds, or an HTTP date.
+pub fn parse_retry_after_header(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<f64>() {
+        return (seconds.is_finite() && seconds >= 0.0).then(|| Duration::from_secs_f64(seconds));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (at.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Find a wait hint like "try again in 20s", "in 1.5s" or "in 500ms".
+fn parse_retry_after(message: &str) -> Option<Duration> {
+    let rest = &message[message.find("try again in ")? + "try again in ".len()..];
+    let number_len = rest
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(rest.len());
+    let value: f64 = rest[..number_len].parse().ok()?;
+    let unit = rest[number_len..].trim_start();
+    if unit.starts_with("ms") {
+        Some(Duration::from_secs_f64(value / 1000.0))
+    } else if unit.starts_with('s') {
+        Some(Duration::from_secs_f64(value))
+    } else {
+        None
+    }
+}
+
+/// A retry that is about to happen, for logging.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryAttempt {
+    /// 1 for the first retry.
+    pub attempt: u32,
+    pub max_retries: u32,
+    pub delay: Duration,
+    pub error: String,
+}
+
+/// Run `operation` until it succeeds, fails permanently, or runs out of retries.
+///
+/// `on_retry` is called before each wait.
+pub async fn with_retry<T, E, F, Fut>(
+    policy: &RetryPolicy,
+    mut operation: F,
+    mut on_retry: impl FnMut(&RetryAttempt),
+) -> Result<T, E>
+where
+    E: Transient,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 0;
+    loop {
+        let error = match operation().await {
+            Ok(value) => return Ok(value),
+            Err(error) => error,
+        };
+        let Some(delay) = policy.retry_delay(attempt, &error) else {
+            return Err(error);
+        };
+        attempt += 1;
+        on_retry(&RetryAttempt {
+            attempt,
+            max_retries: policy.max_retries,
+            delay,
+            error: error.to_string(),
+        });
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::cell::Cell;
+
+    fn api_error(r#type: Option<&str>, code: Option<&str>, message: &str) -> OpenAIError {
+        OpenAIError::ApiError(ApiError {
+            message: message.to_string(),
+            r#type: r#type.map(str::to_string),
+            param: None,
+            code: code.map(str::to_string),
+        })
+    }
+
+    fn rate_limited() -> OpenAIError {
+        api_error(
+            Some("requests"),
+            Some("rate_limit_exceeded"),
+            "Rate limit reached",
+        )
+    }
+
+    fn fast_policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_and_caps() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(5),
+        };
+        let schedule: Vec<_> = (0..6).map(|n| policy.backoff(n, 1.0).as_millis()).collect();
+        assert_eq!(schedule, [500, 1000, 2000, 4000, 5000, 5000]);
+    }
+
+    #[test]
+    fn jitter_scales_between_half_and_full() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(1, 0.0), Duration::from_millis(500));
+        assert_eq!(policy.backoff(1, 0.5), Duration::from_millis(750));
+        assert_eq!(policy.backoff(1, 1.0), Duration::from_millis(1000));
+        assert_eq!(policy.backoff(1, 7.0), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn huge_attempt_numbers_do_not_overflow() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(200, 1.0), policy.max_delay);
+    }
+
+    #[test]
+    fn rate_limits_and_server_errors_are_retryable() {
+        assert!(matches!(classify(&rate_limited()), Retryable::Yes { .. }));
+        assert_eq!(
+            Failure::response(502, None, b"<html>502 Bad Gateway</html>").retryable(),
+            Retryable::Yes { retry_after: None }
+        );
+        for status in [
+            "429 Too Many Requests",
+            "500 Internal Server Error",
+            "503 Service Unavailable",
+        ] {
+            let error = OpenAIError::StreamError(format!("Invalid status code: {}", status));
+            assert!(
+                matches!(classify(&error), Retryable::Yes { .. }),
+                "{status}"
+            );
+        }
+        let transport = OpenAIError::StreamError("error sending request for url".to_string());
+        assert!(matches!(classify(&transport), Retryable::Yes { .. }));
+    }
+
+    #[test]
+    fn client_errors_fail_immediately() {
+        for error in [
+            api_error(
+                Some("invalid_request_error"),
+                Some("invalid_request_error"),
+                "bad",
+            ),
+            api_error(
+                Some("authentication_error"),
+                Some("invalid_api_key"),
+                "bad key",
+            ),
+            api_error(
+                Some("invalid_request_error"),
+                Some("model_not_found"),
+                "no model",
+            ),
+            api_error(
+                Some("insufficient_quota"),
+                None,
+                "Rate limit: quota exhausted",
+            ),
+            OpenAIError::StreamError("Invalid status code: 401 Unauthorized".to_string()),
+            OpenAIError::StreamError("Invalid status code: 404 Not Found".to_string()),
+            OpenAIError::InvalidArgument("stream must be true".to_string()),
+        ] {
+            assert_eq!(classify(&error), Retryable::No, "{error}");
+        }
+    }
+
+    #[test]
+    fn responses_are_classified_by_status() {
+        let bare = br#"{"error":{"message":"bad request"}}"#;
+        for status in [400, 401, 404, 422] {
+            assert_eq!(
+                Failure::response(status, None, bare).retryable(),
+                Retryable::No,
+                "{status}"
+            );
+        }
+        // a bare body alone says nothing, as its status would
+        let error = Failure::response(400, None, bare).error;
+        assert_eq!(classify(&error), Retryable::No);
+        assert_eq!(
+            Failure::response(500, None, bare).retryable(),
+            Retryable::Yes { retry_after: None }
+        );
+
+        // the header's wait comes before the message's
+        let limited = Failure::response(
+            429,
+            Some(Duration::from_secs(2)),
+            br#"{"error":{"message":"Rate limit reached. Please try again in 20s."}}"#,
+        );
+        assert_eq!(
+            limited.retryable(),
+            Retryable::Yes {
+                retry_after: Some(Duration::from_secs(2))
+            }
+        );
+        let quota = br#"{"error":{"message":"You exceeded your current quota","type":"insufficient_quota"}}"#;
+        assert_eq!(
+            Failure::response(429, Some(Duration::from_secs(2)), quota).retryable(),
+            Retryable::No
+        );
+    }
+
+    #[test]
+    fn retry_after_headers_are_seconds_or_dates() {
+        let now = Utc.with_ymd_and_hms(2026, 10, 14, 9, 30, 0).unwrap();
+        assert_eq!(
+            parse_retry_after_header("2", now),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            parse_retry_after_header(" 0.5 ", now),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            parse_retry_after_header("Wed, 14 Oct 2026 09:30:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        // a date already past means now
+        assert_eq!(
+            parse_retry_after_header("Wed, 14 Oct 2026 09:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after_header("-1", now), None);
+        assert_eq!(parse_retry_after_header("soon", now), None);
+    }
+
+    #[test]
+    fn retry_after_hint_is_parsed() {
+        let cases = [
+            ("Rate limit reached. Please try again in 20s.", Some(20_000)),
+            ("rate limit: try again in 1.5s", Some(1_500)),
+            ("rate limit: try again in 250ms", Some(250)),
+            ("rate limit reached", None),
+        ];
+        for (message, expected) in cases {
+            let error = api_error(None, Some("rate_limit_exceeded"), message);
+            assert_eq!(
+                classify(&error),
+                Retryable::Yes {
+                    retry_after: expected.map(Duration::from_millis)
+                },
+                "{message}"
+            );
+        }
+    }
+
+    #[test]
+    fn retry_after_is_capped_by_max_delay() {
+        let policy = fast_policy(3);
+        assert_eq!(
+            policy.delay(0, Some(Duration::from_secs(60))),
+            policy.max_delay
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures_until_success() {
+        let calls = Cell::new(0);
+        let mut retries = Vec::new();
+        let result = with_retry(
+            &fast_policy(3),
+            || {
+                calls.set(calls.get() + 1);
+                let attempt = calls.get();
+                async move {
+                    if attempt < 3 {
+                        Err(rate_limited())
+                    } else {
+                        Ok("done")
+                    }
+                }
+            },
+            |retry| retries.push(retry.attempt),
+        )
+        .await;
+
+        assert_eq!(result.unwrap(), "done");
+        assert_eq!(calls.get(), 3);
+        assert_eq!(retries, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let calls = Cell::new(0);
+        let result: Result<(), _> = with_retry(
+            &fast_policy(2),
+            || {
+                calls.set(calls.get() + 1);
+                async { Err(rate_limited()) }
+            },
+            |_| {},
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_are_not_retried() {
+        let calls = Cell::new(0);
+        let result: Result<(), _> = with_retry(
+            &fast_policy(5),
+            || {
+                calls.set(calls.get() + 1);
+                async { Err(api_error(Some("authentication_error"), None, "bad key")) }
+            },
+            |_| panic!("should not retry"),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+}
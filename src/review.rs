@@ -0,0 +1,736 @@
+//! Reviewing a git diff, for the `review` subcommand.
+//!
+//! The diff is split into files, binaries and lockfiles are set aside, and
+//! the rest is packed into chunks that fit the context budget, a file too big
+//! for one split between its hunks. Each chunk is reviewed on its own, and
+//! the findings come back as JSON so they can be gathered into one report,
+//! grouped by file.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::agent::DeepSeekAgent;
+use crate::config::AgentConfig;
+use crate::error::AgentError;
+use crate::schema::{self, JsonSchema};
+
+/// The system prompt each chunk is reviewed under.
+pub const REVIEW_SYSTEM_PROMPT: &str = "You are a careful senior engineer reviewing a code \
+change. Report real problems in the lines the diff adds or changes: bugs, security holes, data \
+loss, races, broken error handling, missing tests for risky logic. Mark each finding critical \
+if merging it would break something or open a vulnerability, warning if it is likely to cause \
+trouble, and suggestion for anything smaller worth changing. Don't report style the project's \
+formatter would fix, and don't invent problems: no findings is a fine answer.";
+
+/// Files left out unless told otherwise: lockfiles, which are generated and
+/// long, and say little about the change.
+pub const DEFAULT_IGNORES: [&str; 5] = [
+    "*.lock",
+    "package-lock.json",
+    "npm-shrinkwrap.json",
+    "pnpm-lock.yaml",
+    "go.sum",
+];
+
+/// One file's part of a diff.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileDiff {
+    /// Where the file is after the change, or was if it was deleted.
+    pub path: String,
+    /// The `diff --git` line and the lines after it up to the first hunk.
+    pub header: String,
+    /// Each starting with its `@@` line.
+    pub hunks: Vec<String>,
+    /// Git only said the file differs.
+    pub binary: bool,
+}
+
+impl FileDiff {
+    pub fn text(&self) -> String {
+        let mut text = self.header.clone();
+        for hunk in &self.hunks {
+            text.push_str(hunk);
+        }
+        text
+    }
+}
+
+/// The files in `diff`, the output of `git diff`, in order.
+pub fn parse_diff(diff: &str) -> Vec<FileDiff> {
+    let mut files: Vec<FileDiff> = Vec::new();
+    for line in diff.split_inclusive('\n') {
+        if let Some(paths) = line.strip_prefix("diff --git ") {
+            files.push(FileDiff {
+                // `a/old b/new`; replaced by the `+++` line where there is one
+                path: paths
+                    .trim_end()
+                    .rsplit_once(" b/")
+                    .map_or(paths.trim_end(), |(_, new)| new)
+                    .to_string(),
+                header: line.to_string(),
+                hunks: Vec::new(),
+                binary: false,
+            });
+            continue;
+        }
+        // anything before the first file, like a commit message, is left out
+        let Some(file) = files.last_mut() else {
+            continue;
+        };
+        if line.starts_with("@@") {
+            file.hunks.push(line.to_string());
+        } else if let Some(hunk) = file.hunks.last_mut() {
+            hunk.push_str(line);
+        } else {
+            file.header.push_str(line);
+            let line = line.trim_end();
+            if let Some(path) = line.strip_prefix("+++ b/") {
+                file.path = path.to_string();
+            } else if line.starts_with("Binary files ") || line == "GIT binary patch" {
+                file.binary = true;
+            }
+        }
+    }
+    files
+}
+
+/// Whether `path` matches the glob `pattern`. `*` and `?` stay within a
+/// directory and `**` crosses them; a pattern without a `/` is matched
+/// against the file name alone, wherever the file is.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let path = match pattern.contains('/') {
+        true => path,
+        false => path.rsplit('/').next().unwrap_or(path),
+    };
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    matches(&pattern, &path)
+}
+
+fn matches(pattern: &[char], path: &[char]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        ['*', '*', '/', rest @ ..] => (0..=path.len())
+            .filter(|&i| i == 0 || path[i - 1] == '/')
+            .any(|i| matches(rest, &path[i..])),
+        ['*', '*', rest @ ..] => (0..=path.len()).any(|i| matches(rest, &path[i..])),
+        ['*', rest @ ..] => {
+            let within = path.iter().position(|&c| c == '/').unwrap_or(path.len());
+            (0..=within).any(|i| matches(rest, &path[i..]))
+        }
+        ['?', rest @ ..] => path.first().is_some_and(|&c| c != '/') && matches(rest, &path[1..]),
+        [c, rest @ ..] => path.first() == Some(c) && matches(rest, &path[1..]),
+    }
+}
+
+/// Why a file wasn't reviewed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Skip {
+    Binary,
+    /// It matched this ignore glob.
+    Ignored,
+}
+
+impl fmt::Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Binary => "binary",
+            Self::Ignored => "ignored",
+        })
+    }
+}
+
+/// The files to review, and those set aside with why: binaries, and those
+/// matching one of `ignores`.
+pub fn select(files: Vec<FileDiff>, ignores: &[String]) -> (Vec<FileDiff>, Vec<(String, Skip)>) {
+    let mut skipped = Vec::new();
+    let files = files
+        .into_iter()
+        .filter(|file| {
+            let skip = if file.binary {
+                Some(Skip::Binary)
+            } else if ignores.iter().any(|glob| glob_match(glob, &file.path)) {
+                Some(Skip::Ignored)
+            } else {
+                None
+            };
+            if let Some(skip) = skip {
+                skipped.push((file.path.clone(), skip));
+            }
+            skip.is_none()
+        })
+        .collect();
+    (files, skipped)
+}
+
+/// Part of the diff reviewed in one request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    /// The files it has changes to, in order.
+    pub files: Vec<String>,
+    pub text: String,
+}
+
+/// Rough tokens in `text`, at four characters a token like the history.
+fn tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Pack `files` into chunks of at most about `max_tokens` each, whole files
+/// together where they fit. A file too big for a chunk is split between its
+/// hunks, each part repeating the file's header, and a hunk too big on its
+/// own between its lines.
+pub fn chunk(files: &[FileDiff], max_tokens: usize) -> Vec<Chunk> {
+    let mut chunks: Vec<Chunk> = Vec::new();
+    for file in files {
+        for piece in pieces(file, max_tokens) {
+            match chunks.last_mut() {
+                Some(last) if tokens(&last.text) + tokens(&piece) <= max_tokens => {
+                    if last.files.last() != Some(&file.path) {
+                        last.files.push(file.path.clone());
+                    }
+                    last.text.push_str(&piece);
+                }
+                _ => chunks.push(Chunk {
+                    files: vec![file.path.clone()],
+                    text: piece,
+                }),
+            }
+        }
+    }
+    chunks
+}
+
+/// `file` in parts of at most about `max_tokens`, or whole if it fits.
+fn pieces(file: &FileDiff, max_tokens: usize) -> Vec<String> {
+    let whole = file.text();
+    if tokens(&whole) <= max_tokens {
+        return vec![whole];
+    }
+    let room = max_tokens.saturating_sub(tokens(&file.header)).max(1);
+    let mut parts: Vec<String> = Vec::new();
+    let mut part = String::new();
+    let hunks = file.hunks.iter().flat_map(|hunk| split_hunk(hunk, room));
+    for hunk in hunks {
+        if !part.is_empty() && tokens(&part) + tokens(&hunk) > room {
+            parts.push(std::mem::take(&mut part));
+        }
+        part.push_str(&hunk);
+    }
+    if !part.is_empty() {
+        parts.push(part);
+    }
+    parts
+        .into_iter()
+        .map(|part| format!("{}{}", file.header, part))
+        .collect()
+}
+
+/// `hunk` between its lines into parts of at most about `max_tokens`, the
+/// later ones under its `@@` line again.
+fn split_hunk(hunk: &str, max_tokens: usize) -> Vec<String> {
+    if tokens(hunk) <= max_tokens {
+        return vec![hunk.to_string()];
+    }
+    let mut lines = hunk.split_inclusive('\n');
+    let range = lines.next().unwrap_or_default();
+    let mut parts = Vec::new();
+    let mut part = range.to_string();
+    for line in lines {
+        if part.len() > range.len() && tokens(&part) + tokens(line) > max_tokens {
+            parts.push(std::mem::replace(&mut part, range.to_string()));
+        }
+        part.push_str(line);
+    }
+    parts.push(part);
+    parts
+}
+
+/// What a chunk is sent as.
+pub fn review_prompt(chunk: &Chunk) -> String {
+    format!(
+        "Review this diff of {}. Name the file of every finding as the diff does, and the \
+         line in the new version where there is one.\n\n```diff\n{}```",
+        chunk.files.join(", "),
+        chunk.text
+    )
+}
+
+/// How serious a finding is, most serious first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Critical,
+    Warning,
+    Suggestion,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Critical => "critical",
+            Self::Warning => "warning",
+            Self::Suggestion => "suggestion",
+        })
+    }
+}
+
+/// One problem the model found.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Finding {
+    pub file: String,
+    #[serde(default)]
+    pub line: Option<u64>,
+    pub severity: Severity,
+    pub title: String,
+    #[serde(default)]
+    pub detail: String,
+}
+
+/// The shape every review answer is held to.
+pub fn findings_schema() -> JsonSchema {
+    JsonSchema::new(json!({
+        "type": "object",
+        "required": ["findings"],
+        "properties": {
+            "findings": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["file", "severity", "title"],
+                    "properties": {
+                        "file": { "type": "string" },
+                        "line": { "type": ["integer", "null"], "minimum": 1 },
+                        "severity": { "enum": ["critical", "warning", "suggestion"] },
+                        "title": { "type": "string" },
+                        "detail": { "type": "string" }
+                    }
+                }
+            }
+        }
+    }))
+    .expect("the findings schema is valid")
+}
+
+/// The findings in an answer that matched [`findings_schema`].
+pub fn parse_findings(answer: &Value) -> Result<Vec<Finding>, String> {
+    serde_json::from_value(answer["findings"].clone()).map_err(|e| e.to_string())
+}
+
+/// The diff `review` is asked for: the working tree's unstaged changes, the
+/// staged ones, or a range of commits.
+pub fn git_diff(staged: bool, range: Option<&str>) -> Result<String, AgentError> {
+    let mut git = std::process::Command::new("git");
+    git.args(["diff", "--no-color", "--no-ext-diff"]);
+    if staged {
+        git.arg("--staged");
+    }
+    if let Some(range) = range {
+        git.arg(range);
+    }
+    let output = git
+        .output()
+        .map_err(|e| AgentError::Input(format!("could not run git: {}", e)))?;
+    if !output.status.success() {
+        return Err(AgentError::Input(format!(
+            "git diff failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// `config` set up for reviewing: the reviewer's system prompt, and answers
+/// in JSON.
+pub fn reviewer_config(mut config: AgentConfig) -> AgentConfig {
+    config.system_prompt = REVIEW_SYSTEM_PROMPT.to_string();
+    config.params.json_mode = true;
+    config
+}
+
+/// How far [`review_chunks`] has got.
+#[derive(Debug)]
+pub enum Progress<'a> {
+    /// The chunk at this index is being sent.
+    Sending(usize, &'a Chunk),
+    /// It got no usable answer; the rest go on.
+    Failed(usize, &'a AgentError),
+}
+
+/// What reviewing the chunks came to.
+#[derive(Debug)]
+pub struct Review {
+    pub report: Report,
+    /// Chunks that were answered.
+    pub reviewed: usize,
+    /// The budget error the chunks after it weren't sent for.
+    pub stopped: Option<AgentError>,
+    /// Why the last chunk that failed did.
+    pub last_error: Option<AgentError>,
+}
+
+impl Review {
+    /// The review as a command's result: the error that stopped it or that
+    /// every chunk failed with, then any critical finding, so CI can fail on it.
+    pub fn verdict(self) -> Result<(), AgentError> {
+        if let Some(e) = self.stopped {
+            return Err(e);
+        }
+        // a review that saw nothing mustn't pass as a clean one
+        if self.reviewed == 0
+            && let Some(e) = self.last_error
+        {
+            return Err(e);
+        }
+        match self.report.count(Severity::Critical) {
+            0 => Ok(()),
+            critical => Err(AgentError::CriticalFindings(critical)),
+        }
+    }
+}
+
+/// Ask `agent` to review each of `chunks` on its own and gather the findings
+/// into a report with the `skipped` files. A chunk that can't be reviewed is
+/// noted in the report and the rest go on, unless the budget is reached.
+pub async fn review_chunks(
+    agent: &mut DeepSeekAgent,
+    chunks: &[Chunk],
+    skipped: Vec<(String, Skip)>,
+    mut progress: impl FnMut(Progress<'_>),
+) -> Result<Review, AgentError> {
+    let schema = findings_schema();
+    let mut review = Review {
+        report: Report::new(skipped),
+        reviewed: 0,
+        stopped: None,
+        last_error: None,
+    };
+    for (i, chunk) in chunks.iter().enumerate() {
+        if let Some(e) = &review.stopped {
+            review
+                .report
+                .fail(&chunk.files, &format!("not sent: {}", e));
+            continue;
+        }
+        progress(Progress::Sending(i, chunk));
+        // every chunk is reviewed on its own
+        agent.reset();
+        let answer = agent
+            .ask_json(&review_prompt(chunk), &schema, schema::DEFAULT_MAX_REPAIRS)
+            .await;
+        match answer.and_then(|answer| parse_findings(&answer).map_err(AgentError::Input)) {
+            Ok(findings) => {
+                review.report.add(findings);
+                review.reviewed += 1;
+            }
+            Err(AgentError::Interrupted) => return Err(AgentError::Interrupted),
+            Err(e @ AgentError::BudgetExceeded { .. }) => {
+                review.report.fail(&chunk.files, &e.to_string());
+                review.stopped = Some(e);
+            }
+            Err(e) => {
+                progress(Progress::Failed(i, &e));
+                review.report.fail(&chunk.files, &e.to_string());
+                review.last_error = Some(e);
+            }
+        }
+    }
+    Ok(review)
+}
+
+/// Everything found, and what wasn't reviewed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    findings: Vec<Finding>,
+    skipped: Vec<(String, Skip)>,
+    /// The files of chunks that got no answer, with why.
+    failed: Vec<(Vec<String>, String)>,
+}
+
+impl Report {
+    pub fn new(skipped: Vec<(String, Skip)>) -> Self {
+        Self {
+            skipped,
+            ..Self::default()
+        }
+    }
+
+    pub fn add(&mut self, findings: Vec<Finding>) {
+        self.findings.extend(findings);
+    }
+
+    /// Note that the chunk of `files` couldn't be reviewed, for `reason`.
+    pub fn fail(&mut self, files: &[String], reason: &str) {
+        self.failed.push((files.to_vec(), reason.to_string()));
+    }
+
+    pub fn count(&self, severity: Severity) -> usize {
+        self.findings
+            .iter()
+            .filter(|finding| finding.severity == severity)
+            .count()
+    }
+
+    /// The report as Markdown: a summary line, then each file's findings,
+    /// most serious first, and last what wasn't reviewed.
+    pub fn to_markdown(&self) -> String {
+        let mut by_file: BTreeMap<&str, Vec<&Finding>> = BTreeMap::new();
+        for finding in &self.findings {
+            by_file.entry(&finding.file).or_default().push(finding);
+        }
+        let mut out = String::from("# Code review\n\n");
+        if self.findings.is_empty() && self.failed.is_empty() {
+            out.push_str("No findings.\n");
+        } else if self.findings.is_empty() {
+            out.push_str("No findings in the files reviewed.\n");
+        } else {
+            let _ = writeln!(
+                out,
+                "{} in {}: {} critical, {} warning, {} suggestion.",
+                count(self.findings.len(), "finding", "findings"),
+                count(by_file.len(), "file", "files"),
+                self.count(Severity::Critical),
+                self.count(Severity::Warning),
+                self.count(Severity::Suggestion)
+            );
+        }
+        for (file, mut findings) in by_file {
+            findings.sort_by_key(|finding| (finding.severity, finding.line));
+            let _ = write!(out, "\n## {}\n\n", file);
+            for finding in findings {
+                let _ = write!(out, "- **{}**", finding.severity);
+                if let Some(line) = finding.line {
+                    let _ = write!(out, " (line {})", line);
+                }
+                let _ = writeln!(out, ": {}", finding.title.trim());
+                for line in finding.detail.trim().lines() {
+                    let _ = writeln!(out, "  {}", line);
+                }
+            }
+        }
+        if !self.failed.is_empty() {
+            out.push_str("\n## Not reviewed\n\n");
+            for (files, reason) in &self.failed {
+                let _ = writeln!(out, "- {}: {}", files.join(", "), reason);
+            }
+        }
+        if !self.skipped.is_empty() {
+            out.push_str("\n## Skipped\n\n");
+            for (file, skip) in &self.skipped {
+                let _ = writeln!(out, "- {} ({})", file, skip);
+            }
+        }
+        out
+    }
+}
+
+fn count(n: usize, one: &str, many: &str) -> String {
+    format!("{} {}", n, if n == 1 { one } else { many })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFF: &str = include_str!("../tests/fixtures/review/change.diff");
+
+    #[test]
+    fn diffs_split_into_files() {
+        let files = parse_diff(DIFF);
+        let paths: Vec<_> = files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "src/retry.rs",
+                "Cargo.lock",
+                "docs/logo.png",
+                "src/old.rs",
+                "src/new_name.rs"
+            ]
+        );
+        let retry = &files[0];
+        assert!(
+            retry
+                .header
+                .starts_with("diff --git a/src/retry.rs b/src/retry.rs\n")
+        );
+        assert!(retry.header.ends_with("+++ b/src/retry.rs\n"));
+        assert_eq!(retry.hunks.len(), 2);
+        assert!(retry.hunks[1].starts_with("@@ -40,5 +41,7 @@"));
+        assert_eq!(
+            retry.text(),
+            DIFF[..DIFF.find("diff --git a/Cargo.lock").unwrap()]
+        );
+        assert!(files[2].binary);
+        assert!(!files[3].binary, "deleted files keep their old path");
+        assert_eq!(parse_diff(""), []);
+    }
+
+    #[test]
+    fn globs_match_names_or_whole_paths() {
+        assert!(glob_match("*.lock", "Cargo.lock"));
+        assert!(glob_match("*.lock", "web/yarn.lock"));
+        assert!(!glob_match("*.lock", "src/lock.rs"));
+        assert!(glob_match("docs/*.md", "docs/intro.md"));
+        assert!(!glob_match("docs/*.md", "docs/api/intro.md"));
+        assert!(glob_match("docs/**/*.md", "docs/api/intro.md"));
+        assert!(glob_match("docs/**/*.md", "docs/intro.md"));
+        assert!(glob_match("**/generated/**", "src/generated/schema.rs"));
+        assert!(glob_match("v?.txt", "v1.txt"));
+        assert!(!glob_match("v?.txt", "v10.txt"));
+    }
+
+    #[test]
+    fn binaries_and_lockfiles_are_skipped() {
+        let ignores: Vec<String> = DEFAULT_IGNORES
+            .iter()
+            .map(|glob| glob.to_string())
+            .collect();
+        let (files, skipped) = select(parse_diff(DIFF), &ignores);
+        assert_eq!(files.len(), 3);
+        assert_eq!(
+            skipped,
+            [
+                ("Cargo.lock".to_string(), Skip::Ignored),
+                ("docs/logo.png".to_string(), Skip::Binary)
+            ]
+        );
+        let (files, _) = select(parse_diff(DIFF), &[]);
+        assert_eq!(files.len(), 4, "binaries are never reviewed");
+    }
+
+    #[test]
+    fn small_files_share_a_chunk() {
+        let (files, _) = select(parse_diff(DIFF), &["*.lock".to_string()]);
+        let chunks = chunk(&files, 10_000);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(
+            chunks[0].files,
+            ["src/retry.rs", "src/old.rs", "src/new_name.rs"]
+        );
+        let prompt = review_prompt(&chunks[0]);
+        assert!(prompt.contains("diff of src/retry.rs, src/old.rs, src/new_name.rs."));
+        assert!(prompt.contains("```diff\ndiff --git a/src/retry.rs"));
+    }
+
+    #[test]
+    fn big_files_are_split_between_hunks_under_their_header() {
+        let files = parse_diff(DIFF);
+        let retry = &files[0];
+        // room for either hunk, but not both
+        let limit = tokens(&retry.header) + retry.hunks.iter().map(|h| tokens(h)).max().unwrap();
+        let chunks = chunk(&files[..1], limit);
+        assert_eq!(chunks.len(), 2);
+        for (chunk, hunk) in chunks.iter().zip(&retry.hunks) {
+            assert_eq!(chunk.files, ["src/retry.rs"]);
+            assert_eq!(chunk.text, format!("{}{}", retry.header, hunk));
+        }
+
+        // and a hunk too big for any chunk between its lines
+        let chunks = chunk(&files[..1], tokens(&retry.header) + 12);
+        assert!(chunks.len() > 2);
+        for chunk in &chunks {
+            assert!(chunk.text.starts_with(&retry.header));
+            assert!(chunk.text[retry.header.len()..].starts_with("@@ "));
+        }
+        let lines: usize = chunks
+            .iter()
+            .map(|chunk| chunk.text[retry.header.len()..].lines().skip(1).count())
+            .sum();
+        let hunk_lines: usize = retry
+            .hunks
+            .iter()
+            .map(|hunk| hunk.lines().count() - 1)
+            .sum();
+        assert_eq!(lines, hunk_lines, "no line is lost or repeated");
+    }
+
+    fn finding(file: &str, line: Option<u64>, severity: Severity, title: &str) -> Finding {
+        Finding {
+            file: file.to_string(),
+            line,
+            severity,
+            title: title.to_string(),
+            detail: String::new(),
+        }
+    }
+
+    #[test]
+    fn findings_are_parsed_from_answers_matching_the_schema() {
+        let answer = json!({ "findings": [
+            { "file": "src/retry.rs", "line": 42, "severity": "critical",
+              "title": "Retries never stop", "detail": "The loop ignores max_retries." },
+            { "file": "src/old.rs", "severity": "suggestion", "title": "Say why it went" }
+        ] });
+        let schema = findings_schema();
+        assert!(schema.check(&answer.to_string()).is_ok());
+        let findings = parse_findings(&answer).unwrap();
+        assert_eq!(findings[0].line, Some(42));
+        assert_eq!(findings[1].severity, Severity::Suggestion);
+        assert_eq!(findings[1].detail, "");
+
+        let unknown = json!({ "findings": [
+            { "file": "a.rs", "severity": "blocker", "title": "?" }
+        ] });
+        assert!(schema.check(&unknown.to_string()).is_err());
+        assert!(schema.check(r#"{"findings": []}"#).is_ok());
+    }
+
+    #[test]
+    fn reports_group_findings_by_file_most_serious_first() {
+        let mut report = Report::new(vec![("Cargo.lock".to_string(), Skip::Ignored)]);
+        report.add(vec![
+            finding(
+                "src/retry.rs",
+                Some(50),
+                Severity::Warning,
+                "Delay can overflow",
+            ),
+            finding("src/old.rs", None, Severity::Suggestion, "Say why it went"),
+        ]);
+        report.add(vec![Finding {
+            detail: "The loop ignores max_retries.\nIt retries forever.".to_string(),
+            ..finding(
+                "src/retry.rs",
+                Some(42),
+                Severity::Critical,
+                "Retries never stop",
+            )
+        }]);
+        report.fail(&["src/big.rs".to_string()], "the request timed out");
+        assert_eq!(report.count(Severity::Critical), 1);
+
+        assert_eq!(
+            report.to_markdown(),
+            "# Code review\n\
+             \n\
+             3 findings in 2 files: 1 critical, 1 warning, 1 suggestion.\n\
+             \n\
+             ## src/old.rs\n\
+             \n\
+             - **suggestion**: Say why it went\n\
+             \n\
+             ## src/retry.rs\n\
+             \n\
+             - **critical** (line 42): Retries never stop\n  \
+               The loop ignores max_retries.\n  \
+               It retries forever.\n\
+             - **warning** (line 50): Delay can overflow\n\
+             \n\
+             ## Not reviewed\n\
+             \n\
+             - src/big.rs: the request timed out\n\
+             \n\
+             ## Skipped\n\
+             \n\
+             - Cargo.lock (ignored)\n"
+        );
+        assert_eq!(
+            Report::default().to_markdown(),
+            "# Code review\n\nNo findings.\n"
+        );
+    }
+}
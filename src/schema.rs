@@ -0,0 +1,246 @@
+//! Replies held to a JSON Schema, for `--json-schema`.
+//!
+//! The model is told the schema in the system prompt and asked for JSON mode,
+//! but still tends to wrap its answer in a markdown fence or add a sentence
+//! after it. The JSON is dug out of the reply and validated; a reply that
+//! doesn't match is sent back with what was wrong, for the model to repair.
+
+use std::path::Path;
+
+use jsonschema::Validator;
+use jsonschema::error::ValidationErrorKind;
+use serde_json::Value;
+
+use crate::error::AgentError;
+
+/// Repair rounds allowed after the first reply unless configured otherwise.
+pub const DEFAULT_MAX_REPAIRS: usize = 2;
+
+/// Most validation errors sent back or reported at once.
+const MAX_ERRORS: usize = 10;
+
+/// A compiled JSON Schema and the document it came from.
+#[derive(Debug, Clone)]
+pub struct JsonSchema {
+    schema: Value,
+    validator: Validator,
+}
+
+impl JsonSchema {
+    pub fn new(schema: Value) -> Result<Self, AgentError> {
+        let validator = jsonschema::validator_for(&schema)
+            .map_err(|e| AgentError::InvalidConfig(format!("invalid JSON schema: {}", e)))?;
+        Ok(Self { schema, validator })
+    }
+
+    /// Read and compile the schema in the JSON file at `path`.
+    pub fn load(path: &Path) -> Result<Self, AgentError> {
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            AgentError::InvalidConfig(format!("could not read {}: {}", path.display(), e))
+        })?;
+        let schema = serde_json::from_str(&text).map_err(|e| {
+            AgentError::InvalidConfig(format!("{} is not JSON: {}", path.display(), e))
+        })?;
+        Self::new(schema)
+    }
+
+    pub fn schema(&self) -> &Value {
+        &self.schema
+    }
+
+    /// What goes after the system prompt so the model knows the shape wanted.
+    pub fn instructions(&self) -> String {
+        format!(
+            "Answer with a single JSON value matching this JSON Schema, and nothing else: \
+             no markdown code fence and no text before or after it.\n\n{}",
+            serde_json::to_string_pretty(&self.schema).expect("JSON values serialize")
+        )
+    }
+
+    /// The JSON in `content`, if there is some and it matches the schema;
+    /// otherwise what was wrong with it.
+    pub fn check(&self, content: &str) -> Result<Value, Vec<String>> {
+        let Some(value) = extract_json(content) else {
+            return Err(vec![
+                "the reply is not JSON, and has no JSON object or array in it".to_string(),
+            ]);
+        };
+        let errors: Vec<String> = self
+            .validator
+            .iter_errors(&value)
+            .take(MAX_ERRORS)
+            .map(|error| {
+                let path = pointer_path(error.instance_path().as_str());
+                match error.kind() {
+                    ValidationErrorKind::Required { property } => {
+                        let field = property
+                            .as_str()
+                            .map_or(property.to_string(), str::to_string);
+                        match path {
+                            Some(path) => format!("missing field `{}.{}`", path, field),
+                            None => format!("missing field `{}`", field),
+                        }
+                    }
+                    _ => match path {
+                        Some(path) => format!("`{}`: {}", path, error),
+                        None => error.to_string(),
+                    },
+                }
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(value)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// The message that sends a reply back with what was wrong with it.
+pub fn repair_prompt(errors: &[String]) -> String {
+    format!(
+        "Your output failed validation against the JSON Schema: {}. \
+         Reply again with only the corrected JSON.",
+        errors.join("; ")
+    )
+}
+
+/// The JSON value in a reply: all of it, the inside of a markdown code
+/// fence, or the first object or array in it, with any text around it dropped.
+pub fn extract_json(content: &str) -> Option<Value> {
+    let content = content.trim();
+    if let Ok(value) = serde_json::from_str(content) {
+        return Some(value);
+    }
+    if let Some(fenced) = fenced(content)
+        && let Ok(value) = serde_json::from_str(fenced.trim())
+    {
+        return Some(value);
+    }
+    content
+        .match_indices(['{', '['])
+        .find_map(|(start, _)| first_value(&content[start..]))
+}
+
+/// The text inside the first ``` fence, language tag dropped.
+fn fenced(content: &str) -> Option<&str> {
+    let (_, rest) = content.split_once("```")?;
+    // the tag runs to the end of the opening line
+    let (_, body) = rest.split_once('\n')?;
+    Some(body.split_once("```").map_or(body, |(inside, _)| inside))
+}
+
+/// The JSON value `text` starts with, ignoring whatever follows it.
+fn first_value(text: &str) -> Option<Value> {
+    serde_json::Deserializer::from_str(text)
+        .into_iter::<Value>()
+        .next()?
+        .ok()
+        .filter(|value| value.is_object() || value.is_array())
+}
+
+/// A JSON pointer like `/items/0/price` as `items[0].price`; `None` for the
+/// top level.
+fn pointer_path(pointer: &str) -> Option<String> {
+    let mut path = String::new();
+    for segment in pointer.split('/').skip(1) {
+        let segment = segment.replace("~1", "/").replace("~0", "~");
+        if segment.parse::<usize>().is_ok() {
+            path.push_str(&format!("[{}]", segment));
+        } else {
+            if !path.is_empty() {
+                path.push('.');
+            }
+            path.push_str(&segment);
+        }
+    }
+    (!path.is_empty()).then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn order_schema() -> JsonSchema {
+        JsonSchema::new(json!({
+            "type": "object",
+            "required": ["items"],
+            "properties": {
+                "items": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["name", "price"],
+                        "properties": {
+                            "name": { "type": "string" },
+                            "price": { "type": "number" }
+                        }
+                    }
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn json_is_dug_out_of_fences_and_prose() {
+        let expected = json!({ "a": [1, 2] });
+        for reply in [
+            r#"{"a": [1, 2]}"#,
+            "```json\n{\"a\": [1, 2]}\n```",
+            "```\n{\"a\": [1, 2]}\n```\nLet me know if you need more.",
+            "Here you go:\n```json\n{\"a\": [1, 2]}\n```",
+            "Sure! {\"a\": [1, 2]} Hope that helps, {really}.",
+            "  {\"a\": [1, 2]}\n\nThis lists the numbers.",
+        ] {
+            assert_eq!(extract_json(reply), Some(expected.clone()), "{reply}");
+        }
+        assert_eq!(extract_json("[1, 2] and more"), Some(json!([1, 2])));
+        assert_eq!(extract_json("no JSON here"), None);
+        assert_eq!(extract_json("a {broken: json"), None);
+    }
+
+    #[test]
+    fn replies_are_validated_with_readable_errors() {
+        let schema = order_schema();
+        let valid = schema
+            .check("```json\n{\"items\": [{\"name\": \"tea\", \"price\": 3.5}]}\n```")
+            .unwrap();
+        assert_eq!(valid["items"][0]["price"], 3.5);
+
+        let errors = schema
+            .check(r#"{"items": [{"name": "tea"}, {"name": 7, "price": 1}]}"#)
+            .unwrap_err();
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert_eq!(errors[0], "missing field `items[0].price`");
+        assert!(errors[1].starts_with("`items[1].name`: "), "{errors:?}");
+
+        assert_eq!(schema.check("{}").unwrap_err(), ["missing field `items`"]);
+        assert!(schema.check("Sorry, I can't.").unwrap_err()[0].contains("not JSON"));
+    }
+
+    #[test]
+    fn repair_prompts_list_the_errors() {
+        let prompt = repair_prompt(&["missing field `a`".to_string(), "`b`: bad".to_string()]);
+        assert!(prompt.contains("missing field `a`; `b`: bad"), "{prompt}");
+    }
+
+    #[test]
+    fn invalid_schemas_are_config_errors() {
+        let err = JsonSchema::new(json!({ "type": "no-such-type" })).unwrap_err();
+        assert!(matches!(err, AgentError::InvalidConfig(_)), "{err:?}");
+        assert!(order_schema().instructions().contains("\"required\""));
+    }
+
+    #[test]
+    fn pointers_read_like_field_paths() {
+        assert_eq!(pointer_path(""), None);
+        assert_eq!(
+            pointer_path("/items/0/price").as_deref(),
+            Some("items[0].price")
+        );
+        assert_eq!(pointer_path("/0").as_deref(), Some("[0]"));
+        assert_eq!(pointer_path("/a~1b").as_deref(), Some("a/b"));
+    }
+}
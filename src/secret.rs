@@ -0,0 +1,159 @@
+use std::fmt;
+
+use async_openai::error::OpenAIError;
+use serde::Deserialize;
+
+/// An API key that never prints in full.
+///
+/// `Display` and `Debug` show at most the `sk-` prefix and the last four
+/// characters, e.g. `sk-****1234`. Use [`expose`](Self::expose) only where the raw
+/// value has to go over the wire.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn new(secret: impl Into<String>) -> Self {
+        Self(secret.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn redacted(&self) -> String {
+        // short keys would give away too much of themselves
+        if self.0.chars().count() < 12 {
+            return "****".to_string();
+        }
+        let prefix = if self.0.starts_with("sk-") { "sk-" } else { "" };
+        let tail_start = self.0.char_indices().rev().nth(3).map_or(0, |(i, _)| i);
+        format!("{}****{}", prefix, &self.0[tail_start..])
+    }
+
+    /// Replace every occurrence of the key, and any bearer token, in `text`.
+    pub fn scrub(&self, text: &str) -> String {
+        let text = if self.0.is_empty() {
+            text.to_string()
+        } else {
+            text.replace(&self.0, &self.redacted())
+        };
+        redact_bearer_tokens(&text)
+    }
+
+    /// Scrub the key out of an API error in case the provider echoed it back.
+    pub fn scrub_error(&self, error: OpenAIError) -> OpenAIError {
+        match error {
+            OpenAIError::ApiError(mut api_error) => {
+                api_error.message = self.scrub(&api_error.message);
+                api_error.param = api_error.param.map(|param| self.scrub(&param));
+                OpenAIError::ApiError(api_error)
+            }
+            OpenAIError::StreamError(message) => OpenAIError::StreamError(self.scrub(&message)),
+            OpenAIError::InvalidArgument(message) => {
+                OpenAIError::InvalidArgument(self.scrub(&message))
+            }
+            other => other,
+        }
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        Self(secret)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(secret: &str) -> Self {
+        Self(secret.to_string())
+    }
+}
+
+impl fmt::Display for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.redacted())
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretString({})", self.redacted())
+    }
+}
+
+/// Mask whatever follows `Bearer ` up to the next whitespace or quote.
+pub fn redact_bearer_tokens(text: &str) -> String {
+    const MARKER: &str = "Bearer ";
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find(MARKER) {
+        let (before, after) = rest.split_at(index + MARKER.len());
+        out.push_str(before);
+        let end = after
+            .find(|c: char| c.is_whitespace() || c == '"' || c == '\'')
+            .unwrap_or(after.len());
+        if end > 0 {
+            out.push_str("****");
+        }
+        rest = &after[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::error::ApiError;
+
+    const KEY: &str = "sk-0123456789abcdef1234";
+
+    #[test]
+    fn display_and_debug_show_only_last_four() {
+        let secret = SecretString::new(KEY);
+        assert_eq!(secret.to_string(), "sk-****1234");
+        assert_eq!(format!("{:?}", secret), "SecretString(sk-****1234)");
+        assert_eq!(secret.expose(), KEY);
+    }
+
+    #[test]
+    fn short_or_unprefixed_keys() {
+        assert_eq!(SecretString::new("abc").to_string(), "****");
+        assert_eq!(SecretString::new("").to_string(), "****");
+        assert_eq!(
+            SecretString::new("ollama-local-key-9876").to_string(),
+            "****9876"
+        );
+    }
+
+    #[test]
+    fn scrub_replaces_key_and_bearer_tokens() {
+        let secret = SecretString::new(KEY);
+        let scrubbed = secret.scrub(&format!("bad key {} sent as 'Bearer {}'", KEY, KEY));
+        assert!(!scrubbed.contains(KEY));
+        assert_eq!(scrubbed, "bad key sk-****1234 sent as 'Bearer ****'");
+
+        assert_eq!(
+            redact_bearer_tokens("Authorization: Bearer other-token end"),
+            "Authorization: Bearer **** end"
+        );
+    }
+
+    #[test]
+    fn scrub_error_cleans_api_messages() {
+        let secret = SecretString::new(KEY);
+        let error = OpenAIError::ApiError(ApiError {
+            message: format!("Incorrect API key provided: {}", KEY),
+            r#type: Some("authentication_error".to_string()),
+            param: None,
+            code: None,
+        });
+        let message = secret.scrub_error(error).to_string();
+        assert!(!message.contains(KEY));
+        assert!(message.contains("sk-****1234"));
+
+        let stream = secret.scrub_error(OpenAIError::StreamError(format!("echo {}", KEY)));
+        assert!(!stream.to_string().contains(KEY));
+    }
+}
@@ -0,0 +1,684 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use async_openai::types::ChatCompletionRequestMessage;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::chat::estimate_tokens;
+use crate::conversation::Conversation;
+use crate::error::AgentError;
+use crate::usage::UsageTotals;
+
+/// Format version written by this build. Version 1, from before sessions
+/// could hold several conversations, is read too.
+pub const SESSION_VERSION: u32 = 2;
+
+/// Name of the conversation a session starts in, and falls back to when the
+/// one in use is deleted.
+pub const DEFAULT_CONVERSATION: &str = "default";
+
+/// A conversation saved to disk so it can be resumed later.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub version: u32,
+    /// Model the conversation was held with.
+    pub model: String,
+    pub saved_at: DateTime<Utc>,
+    pub usage: UsageTotals,
+    /// Every message, system prompt first.
+    pub messages: Vec<SessionMessage>,
+    /// Name of the conversation in `messages`, the one resumed.
+    #[serde(default = "default_conversation")]
+    pub conversation: String,
+    /// The settings it was held with, restored along with it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings: Option<ConversationSettings>,
+    /// The conversations held beside it, to switch to later.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub others: Vec<SavedConversation>,
+}
+
+fn default_conversation() -> String {
+    DEFAULT_CONVERSATION.to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionMessage {
+    pub timestamp: DateTime<Utc>,
+    pub message: ChatCompletionRequestMessage,
+    /// What the model showed before answering, for transcripts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<String>,
+}
+
+impl Session {
+    /// Snapshot `conversation` and the usage that went into it.
+    pub fn new(model: &str, conversation: &Conversation, usage: &UsageTotals) -> Self {
+        Self {
+            version: SESSION_VERSION,
+            model: model.to_string(),
+            saved_at: Utc::now(),
+            usage: usage.clone(),
+            messages: saved_messages(conversation),
+            conversation: default_conversation(),
+            settings: None,
+            others: Vec::new(),
+        }
+    }
+
+    /// The session with the settings its conversation was held with.
+    pub fn with_settings(mut self, settings: ConversationSettings) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// The session with the conversations of `store` beside its own, which
+    /// is the store's active one.
+    pub fn with_store(mut self, store: &ConversationStore) -> Self {
+        self.conversation = store.active.clone();
+        self.others = store
+            .parked
+            .iter()
+            .map(|(name, parked)| SavedConversation {
+                name: name.clone(),
+                settings: parked.settings.clone(),
+                messages: saved_messages(&parked.conversation),
+            })
+            .collect();
+        self
+    }
+
+    /// The saved messages as a conversation to continue.
+    pub fn conversation(&self) -> Result<Conversation, AgentError> {
+        restore(&self.messages)
+    }
+
+    /// The conversations saved beside the one in `messages`, which is the
+    /// active one.
+    pub fn store(&self) -> Result<ConversationStore, AgentError> {
+        let mut store = ConversationStore {
+            active: self.conversation.clone(),
+            parked: BTreeMap::new(),
+        };
+        for saved in &self.others {
+            let parked = ParkedConversation {
+                conversation: restore(&saved.messages).map_err(|e| match e {
+                    AgentError::Session(message) => {
+                        AgentError::Session(format!("conversation '{}': {}", saved.name, message))
+                    }
+                    other => other,
+                })?,
+                settings: saved.settings.clone(),
+            };
+            if saved.name == store.active
+                || store.parked.insert(saved.name.clone(), parked).is_some()
+            {
+                return Err(AgentError::Session(format!(
+                    "there are two conversations named '{}'",
+                    saved.name
+                )));
+            }
+        }
+        Ok(store)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("sessions always serialize")
+    }
+
+    /// Parse a session, naming the field that failed if it doesn't.
+    pub fn from_json(json: &str) -> Result<Self, AgentError> {
+        let value: serde_json::Value = serde_json::from_str(json)
+            .map_err(|e| AgentError::Session(format!("not valid JSON: {}", e)))?;
+        match value.get("version").map(|v| v.as_u64()) {
+            None => return Err(AgentError::Session("missing field `version`".into())),
+            Some(Some(version)) if (1..=u64::from(SESSION_VERSION)).contains(&version) => {}
+            Some(Some(version)) => {
+                return Err(AgentError::Session(format!(
+                    "unsupported version {} (this build reads versions 1 to {})",
+                    version, SESSION_VERSION
+                )));
+            }
+            Some(None) => {
+                return Err(AgentError::Session(
+                    "field `version` must be a number".into(),
+                ));
+            }
+        }
+        serde_path_to_error::deserialize(value).map_err(|e| {
+            AgentError::Session(format!(
+                "field `{}` failed to parse: {}",
+                e.path(),
+                e.inner()
+            ))
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, AgentError> {
+        let json = std::fs::read_to_string(path).map_err(|e| {
+            AgentError::Session(format!("could not read '{}': {}", path.display(), e))
+        })?;
+        Self::from_json(&json).map_err(|e| match e {
+            AgentError::Session(message) => {
+                AgentError::Session(format!("'{}': {}", path.display(), message))
+            }
+            other => other,
+        })
+    }
+
+    /// Write the session to `path`, replacing it only once the new file is complete.
+    pub fn save(&self, path: &Path) -> Result<(), AgentError> {
+        let file_name = path.file_name().map_or_else(
+            || "session".into(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        let partial = path.with_file_name(format!(".{}.partial", file_name));
+        std::fs::write(&partial, self.to_json())?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+}
+
+fn saved_messages(conversation: &Conversation) -> Vec<SessionMessage> {
+    conversation
+        .entries()
+        .map(|(timestamp, message, reasoning)| SessionMessage {
+            timestamp,
+            message: message.clone(),
+            reasoning,
+        })
+        .collect()
+}
+
+fn restore(messages: &[SessionMessage]) -> Result<Conversation, AgentError> {
+    Conversation::from_entries(
+        messages
+            .iter()
+            .map(|m| (m.timestamp, m.message.clone(), m.reasoning.clone())),
+    )
+    .ok_or_else(|| AgentError::Session("the first message must be the system prompt".into()))
+}
+
+/// The request settings a conversation keeps for itself: those `/set`
+/// changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConversationSettings {
+    pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+}
+
+/// A conversation other than the active one, as a session file keeps it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedConversation {
+    pub name: String,
+    pub settings: ConversationSettings,
+    /// Every message, system prompt first.
+    pub messages: Vec<SessionMessage>,
+}
+
+/// A conversation not in use, with the settings to go back to with it.
+#[derive(Debug, Clone)]
+pub struct ParkedConversation {
+    pub conversation: Conversation,
+    pub settings: ConversationSettings,
+}
+
+/// A line of `/list`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversationSummary {
+    pub name: String,
+    pub active: bool,
+    /// Questions asked in it.
+    pub turns: usize,
+    /// Estimated tokens of its history, system prompt included.
+    pub tokens: usize,
+    /// When a message was last added to it.
+    pub last_active: DateTime<Utc>,
+}
+
+impl ConversationSummary {
+    fn of(name: &str, active: bool, conversation: &Conversation) -> Self {
+        Self {
+            name: name.to_string(),
+            active,
+            turns: conversation
+                .messages()
+                .iter()
+                .filter(|m| matches!(m, ChatCompletionRequestMessage::User(_)))
+                .count(),
+            tokens: estimate_tokens(conversation.messages()),
+            last_active: conversation
+                .entries()
+                .map(|(timestamp, _, _)| timestamp)
+                .max()
+                .expect("a conversation always has its system prompt"),
+        }
+    }
+}
+
+/// What deleting a conversation left in use.
+#[derive(Debug, Clone)]
+pub enum Deleted {
+    /// It wasn't the active one, which stays.
+    Parked,
+    /// It was the active one; the default conversation is now, as it was
+    /// parked, or started afresh if `None`.
+    Active(Option<ParkedConversation>),
+}
+
+/// Conversations held side by side under their names. The active one is
+/// held by whoever is asking questions, e.g. the agent; the store keeps the
+/// others, each with its own history, system prompt and settings, until
+/// they are switched to.
+#[derive(Debug, Clone)]
+pub struct ConversationStore {
+    active: String,
+    parked: BTreeMap<String, ParkedConversation>,
+}
+
+impl Default for ConversationStore {
+    fn default() -> Self {
+        Self {
+            active: default_conversation(),
+            parked: BTreeMap::new(),
+        }
+    }
+}
+
+impl ConversationStore {
+    /// Name of the conversation in use.
+    pub fn active(&self) -> &str {
+        &self.active
+    }
+
+    /// The conversations not in use, by name.
+    pub fn parked(&self) -> &BTreeMap<String, ParkedConversation> {
+        &self.parked
+    }
+
+    /// Set `current`, the active conversation, aside and make `name` the
+    /// active one. The caller starts it afresh.
+    pub fn create(&mut self, name: &str, current: ParkedConversation) -> Result<(), String> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!(
+                "'{}' can't name a conversation; use a single word",
+                name
+            ));
+        }
+        if name == self.active || self.parked.contains_key(name) {
+            return Err(format!(
+                "there is already a conversation named '{}'; /switch {} goes to it",
+                name, name
+            ));
+        }
+        self.park(current);
+        self.active = name.to_string();
+        Ok(())
+    }
+
+    /// Set `current`, the active conversation, aside and take out `name`'s
+    /// to continue.
+    pub fn switch(
+        &mut self,
+        name: &str,
+        current: ParkedConversation,
+    ) -> Result<ParkedConversation, String> {
+        if name == self.active {
+            return Err(format!("'{}' is the conversation in use", name));
+        }
+        let Some(target) = self.parked.remove(name) else {
+            return Err(self.unknown(name));
+        };
+        self.park(current);
+        self.active = name.to_string();
+        Ok(target)
+    }
+
+    /// Forget `name`'s conversation. Deleting the active one goes back to
+    /// the default conversation.
+    pub fn delete(&mut self, name: &str) -> Result<Deleted, String> {
+        if self.parked.remove(name).is_some() {
+            return Ok(Deleted::Parked);
+        }
+        if name != self.active {
+            return Err(self.unknown(name));
+        }
+        self.active = default_conversation();
+        Ok(Deleted::Active(self.parked.remove(DEFAULT_CONVERSATION)))
+    }
+
+    /// Every conversation by name, `current` being the active one's.
+    pub fn summaries(&self, current: &Conversation) -> Vec<ConversationSummary> {
+        let mut summaries: Vec<_> = self
+            .parked
+            .iter()
+            .map(|(name, parked)| ConversationSummary::of(name, false, &parked.conversation))
+            .collect();
+        summaries.push(ConversationSummary::of(&self.active, true, current));
+        summaries.sort_by(|a, b| a.name.cmp(&b.name));
+        summaries
+    }
+
+    fn park(&mut self, current: ParkedConversation) {
+        self.parked.insert(self.active.clone(), current);
+    }
+
+    fn unknown(&self, name: &str) -> String {
+        format!("no conversation named '{}'; /list shows them", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::describe;
+    use crate::usage::Usage;
+
+    fn sample() -> Session {
+        let mut conversation = Conversation::new("Be terse.");
+        conversation.push_user("What is 2+2?");
+        conversation.push_assistant("4");
+        let usage = UsageTotals {
+            turns: 1,
+            usage: Usage {
+                prompt_tokens: 20,
+                completion_tokens: 1,
+            },
+            cost: 0.00001,
+            unpriced_turns: 0,
+        };
+        Session::new("deepseek-chat", &conversation, &usage)
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let session = sample();
+        let restored = Session::from_json(&session.to_json()).unwrap();
+        assert_eq!(restored, session);
+
+        let conversation = restored.conversation().unwrap();
+        let turns: Vec<_> = conversation.messages().iter().map(describe).collect();
+        assert_eq!(turns[1], ("user", "What is 2+2?".to_string()));
+        assert_eq!(turns[2], ("assistant", "4".to_string()));
+    }
+
+    #[test]
+    fn round_trips_through_a_file() {
+        let path =
+            std::env::temp_dir().join(format!("deepseek_session_{}.json", std::process::id()));
+        let session = sample();
+        session.save(&path).unwrap();
+        assert_eq!(Session::load(&path).unwrap(), session);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reads_the_version_1_fixture() {
+        let session =
+            Session::from_json(include_str!("../tests/fixtures/session_v1.json")).unwrap();
+        assert_eq!(session.version, 1);
+        assert_eq!(session.model, "deepseek-chat");
+        assert_eq!(session.usage.turns, 2);
+        assert_eq!(session.usage.usage.prompt_tokens, 310);
+        // from before there were several conversations
+        assert_eq!(session.conversation, DEFAULT_CONVERSATION);
+        let store = session.store().unwrap();
+        assert_eq!(store.active(), DEFAULT_CONVERSATION);
+        assert!(store.parked().is_empty());
+
+        let conversation = session.conversation().unwrap();
+        let roles: Vec<_> = conversation
+            .messages()
+            .iter()
+            .map(|m| describe(m).0)
+            .collect();
+        assert_eq!(
+            roles,
+            [
+                "system",
+                "user",
+                "assistant",
+                "tool",
+                "assistant",
+                "user",
+                "assistant"
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_versions_are_refused() {
+        let mut json: serde_json::Value = serde_json::from_str(&sample().to_json()).unwrap();
+        json["version"] = 3.into();
+        let err = Session::from_json(&json.to_string()).unwrap_err();
+        assert!(err.to_string().contains("unsupported version 3"), "{err}");
+
+        json.as_object_mut().unwrap().remove("version");
+        let err = Session::from_json(&json.to_string()).unwrap_err();
+        assert!(err.to_string().contains("missing field `version`"), "{err}");
+    }
+
+    #[test]
+    fn errors_name_the_failing_field() {
+        let mut json: serde_json::Value = serde_json::from_str(&sample().to_json()).unwrap();
+        json["messages"][1]["timestamp"] = "yesterday".into();
+        let err = Session::from_json(&json.to_string()).unwrap_err();
+        assert!(err.to_string().contains("messages[1].timestamp"), "{err}");
+
+        let err = Session::from_json("{ not json").unwrap_err();
+        assert!(err.to_string().contains("not valid JSON"), "{err}");
+    }
+
+    fn settings(model: &str) -> ConversationSettings {
+        ConversationSettings {
+            model: model.to_string(),
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
+        }
+    }
+
+    fn parked(system_prompt: &str, questions: &[&str]) -> ParkedConversation {
+        let mut conversation = Conversation::new(system_prompt);
+        for question in questions {
+            conversation.push_user(question);
+            conversation.push_assistant("ok");
+        }
+        ParkedConversation {
+            conversation,
+            settings: settings("deepseek-chat"),
+        }
+    }
+
+    fn texts(conversation: &Conversation) -> Vec<String> {
+        conversation
+            .messages()
+            .iter()
+            .map(|m| describe(m).1)
+            .collect()
+    }
+
+    #[test]
+    fn switching_keeps_each_history_to_itself() {
+        let mut store = ConversationStore::default();
+        store
+            .create("work", parked("Be a tutor.", &["what is a lifetime?"]))
+            .unwrap();
+        assert_eq!(store.active(), "work");
+        assert_eq!(store.parked().keys().collect::<Vec<_>>(), ["default"]);
+
+        let mut work = parked("Be terse.", &["index this query"]);
+        work.settings.temperature = Some(0.2);
+        let default = store.switch("default", work).unwrap();
+        assert_eq!(store.active(), "default");
+        assert_eq!(
+            texts(&default.conversation),
+            ["Be a tutor.", "what is a lifetime?", "ok"]
+        );
+
+        let work = store.switch("work", default).unwrap();
+        assert_eq!(
+            texts(&work.conversation),
+            ["Be terse.", "index this query", "ok"]
+        );
+        assert_eq!(work.settings.temperature, Some(0.2));
+        assert!(
+            !texts(&store.parked()["default"].conversation)
+                .contains(&"index this query".to_string())
+        );
+    }
+
+    #[test]
+    fn names_must_be_new_single_words_and_known() {
+        let mut store = ConversationStore::default();
+        let err = store.create("default", parked("s", &[])).unwrap_err();
+        assert!(
+            err.contains("already a conversation named 'default'"),
+            "{err}"
+        );
+        assert!(store.create("two words", parked("s", &[])).is_err());
+        assert!(store.create("", parked("s", &[])).is_err());
+        assert_eq!(store.active(), "default");
+        assert!(store.parked().is_empty());
+
+        store.create("work", parked("s", &[])).unwrap();
+        let err = store.switch("work", parked("s", &[])).unwrap_err();
+        assert_eq!(err, "'work' is the conversation in use");
+        let err = store.switch("play", parked("s", &[])).unwrap_err();
+        assert_eq!(err, "no conversation named 'play'; /list shows them");
+        // a failed switch sets nothing aside
+        assert_eq!(store.parked().keys().collect::<Vec<_>>(), ["default"]);
+        assert!(store.delete("play").is_err());
+    }
+
+    #[test]
+    fn deleting_falls_back_to_the_default() {
+        let mut store = ConversationStore::default();
+        store.create("a", parked("s", &["in default"])).unwrap();
+        store.create("b", parked("s", &["in a"])).unwrap();
+
+        assert!(matches!(store.delete("a"), Ok(Deleted::Parked)));
+        assert_eq!(store.active(), "b");
+
+        let Ok(Deleted::Active(Some(default))) = store.delete("b") else {
+            panic!("the default conversation should be taken up");
+        };
+        assert_eq!(texts(&default.conversation)[1], "in default");
+        assert_eq!(store.active(), "default");
+        assert!(store.parked().is_empty());
+
+        // with the default itself gone, one is started afresh
+        assert!(matches!(store.delete("default"), Ok(Deleted::Active(None))));
+        assert_eq!(store.active(), "default");
+    }
+
+    #[test]
+    fn summaries_list_every_conversation_by_name() {
+        let mut store = ConversationStore::default();
+        store
+            .create("zeta", parked("sys", &["one", "two"]))
+            .unwrap();
+        let current = parked("sys", &["three"]).conversation;
+        let summaries = store.summaries(&current);
+        let listed: Vec<_> = summaries
+            .iter()
+            .map(|s| (s.name.as_str(), s.active, s.turns))
+            .collect();
+        assert_eq!(listed, [("default", false, 2), ("zeta", true, 1)]);
+        assert_eq!(
+            summaries[0].tokens,
+            estimate_tokens(store.parked()["default"].conversation.messages())
+        );
+        assert!(summaries[0].tokens > summaries[1].tokens);
+        assert!(summaries[1].last_active <= Utc::now());
+    }
+
+    #[test]
+    fn every_conversation_round_trips_through_json() {
+        let mut store = ConversationStore::default();
+        let mut default = parked("Be a tutor.", &["what is 2+2?"]);
+        default.settings = ConversationSettings {
+            model: "deepseek-reasoner".into(),
+            temperature: Some(1.3),
+            top_p: Some(0.9),
+            max_tokens: Some(512),
+        };
+        store.create("work", default).unwrap();
+        store
+            .create("notes", parked("Be terse.", &["list them"]))
+            .unwrap();
+        let active = parked("Be terse.", &["in notes"]).conversation;
+        let active_settings = ConversationSettings {
+            temperature: Some(0.2),
+            ..settings("deepseek-chat")
+        };
+        let session = Session::new("deepseek-chat", &active, &UsageTotals::default())
+            .with_store(&store)
+            .with_settings(active_settings.clone());
+        assert_eq!(session.version, SESSION_VERSION);
+        assert_eq!(session.conversation, "notes");
+        assert_eq!(session.others.len(), 2);
+
+        let restored = Session::from_json(&session.to_json()).unwrap();
+        assert_eq!(restored, session);
+        assert_eq!(texts(&restored.conversation().unwrap())[1], "in notes");
+        assert_eq!(restored.settings, Some(active_settings));
+        let store = restored.store().unwrap();
+        assert_eq!(store.active(), "notes");
+        assert_eq!(
+            store.parked().keys().collect::<Vec<_>>(),
+            ["default", "work"]
+        );
+        let default = &store.parked()["default"];
+        assert_eq!(texts(&default.conversation)[1], "what is 2+2?");
+        assert_eq!(default.settings.model, "deepseek-reasoner");
+        assert_eq!(default.settings.max_tokens, Some(512));
+        assert_eq!(store.parked()["work"].settings, settings("deepseek-chat"));
+
+        // a session of one conversation is written as before
+        let json = sample().to_json();
+        assert!(!json.contains("\"others\""));
+        assert!(!json.contains("\"settings\""));
+    }
+
+    #[test]
+    fn broken_conversation_lists_are_refused() {
+        let mut store = ConversationStore::default();
+        store.create("work", parked("s", &[])).unwrap();
+        let active = Conversation::new("s");
+        let session = Session::new("deepseek-chat", &active, &UsageTotals::default());
+
+        let mut twice = session.clone().with_store(&store);
+        twice.others[0].name = "work".into();
+        let err = twice.store().unwrap_err();
+        assert!(
+            err.to_string().contains("two conversations named 'work'"),
+            "{err}"
+        );
+
+        let mut headless = session.with_store(&store);
+        headless.others[0].messages.clear();
+        let err = headless.store().unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("conversation 'default': the first message"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn session_must_start_with_system_prompt() {
+        let mut session = sample();
+        session.messages.remove(0);
+        assert!(matches!(
+            session.conversation(),
+            Err(AgentError::Session(_))
+        ));
+    }
+}
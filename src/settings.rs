@@ -0,0 +1,784 @@
+//! Settings from flags, the template being run, environment variables, the
+//! selected profile and the config file, merged in that order of precedence.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::error::AgentError;
+use crate::provider::ProviderKind;
+use crate::secret::SecretString;
+use crate::templates::TemplateSpec;
+
+/// Location of the config file below the user's config directory.
+pub const CONFIG_FILE: &str = "deepseek_agent/config.toml";
+
+/// Environment variable holding the API key unless a profile names another.
+pub const DEFAULT_API_KEY_ENV: &str = "OPENAI_API_KEY";
+
+/// One layer of settings; `None` leaves the key to the layer below.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub api_key: Option<SecretString>,
+    /// Environment variable to read the API key from.
+    pub api_key_env: Option<String>,
+    pub provider: Option<ProviderKind>,
+    /// The `api-version` of requests to Azure OpenAI.
+    pub azure_api_version: Option<String>,
+    pub base_url: Option<String>,
+    pub model: Option<String>,
+    pub system_prompt: Option<String>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    /// Most the session may spend, in US dollars, as estimated from prices.
+    pub budget_usd: Option<f64>,
+}
+
+impl Settings {
+    /// The environment variable the API key is read from.
+    pub fn api_key_var(&self) -> &str {
+        self.api_key_env.as_deref().unwrap_or(DEFAULT_API_KEY_ENV)
+    }
+}
+
+/// The config file: top-level settings plus named profiles layered over them.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Profile used when none is selected.
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Settings>,
+    /// Prompt templates defined inline rather than as files.
+    #[serde(default)]
+    pub templates: BTreeMap<String, TemplateSpec>,
+    // the top-level keys; serde can't flatten `Settings` in here and still
+    // reject unknown keys
+    api_key: Option<SecretString>,
+    api_key_env: Option<String>,
+    provider: Option<ProviderKind>,
+    azure_api_version: Option<String>,
+    base_url: Option<String>,
+    model: Option<String>,
+    system_prompt: Option<String>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    max_tokens: Option<u32>,
+    budget_usd: Option<f64>,
+}
+
+impl ConfigFile {
+    /// The settings outside any profile.
+    pub fn settings(&self) -> Settings {
+        Settings {
+            api_key: self.api_key.clone(),
+            api_key_env: self.api_key_env.clone(),
+            provider: self.provider,
+            azure_api_version: self.azure_api_version.clone(),
+            base_url: self.base_url.clone(),
+            model: self.model.clone(),
+            system_prompt: self.system_prompt.clone(),
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            budget_usd: self.budget_usd,
+        }
+    }
+
+    /// The profile called `name`, or the default profile if `name` is `None`.
+    ///
+    /// `Ok(None)` when neither is given.
+    pub fn profile(&self, name: Option<&str>) -> Result<Option<(&str, &Settings)>, AgentError> {
+        let Some(name) = name.or(self.default_profile.as_deref()) else {
+            return Ok(None);
+        };
+        match self.profiles.get_key_value(name) {
+            Some((name, settings)) => Ok(Some((name, settings))),
+            None if self.profiles.is_empty() => Err(AgentError::InvalidConfig(format!(
+                "unknown profile '{}': no profiles are defined in the config file",
+                name
+            ))),
+            None => Err(AgentError::InvalidConfig(format!(
+                "unknown profile '{}'; available profiles: {}",
+                name,
+                self.profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+            ))),
+        }
+    }
+}
+
+/// Where a setting came from, highest precedence first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Flag,
+    Template,
+    Env,
+    Profile,
+    File,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Source::Flag => "command-line flag",
+            Source::Template => "template",
+            Source::Env => "environment variable",
+            Source::Profile => "profile",
+            Source::File => "config file",
+        })
+    }
+}
+
+/// A key set in a lower layer that a higher one replaced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    pub key: &'static str,
+    pub by: Source,
+    pub over: Source,
+}
+
+impl fmt::Display for Override {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} from the {} overridden by {}",
+            self.key, self.over, self.by
+        )
+    }
+}
+
+/// The merged settings and what each layer contributed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Merged {
+    pub settings: Settings,
+    /// Where each key that ended up set came from.
+    pub sources: Vec<(&'static str, Source)>,
+    pub overrides: Vec<Override>,
+}
+
+impl Merged {
+    pub fn source(&self, key: &str) -> Option<Source> {
+        self.sources
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, source)| *source)
+    }
+}
+
+/// Merge `layers`, given highest precedence first. Keys no layer sets stay
+/// `None` for the caller to default.
+pub fn merge(layers: impl IntoIterator<Item = (Source, Settings)>) -> Merged {
+    let mut merged = Merged::default();
+    for (source, layer) in layers {
+        macro_rules! take {
+            ($($key:ident),*) => {$(
+                if let Some(value) = layer.$key {
+                    let key = stringify!($key);
+                    match merged.source(key) {
+                        Some(by) => merged.overrides.push(Override { key, by, over: source }),
+                        None => {
+                            merged.settings.$key = Some(value);
+                            merged.sources.push((key, source));
+                        }
+                    }
+                }
+            )*};
+        }
+        take!(
+            api_key,
+            api_key_env,
+            provider,
+            azure_api_version,
+            base_url,
+            model,
+            system_prompt,
+            temperature,
+            top_p,
+            max_tokens,
+            budget_usd
+        );
+    }
+    merged
+}
+
+/// `$XDG_CONFIG_HOME/deepseek_agent/config.toml`, falling back to `~/.config`.
+///
+/// Takes the variables as arguments so callers decide where they come from.
+pub fn default_config_path(xdg_config_home: Option<&str>, home: Option<&str>) -> Option<PathBuf> {
+    Some(config_home(xdg_config_home, home)?.join(CONFIG_FILE))
+}
+
+/// The user's config directory: `$XDG_CONFIG_HOME`, falling back to `~/.config`.
+pub(crate) fn config_home(xdg_config_home: Option<&str>, home: Option<&str>) -> Option<PathBuf> {
+    // the XDG spec says relative paths are to be ignored
+    match xdg_config_home.map(Path::new) {
+        Some(dir) if dir.is_absolute() => Some(dir.to_path_buf()),
+        _ => Some(Path::new(home.filter(|h| !h.is_empty())?).join(".config")),
+    }
+}
+
+/// The user's cache directory: `$XDG_CACHE_HOME`, falling back to `~/.cache`.
+pub(crate) fn cache_home(xdg_cache_home: Option<&str>, home: Option<&str>) -> Option<PathBuf> {
+    match xdg_cache_home.map(Path::new) {
+        Some(dir) if dir.is_absolute() => Some(dir.to_path_buf()),
+        _ => Some(Path::new(home.filter(|h| !h.is_empty())?).join(".cache")),
+    }
+}
+
+/// Read the config file at `path`.
+///
+/// A missing file is `None` unless `required`, i.e. named with `--config`.
+pub fn load(path: &Path, required: bool) -> Result<Option<ConfigFile>, AgentError> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => return Ok(None),
+        Err(e) => {
+            return Err(AgentError::InvalidConfig(format!(
+                "could not read config file '{}': {}",
+                path.display(),
+                e
+            )));
+        }
+    };
+    let file = parse(&text).map_err(|message| {
+        AgentError::InvalidConfig(format!("config file '{}': {}", path.display(), message))
+    })?;
+    Ok(Some(file))
+}
+
+/// What `init` sets up: the key, and the endpoint and model to use it with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub api_key: SecretString,
+    pub base_url: String,
+    pub model: String,
+}
+
+/// Write `credentials` as the top-level keys of the config file at `path`,
+/// keeping its profiles, templates and other keys, though not its comments.
+///
+/// The file is made readable by the user alone, as it holds the key. One
+/// that isn't valid TOML is left as it is.
+pub fn save_credentials(path: &Path, credentials: &Credentials) -> Result<(), AgentError> {
+    let mut table = match std::fs::read_to_string(path) {
+        Ok(text) => text.parse::<toml::Table>().map_err(|e| {
+            AgentError::InvalidConfig(format!(
+                "config file '{}' is not valid TOML, so it was left alone: {}",
+                path.display(),
+                e.message()
+            ))
+        })?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => toml::Table::new(),
+        Err(e) => return Err(e.into()),
+    };
+    for (key, value) in [
+        ("api_key", credentials.api_key.expose()),
+        ("base_url", &credentials.base_url),
+        ("model", &credentials.model),
+    ] {
+        table.insert(key.to_string(), value.into());
+    }
+    let text = toml::to_string(&table).expect("tables always serialize");
+    write_private(path, &text)?;
+    Ok(())
+}
+
+/// Set `var` to `key` in the `.env` file at `path`, replacing an earlier
+/// line for it and keeping the others. Like the config file, only the user
+/// can read it.
+pub fn save_env_key(path: &Path, var: &str, key: &SecretString) -> Result<(), AgentError> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let assignment = format!("{}=", var);
+    let mut lines: Vec<String> = text
+        .lines()
+        .filter(|line| !line.trim_start().starts_with(&assignment))
+        .map(str::to_string)
+        .collect();
+    lines.push(format!("{}{}", assignment, key.expose()));
+    write_private(path, &(lines.join("\n") + "\n"))?;
+    Ok(())
+}
+
+/// Write `contents` to `path` through a file only the user can read, renamed
+/// into place, so the key is never readable by others, not even briefly.
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(format!(".{}.partial", std::process::id()));
+    let partial = PathBuf::from(partial);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let written = options
+        .open(&partial)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, contents.as_bytes()))
+        .and_then(|()| std::fs::rename(&partial, path));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    written
+}
+
+/// Parse and validate config file contents. Errors say where in the file the
+/// problem is.
+pub fn parse(text: &str) -> Result<ConfigFile, String> {
+    let file: ConfigFile = toml::from_str(text).map_err(|e| {
+        let message = e.message().trim_end().replace('\n', "; ");
+        match e.span() {
+            Some(span) => {
+                let (line, column) = line_and_column(text, span.start);
+                format!("line {}, column {}: {}", line, column, message)
+            }
+            None => message,
+        }
+    })?;
+    validate(&file.settings())?;
+    for (name, profile) in &file.profiles {
+        validate(profile).map_err(|message| format!("profile '{}': {}", name, message))?;
+    }
+    Ok(file)
+}
+
+/// Check ranges the API would otherwise reject with a less helpful error.
+pub(crate) fn validate(settings: &Settings) -> Result<(), String> {
+    if let Some(t) = settings.temperature
+        && !(0.0..=2.0).contains(&t)
+    {
+        return Err(format!("temperature must be between 0 and 2, got {}", t));
+    }
+    if let Some(p) = settings.top_p
+        && !(0.0..=1.0).contains(&p)
+    {
+        return Err(format!("top_p must be between 0 and 1, got {}", p));
+    }
+    if settings.max_tokens == Some(0) {
+        return Err("max_tokens must be at least 1".to_string());
+    }
+    if let Some(budget) = settings.budget_usd
+        && !(budget > 0.0 && budget.is_finite())
+    {
+        return Err(format!("budget_usd must be more than 0, got {}", budget));
+    }
+    Ok(())
+}
+
+/// 1-based line and column of byte `offset` in `text`.
+fn line_and_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(name: &str) -> Settings {
+        Settings {
+            model: Some(name.to_string()),
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn parses_every_key() {
+        let settings = parse(
+            r#"
+            api_key = "sk-from-file"
+            base_url = "http://localhost:11434/v1"
+            model = "qwen2.5-coder"
+            system_prompt = "Be terse."
+            temperature = 0.2
+            top_p = 0.9
+            max_tokens = 512
+            "#,
+        )
+        .unwrap()
+        .settings();
+        assert_eq!(settings.api_key.unwrap().expose(), "sk-from-file");
+        assert_eq!(
+            settings.base_url.as_deref(),
+            Some("http://localhost:11434/v1")
+        );
+        assert_eq!(settings.model.as_deref(), Some("qwen2.5-coder"));
+        assert_eq!(settings.system_prompt.as_deref(), Some("Be terse."));
+        assert_eq!(settings.temperature, Some(0.2));
+        assert_eq!(settings.top_p, Some(0.9));
+        assert_eq!(settings.max_tokens, Some(512));
+        assert_eq!(parse("").unwrap(), ConfigFile::default());
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("deepseek_init_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[cfg(unix)]
+    fn mode(path: &Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    fn credentials() -> Credentials {
+        Credentials {
+            api_key: "sk-new".into(),
+            base_url: "https://api.deepseek.com/v1".to_string(),
+            model: "deepseek-reasoner".to_string(),
+        }
+    }
+
+    #[test]
+    fn credentials_are_saved_readable_by_the_user_alone() {
+        let dir = temp_dir("new");
+        let path = dir.join("deepseek_agent").join("config.toml");
+        save_credentials(&path, &credentials()).unwrap();
+
+        let settings = load(&path, true).unwrap().unwrap().settings();
+        assert_eq!(settings.api_key.unwrap().expose(), "sk-new");
+        assert_eq!(
+            settings.base_url.as_deref(),
+            Some("https://api.deepseek.com/v1")
+        );
+        assert_eq!(settings.model.as_deref(), Some("deepseek-reasoner"));
+        #[cfg(unix)]
+        assert_eq!(mode(&path), 0o600);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn saving_credentials_keeps_the_rest_of_the_file() {
+        let dir = temp_dir("existing");
+        let path = dir.join("config.toml");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            &path,
+            "api_key = \"sk-old\"\ntemperature = 0.3\ndefault_profile = \"local\"\n\n\
+             [profiles.local]\nbase_url = \"http://localhost:11434/v1\"\n",
+        )
+        .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        }
+
+        save_credentials(&path, &credentials()).unwrap();
+        let file = load(&path, true).unwrap().unwrap();
+        assert_eq!(file.settings().api_key.unwrap().expose(), "sk-new");
+        assert_eq!(file.settings().temperature, Some(0.3));
+        assert_eq!(file.default_profile.as_deref(), Some("local"));
+        assert_eq!(
+            file.profiles["local"].base_url.as_deref(),
+            Some("http://localhost:11434/v1")
+        );
+        #[cfg(unix)]
+        assert_eq!(mode(&path), 0o600);
+        let entries = std::fs::read_dir(&dir).unwrap().count();
+        assert_eq!(entries, 1, "no partial files left");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_config_files_are_left_alone() {
+        let dir = temp_dir("invalid");
+        let path = dir.join("config.toml");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, "model = = \"x\"").unwrap();
+
+        let err = save_credentials(&path, &credentials()).unwrap_err();
+        assert!(err.to_string().contains("not valid TOML"), "{err}");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "model = = \"x\"");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn env_keys_replace_earlier_ones() {
+        let dir = temp_dir("env");
+        let path = dir.join(".env");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, "MODEL=deepseek-chat\nOPENAI_API_KEY=sk-old\n").unwrap();
+
+        save_env_key(&path, "OPENAI_API_KEY", &"sk-new".into()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "MODEL=deepseek-chat\nOPENAI_API_KEY=sk-new\n"
+        );
+        #[cfg(unix)]
+        assert_eq!(mode(&path), 0o600);
+
+        let fresh = dir.join("fresh.env");
+        save_env_key(&fresh, "OPENAI_API_KEY", &"sk-new".into()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&fresh).unwrap(),
+            "OPENAI_API_KEY=sk-new\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn syntax_errors_report_line_and_column() {
+        let err = parse("model = \"deepseek-chat\"\ntemperature = = 1\n").unwrap_err();
+        assert!(err.starts_with("line 2, column 15:"), "{err}");
+
+        let err = parse("modle = \"x\"").unwrap_err();
+        assert!(err.starts_with("line 1, column 1:"), "{err}");
+        assert!(err.contains("unknown field `modle`"), "{err}");
+
+        let err = parse("max_tokens = \"lots\"").unwrap_err();
+        assert!(err.starts_with("line 1"), "{err}");
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected() {
+        assert!(
+            parse("temperature = 2.5")
+                .unwrap_err()
+                .contains("temperature")
+        );
+        assert!(parse("top_p = -0.1").unwrap_err().contains("top_p"));
+        assert!(parse("max_tokens = 0").unwrap_err().contains("max_tokens"));
+        assert!(
+            parse("budget_usd = 0.0")
+                .unwrap_err()
+                .contains("budget_usd")
+        );
+    }
+
+    #[test]
+    fn flag_beats_env_beats_profile_beats_file() {
+        // every combination of the four layers setting the same key
+        let layers = [
+            (Source::Flag, "from-flag"),
+            (Source::Env, "from-env"),
+            (Source::Profile, "from-profile"),
+            (Source::File, "from-file"),
+        ];
+        for mask in 0u8..16 {
+            let present: Vec<_> = layers
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| mask & (1 << i) != 0)
+                .map(|(_, layer)| *layer)
+                .collect();
+            let merged = merge(layers.iter().enumerate().map(|(i, (source, value))| {
+                let settings = if mask & (1 << i) != 0 {
+                    model(value)
+                } else {
+                    Settings::default()
+                };
+                (*source, settings)
+            }));
+
+            let expected = present.first();
+            assert_eq!(
+                merged.settings.model.as_deref(),
+                expected.map(|(_, value)| *value),
+                "mask {mask:04b}"
+            );
+            assert_eq!(
+                merged.source("model"),
+                expected.map(|(source, _)| *source),
+                "mask {mask:04b}"
+            );
+            assert_eq!(
+                merged.overrides.len(),
+                present.len().saturating_sub(1),
+                "mask {mask:04b}"
+            );
+        }
+    }
+
+    #[test]
+    fn keys_merge_independently() {
+        let flag = Settings {
+            temperature: Some(1.0),
+            ..Settings::default()
+        };
+        let env = Settings {
+            model: Some("env-model".to_string()),
+            api_key: Some("sk-env".into()),
+            ..Settings::default()
+        };
+        let file = Settings {
+            api_key: Some("sk-file".into()),
+            base_url: Some("http://localhost:8080/v1".to_string()),
+            model: Some("file-model".to_string()),
+            temperature: Some(0.1),
+            max_tokens: Some(100),
+            ..Settings::default()
+        };
+
+        let merged = merge([
+            (Source::Flag, flag),
+            (Source::Env, env),
+            (Source::File, file),
+        ]);
+        let settings = &merged.settings;
+        assert_eq!(settings.temperature, Some(1.0));
+        assert_eq!(settings.model.as_deref(), Some("env-model"));
+        assert_eq!(settings.api_key.as_ref().unwrap().expose(), "sk-env");
+        assert_eq!(
+            settings.base_url.as_deref(),
+            Some("http://localhost:8080/v1")
+        );
+        assert_eq!(settings.max_tokens, Some(100));
+        assert_eq!(settings.top_p, None);
+        assert_eq!(settings.system_prompt, None);
+
+        assert_eq!(merged.source("temperature"), Some(Source::Flag));
+        assert_eq!(merged.source("base_url"), Some(Source::File));
+        assert_eq!(merged.source("top_p"), None);
+        let overridden: Vec<_> = merged.overrides.iter().map(|o| o.to_string()).collect();
+        assert_eq!(
+            overridden,
+            [
+                "api_key from the config file overridden by environment variable",
+                "model from the config file overridden by environment variable",
+                "temperature from the config file overridden by command-line flag",
+            ]
+        );
+    }
+
+    const PROFILES: &str = r#"
+        model = "deepseek-chat"
+        default_profile = "deepseek"
+
+        [profiles.deepseek]
+        base_url = "https://api.deepseek.com/v1"
+        model = "deepseek-reasoner"
+
+        [profiles.local]
+        base_url = "http://localhost:11434/v1"
+        api_key_env = "OLLAMA_API_KEY"
+        model = "qwen2.5-coder"
+        temperature = 0.2
+    "#;
+
+    #[test]
+    fn profiles_are_selected_by_name() {
+        let file = parse(PROFILES).unwrap();
+        let (name, local) = file.profile(Some("local")).unwrap().unwrap();
+        assert_eq!(name, "local");
+        assert_eq!(local.base_url.as_deref(), Some("http://localhost:11434/v1"));
+        assert_eq!(local.api_key_var(), "OLLAMA_API_KEY");
+        assert_eq!(local.temperature, Some(0.2));
+    }
+
+    #[test]
+    fn default_profile_is_used_when_none_is_named() {
+        let file = parse(PROFILES).unwrap();
+        let (name, profile) = file.profile(None).unwrap().unwrap();
+        assert_eq!(name, "deepseek");
+        assert_eq!(profile.model.as_deref(), Some("deepseek-reasoner"));
+
+        let without_default = parse("[profiles.local]\nmodel = \"m\"").unwrap();
+        assert_eq!(without_default.profile(None).unwrap(), None);
+        assert_eq!(ConfigFile::default().profile(None).unwrap(), None);
+    }
+
+    #[test]
+    fn unknown_profile_lists_the_available_ones() {
+        let file = parse(PROFILES).unwrap();
+        let err = file.profile(Some("work")).unwrap_err().to_string();
+        assert!(err.contains("unknown profile 'work'"), "{err}");
+        assert!(err.contains("available profiles: deepseek, local"), "{err}");
+
+        let err = ConfigFile::default().profile(Some("work")).unwrap_err();
+        assert!(err.to_string().contains("no profiles are defined"), "{err}");
+    }
+
+    #[test]
+    fn profile_overrides_top_level_keys() {
+        let file = parse(PROFILES).unwrap();
+        let (_, profile) = file.profile(Some("local")).unwrap().unwrap();
+        let merged = merge([
+            (Source::Profile, profile.clone()),
+            (Source::File, file.settings()),
+        ]);
+        assert_eq!(merged.settings.model.as_deref(), Some("qwen2.5-coder"));
+        assert_eq!(merged.source("model"), Some(Source::Profile));
+        assert_eq!(merged.settings.api_key_var(), "OLLAMA_API_KEY");
+        assert_eq!(file.settings().api_key_var(), DEFAULT_API_KEY_ENV);
+    }
+
+    #[test]
+    fn profiles_are_validated() {
+        let err = parse("[profiles.hot]\ntemperature = 9.0").unwrap_err();
+        assert!(err.starts_with("profile 'hot': temperature"), "{err}");
+        let err = parse("[profiles.x]\nmodle = \"m\"").unwrap_err();
+        assert!(err.starts_with("line 2"), "{err}");
+    }
+
+    #[test]
+    fn templates_can_be_defined_inline() {
+        let file = parse(
+            r#"
+            [templates.haiku]
+            description = "A haiku"
+            prompt = "Write a haiku about {{topic}}."
+            temperature = 1.3
+            "#,
+        )
+        .unwrap();
+        let haiku = &file.templates["haiku"];
+        assert_eq!(
+            haiku.prompt.as_deref(),
+            Some("Write a haiku about {{topic}}.")
+        );
+        assert_eq!(haiku.temperature, Some(1.3));
+        let err = parse("[templates.x]\nprompt = \"p\"\ncolour = 1").unwrap_err();
+        assert!(err.starts_with("line 3"), "{err}");
+    }
+
+    #[test]
+    fn default_path_follows_xdg() {
+        assert_eq!(
+            default_config_path(Some("/xdg"), Some("/home/me")),
+            Some(PathBuf::from("/xdg/deepseek_agent/config.toml"))
+        );
+        assert_eq!(
+            default_config_path(None, Some("/home/me")),
+            Some(PathBuf::from("/home/me/.config/deepseek_agent/config.toml"))
+        );
+        assert_eq!(
+            default_config_path(Some("relative"), Some("/home/me")),
+            Some(PathBuf::from("/home/me/.config/deepseek_agent/config.toml"))
+        );
+        assert_eq!(default_config_path(None, None), None);
+    }
+
+    #[test]
+    fn missing_file_is_fine_unless_named() {
+        let path = Path::new("/definitely/not/here/config.toml");
+        assert_eq!(load(path, false).unwrap(), None);
+        assert!(matches!(
+            load(path, true),
+            Err(AgentError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn load_names_the_file_in_errors() {
+        let path =
+            std::env::temp_dir().join(format!("deepseek_settings_{}.toml", std::process::id()));
+        std::fs::write(&path, "model = \n").unwrap();
+        let err = load(&path, true).unwrap_err().to_string();
+        assert!(err.contains(&path.display().to_string()), "{err}");
+        assert!(err.contains("line 1"), "{err}");
+        std::fs::remove_file(path).unwrap();
+    }
+}
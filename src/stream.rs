@@ -0,0 +1,279 @@
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionMessageToolCallChunk, ChatCompletionToolType,
+    CreateChatCompletionStreamResponse, FinishReason, FunctionCall,
+};
+
+use async_openai::error::OpenAIError;
+use serde_json::Value;
+
+use crate::chat::{Reply, normalize_response, reasoning_content};
+use crate::usage::Usage;
+
+/// A piece of streamed text: the model's reasoning, or its answer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Delta {
+    Reasoning(String),
+    Content(String),
+}
+
+/// Collects the deltas of a streamed reply into the full text and tool calls.
+#[derive(Debug, Default)]
+pub struct StreamAccumulator {
+    content: String,
+    reasoning: String,
+    finish_reason: Option<FinishReason>,
+    refusal: String,
+    tool_calls: Vec<ChatCompletionMessageToolCall>,
+    usage: Option<Usage>,
+}
+
+impl StreamAccumulator {
+    /// Add a chunk and return the new text it carried, if any.
+    pub fn push(&mut self, chunk: &CreateChatCompletionStreamResponse) -> Option<String> {
+        // the usage chunk comes last, with no choices
+        if let Some(usage) = &chunk.usage {
+            self.usage = Some(usage.into());
+        }
+        let choice = chunk.choices.first()?;
+        if choice.finish_reason.is_some() {
+            self.finish_reason = choice.finish_reason;
+        }
+        if let Some(refusal) = &choice.delta.refusal {
+            self.refusal.push_str(refusal);
+        }
+        for tool_call in choice.delta.tool_calls.iter().flatten() {
+            self.push_tool_call(tool_call);
+        }
+
+        let delta = choice.delta.content.as_deref().filter(|d| !d.is_empty())?;
+        self.content.push_str(delta);
+        Some(delta.to_string())
+    }
+
+    /// Add a raw chunk, keeping the `reasoning_content` the typed chunk has no
+    /// field for, and return the new text it carried, reasoning first.
+    pub fn push_raw(&mut self, mut chunk: Value) -> Result<Vec<Delta>, OpenAIError> {
+        normalize_response(&mut chunk, "chat.completion.chunk");
+        let mut deltas = Vec::new();
+        if let Some(reasoning) = reasoning_content(&chunk["choices"][0]["delta"]) {
+            self.reasoning.push_str(reasoning);
+            deltas.push(Delta::Reasoning(reasoning.to_string()));
+        }
+        let chunk = serde_json::from_value(chunk).map_err(OpenAIError::JSONDeserialize)?;
+        if let Some(content) = self.push(&chunk) {
+            deltas.push(Delta::Content(content));
+        }
+        Ok(deltas)
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    /// Whether no text at all, reasoning or answer, has arrived yet.
+    pub fn is_empty(&self) -> bool {
+        self.content.is_empty() && self.reasoning.is_empty()
+    }
+
+    pub fn finish(self) -> Reply {
+        Reply {
+            content: self.content,
+            reasoning: Some(self.reasoning).filter(|r| !r.is_empty()),
+            finish_reason: self.finish_reason,
+            truncated: self.finish_reason == Some(FinishReason::Length),
+            refusal: Some(self.refusal).filter(|r| !r.is_empty()),
+            tool_calls: self.tool_calls,
+            usage: self.usage,
+            cached: false,
+            metrics: None,
+        }
+    }
+
+    /// The first chunk of a call carries its id and name; later ones with the
+    /// same index append to the arguments.
+    fn push_tool_call(&mut self, chunk: &ChatCompletionMessageToolCallChunk) {
+        let index = chunk.index as usize;
+        while self.tool_calls.len() <= index {
+            self.tool_calls.push(ChatCompletionMessageToolCall {
+                id: String::new(),
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionCall {
+                    name: String::new(),
+                    arguments: String::new(),
+                },
+            });
+        }
+        let call = &mut self.tool_calls[index];
+        if let Some(id) = &chunk.id {
+            call.id.push_str(id);
+        }
+        if let Some(function) = &chunk.function {
+            if let Some(name) = &function.name {
+                call.function.name.push_str(name);
+            }
+            if let Some(arguments) = &function.arguments {
+                call.function.arguments.push_str(arguments);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn chunk(
+        content: Option<&str>,
+        finish_reason: Option<&str>,
+    ) -> CreateChatCompletionStreamResponse {
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 1700000000,
+            "model": "deepseek-chat",
+            "choices": [{
+                "index": 0,
+                "delta": { "content": content },
+                "finish_reason": finish_reason,
+                "logprobs": null
+            }]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn accumulates_deltas_in_order() {
+        let mut acc = StreamAccumulator::default();
+        assert_eq!(acc.push(&chunk(Some("Hel"), None)).as_deref(), Some("Hel"));
+        assert_eq!(acc.push(&chunk(Some("lo"), None)).as_deref(), Some("lo"));
+        assert_eq!(acc.push(&chunk(None, Some("stop"))), None);
+
+        let reply = acc.finish();
+        assert_eq!(reply.content, "Hello");
+        assert!(!reply.truncated);
+    }
+
+    #[test]
+    fn reasoning_deltas_are_told_apart_from_answer_deltas() {
+        let mut acc = StreamAccumulator::default();
+        let mut deltas = Vec::new();
+        for line in include_str!("../tests/fixtures/reasoner_stream.jsonl").lines() {
+            deltas.extend(acc.push_raw(serde_json::from_str(line).unwrap()).unwrap());
+        }
+        assert_eq!(
+            deltas,
+            [
+                Delta::Reasoning("sqrt(1013) is about 31.8".into()),
+                Delta::Reasoning("; no prime up to 31 divides it.".into()),
+                Delta::Content("1013 is".into()),
+                Delta::Content(" prime.".into()),
+            ]
+        );
+
+        let reply = acc.finish();
+        assert_eq!(reply.content, "1013 is prime.");
+        assert_eq!(
+            reply.reasoning.as_deref(),
+            Some("sqrt(1013) is about 31.8; no prime up to 31 divides it.")
+        );
+        assert_eq!(reply.usage.unwrap().completion_tokens, 40);
+    }
+
+    #[test]
+    fn plain_chunks_have_no_reasoning() {
+        let mut acc = StreamAccumulator::default();
+        let raw = serde_json::to_value(chunk(Some("Hi"), None)).unwrap();
+        assert_eq!(acc.push_raw(raw).unwrap(), [Delta::Content("Hi".into())]);
+        assert!(!acc.is_empty());
+        assert_eq!(acc.finish().reasoning, None);
+    }
+
+    #[test]
+    fn empty_and_role_only_chunks_yield_nothing() {
+        let mut acc = StreamAccumulator::default();
+        assert_eq!(acc.push(&chunk(Some(""), None)), None);
+        assert_eq!(acc.push(&chunk(None, None)), None);
+        assert_eq!(acc.content(), "");
+    }
+
+    #[test]
+    fn length_finish_reason_marks_truncated() {
+        let mut acc = StreamAccumulator::default();
+        acc.push(&chunk(Some("Once upon"), None));
+        acc.push(&chunk(Some(" a"), Some("length")));
+
+        let reply = acc.finish();
+        assert_eq!(reply.content, "Once upon a");
+        assert_eq!(reply.finish_reason, Some(FinishReason::Length));
+        assert!(reply.truncated);
+    }
+
+    #[test]
+    fn tool_call_fragments_are_joined_by_index() {
+        let tool_chunk = |delta: serde_json::Value| -> CreateChatCompletionStreamResponse {
+            serde_json::from_value(json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion.chunk",
+                "created": 1700000000,
+                "model": "deepseek-chat",
+                "choices": [{ "index": 0, "delta": delta, "finish_reason": null, "logprobs": null }]
+            }))
+            .unwrap()
+        };
+        let mut acc = StreamAccumulator::default();
+        acc.push(&tool_chunk(json!({ "tool_calls": [{
+            "index": 0, "id": "call_1", "type": "function",
+            "function": { "name": "calculator", "arguments": "{\"expr" }
+        }]})));
+        acc.push(&tool_chunk(json!({ "tool_calls": [{
+            "index": 0, "function": { "arguments": "ession\":\"1+1\"}" }
+        }]})));
+        acc.push(&tool_chunk(json!({ "tool_calls": [{
+            "index": 1, "id": "call_2", "type": "function",
+            "function": { "name": "current_time", "arguments": "{}" }
+        }]})));
+
+        let reply = acc.finish();
+        assert_eq!(reply.content, "");
+        assert_eq!(reply.tool_calls.len(), 2);
+        assert_eq!(reply.tool_calls[0].id, "call_1");
+        assert_eq!(
+            reply.tool_calls[0].function.arguments,
+            r#"{"expression":"1+1"}"#
+        );
+        assert_eq!(reply.tool_calls[1].function.name, "current_time");
+    }
+
+    #[test]
+    fn final_usage_chunk_is_recorded() {
+        let mut acc = StreamAccumulator::default();
+        acc.push(&chunk(Some("Hi"), Some("stop")));
+        let mut usage = chunk(None, None);
+        usage.choices.clear();
+        usage.usage = serde_json::from_value(
+            json!({ "prompt_tokens": 9, "completion_tokens": 1, "total_tokens": 10 }),
+        )
+        .unwrap();
+        assert_eq!(acc.push(&usage), None);
+
+        let reply = acc.finish();
+        assert_eq!(reply.content, "Hi");
+        assert_eq!(
+            reply.usage,
+            Some(Usage {
+                prompt_tokens: 9,
+                completion_tokens: 1
+            })
+        );
+    }
+
+    #[test]
+    fn chunk_without_choices_is_ignored() {
+        let mut acc = StreamAccumulator::default();
+        let mut empty = chunk(Some("x"), None);
+        empty.choices.clear();
+        assert_eq!(acc.push(&empty), None);
+        assert_eq!(acc.content(), "");
+    }
+}
@@ -0,0 +1,310 @@
+//! A copy of every answer in a file, for `--output-file` and `/tee`.
+//!
+//! Only what the model answered goes there: no reasoning, usage or status
+//! lines. Without `--append` the file holds the last answer and is replaced
+//! only once that is complete, through a partial file renamed into place;
+//! with it, answers are added to the end as they stream, so a terminal dying
+//! mid-answer loses nothing already received. The terminal always gets the
+//! answer too: a file that can't be written is warned about once an answer
+//! and then left be.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use deepseek_tutor::stream::Delta;
+
+/// Where answers are copied, and the file of the one being written.
+#[derive(Debug)]
+pub struct Tee {
+    path: PathBuf,
+    append: bool,
+    /// Written before the first text of the answer.
+    header: Option<String>,
+    file: Option<File>,
+    /// Whether the answer written so far ends a line.
+    line_ended: bool,
+    /// Set once writing this answer failed; the rest of it isn't tried.
+    failed: bool,
+}
+
+impl Tee {
+    pub fn new(path: PathBuf, append: bool) -> Self {
+        Self {
+            path,
+            append,
+            header: None,
+            file: None,
+            line_ended: true,
+            failed: false,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Start copying an answer, under `header` if given. The file is only
+    /// opened once there is text, so a request that fails leaves it alone.
+    pub fn begin(&mut self, header: Option<String>) {
+        self.abandon();
+        self.header = header;
+        self.line_ended = true;
+        self.failed = false;
+    }
+
+    /// Copy the answer's part of `delta`.
+    pub fn delta(&mut self, delta: &Delta) {
+        if let Delta::Content(text) = delta {
+            self.write(text);
+        }
+    }
+
+    /// Copy `text`, part of the answer.
+    pub fn write(&mut self, text: &str) {
+        if self.failed || text.is_empty() {
+            return;
+        }
+        let written = self.open().and_then(|file| file.write_all(text.as_bytes()));
+        match written {
+            Ok(()) => self.line_ended = text.ends_with('\n'),
+            Err(e) => self.fail(&e),
+        }
+    }
+
+    /// End the answer: a partial file replaces the last one, an appended
+    /// answer ends its line.
+    pub fn finish(&mut self) {
+        if self.file.is_none() {
+            return;
+        }
+        let ended = if self.line_ended {
+            Ok(())
+        } else {
+            self.write_raw("\n")
+        };
+        let finished = ended
+            .and_then(|()| self.file.take().expect("checked above").sync_all())
+            .and_then(|()| match self.append {
+                true => Ok(()),
+                false => std::fs::rename(self.partial(), &self.path),
+            });
+        if let Err(e) = finished {
+            self.fail(&e);
+        }
+    }
+
+    /// The file answers go to while being written.
+    fn open(&mut self) -> std::io::Result<&mut File> {
+        if self.file.is_none() {
+            let file = if self.append {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?;
+                // answers are kept a blank line apart
+                if let Some(header) = &mut self.header
+                    && file.metadata()?.len() > 0
+                {
+                    header.insert(0, '\n');
+                }
+                file
+            } else {
+                File::create(self.partial())?
+            };
+            self.file = Some(file);
+            if let Some(header) = self.header.take() {
+                self.write_raw(&header)?;
+            }
+        }
+        Ok(self.file.as_mut().expect("opened above"))
+    }
+
+    fn write_raw(&mut self, text: &str) -> std::io::Result<()> {
+        self.file
+            .as_mut()
+            .expect("only written once open")
+            .write_all(text.as_bytes())
+    }
+
+    fn partial(&self) -> PathBuf {
+        let file_name = self.path.file_name().map_or_else(
+            || "answer".into(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        self.path.with_file_name(format!(".{}.partial", file_name))
+    }
+
+    /// Warn that the answer couldn't be copied, and stop trying until the next.
+    fn fail(&mut self, error: &std::io::Error) {
+        eprintln!(
+            "Warning: could not write the answer to {}: {}; it is only printed here.",
+            self.path.display(),
+            error
+        );
+        self.failed = true;
+        self.abandon();
+    }
+
+    /// Give up on the answer being written, as when its request failed or
+    /// was interrupted: the file it would have replaced is kept as it was,
+    /// and an appended answer keeps what arrived.
+    pub fn cancel(&mut self) {
+        self.abandon();
+        self.header = None;
+    }
+
+    /// Drop an answer not finished; the file it would have replaced is kept.
+    fn abandon(&mut self) {
+        if self.file.take().is_some() && !self.append {
+            let _ = std::fs::remove_file(self.partial());
+        }
+    }
+}
+
+impl Drop for Tee {
+    /// An answer never finished doesn't replace the file; an appended one
+    /// still ends its line.
+    fn drop(&mut self) {
+        match self.append {
+            true => self.finish(),
+            false => self.abandon(),
+        }
+    }
+}
+
+/// The line each answer copied in the REPL starts with.
+pub fn header(at: DateTime<Utc>, model: &str) -> String {
+    format!("## {} · {}\n\n", at.format("%Y-%m-%d %H:%M:%S UTC"), model)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("deepseek_tee_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("answer.md")
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    fn remove(path: &Path) {
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn only_the_answer_is_copied() {
+        let path = temp_path("content");
+        let mut tee = Tee::new(path.clone(), false);
+        tee.begin(None);
+        tee.delta(&Delta::Reasoning("thinking it over".into()));
+        tee.delta(&Delta::Content("1013 ".into()));
+        tee.delta(&Delta::Reasoning("more".into()));
+        tee.delta(&Delta::Content("is prime.".into()));
+        tee.finish();
+        assert_eq!(read(&path), "1013 is prime.\n");
+        remove(&path);
+    }
+
+    #[test]
+    fn answers_replace_the_file_only_once_complete() {
+        let path = temp_path("atomic");
+        let mut tee = Tee::new(path.clone(), false);
+        tee.begin(None);
+        tee.write("first\n");
+        tee.finish();
+
+        tee.begin(None);
+        tee.write("second, half");
+        // the earlier answer is there until this one is done
+        assert_eq!(read(&path), "first\n");
+        assert!(tee.partial().exists());
+        tee.finish();
+        assert_eq!(read(&path), "second, half\n");
+        assert!(!tee.partial().exists());
+
+        // an answer that never came leaves the last one
+        tee.begin(None);
+        tee.finish();
+        tee.begin(None);
+        tee.write("abandoned");
+        tee.begin(None);
+        assert_eq!(read(&path), "second, half\n");
+        assert!(!tee.partial().exists());
+        remove(&path);
+    }
+
+    #[test]
+    fn failed_answers_leave_the_last_file_untouched() {
+        let path = temp_path("cancel");
+        let earlier = "an earlier answer\r\nwith its own line ends, and no final one";
+        std::fs::write(&path, earlier).unwrap();
+
+        let mut tee = Tee::new(path.clone(), false);
+        tee.begin(None);
+        tee.write("half an ans");
+        tee.cancel();
+        assert_eq!(std::fs::read(&path).unwrap(), earlier.as_bytes());
+        assert!(!tee.partial().exists());
+
+        // nor does one cut short by the tee going away
+        tee.begin(None);
+        tee.write("half another");
+        let partial = tee.partial();
+        drop(tee);
+        assert_eq!(std::fs::read(&path).unwrap(), earlier.as_bytes());
+        assert!(!partial.exists());
+        remove(&path);
+    }
+
+    #[test]
+    fn appended_answers_accumulate_under_headers() {
+        let path = temp_path("append");
+        std::fs::write(&path, "from an earlier session\n").unwrap();
+        let at = Utc.with_ymd_and_hms(2026, 10, 14, 9, 30, 0).unwrap();
+        let mut tee = Tee::new(path.clone(), true);
+        tee.begin(Some(header(at, "deepseek-chat")));
+        tee.write("Yes.");
+        // appending writes straight to the file
+        assert!(read(&path).ends_with("Yes."));
+        tee.finish();
+        tee.begin(Some(header(at, "deepseek-reasoner")));
+        tee.write("No.\n");
+        drop(tee);
+
+        assert_eq!(
+            read(&path),
+            "from an earlier session\n\
+             \n## 2026-10-14 09:30:00 UTC · deepseek-chat\n\nYes.\n\
+             \n## 2026-10-14 09:30:00 UTC · deepseek-reasoner\n\nNo.\n"
+        );
+        remove(&path);
+    }
+
+    #[test]
+    fn unwritable_files_are_given_up_on() {
+        let base = temp_path("unwritable");
+        let path = base.join("missing").join("answer.md");
+        let mut tee = Tee::new(path.clone(), false);
+        tee.begin(None);
+        tee.write("one");
+        assert!(tee.failed);
+        tee.write("two");
+        tee.finish();
+        assert!(!path.exists());
+
+        // the next answer is tried again
+        tee.begin(None);
+        assert!(!tee.failed);
+        drop(tee);
+        remove(&base);
+    }
+}
@@ -0,0 +1,452 @@
+//! Named prompt templates with `{{variable}}` placeholders.
+//!
+//! A template is a Markdown file in the templates directory, named after the
+//! file, or an entry in the config file's `[templates]` table. Either can set
+//! the model, sampling parameters and system prompt for the request; a file
+//! does so in TOML front matter between `+++` lines.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::error::AgentError;
+use crate::settings::{self, Settings};
+
+/// Location of the templates directory below the user's config directory.
+pub const TEMPLATES_DIR: &str = "deepseek_agent/templates";
+
+/// A template as written: its prompt and what it overrides.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateSpec {
+    /// One line on what the template is for, shown by `templates list`.
+    pub description: Option<String>,
+    pub model: Option<String>,
+    pub system_prompt: Option<String>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    /// The prompt, for templates in the config file; a file's is its body.
+    pub prompt: Option<String>,
+}
+
+/// A named prompt ready to be filled in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    pub name: String,
+    pub description: Option<String>,
+    /// Request settings the template overrides, as a settings layer.
+    pub settings: Settings,
+    pub body: String,
+    /// The file it was read from; `None` for the config file.
+    pub path: Option<PathBuf>,
+}
+
+/// A filled-in template.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rendered {
+    pub prompt: String,
+    /// Variables given that the template has no placeholder for.
+    pub unused: Vec<String>,
+}
+
+impl Template {
+    /// A template from the config file's `[templates]` table.
+    pub fn from_spec(name: &str, spec: TemplateSpec) -> Result<Self, String> {
+        let Some(body) = spec.prompt.clone() else {
+            return Err("missing prompt".to_string());
+        };
+        Self::build(name, spec, body, None)
+    }
+
+    /// A template file: optional `+++` front matter, then the prompt.
+    pub fn parse(name: &str, text: &str, path: Option<PathBuf>) -> Result<Self, String> {
+        let (spec, body) = match split_front_matter(text)? {
+            Some((front, body)) => {
+                let spec: TemplateSpec = toml::from_str(front)
+                    .map_err(|e| format!("front matter: {}", e.message().trim_end()))?;
+                if spec.prompt.is_some() {
+                    return Err(
+                        "front matter: prompt is the text after the front matter".to_string()
+                    );
+                }
+                (spec, body)
+            }
+            None => (TemplateSpec::default(), text),
+        };
+        Self::build(name, spec, body.trim().to_string(), path)
+    }
+
+    fn build(
+        name: &str,
+        spec: TemplateSpec,
+        body: String,
+        path: Option<PathBuf>,
+    ) -> Result<Self, String> {
+        let settings = Settings {
+            model: spec.model,
+            system_prompt: spec.system_prompt,
+            temperature: spec.temperature,
+            top_p: spec.top_p,
+            max_tokens: spec.max_tokens,
+            ..Settings::default()
+        };
+        settings::validate(&settings)?;
+        Ok(Self {
+            name: name.to_string(),
+            description: spec.description,
+            settings,
+            body,
+            path,
+        })
+    }
+
+    /// Names of the placeholders in the body, each once, in order.
+    pub fn placeholders(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        for (_, name) in placeholders(&self.body) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    /// Fill in every placeholder from `vars`; later duplicates win.
+    ///
+    /// Placeholders without a value are an error naming all of them.
+    pub fn render(&self, vars: &[(String, String)]) -> Result<Rendered, AgentError> {
+        let names = self.placeholders();
+        let missing: Vec<&str> = names
+            .iter()
+            .copied()
+            .filter(|name| !vars.iter().any(|(var, _)| var == name))
+            .collect();
+        if !missing.is_empty() {
+            return Err(AgentError::Input(format!(
+                "template '{}' needs a value for {}; pass {}",
+                self.name,
+                missing
+                    .iter()
+                    .map(|name| format!("{{{{{}}}}}", name))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                missing
+                    .iter()
+                    .map(|name| format!("--var {}=...", name))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            )));
+        }
+
+        let mut prompt = String::with_capacity(self.body.len());
+        let mut rest = 0;
+        for (range, name) in placeholders(&self.body) {
+            prompt.push_str(&self.body[rest..range.start]);
+            let value = vars.iter().rev().find(|(var, _)| var == name).unwrap();
+            prompt.push_str(&value.1);
+            rest = range.end;
+        }
+        prompt.push_str(&self.body[rest..]);
+
+        let mut unused = Vec::new();
+        for (var, _) in vars {
+            if !names.contains(&var.as_str()) && !unused.contains(var) {
+                unused.push(var.clone());
+            }
+        }
+        Ok(Rendered { prompt, unused })
+    }
+}
+
+/// Split `+++` front matter from the rest, if the text starts with it.
+fn split_front_matter(text: &str) -> Result<Option<(&str, &str)>, String> {
+    let Some(rest) = text
+        .strip_prefix("+++\n")
+        .or_else(|| text.strip_prefix("+++\r\n"))
+    else {
+        return Ok(None);
+    };
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == "+++" {
+            return Ok(Some((&rest[..offset], &rest[offset + line.len()..])));
+        }
+        offset += line.len();
+    }
+    Err("front matter is not closed with +++".to_string())
+}
+
+/// Every `{{name}}` in `body`, with its byte range. Braces around anything
+/// that isn't a name, like `{{ }}`, are left alone.
+fn placeholders(body: &str) -> Vec<(std::ops::Range<usize>, &str)> {
+    let mut found = Vec::new();
+    let mut from = 0;
+    while let Some(open) = body[from..].find("{{").map(|at| from + at) {
+        let Some(close) = body[open + 2..].find("}}").map(|at| open + 2 + at) else {
+            break;
+        };
+        let name = body[open + 2..close].trim();
+        let is_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'));
+        if is_name {
+            found.push((open..close + 2, name));
+            from = close + 2;
+        } else {
+            from = open + 2;
+        }
+    }
+    found
+}
+
+/// `$XDG_CONFIG_HOME/deepseek_agent/templates`, falling back to `~/.config`.
+pub fn default_templates_dir(xdg_config_home: Option<&str>, home: Option<&str>) -> Option<PathBuf> {
+    Some(settings::config_home(xdg_config_home, home)?.join(TEMPLATES_DIR))
+}
+
+/// Every template: those in the config file, then the `.md` files in `dir`,
+/// which win over a config file template of the same name. A missing
+/// directory has no templates.
+pub fn load(
+    dir: Option<&Path>,
+    inline: &BTreeMap<String, TemplateSpec>,
+) -> Result<BTreeMap<String, Template>, AgentError> {
+    let mut templates = BTreeMap::new();
+    for (name, spec) in inline {
+        let template = Template::from_spec(name, spec.clone()).map_err(|message| {
+            AgentError::InvalidConfig(format!("config file template '{}': {}", name, message))
+        })?;
+        templates.insert(name.clone(), template);
+    }
+
+    let Some(dir) = dir else {
+        return Ok(templates);
+    };
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(templates),
+        Err(e) => {
+            return Err(AgentError::InvalidConfig(format!(
+                "could not read templates directory '{}': {}",
+                dir.display(),
+                e
+            )));
+        }
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let Some(name) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .filter(|_| path.extension().is_some_and(|ext| ext == "md"))
+        else {
+            continue;
+        };
+        let invalid = |message: String| {
+            AgentError::InvalidConfig(format!("template '{}': {}", path.display(), message))
+        };
+        let text = std::fs::read_to_string(&path).map_err(|e| invalid(e.to_string()))?;
+        let template = Template::parse(name, &text, Some(path.clone())).map_err(invalid)?;
+        templates.insert(name.to_string(), template);
+    }
+    Ok(templates)
+}
+
+/// The template called `name`, or an error listing the ones there are.
+pub fn find<'a>(
+    templates: &'a BTreeMap<String, Template>,
+    name: &str,
+) -> Result<&'a Template, AgentError> {
+    match templates.get(name) {
+        Some(template) => Ok(template),
+        None if templates.is_empty() => Err(AgentError::InvalidConfig(format!(
+            "unknown template '{}': no templates are defined",
+            name
+        ))),
+        None => Err(AgentError::InvalidConfig(format!(
+            "unknown template '{}'; available templates: {}",
+            name,
+            templates.keys().cloned().collect::<Vec<_>>().join(", ")
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    const REVIEW: &str = "+++
+description = \"Review a file\"
+model = \"deepseek-reasoner\"
+temperature = 0.2
+system_prompt = \"You are a strict reviewer.\"
++++
+
+Review {{file}}, which is written in {{ lang }}.
+Point out bugs in {{file}} first.
+";
+
+    #[test]
+    fn front_matter_sets_overrides_and_the_rest_is_the_prompt() {
+        let template = Template::parse("review", REVIEW, None).unwrap();
+        assert_eq!(template.description.as_deref(), Some("Review a file"));
+        assert_eq!(
+            template.settings.model.as_deref(),
+            Some("deepseek-reasoner")
+        );
+        assert_eq!(template.settings.temperature, Some(0.2));
+        assert_eq!(
+            template.settings.system_prompt.as_deref(),
+            Some("You are a strict reviewer.")
+        );
+        assert_eq!(template.settings.base_url, None);
+        assert!(template.body.starts_with("Review {{file}}"));
+        assert_eq!(template.placeholders(), ["file", "lang"]);
+    }
+
+    #[test]
+    fn templates_without_front_matter_override_nothing() {
+        let template = Template::parse("plain", "Explain {{topic}}.\n", None).unwrap();
+        assert_eq!(template.settings, Settings::default());
+        assert_eq!(template.body, "Explain {{topic}}.");
+    }
+
+    #[test]
+    fn bad_front_matter_is_rejected() {
+        for (text, expected) in [
+            ("+++\nmodel = \"x\"\nExplain.", "not closed"),
+            ("+++\ncolour = \"red\"\n+++\nExplain.", "unknown field"),
+            ("+++\ntemperature = 3.0\n+++\nExplain.", "temperature"),
+            ("+++\nprompt = \"hi\"\n+++\nExplain.", "prompt is the text"),
+        ] {
+            let err = Template::parse("bad", text, None).unwrap_err();
+            assert!(err.contains(expected), "{text:?}: {err}");
+        }
+    }
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        let template = Template::parse("review", REVIEW, None).unwrap();
+        let rendered = template
+            .render(&vars(&[("file", "src/main.rs"), ("lang", "rust")]))
+            .unwrap();
+        assert_eq!(
+            rendered.prompt,
+            "Review src/main.rs, which is written in rust.\nPoint out bugs in src/main.rs first."
+        );
+        assert!(rendered.unused.is_empty());
+    }
+
+    #[test]
+    fn missing_variables_are_all_named() {
+        let template = Template::parse("review", REVIEW, None).unwrap();
+        let err = template.render(&[]).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "template 'review' needs a value for {{file}}, {{lang}}; \
+             pass --var file=... --var lang=..."
+        );
+    }
+
+    #[test]
+    fn extra_variables_are_reported_and_later_values_win() {
+        let template = Template::parse("t", "Hi {{name}}", None).unwrap();
+        let rendered = template
+            .render(&vars(&[("name", "a"), ("name", "b"), ("tone", "dry")]))
+            .unwrap();
+        assert_eq!(rendered.prompt, "Hi b");
+        assert_eq!(rendered.unused, ["tone"]);
+    }
+
+    #[test]
+    fn braces_that_are_not_placeholders_stay() {
+        let template = Template::parse("t", "fn f() {{ }} {{x}} {{ a b }} {{", None).unwrap();
+        assert_eq!(template.placeholders(), ["x"]);
+        let rendered = template.render(&vars(&[("x", "1")])).unwrap();
+        assert_eq!(rendered.prompt, "fn f() {{ }} 1 {{ a b }} {{");
+    }
+
+    #[test]
+    fn files_win_over_config_file_templates() {
+        let dir = std::env::temp_dir().join(format!("deepseek_templates_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("review.md"), REVIEW).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a template").unwrap();
+        let inline = BTreeMap::from([
+            (
+                "review".to_string(),
+                TemplateSpec {
+                    prompt: Some("inline review".to_string()),
+                    ..TemplateSpec::default()
+                },
+            ),
+            (
+                "haiku".to_string(),
+                TemplateSpec {
+                    prompt: Some("A haiku about {{topic}}".to_string()),
+                    temperature: Some(1.2),
+                    ..TemplateSpec::default()
+                },
+            ),
+        ]);
+
+        let templates = load(Some(&dir), &inline).unwrap();
+        assert_eq!(templates.keys().collect::<Vec<_>>(), ["haiku", "review"]);
+        assert_eq!(templates["review"].path, Some(dir.join("review.md")));
+        assert_eq!(templates["haiku"].settings.temperature, Some(1.2));
+        assert_eq!(templates["haiku"].path, None);
+
+        let missing = load(Some(&dir.join("missing")), &BTreeMap::new()).unwrap();
+        assert!(missing.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn config_file_templates_need_a_prompt() {
+        let inline = BTreeMap::from([("empty".to_string(), TemplateSpec::default())]);
+        let err = load(None, &inline).unwrap_err().to_string();
+        assert!(err.contains("template 'empty': missing prompt"), "{err}");
+    }
+
+    #[test]
+    fn unknown_names_list_the_templates() {
+        let templates = load(None, &BTreeMap::new()).unwrap();
+        let err = find(&templates, "review").unwrap_err().to_string();
+        assert!(err.contains("no templates are defined"), "{err}");
+
+        let inline = BTreeMap::from([(
+            "haiku".to_string(),
+            TemplateSpec {
+                prompt: Some("A haiku".to_string()),
+                ..TemplateSpec::default()
+            },
+        )]);
+        let templates = load(None, &inline).unwrap();
+        let err = find(&templates, "review").unwrap_err().to_string();
+        assert!(err.ends_with("available templates: haiku"), "{err}");
+        assert_eq!(find(&templates, "haiku").unwrap().body, "A haiku");
+    }
+
+    #[test]
+    fn directory_sits_next_to_the_config_file() {
+        assert_eq!(
+            default_templates_dir(Some("/xdg"), Some("/home/me")),
+            Some(PathBuf::from("/xdg/deepseek_agent/templates"))
+        );
+        assert_eq!(
+            default_templates_dir(None, Some("/home/me")),
+            Some(PathBuf::from("/home/me/.config/deepseek_agent/templates"))
+        );
+        assert_eq!(default_templates_dir(None, None), None);
+    }
+}
@@ -0,0 +1,279 @@
+use anyhow::{Context, anyhow, bail};
+use async_trait::async_trait;
+use serde_json::{Value, json};
+
+use super::Tool;
+
+/// Evaluates arithmetic expressions, which models are unreliable at doing in their head.
+pub struct Calculator;
+
+#[async_trait]
+impl Tool for Calculator {
+    fn name(&self) -> &str {
+        "calculator"
+    }
+
+    fn description(&self) -> &str {
+        "Evaluate an arithmetic expression. Supports + - * / % ^, parentheses, \
+         the constants pi and e, and sqrt, abs, exp, ln, log10, sin, cos, tan."
+    }
+
+    fn json_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "expression": {
+                    "type": "string",
+                    "description": "The expression to evaluate, e.g. \"(3 + 4) * 2 ^ 10\""
+                }
+            },
+            "required": ["expression"]
+        })
+    }
+
+    async fn execute(&self, args: Value) -> anyhow::Result<String> {
+        let expression = args["expression"]
+            .as_str()
+            .context("missing string argument 'expression'")?;
+        Ok(format_number(evaluate(expression)?))
+    }
+}
+
+/// Evaluate `expression` with the usual precedence; `^` binds tightest and
+/// associates to the right.
+pub fn evaluate(expression: &str) -> anyhow::Result<f64> {
+    let mut parser = Parser {
+        input: expression.as_bytes(),
+        pos: 0,
+    };
+    let value = parser.expr()?;
+    parser.skip_whitespace();
+    if parser.pos < parser.input.len() {
+        bail!(
+            "unexpected '{}' at position {}",
+            parser.input[parser.pos] as char,
+            parser.pos
+        );
+    }
+    if !value.is_finite() {
+        bail!("result is not a finite number");
+    }
+    Ok(value)
+}
+
+/// Whole numbers print without a fractional part.
+fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{}", value)
+    }
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn expr(&mut self) -> anyhow::Result<f64> {
+        let mut value = self.term()?;
+        loop {
+            if self.eat(b'+') {
+                value += self.term()?;
+            } else if self.eat(b'-') {
+                value -= self.term()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> anyhow::Result<f64> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat(b'*') {
+                value *= self.unary()?;
+            } else if self.eat(b'/') {
+                let divisor = self.unary()?;
+                if divisor == 0.0 {
+                    bail!("division by zero");
+                }
+                value /= divisor;
+            } else if self.eat(b'%') {
+                let divisor = self.unary()?;
+                if divisor == 0.0 {
+                    bail!("division by zero");
+                }
+                value %= divisor;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> anyhow::Result<f64> {
+        if self.eat(b'-') {
+            Ok(-self.unary()?)
+        } else if self.eat(b'+') {
+            self.unary()
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> anyhow::Result<f64> {
+        let base = self.atom()?;
+        if self.eat(b'^') {
+            Ok(base.powf(self.unary()?))
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn atom(&mut self) -> anyhow::Result<f64> {
+        self.skip_whitespace();
+        match self.input.get(self.pos) {
+            Some(b'(') => {
+                self.pos += 1;
+                let value = self.expr()?;
+                if !self.eat(b')') {
+                    bail!("missing ')' at position {}", self.pos);
+                }
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_digit() || *c == b'.' => self.number(),
+            Some(c) if c.is_ascii_alphabetic() => self.identifier(),
+            Some(c) => bail!("unexpected '{}' at position {}", *c as char, self.pos),
+            None => bail!("unexpected end of expression"),
+        }
+    }
+
+    fn number(&mut self) -> anyhow::Result<f64> {
+        let start = self.pos;
+        while self
+            .input
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_digit() || *c == b'.')
+        {
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.input[start..self.pos])?;
+        text.parse()
+            .map_err(|_| anyhow!("invalid number '{}' at position {}", text, start))
+    }
+
+    fn identifier(&mut self) -> anyhow::Result<f64> {
+        let start = self.pos;
+        while self
+            .input
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_alphanumeric())
+        {
+            self.pos += 1;
+        }
+        let name = std::str::from_utf8(&self.input[start..self.pos])?;
+        match name {
+            "pi" => return Ok(std::f64::consts::PI),
+            "e" => return Ok(std::f64::consts::E),
+            _ => {}
+        }
+        let function: fn(f64) -> f64 = match name {
+            "sqrt" => f64::sqrt,
+            "abs" => f64::abs,
+            "exp" => f64::exp,
+            "ln" => f64::ln,
+            "log10" => f64::log10,
+            "sin" => f64::sin,
+            "cos" => f64::cos,
+            "tan" => f64::tan,
+            _ => bail!("unknown name '{}'", name),
+        };
+        if !self.eat(b'(') {
+            bail!("expected '(' after {}", name);
+        }
+        let argument = self.expr()?;
+        if !self.eat(b')') {
+            bail!("missing ')' at position {}", self.pos);
+        }
+        Ok(function(argument))
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        if self.input.get(self.pos) == Some(&byte) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .input
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_operator_precedence() {
+        assert_eq!(evaluate("1 + 2 * 3").unwrap(), 7.0);
+        assert_eq!(evaluate("(1 + 2) * 3").unwrap(), 9.0);
+        assert_eq!(evaluate("2 ^ 3 ^ 2").unwrap(), 512.0);
+        assert_eq!(evaluate("-2 ^ 2").unwrap(), -4.0);
+        assert_eq!(evaluate("10 % 4 - 7 / 2").unwrap(), -1.5);
+    }
+
+    #[test]
+    fn functions_and_constants() {
+        assert_eq!(evaluate("sqrt(16) + abs(-2)").unwrap(), 6.0);
+        assert!((evaluate("cos(pi)").unwrap() + 1.0).abs() < 1e-12);
+        assert!((evaluate("ln(e)").unwrap() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for (expression, message) in [
+            ("1 +", "unexpected end"),
+            ("(1 + 2", "missing ')'"),
+            ("2 $ 3", "unexpected '$'"),
+            ("foo(1)", "unknown name 'foo'"),
+            ("1..2", "invalid number"),
+            ("5 / (2 - 2)", "division by zero"),
+            ("sqrt(-1)", "not a finite number"),
+        ] {
+            let err = evaluate(expression).unwrap_err().to_string();
+            assert!(err.contains(message), "{expression}: {err}");
+        }
+    }
+
+    #[test]
+    fn whole_results_print_as_integers() {
+        assert_eq!(format_number(42.0), "42");
+        assert_eq!(format_number(-3.0), "-3");
+        assert_eq!(format_number(0.25), "0.25");
+        assert_eq!(format_number(1e20), "100000000000000000000");
+    }
+
+    #[tokio::test]
+    async fn execute_requires_an_expression() {
+        let err = Calculator.execute(json!({})).await.unwrap_err();
+        assert!(err.to_string().contains("'expression'"));
+        assert_eq!(
+            Calculator
+                .execute(json!({"expression": "2 ^ 10"}))
+                .await
+                .unwrap(),
+            "1024"
+        );
+    }
+}
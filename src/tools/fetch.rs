@@ -0,0 +1,691 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{Context, anyhow, bail};
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use reqwest::redirect::Policy;
+use serde_json::{Value, json};
+use url::{Host, Url};
+
+use super::Tool;
+use super::shell::truncate;
+use crate::error::AgentError;
+use crate::network::NetworkConfig;
+
+/// Bytes of a page's text `fetch_url` returns at most, unless configured otherwise.
+pub const DEFAULT_FETCH_MAX_BYTES: usize = 16 * 1024;
+
+/// Bytes of a response read at most, before it is turned into text.
+const MAX_DOWNLOAD_BYTES: usize = 2 * 1024 * 1024;
+
+/// Redirects followed before giving up.
+const MAX_REDIRECTS: usize = 10;
+
+/// How long a fetch may take, how much of it is kept, and where it may go.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FetchConfig {
+    pub timeout: Duration,
+    /// Bytes of text returned to the model.
+    pub max_bytes: usize,
+    /// Allow localhost, private and link-local addresses.
+    pub allow_local: bool,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(20),
+            max_bytes: DEFAULT_FETCH_MAX_BYTES,
+            allow_local: false,
+        }
+    }
+}
+
+/// Looks up the addresses of a host and port.
+type Resolver = fn(String, u16) -> BoxFuture<'static, std::io::Result<Vec<SocketAddr>>>;
+
+fn lookup(host: String, port: u16) -> BoxFuture<'static, std::io::Result<Vec<SocketAddr>>> {
+    Box::pin(async move {
+        Ok(tokio::net::lookup_host((host.as_str(), port))
+            .await?
+            .collect())
+    })
+}
+
+/// GETs a web page and returns it as plain text.
+///
+/// Redirects are followed by hand so every hop is checked with [`check_url`]
+/// and [`blocked_address`], not just the first, and each request connects to
+/// the addresses that were checked rather than looking the host up again.
+pub struct FetchUrl {
+    config: FetchConfig,
+    network: NetworkConfig,
+    /// For requests that go wherever the host resolves, with `allow_local`.
+    client: reqwest::Client,
+    resolve: Resolver,
+}
+
+impl FetchUrl {
+    /// A fetch tool sending requests the way `network` says, through its
+    /// proxy if it has one.
+    pub fn new(config: FetchConfig, network: &NetworkConfig) -> Result<Self, AgentError> {
+        Ok(Self {
+            client: network.client_with(client_builder(&config))?,
+            network: network.clone(),
+            config,
+            resolve: lookup,
+        })
+    }
+
+    /// Fail unless `url` may be fetched, resolving its host to check where
+    /// it really points. Returns the host and the addresses checked, for the
+    /// request to be pinned to, unless local addresses are allowed anyway.
+    async fn check(&self, url: &Url) -> anyhow::Result<Option<(String, Vec<SocketAddr>)>> {
+        check_url(url, self.config.allow_local)?;
+        if self.config.allow_local {
+            return Ok(None);
+        }
+        let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+            bail!("{} has no host", url);
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addresses = (self.resolve)(host.to_string(), port)
+            .await
+            .with_context(|| format!("could not resolve {}", host))?;
+        if addresses.is_empty() {
+            bail!("could not resolve {}", host);
+        }
+        for address in &addresses {
+            if blocked_address(address.ip()) {
+                bail!(
+                    "refused: {} resolves to {}, a local address",
+                    host,
+                    address.ip()
+                );
+            }
+        }
+        Ok(Some((host.to_string(), addresses)))
+    }
+
+    /// A client that connects to `addresses` for `host` instead of looking
+    /// it up again, so the answer can't change between the check and the
+    /// request.
+    fn pinned(&self, host: &str, addresses: &[SocketAddr]) -> anyhow::Result<reqwest::Client> {
+        let builder = client_builder(&self.config).resolve_to_addrs(host, addresses);
+        Ok(self.network.client_with(builder)?)
+    }
+
+    /// GET `url`, following redirects, and return the final URL and response.
+    async fn follow(&self, mut url: Url) -> anyhow::Result<(Url, reqwest::Response)> {
+        for _ in 0..=MAX_REDIRECTS {
+            let client = match self.check(&url).await? {
+                Some((host, addresses)) => self.pinned(&host, &addresses)?,
+                None => self.client.clone(),
+            };
+            let response = client
+                .get(url.clone())
+                .send()
+                .await
+                .map_err(|e| anyhow!("could not fetch {}: {}", url, e.without_url()))?;
+            let location = match response.headers().get(LOCATION) {
+                Some(location) if response.status().is_redirection() => location,
+                _ => return Ok((url, response)),
+            };
+            let location = location.to_str().context("redirected to a malformed URL")?;
+            url = url
+                .join(location)
+                .with_context(|| format!("redirected to '{}', which is not a URL", location))?;
+        }
+        bail!("gave up after {} redirects", MAX_REDIRECTS)
+    }
+}
+
+fn client_builder(config: &FetchConfig) -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .redirect(Policy::none())
+        .timeout(config.timeout)
+        .user_agent(concat!("deepseek_agent/", env!("CARGO_PKG_VERSION")))
+}
+
+#[async_trait]
+impl Tool for FetchUrl {
+    fn name(&self) -> &str {
+        "fetch_url"
+    }
+
+    fn description(&self) -> &str {
+        "Fetch a web page with an HTTP GET and return its text, without markup, \
+         along with the HTTP status and the final URL after redirects."
+    }
+
+    fn json_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "url": {
+                    "type": "string",
+                    "description": "The http:// or https:// URL to fetch"
+                }
+            },
+            "required": ["url"]
+        })
+    }
+
+    async fn execute(&self, args: Value) -> anyhow::Result<String> {
+        let raw = args["url"]
+            .as_str()
+            .context("missing string argument 'url'")?;
+        let url = Url::parse(raw).with_context(|| format!("'{}' is not a URL", raw))?;
+        let (url, mut response) = self.follow(url).await?;
+
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        if !is_text(&mime) {
+            bail!(
+                "{} is {}, not text; only web pages and text can be read",
+                url,
+                if mime.is_empty() {
+                    "of no content type"
+                } else {
+                    &mime
+                }
+            );
+        }
+
+        let mut body = Vec::new();
+        let mut cut = false;
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| anyhow!("could not read {}: {}", url, e.without_url()))?
+        {
+            body.extend_from_slice(&chunk);
+            if body.len() > MAX_DOWNLOAD_BYTES {
+                body.truncate(MAX_DOWNLOAD_BYTES);
+                cut = true;
+                break;
+            }
+        }
+        let body = String::from_utf8_lossy(&body);
+        let mut text = if mime.contains("html") {
+            html_to_text(&body)
+        } else {
+            body.into_owned()
+        };
+        if cut {
+            text.push_str("\n[stopped reading after 2 MiB]");
+        }
+        Ok(format!(
+            "status: {}\nurl: {}\ncontent-type: {}\n\n{}",
+            status,
+            url,
+            content_type,
+            truncate(&text, self.config.max_bytes)
+        ))
+    }
+}
+
+fn is_text(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || mime.ends_with("+xml")
+        || mime.ends_with("+json")
+        || matches!(
+            mime,
+            "application/json"
+                | "application/xml"
+                | "application/xhtml+xml"
+                | "application/javascript"
+        )
+}
+
+/// Fail unless `url` is http or https and, without `allow_local`, names a
+/// host that isn't obviously local. Names are checked as written; where they
+/// resolve to is checked with [`blocked_address`].
+pub fn check_url(url: &Url, allow_local: bool) -> anyhow::Result<()> {
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!(
+            "refused: only http and https URLs can be fetched, not {}",
+            url.scheme()
+        );
+    }
+    if allow_local {
+        return Ok(());
+    }
+    let local = match url.host() {
+        None => bail!("{} has no host", url),
+        Some(Host::Domain(name)) => {
+            let name = name.trim_end_matches('.').to_ascii_lowercase();
+            name == "localhost" || name.ends_with(".localhost")
+        }
+        Some(Host::Ipv4(ip)) => blocked_address(IpAddr::V4(ip)),
+        Some(Host::Ipv6(ip)) => blocked_address(IpAddr::V6(ip)),
+    };
+    if local {
+        bail!(
+            "refused: {} is a local address; start with --allow-local-fetch to fetch it",
+            url.host_str().unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+/// Whether `ip` is loopback, private (RFC 1918 or unique local), link-local,
+/// shared, or otherwise not on the public internet.
+pub fn blocked_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => blocked_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => blocked_v4(ip),
+            None => blocked_v6(ip),
+        },
+    }
+}
+
+fn blocked_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        // shared address space, 100.64.0.0/10
+        || (a == 100 && (64..128).contains(&b))
+}
+
+fn blocked_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // unique local, fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // link-local, fe80::/10
+        || (first & 0xffc0) == 0xfe80
+}
+
+/// Elements whose contents are never shown.
+const HIDDEN: [&str; 5] = ["script", "style", "noscript", "template", "svg"];
+
+/// Elements that start a new line.
+const LINES: [&str; 6] = ["br", "div", "li", "tr", "dt", "dd"];
+
+/// Elements set apart by a blank line.
+const PARAGRAPHS: [&str; 19] = [
+    "p",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "ul",
+    "ol",
+    "dl",
+    "table",
+    "blockquote",
+    "pre",
+    "section",
+    "article",
+    "header",
+    "footer",
+    "hr",
+    "title",
+];
+
+/// The readable text of an HTML page: tags removed, scripts, styles and
+/// comments dropped, entities decoded, and whitespace collapsed, with a line
+/// per block element and a blank line between paragraphs.
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        push_text(&mut text, &rest[..start]);
+        rest = &rest[start..];
+        if rest.starts_with("<!--") {
+            rest = rest.find("-->").map_or("", |end| &rest[end + 3..]);
+            continue;
+        }
+        // a `<` that doesn't start a tag, as in `a < b`
+        if !rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '/' || c == '!') {
+            text.push('<');
+            rest = &rest[1..];
+            continue;
+        }
+        let tag;
+        (tag, rest) = match rest.find('>') {
+            Some(end) => (&rest[1..end], &rest[end + 1..]),
+            None => (&rest[1..], ""),
+        };
+
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/' || c == '>')
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        if !closing && HIDDEN.contains(&name.as_str()) {
+            // ASCII lowercasing keeps byte offsets, so they index `rest` too
+            let close = format!("</{}", name);
+            rest = match rest.to_ascii_lowercase().find(&close) {
+                Some(at) => rest[at..].find('>').map_or("", |end| &rest[at + end + 1..]),
+                None => "",
+            };
+            continue;
+        }
+        if PARAGRAPHS.contains(&name.as_str()) {
+            text.push_str("\n\n");
+        } else if LINES.contains(&name.as_str()) {
+            // `</li><li>` is one line break, not two
+            if !text.trim_end_matches(' ').ends_with('\n') {
+                text.push('\n');
+            }
+            if name == "li" && !closing {
+                text.push_str("- ");
+            }
+        } else if matches!(name.as_str(), "td" | "th") {
+            text.push(' ');
+        }
+    }
+    push_text(&mut text, rest);
+
+    let mut out = String::new();
+    let mut blank = true;
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            if !blank {
+                out.push('\n');
+            }
+            blank = true;
+        } else {
+            out.push_str(&line);
+            out.push('\n');
+            blank = false;
+        }
+    }
+    out.trim_end().to_string()
+}
+
+/// Add the text between two tags, where line breaks are only whitespace.
+fn push_text(text: &mut String, raw: &str) {
+    let decoded = decode_entities(raw);
+    text.extend(
+        decoded
+            .chars()
+            .map(|c| if c.is_whitespace() { ' ' } else { c }),
+    );
+}
+
+/// `raw` with the common named entities and all numeric ones decoded.
+fn decode_entities(raw: &str) -> String {
+    let mut out = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| Some((entity(&rest[1..end + 1])?, end + 2)));
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn entity(name: &str) -> Option<char> {
+    let code = if let Some(hex) = name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+        u32::from_str_radix(hex, 16).ok()?
+    } else if let Some(decimal) = name.strip_prefix('#') {
+        decimal.parse().ok()?
+    } else {
+        return Some(match name {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            "nbsp" => ' ',
+            "mdash" => '—',
+            "ndash" => '–',
+            "hellip" => '…',
+            "copy" => '©',
+            _ => return None,
+        });
+    };
+    char::from_u32(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn url(raw: &str) -> Url {
+        Url::parse(raw).unwrap()
+    }
+
+    #[test]
+    fn pages_become_readable_text() {
+        let html = r#"<!DOCTYPE html>
+<html><head><title>Ownership</title>
+<style>body { color: red; }</style>
+<script>var x = "<p>not text</p>";</script></head>
+<body>
+  <!-- navigation -->
+  <h1>What is   ownership?</h1>
+  <p>Each value has an <b>owner</b>,
+     and there is only one at a time.</p>
+  <ul><li>Move</li><li>Borrow &amp; return</li></ul>
+  <p>1 &lt; 2 &#8212; and a < b&nbsp;too&#x21;</p>
+</body></html>"#;
+        assert_eq!(
+            html_to_text(html),
+            "Ownership\n\nWhat is ownership?\n\n\
+             Each value has an owner, and there is only one at a time.\n\n\
+             - Move\n- Borrow & return\n\n\
+             1 < 2 — and a < b too!"
+        );
+    }
+
+    #[test]
+    fn unknown_entities_and_unclosed_tags_are_left_alone() {
+        assert_eq!(
+            decode_entities("AT&T &bogus; &#xZZ;"),
+            "AT&T &bogus; &#xZZ;"
+        );
+        assert_eq!(html_to_text("<SCRIPT>alert(1)</Script>after"), "after");
+        assert_eq!(html_to_text("cut off <a href="), "cut off");
+        assert_eq!(html_to_text("<style>never closed"), "");
+    }
+
+    #[test]
+    fn local_addresses_are_blocked() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "172.31.255.255",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(blocked_address(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["8.8.8.8", "172.32.0.1", "100.128.0.1", "2606:4700::1111"] {
+            assert!(!blocked_address(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn urls_are_checked_before_fetching() {
+        assert!(check_url(&url("https://doc.rust-lang.org/book/"), false).is_ok());
+        for raw in [
+            "http://localhost:8080/",
+            "http://api.localhost/",
+            "http://127.0.0.1/",
+            "http://[::1]/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://192.168.0.1/admin",
+        ] {
+            let err = check_url(&url(raw), false).unwrap_err().to_string();
+            assert!(err.contains("--allow-local-fetch"), "{raw}: {err}");
+            assert!(check_url(&url(raw), true).is_ok(), "{raw}");
+        }
+        let err = check_url(&url("file:///etc/passwd"), true).unwrap_err();
+        assert!(err.to_string().contains("only http and https"), "{err}");
+    }
+
+    fn fetcher(allow_local: bool, max_bytes: usize) -> FetchUrl {
+        let config = FetchConfig {
+            allow_local,
+            max_bytes,
+            ..FetchConfig::default()
+        };
+        FetchUrl::new(config, &NetworkConfig::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn pages_are_fetched_through_redirects() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/old"))
+            .respond_with(ResponseTemplate::new(301).insert_header("Location", "/new"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/new"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                "<html><body><p>Hello, <em>world</em></p></body></html>",
+                "text/html; charset=utf-8",
+            ))
+            .mount(&server)
+            .await;
+
+        let output = fetcher(true, DEFAULT_FETCH_MAX_BYTES)
+            .execute(json!({ "url": format!("{}/old", server.uri()) }))
+            .await
+            .unwrap();
+        assert_eq!(
+            output,
+            format!(
+                "status: 200 OK\nurl: {}/new\ncontent-type: text/html; charset=utf-8\n\nHello, world",
+                server.uri()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_and_limits_are_reported() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/missing"))
+            .respond_with(ResponseTemplate::new(404).set_body_raw("no such page", "text/plain"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/long"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw("x".repeat(100), "text/plain"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/image"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(vec![0u8; 8], "image/png"))
+            .mount(&server)
+            .await;
+        let tool = fetcher(true, 10);
+
+        let output = tool
+            .execute(json!({ "url": format!("{}/missing", server.uri()) }))
+            .await
+            .unwrap();
+        assert!(output.starts_with("status: 404 Not Found\n"), "{output}");
+        let output = tool
+            .execute(json!({ "url": format!("{}/long", server.uri()) }))
+            .await
+            .unwrap();
+        assert!(output.ends_with("[truncated 90 of 100 bytes]"), "{output}");
+        let err = tool
+            .execute(json!({ "url": format!("{}/image", server.uri()) }))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("image/png, not text"), "{err}");
+
+        // the mock server is on localhost
+        let err = fetcher(false, 10)
+            .execute(json!({ "url": server.uri() }))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("refused"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn hosts_resolving_to_local_addresses_are_refused() {
+        let tool = FetchUrl {
+            resolve: |_, port| {
+                Box::pin(async move { Ok(vec![SocketAddr::from(([127, 0, 0, 1], port))]) })
+            },
+            ..fetcher(false, 10)
+        };
+        let err = tool
+            .execute(json!({ "url": "http://rebound.example.com/" }))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "refused: rebound.example.com resolves to 127.0.0.1, a local address"
+        );
+    }
+
+    #[tokio::test]
+    async fn pinned_requests_go_to_the_checked_address() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw("pinned", "text/plain"))
+            .mount(&server)
+            .await;
+        // a name that doesn't resolve, so only the pinned address can answer
+        let client = fetcher(false, 10)
+            .pinned("pinned.invalid", &[*server.address()])
+            .unwrap();
+        let response = client
+            .get(format!(
+                "http://pinned.invalid:{}/",
+                server.address().port()
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "pinned");
+    }
+}
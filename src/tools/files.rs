@@ -0,0 +1,570 @@
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, bail};
+use async_trait::async_trait;
+use serde_json::{Value, json};
+
+use super::{Approver, Tool};
+
+/// Bytes of a file `read_file` returns at most, unless configured otherwise.
+pub const DEFAULT_MAX_READ_BYTES: usize = 64 * 1024;
+
+/// Entries `list_directory` returns at most.
+const MAX_ENTRIES: usize = 1000;
+
+/// The directory the file tools are confined to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workspace {
+    root: PathBuf,
+}
+
+impl Workspace {
+    /// Confine the file tools to `root`, which must be an existing directory.
+    pub fn new(root: impl AsRef<Path>) -> std::io::Result<Self> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(std::io::Error::other(format!(
+                "'{}' is not a directory",
+                root.display()
+            )));
+        }
+        Ok(Self { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolve `path`, relative to the root unless absolute, to a location
+    /// inside the workspace, following symlinks.
+    ///
+    /// The path need not exist yet, but its nearest existing ancestor is
+    /// resolved and the rest may not climb out with `..`.
+    pub fn resolve(&self, path: &str) -> anyhow::Result<PathBuf> {
+        let joined = self.root.join(path);
+        let mut existing = joined.as_path();
+        let mut rest = Vec::new();
+        // symlink_metadata, so a dangling link counts as existing and fails to resolve
+        while std::fs::symlink_metadata(existing).is_err() {
+            let (Some(parent), Some(name)) = (existing.parent(), existing.file_name()) else {
+                bail!("'{}' is outside the workspace", path);
+            };
+            rest.push(name);
+            existing = parent;
+        }
+        let mut resolved = existing
+            .canonicalize()
+            .with_context(|| format!("could not resolve '{}'", path))?;
+        for name in rest.into_iter().rev() {
+            match Path::new(name).components().next() {
+                Some(Component::Normal(name)) => resolved.push(name),
+                _ => bail!("'{}' is outside the workspace", path),
+            }
+        }
+        if !resolved.starts_with(&self.root) {
+            bail!("'{}' is outside the workspace", path);
+        }
+        Ok(resolved)
+    }
+
+    /// `path` as the model should see it, relative to the root.
+    fn display(&self, path: &Path) -> String {
+        match path.strip_prefix(&self.root) {
+            Ok(relative) if relative.as_os_str().is_empty() => ".".to_string(),
+            Ok(relative) => relative.display().to_string(),
+            Err(_) => path.display().to_string(),
+        }
+    }
+}
+
+/// Reads a text file in the workspace, optionally a range of its lines.
+pub struct ReadFile {
+    workspace: Workspace,
+    max_bytes: usize,
+}
+
+impl ReadFile {
+    pub fn new(workspace: Workspace, max_bytes: usize) -> Self {
+        Self {
+            workspace,
+            max_bytes,
+        }
+    }
+}
+
+#[async_trait]
+impl Tool for ReadFile {
+    fn name(&self) -> &str {
+        "read_file"
+    }
+
+    fn description(&self) -> &str {
+        "Read a text file in the workspace. Large files are cut short; use offset \
+         and limit to read them a range of lines at a time."
+    }
+
+    fn json_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "path": {
+                    "type": "string",
+                    "description": "File path, relative to the workspace root"
+                },
+                "offset": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "description": "First line to read, counting from 1"
+                },
+                "limit": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "description": "Most lines to read"
+                }
+            },
+            "required": ["path"]
+        })
+    }
+
+    async fn execute(&self, args: Value) -> anyhow::Result<String> {
+        let path = string_arg(&args, "path")?;
+        let offset = optional_count(&args, "offset")?.unwrap_or(1).max(1);
+        let limit = optional_count(&args, "limit")?;
+        let resolved = self.workspace.resolve(path)?;
+        let bytes = tokio::fs::read(&resolved)
+            .await
+            .with_context(|| format!("could not read '{}'", path))?;
+        let text = String::from_utf8(bytes)
+            .map_err(|_| anyhow::anyhow!("'{}' is not a UTF-8 text file", path))?;
+
+        let lines: Vec<&str> = text.split_inclusive('\n').collect();
+        let end = match limit {
+            Some(limit) => (offset - 1).saturating_add(limit).min(lines.len()),
+            None => lines.len(),
+        };
+        let selected = lines.get(offset - 1..end).unwrap_or_default().concat();
+        if selected.len() <= self.max_bytes {
+            return Ok(selected);
+        }
+        let mut cut = self.max_bytes;
+        while !selected.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        let shown = &selected[..cut];
+        Ok(format!(
+            "{}\n[truncated: showed {} of {} bytes, through line {}; use offset and limit to read more]",
+            shown,
+            cut,
+            selected.len(),
+            offset - 1 + shown.matches('\n').count() + 1
+        ))
+    }
+}
+
+/// Writes a file in the workspace once the approver allows it.
+pub struct WriteFile {
+    workspace: Workspace,
+    approver: Box<dyn Approver>,
+    backup: bool,
+}
+
+impl WriteFile {
+    /// With `backup`, a file about to be overwritten is first copied to `<name>.bak`.
+    pub fn new(workspace: Workspace, approver: impl Approver + 'static, backup: bool) -> Self {
+        Self {
+            workspace,
+            approver: Box::new(approver),
+            backup,
+        }
+    }
+}
+
+#[async_trait]
+impl Tool for WriteFile {
+    fn name(&self) -> &str {
+        "write_file"
+    }
+
+    fn description(&self) -> &str {
+        "Create or overwrite a text file in the workspace with the given content. \
+         The user may decline the write."
+    }
+
+    fn json_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "path": {
+                    "type": "string",
+                    "description": "File path, relative to the workspace root"
+                },
+                "content": {
+                    "type": "string",
+                    "description": "The complete new content of the file"
+                }
+            },
+            "required": ["path", "content"]
+        })
+    }
+
+    async fn execute(&self, args: Value) -> anyhow::Result<String> {
+        let path = string_arg(&args, "path")?;
+        let content = string_arg(&args, "content")?;
+        let resolved = self.workspace.resolve(path)?;
+        if resolved.is_dir() {
+            bail!("'{}' is a directory", path);
+        }
+        let shown = self.workspace.display(&resolved);
+        let action = format!("write {} bytes to {}", content.len(), shown);
+        if !self.approver.approve(&action).await {
+            bail!("the user declined this write");
+        }
+
+        let mut backup = None;
+        if self.backup && resolved.is_file() {
+            // resolved like the path itself, so a symlinked backup cannot point outside
+            let backup_path = self.workspace.resolve(&format!("{}.bak", path))?;
+            tokio::fs::copy(&resolved, &backup_path)
+                .await
+                .with_context(|| format!("could not back up '{}'", path))?;
+            backup = Some(self.workspace.display(&backup_path));
+        }
+        if let Some(parent) = resolved.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&resolved, content)
+            .await
+            .with_context(|| format!("could not write '{}'", path))?;
+
+        Ok(match backup {
+            Some(backup) => format!(
+                "wrote {} bytes to {} (previous version in {})",
+                content.len(),
+                shown,
+                backup
+            ),
+            None => format!("wrote {} bytes to {}", content.len(), shown),
+        })
+    }
+}
+
+/// Lists a directory in the workspace.
+pub struct ListDirectory {
+    workspace: Workspace,
+}
+
+impl ListDirectory {
+    pub fn new(workspace: Workspace) -> Self {
+        Self { workspace }
+    }
+}
+
+#[async_trait]
+impl Tool for ListDirectory {
+    fn name(&self) -> &str {
+        "list_directory"
+    }
+
+    fn description(&self) -> &str {
+        "List the entries of a directory in the workspace, one per line; \
+         subdirectories end in '/'."
+    }
+
+    fn json_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "path": {
+                    "type": "string",
+                    "description": "Directory path, relative to the workspace root [default: the root]"
+                }
+            }
+        })
+    }
+
+    async fn execute(&self, args: Value) -> anyhow::Result<String> {
+        let path = args["path"].as_str().unwrap_or(".");
+        let resolved = self.workspace.resolve(path)?;
+        let mut entries = tokio::fs::read_dir(&resolved)
+            .await
+            .with_context(|| format!("could not list '{}'", path))?;
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let mut name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type().await?.is_dir() {
+                name.push('/');
+            }
+            names.push(name);
+        }
+        names.sort();
+        if names.is_empty() {
+            return Ok("(empty directory)".to_string());
+        }
+        let total = names.len();
+        names.truncate(MAX_ENTRIES);
+        let mut listing = names.join("\n");
+        if total > MAX_ENTRIES {
+            listing.push_str(&format!(
+                "\n[{} more entries not shown]",
+                total - MAX_ENTRIES
+            ));
+        }
+        Ok(listing)
+    }
+}
+
+fn string_arg<'a>(args: &'a Value, name: &str) -> anyhow::Result<&'a str> {
+    args[name]
+        .as_str()
+        .with_context(|| format!("missing string argument '{}'", name))
+}
+
+fn optional_count(args: &Value, name: &str) -> anyhow::Result<Option<usize>> {
+    match &args[name] {
+        Value::Null => Ok(None),
+        value => value
+            .as_u64()
+            .map(|n| Some(n as usize))
+            .with_context(|| format!("'{}' must be a positive integer", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::AutoApprove;
+
+    struct Decline;
+
+    #[async_trait]
+    impl Approver for Decline {
+        async fn approve(&self, _action: &str) -> bool {
+            false
+        }
+    }
+
+    /// A fresh workspace with `notes.txt` and `src/lib.rs`.
+    fn workspace(name: &str) -> Workspace {
+        let root =
+            std::env::temp_dir().join(format!("deepseek_files_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("notes.txt"), "one\ntwo\nthree\n").unwrap();
+        std::fs::write(root.join("src/lib.rs"), "pub fn f() {}\n").unwrap();
+        Workspace::new(&root).unwrap()
+    }
+
+    fn cleanup(workspace: Workspace) {
+        std::fs::remove_dir_all(workspace.root()).unwrap();
+    }
+
+    #[test]
+    fn paths_are_confined_to_the_workspace() {
+        let workspace = workspace("confine");
+        let root = workspace.root().to_path_buf();
+
+        assert_eq!(
+            workspace.resolve("notes.txt").unwrap(),
+            root.join("notes.txt")
+        );
+        assert_eq!(
+            workspace.resolve("src/../notes.txt").unwrap(),
+            root.join("notes.txt")
+        );
+        assert_eq!(
+            workspace.resolve("new/dir/file.md").unwrap(),
+            root.join("new/dir/file.md")
+        );
+        assert_eq!(
+            workspace
+                .resolve(root.join("src").to_str().unwrap())
+                .unwrap(),
+            root.join("src")
+        );
+
+        for escape in [
+            "../outside.txt",
+            "src/../../x",
+            "new/../../x",
+            "/etc/passwd",
+            "/",
+        ] {
+            let err = workspace.resolve(escape).unwrap_err();
+            assert!(
+                err.to_string().contains("outside the workspace"),
+                "{escape}: {err}"
+            );
+        }
+        cleanup(workspace);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_cannot_escape() {
+        let workspace = workspace("symlinks");
+        let root = workspace.root();
+        std::os::unix::fs::symlink("/etc", root.join("etc")).unwrap();
+        std::os::unix::fs::symlink("/nonexistent/target", root.join("dangling")).unwrap();
+        std::os::unix::fs::symlink(root.join("src"), root.join("code")).unwrap();
+
+        assert!(workspace.resolve("etc/passwd").is_err());
+        assert!(workspace.resolve("etc/new-file").is_err());
+        assert!(workspace.resolve("dangling").is_err());
+        assert_eq!(
+            workspace.resolve("code/lib.rs").unwrap(),
+            root.join("src/lib.rs")
+        );
+        cleanup(workspace);
+    }
+
+    #[tokio::test]
+    async fn escapes_come_back_as_tool_errors() {
+        let workspace = workspace("tool_errors");
+        let read = ReadFile::new(workspace.clone(), DEFAULT_MAX_READ_BYTES);
+        let err = read
+            .execute(json!({ "path": "../../etc/passwd" }))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("outside the workspace"), "{err}");
+
+        let write = WriteFile::new(workspace.clone(), AutoApprove, false);
+        assert!(
+            write
+                .execute(json!({ "path": "../escaped.txt", "content": "x" }))
+                .await
+                .is_err()
+        );
+        assert!(
+            !workspace
+                .root()
+                .parent()
+                .unwrap()
+                .join("escaped.txt")
+                .exists()
+        );
+        cleanup(workspace);
+    }
+
+    #[tokio::test]
+    async fn reads_ranges_and_truncates_large_files() {
+        let workspace = workspace("read");
+        let read = ReadFile::new(workspace.clone(), 10);
+        assert_eq!(
+            read.execute(json!({ "path": "notes.txt", "offset": 2, "limit": 1 }))
+                .await
+                .unwrap(),
+            "two\n"
+        );
+        assert_eq!(
+            read.execute(json!({ "path": "notes.txt", "offset": 9 }))
+                .await
+                .unwrap(),
+            ""
+        );
+
+        let output = read.execute(json!({ "path": "notes.txt" })).await.unwrap();
+        assert!(output.starts_with("one\ntwo\nth\n"), "{output}");
+        assert!(
+            output.contains("showed 10 of 14 bytes, through line 3"),
+            "{output}"
+        );
+
+        let err = read
+            .execute(json!({ "path": "notes.txt", "limit": -1 }))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("positive integer"), "{err}");
+        cleanup(workspace);
+    }
+
+    #[tokio::test]
+    async fn writes_back_up_the_previous_version() {
+        let workspace = workspace("backup");
+        let root = workspace.root().to_path_buf();
+        let write = WriteFile::new(workspace.clone(), AutoApprove, true);
+
+        let output = write
+            .execute(json!({ "path": "notes.txt", "content": "new\n" }))
+            .await
+            .unwrap();
+        assert_eq!(
+            output,
+            "wrote 4 bytes to notes.txt (previous version in notes.txt.bak)"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.join("notes.txt")).unwrap(),
+            "new\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.join("notes.txt.bak")).unwrap(),
+            "one\ntwo\nthree\n"
+        );
+
+        // nothing to back up for a new file
+        let output = write
+            .execute(json!({ "path": "docs/todo.md", "content": "- x" }))
+            .await
+            .unwrap();
+        assert_eq!(output, "wrote 3 bytes to docs/todo.md");
+        assert!(!root.join("docs/todo.md.bak").exists());
+        cleanup(workspace);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn backups_cannot_escape_through_symlinks() {
+        let workspace = workspace("backup_symlink");
+        let root = workspace.root().to_path_buf();
+        let outside = root.with_file_name(format!(
+            "{}_outside.txt",
+            root.file_name().unwrap().to_string_lossy()
+        ));
+        std::fs::write(&outside, "keep me\n").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("notes.txt.bak")).unwrap();
+
+        let write = WriteFile::new(workspace.clone(), AutoApprove, true);
+        let err = write
+            .execute(json!({ "path": "notes.txt", "content": "new\n" }))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("outside the workspace"), "{err}");
+        assert_eq!(std::fs::read_to_string(&outside).unwrap(), "keep me\n");
+        assert_eq!(
+            std::fs::read_to_string(root.join("notes.txt")).unwrap(),
+            "one\ntwo\nthree\n"
+        );
+        std::fs::remove_file(outside).unwrap();
+        cleanup(workspace);
+    }
+
+    #[tokio::test]
+    async fn declined_writes_change_nothing() {
+        let workspace = workspace("declined");
+        let write = WriteFile::new(workspace.clone(), Decline, true);
+        let err = write
+            .execute(json!({ "path": "notes.txt", "content": "gone" }))
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("declined"), "{err}");
+        let root = workspace.root();
+        assert_eq!(
+            std::fs::read_to_string(root.join("notes.txt")).unwrap(),
+            "one\ntwo\nthree\n"
+        );
+        assert!(!root.join("notes.txt.bak").exists());
+        cleanup(workspace);
+    }
+
+    #[tokio::test]
+    async fn lists_directories_sorted() {
+        let workspace = workspace("list");
+        let list = ListDirectory::new(workspace.clone());
+        assert_eq!(list.execute(json!({})).await.unwrap(), "notes.txt\nsrc/");
+        assert_eq!(
+            list.execute(json!({ "path": "src" })).await.unwrap(),
+            "lib.rs"
+        );
+        cleanup(workspace);
+    }
+}
@@ -0,0 +1,201 @@
+//! Tools the model can call while answering.
+
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionTool, ChatCompletionToolType, FunctionObject,
+};
+use async_trait::async_trait;
+use serde_json::Value;
+
+mod calculator;
+mod fetch;
+mod files;
+mod shell;
+mod time;
+
+pub use calculator::Calculator;
+pub use fetch::{
+    DEFAULT_FETCH_MAX_BYTES, FetchConfig, FetchUrl, blocked_address, check_url, html_to_text,
+};
+pub use files::{DEFAULT_MAX_READ_BYTES, ListDirectory, ReadFile, Workspace, WriteFile};
+pub use shell::{ShellConfig, ShellTool};
+pub use time::CurrentTime;
+
+/// Tool-call rounds allowed per question before the agent gives up.
+pub const DEFAULT_MAX_TOOL_ITERATIONS: usize = 5;
+
+/// A function the model can ask the agent to run.
+#[async_trait]
+pub trait Tool: Send + Sync {
+    /// Name the model calls the tool by; letters, digits, `_` and `-` only.
+    fn name(&self) -> &str;
+
+    /// What the tool does, shown to the model to decide when to use it.
+    fn description(&self) -> &str;
+
+    /// JSON schema of the arguments object.
+    fn json_schema(&self) -> Value;
+
+    /// Run the tool. Errors are reported back to the model, not to the user.
+    async fn execute(&self, args: Value) -> anyhow::Result<String>;
+}
+
+/// Decides whether the model may do something with side effects, e.g. by
+/// asking the user. `action` reads like "run `ls`" or "write 12 bytes to a.txt".
+#[async_trait]
+pub trait Approver: Send + Sync {
+    async fn approve(&self, action: &str) -> bool;
+}
+
+/// Approves everything that isn't refused outright, like denylisted commands.
+pub struct AutoApprove;
+
+#[async_trait]
+impl Approver for AutoApprove {
+    async fn approve(&self, _action: &str) -> bool {
+        true
+    }
+}
+
+/// One tool call the agent ran, for display.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolExecution {
+    pub name: String,
+    pub arguments: String,
+    pub output: String,
+}
+
+/// The set of tools offered to the model.
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    /// The calculator and current-time tools.
+    pub fn builtin() -> Self {
+        let mut registry = Self::default();
+        registry.register(Calculator);
+        registry.register(CurrentTime);
+        registry
+    }
+
+    /// Add `tool`, replacing any tool with the same name.
+    pub fn register(&mut self, tool: impl Tool + 'static) {
+        self.tools.retain(|t| t.name() != tool.name());
+        self.tools.push(Box::new(tool));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.tools.iter().map(|t| t.name()).collect()
+    }
+
+    /// Tool definitions for the `tools` field of a chat request.
+    pub fn definitions(&self) -> Vec<ChatCompletionTool> {
+        self.tools
+            .iter()
+            .map(|tool| ChatCompletionTool {
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionObject {
+                    name: tool.name().to_string(),
+                    description: Some(tool.description().to_string()),
+                    parameters: Some(tool.json_schema()),
+                    strict: None,
+                },
+            })
+            .collect()
+    }
+
+    /// Run the tool the model asked for and return the text to send back.
+    ///
+    /// Unknown tools, malformed arguments and tool failures come back as an
+    /// `error: ...` result so the model can correct itself.
+    pub async fn call(&self, call: &ChatCompletionMessageToolCall) -> String {
+        let name = &call.function.name;
+        let Some(tool) = self.tools.iter().find(|t| t.name() == name) else {
+            return format!("error: unknown tool '{}'", name);
+        };
+        let arguments = match call.function.arguments.trim() {
+            "" => Value::Object(Default::default()),
+            raw => match serde_json::from_str(raw) {
+                Ok(arguments) => arguments,
+                Err(e) => return format!("error: arguments are not valid JSON: {}", e),
+            },
+        };
+        match tool.execute(arguments).await {
+            Ok(output) => output,
+            Err(e) => format!("error: {:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::FunctionCall;
+    use serde_json::json;
+
+    fn tool_call(name: &str, arguments: &str) -> ChatCompletionMessageToolCall {
+        ChatCompletionMessageToolCall {
+            id: "call_1".to_string(),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments: arguments.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn definitions_describe_every_tool() {
+        let registry = ToolRegistry::builtin();
+        let body = serde_json::to_value(registry.definitions()).unwrap();
+        assert_eq!(body[0]["type"], "function");
+        assert_eq!(body[0]["function"]["name"], "calculator");
+        assert_eq!(
+            body[0]["function"]["parameters"]["required"],
+            json!(["expression"])
+        );
+        assert_eq!(body[1]["function"]["name"], "current_time");
+    }
+
+    #[test]
+    fn registering_a_name_twice_replaces_the_tool() {
+        let mut registry = ToolRegistry::builtin();
+        registry.register(Calculator);
+        assert_eq!(registry.names(), ["current_time", "calculator"]);
+    }
+
+    #[tokio::test]
+    async fn calls_the_named_tool() {
+        let registry = ToolRegistry::builtin();
+        let output = registry
+            .call(&tool_call("calculator", r#"{"expression": "6 * 7"}"#))
+            .await;
+        assert_eq!(output, "42");
+    }
+
+    #[tokio::test]
+    async fn problems_are_reported_to_the_model() {
+        let registry = ToolRegistry::builtin();
+        assert_eq!(
+            registry.call(&tool_call("weather", "{}")).await,
+            "error: unknown tool 'weather'"
+        );
+        assert!(
+            registry
+                .call(&tool_call("calculator", "{not json"))
+                .await
+                .starts_with("error: arguments are not valid JSON")
+        );
+        assert!(
+            registry
+                .call(&tool_call("calculator", r#"{"expression": "1 / 0"}"#))
+                .await
+                .contains("division by zero")
+        );
+    }
+}
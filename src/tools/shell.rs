@@ -0,0 +1,332 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{Context, bail};
+use async_trait::async_trait;
+use serde_json::{Value, json};
+use tokio::process::Command;
+
+use super::{Approver, Tool};
+
+/// Where and for how long commands run, and how much of their output is kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShellConfig {
+    pub working_dir: PathBuf,
+    pub timeout: Duration,
+    /// Bytes kept of stdout and of stderr each.
+    pub max_output_bytes: usize,
+}
+
+impl Default for ShellConfig {
+    fn default() -> Self {
+        Self {
+            working_dir: PathBuf::from("."),
+            timeout: Duration::from_secs(30),
+            max_output_bytes: 16 * 1024,
+        }
+    }
+}
+
+/// Runs shell commands with `sh -c`, once the approver allows it.
+///
+/// Commands matching [`denied`] are refused without asking.
+pub struct ShellTool {
+    config: ShellConfig,
+    approver: Box<dyn Approver>,
+}
+
+impl ShellTool {
+    pub fn new(config: ShellConfig, approver: impl Approver + 'static) -> Self {
+        Self {
+            config,
+            approver: Box::new(approver),
+        }
+    }
+}
+
+#[async_trait]
+impl Tool for ShellTool {
+    fn name(&self) -> &str {
+        "shell"
+    }
+
+    fn description(&self) -> &str {
+        "Run a shell command with sh -c and return its exit code, stdout and stderr. \
+         The user may decline to run it."
+    }
+
+    fn json_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "command": {
+                    "type": "string",
+                    "description": "The command line to run, e.g. \"ls -la src\""
+                }
+            },
+            "required": ["command"]
+        })
+    }
+
+    async fn execute(&self, args: Value) -> anyhow::Result<String> {
+        let command = args["command"]
+            .as_str()
+            .context("missing string argument 'command'")?;
+        if let Some(reason) = denied(command) {
+            bail!("refused: the command {}", reason);
+        }
+        if !self.approver.approve(&format!("run `{}`", command)).await {
+            bail!("the user declined to run this command");
+        }
+
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(&self.config.working_dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // a timed-out command must not outlive the tool call
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| {
+                format!(
+                    "could not start sh in '{}'",
+                    self.config.working_dir.display()
+                )
+            })?;
+        let output = match tokio::time::timeout(self.config.timeout, child.wait_with_output()).await
+        {
+            Ok(output) => output?,
+            Err(_) => bail!(
+                "the command timed out after {}s and was killed",
+                self.config.timeout.as_secs_f64()
+            ),
+        };
+
+        let exit_code = match output.status.code() {
+            Some(code) => code.to_string(),
+            None => "none (terminated by a signal)".to_string(),
+        };
+        let limit = self.config.max_output_bytes;
+        Ok(format!(
+            "exit code: {}\nstdout:\n{}\nstderr:\n{}",
+            exit_code,
+            truncate(&String::from_utf8_lossy(&output.stdout), limit),
+            truncate(&String::from_utf8_lossy(&output.stderr), limit)
+        ))
+    }
+}
+
+/// Keep the first `limit` bytes of `text`, cut at a character boundary, and
+/// say how much was dropped.
+pub fn truncate(text: &str, limit: usize) -> String {
+    if text.len() <= limit {
+        return text.to_string();
+    }
+    let mut end = limit;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!(
+        "{}\n[truncated {} of {} bytes]",
+        &text[..end],
+        text.len() - end,
+        text.len()
+    )
+}
+
+/// Why `command` is too destructive to run at all, if it is.
+///
+/// This catches the obvious cases only; it is no substitute for reading a
+/// command before approving it.
+pub fn denied(command: &str) -> Option<&'static str> {
+    let compact: String = command.chars().filter(|c| !c.is_whitespace()).collect();
+    if compact.contains(":(){:|:&};:") || (compact.contains("(){") && compact.contains("&};")) {
+        return Some("looks like a fork bomb");
+    }
+
+    for words in command
+        .split([';', '&', '|', '\n', '(', ')', '`'])
+        .map(|part| part.split_whitespace().collect::<Vec<_>>())
+    {
+        let Some(&program) = words.iter().find(|w| !w.contains('=') && **w != "sudo") else {
+            continue;
+        };
+        let program = program.rsplit('/').next().unwrap_or(program);
+        if program.starts_with("mkfs") {
+            return Some("formats a filesystem");
+        }
+        if program == "rm" && deletes_everything(&words) {
+            return Some("deletes the root or home directory");
+        }
+        if program == "dd" && words.iter().any(|w| w.starts_with("of=/dev/")) {
+            return Some("overwrites a device");
+        }
+    }
+    if compact.contains(">/dev/sd") || compact.contains(">/dev/nvme") {
+        return Some("overwrites a device");
+    }
+    None
+}
+
+/// Whether the words of an `rm` call delete recursively from `/` or `~`.
+fn deletes_everything(words: &[&str]) -> bool {
+    let flags: String = words
+        .iter()
+        .filter(|w| w.starts_with('-') && !w.starts_with("--"))
+        .map(|w| &w[1..])
+        .collect();
+    let recursive = flags.contains(['r', 'R']) || words.contains(&"--recursive");
+    let root = words
+        .iter()
+        .any(|w| matches!(*w, "/" | "/*" | "~" | "~/" | "~/*" | "$HOME" | "$HOME/*"));
+    recursive && (root || words.contains(&"--no-preserve-root"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Answers with `answer` and remembers what it was asked.
+    struct Scripted {
+        answer: bool,
+        asked: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Approver for Scripted {
+        async fn approve(&self, action: &str) -> bool {
+            self.asked.lock().unwrap().push(action.to_string());
+            self.answer
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("deepseek_shell_{}_{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn shell(dir: &std::path::Path, answer: bool) -> (ShellTool, Arc<Mutex<Vec<String>>>) {
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let config = ShellConfig {
+            working_dir: dir.to_path_buf(),
+            timeout: Duration::from_secs(5),
+            max_output_bytes: 64,
+        };
+        let tool = ShellTool::new(
+            config,
+            Scripted {
+                answer,
+                asked: asked.clone(),
+            },
+        );
+        (tool, asked)
+    }
+
+    #[tokio::test]
+    async fn approved_commands_run_in_the_working_directory() {
+        let dir = temp_dir("approved");
+        let (tool, asked) = shell(&dir, true);
+        let output = tool
+            .execute(json!({"command": "touch marker && echo out && echo err >&2 && exit 3"}))
+            .await
+            .unwrap();
+
+        assert_eq!(output, "exit code: 3\nstdout:\nout\n\nstderr:\nerr\n");
+        assert!(dir.join("marker").exists());
+        assert_eq!(asked.lock().unwrap().len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn declined_commands_do_not_run() {
+        let dir = temp_dir("declined");
+        let (tool, asked) = shell(&dir, false);
+        let err = tool
+            .execute(json!({"command": "touch marker"}))
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("declined"), "{err}");
+        assert_eq!(*asked.lock().unwrap(), ["run `touch marker`"]);
+        assert!(!dir.join("marker").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn denied_commands_are_refused_without_asking() {
+        let (tool, asked) = shell(&std::env::temp_dir(), true);
+        let err = tool
+            .execute(json!({"command": "sudo rm -rf /"}))
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().starts_with("refused"), "{err}");
+        assert!(asked.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn long_output_is_truncated_and_slow_commands_killed() {
+        let dir = temp_dir("limits");
+        let (tool, _) = shell(&dir, true);
+        let output = tool
+            .execute(json!({"command": "printf '%0200d' 0"}))
+            .await
+            .unwrap();
+        assert!(output.contains("[truncated 136 of 200 bytes]"), "{output}");
+
+        let (mut tool, _) = shell(&dir, true);
+        tool.config.timeout = Duration::from_millis(100);
+        let err = tool
+            .execute(json!({"command": "sleep 5"}))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn truncation_respects_character_boundaries() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("héllo", 2), "h\n[truncated 5 of 6 bytes]");
+    }
+
+    #[test]
+    fn denylist_matches_destructive_commands() {
+        for command in [
+            "rm -rf /",
+            "rm -fr /*",
+            "rm -r -f ~",
+            "sudo rm -rf --no-preserve-root /",
+            "echo done; /bin/rm -Rf $HOME",
+            "mkfs.ext4 /dev/sda1",
+            "sudo mkfs -t ext4 /dev/sdb",
+            ":(){ :|:& };:",
+            "bomb(){ bomb|bomb& };bomb",
+            "dd if=/dev/zero of=/dev/sda bs=1M",
+            "cat /dev/urandom > /dev/sda",
+        ] {
+            assert!(denied(command).is_some(), "{command}");
+        }
+    }
+
+    #[test]
+    fn denylist_allows_ordinary_commands() {
+        for command in [
+            "ls -la /",
+            "rm -rf target",
+            "rm -f /tmp/scratch.txt",
+            "cargo test && echo ok",
+            "grep -r mkfs docs",
+            "dd if=in.img of=out.img",
+            "echo 'a|b' > notes.txt",
+        ] {
+            assert_eq!(denied(command), None, "{command}");
+        }
+    }
+}
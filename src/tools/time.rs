@@ -0,0 +1,43 @@
+use async_trait::async_trait;
+use chrono::{SecondsFormat, Utc};
+use serde_json::{Value, json};
+
+use super::Tool;
+
+/// Tells the model the current date and time, which it can't know otherwise.
+pub struct CurrentTime;
+
+#[async_trait]
+impl Tool for CurrentTime {
+    fn name(&self) -> &str {
+        "current_time"
+    }
+
+    fn description(&self) -> &str {
+        "Get the current date and time in UTC as an RFC 3339 timestamp."
+    }
+
+    fn json_schema(&self) -> Value {
+        json!({ "type": "object", "properties": {} })
+    }
+
+    async fn execute(&self, _args: Value) -> anyhow::Result<String> {
+        Ok(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    #[tokio::test]
+    async fn returns_a_utc_timestamp() {
+        let before = Utc::now().timestamp();
+        let output = CurrentTime.execute(json!({})).await.unwrap();
+        let parsed = DateTime::parse_from_rfc3339(&output).unwrap();
+
+        assert!(output.ends_with('Z'), "{output}");
+        assert!((parsed.timestamp() - before).abs() <= 1);
+    }
+}
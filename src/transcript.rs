@@ -0,0 +1,377 @@
+//! Readable transcripts of a session, for `/export` and the `export`
+//! subcommand: Markdown, or a standalone HTML page.
+//!
+//! Each message gets a section headed by its role and time, with its text as
+//! the model wrote it, so code fences survive. Tool calls and their results
+//! go in collapsed `<details>` blocks, which GitHub renders in Markdown too.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessageContent, ChatCompletionRequestMessage,
+};
+use chrono::{DateTime, Utc};
+use pulldown_cmark::{Event, Options, Parser};
+
+use crate::conversation::describe;
+use crate::session::Session;
+use crate::usage::UsageTotals;
+
+/// What a transcript is written as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranscriptFormat {
+    Markdown,
+    Html,
+}
+
+impl std::str::FromStr for TranscriptFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "markdown" | "md" => Ok(Self::Markdown),
+            "html" => Ok(Self::Html),
+            _ => Err(format!(
+                "unknown transcript format '{}', expected markdown or html",
+                name
+            )),
+        }
+    }
+}
+
+impl TranscriptFormat {
+    /// The transcript of `session`, with the model's reasoning before its
+    /// answers if `include_reasoning` and it was saved.
+    pub fn render(self, session: &Session, include_reasoning: bool) -> String {
+        let parts = parts(session, include_reasoning);
+        match self {
+            Self::Markdown => markdown(session, &parts),
+            Self::Html => html(session, &parts),
+        }
+    }
+}
+
+/// One thing shown in a transcript.
+#[derive(Debug, PartialEq)]
+enum Part {
+    /// The start of a message's section.
+    Heading {
+        role: &'static str,
+        timestamp: DateTime<Utc>,
+    },
+    /// Markdown, as the user or model wrote it.
+    Text(String),
+    Reasoning(String),
+    ToolCall {
+        name: String,
+        arguments: String,
+    },
+    ToolResult {
+        name: String,
+        content: String,
+    },
+}
+
+fn parts(session: &Session, include_reasoning: bool) -> Vec<Part> {
+    // tool results only carry the id of the call they answer
+    let mut tool_names: HashMap<&str, &str> = HashMap::new();
+    let mut parts = Vec::new();
+    for saved in &session.messages {
+        match &saved.message {
+            ChatCompletionRequestMessage::Assistant(message) => {
+                parts.push(Part::Heading {
+                    role: "Assistant",
+                    timestamp: saved.timestamp,
+                });
+                if include_reasoning && let Some(reasoning) = &saved.reasoning {
+                    parts.push(Part::Reasoning(reasoning.clone()));
+                }
+                if let Some(ChatCompletionRequestAssistantMessageContent::Text(text)) =
+                    &message.content
+                    && !text.is_empty()
+                {
+                    parts.push(Part::Text(text.clone()));
+                }
+                for call in message.tool_calls.iter().flatten() {
+                    tool_names.insert(&call.id, &call.function.name);
+                    parts.push(Part::ToolCall {
+                        name: call.function.name.clone(),
+                        arguments: pretty_arguments(&call.function.arguments),
+                    });
+                }
+            }
+            // results follow their call, in the same section
+            ChatCompletionRequestMessage::Tool(message) => parts.push(Part::ToolResult {
+                name: tool_names
+                    .get(message.tool_call_id.as_str())
+                    .unwrap_or(&"tool")
+                    .to_string(),
+                content: describe(&saved.message).1,
+            }),
+            other => {
+                let role = match other {
+                    ChatCompletionRequestMessage::User(_) => "User",
+                    _ => "System",
+                };
+                parts.push(Part::Heading {
+                    role,
+                    timestamp: saved.timestamp,
+                });
+                parts.push(Part::Text(describe(other).1));
+            }
+        }
+    }
+    parts
+}
+
+/// Arguments indented if they are JSON, as they nearly always are.
+fn pretty_arguments(arguments: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(arguments)
+        .ok()
+        .and_then(|value| serde_json::to_string_pretty(&value).ok())
+        .unwrap_or_else(|| arguments.to_string())
+}
+
+fn title(session: &Session) -> String {
+    format!("Conversation with {}", session.model)
+}
+
+fn time(timestamp: DateTime<Utc>) -> String {
+    timestamp.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+}
+
+fn usage_summary(usage: &UsageTotals) -> String {
+    let mut summary = format!(
+        "{} turns, {} prompt + {} completion = {} tokens, ~${:.6}",
+        usage.turns,
+        usage.usage.prompt_tokens,
+        usage.usage.completion_tokens,
+        usage.usage.total_tokens(),
+        usage.cost
+    );
+    if usage.unpriced_turns > 0 {
+        let _ = write!(summary, " ({} turns without a price)", usage.unpriced_turns);
+    }
+    summary
+}
+
+/// A code fence longer than any run of backticks in `text`.
+fn fence(text: &str) -> String {
+    let longest = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    "`".repeat(longest.max(2) + 1)
+}
+
+fn markdown(session: &Session, parts: &[Part]) -> String {
+    let mut out = format!(
+        "# {}\n\n- **Model:** {}\n- **Saved:** {}\n- **Usage:** {}\n",
+        title(session),
+        session.model,
+        time(session.saved_at),
+        usage_summary(&session.usage)
+    );
+    for part in parts {
+        out.push('\n');
+        match part {
+            Part::Heading { role, timestamp } => {
+                let _ = writeln!(out, "## {} · {}", role, time(*timestamp));
+            }
+            Part::Text(text) => {
+                let _ = writeln!(out, "{}", text.trim_end());
+            }
+            Part::Reasoning(reasoning) => {
+                out.push_str("<details>\n<summary>Reasoning</summary>\n\n");
+                for line in reasoning.trim_end().lines() {
+                    let _ = writeln!(out, "> {}", line);
+                }
+                out.push_str("\n</details>\n");
+            }
+            Part::ToolCall { name, arguments } => {
+                details(&mut out, "Tool call", name, "json", arguments);
+            }
+            Part::ToolResult { name, content } => {
+                details(&mut out, "Tool result", name, "text", content);
+            }
+        }
+    }
+    out
+}
+
+fn details(out: &mut String, label: &str, name: &str, language: &str, body: &str) {
+    let fence = fence(body);
+    let _ = writeln!(
+        out,
+        "<details>\n<summary>{}: <code>{}</code></summary>\n\n{}{}\n{}\n{}\n\n</details>",
+        label,
+        escape(name),
+        fence,
+        language,
+        body.trim_end(),
+        fence
+    );
+}
+
+const STYLE: &str = "\
+body { max-width: 48rem; margin: 2rem auto; padding: 0 1rem; font: 16px/1.5 system-ui, sans-serif; color: #222; }
+h2 { font-size: 1rem; margin: 2rem 0 0.5rem; border-bottom: 1px solid #ddd; }
+h2 time { font-weight: normal; color: #777; margin-left: 0.5rem; }
+pre { background: #f6f8fa; padding: 0.75rem; overflow-x: auto; }
+code { font-family: ui-monospace, monospace; font-size: 0.9em; }
+details { margin: 0.5rem 0; padding: 0.25rem 0.75rem; border-left: 3px solid #ddd; }
+summary { cursor: pointer; color: #555; }
+blockquote { margin: 0; color: #666; }
+.summary { color: #555; }
+";
+
+fn html(session: &Session, parts: &[Part]) -> String {
+    let title = escape(&title(session));
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>{}</title>\n<style>\n{}</style>\n</head>\n<body>\n<h1>{}</h1>\n\
+         <ul class=\"summary\">\n<li><strong>Model:</strong> {}</li>\n\
+         <li><strong>Saved:</strong> {}</li>\n<li><strong>Usage:</strong> {}</li>\n</ul>\n",
+        title,
+        STYLE,
+        title,
+        escape(&session.model),
+        time_html(session.saved_at),
+        escape(&usage_summary(&session.usage))
+    );
+    let mut open = false;
+    for part in parts {
+        match part {
+            Part::Heading { role, timestamp } => {
+                if open {
+                    out.push_str("</section>\n");
+                }
+                open = true;
+                let _ = writeln!(
+                    out,
+                    "<section class=\"{}\">\n<h2>{} {}</h2>",
+                    role.to_lowercase(),
+                    role,
+                    time_html(*timestamp)
+                );
+            }
+            Part::Text(text) => out.push_str(&markdown_html(text)),
+            Part::Reasoning(reasoning) => {
+                let _ = writeln!(
+                    out,
+                    "<details class=\"reasoning\">\n<summary>Reasoning</summary>\n<blockquote>\n{}</blockquote>\n</details>",
+                    markdown_html(reasoning)
+                );
+            }
+            Part::ToolCall { name, arguments } => {
+                html_details(&mut out, "Tool call", name, arguments);
+            }
+            Part::ToolResult { name, content } => {
+                html_details(&mut out, "Tool result", name, content);
+            }
+        }
+    }
+    if open {
+        out.push_str("</section>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+fn time_html(timestamp: DateTime<Utc>) -> String {
+    format!(
+        "<time datetime=\"{}\">{}</time>",
+        timestamp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        time(timestamp)
+    )
+}
+
+fn html_details(out: &mut String, label: &str, name: &str, body: &str) {
+    let _ = writeln!(
+        out,
+        "<details class=\"tool\">\n<summary>{}: <code>{}</code></summary>\n<pre><code>{}</code></pre>\n</details>",
+        label,
+        escape(name),
+        escape(body.trim_end())
+    );
+}
+
+/// `text` as HTML, with any HTML in it shown as text rather than run.
+fn markdown_html(text: &str) -> String {
+    let events = Parser::new_ext(text, Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH).map(
+        |event| match event {
+            Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+            other => other,
+        },
+    );
+    let mut out = String::new();
+    pulldown_cmark::html::push_html(&mut out, events);
+    out
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Session {
+        Session::from_json(include_str!("../tests/fixtures/transcript/session.json")).unwrap()
+    }
+
+    /// Compare with a snapshot in tests/fixtures/transcript.
+    fn assert_snapshot(rendered: &str, snapshot: &str) {
+        assert_eq!(rendered, snapshot, "rendered:\n{rendered}");
+    }
+
+    #[test]
+    fn markdown_snapshots() {
+        assert_snapshot(
+            &TranscriptFormat::Markdown.render(&session(), false),
+            include_str!("../tests/fixtures/transcript/transcript.md"),
+        );
+        assert_snapshot(
+            &TranscriptFormat::Markdown.render(&session(), true),
+            include_str!("../tests/fixtures/transcript/transcript.reasoning.md"),
+        );
+    }
+
+    #[test]
+    fn html_snapshot() {
+        assert_snapshot(
+            &TranscriptFormat::Html.render(&session(), true),
+            include_str!("../tests/fixtures/transcript/transcript.html"),
+        );
+    }
+
+    #[test]
+    fn html_in_messages_is_shown_not_run() {
+        let html = markdown_html("Try <script>alert(1)</script> *now*");
+        assert!(html.contains("&lt;script&gt;"), "{html}");
+        assert!(html.contains("<em>now</em>"), "{html}");
+    }
+
+    #[test]
+    fn fences_outrun_the_backticks_inside() {
+        assert_eq!(fence("plain"), "```");
+        assert_eq!(fence("```rust\nfn main() {}\n```"), "````");
+    }
+
+    #[test]
+    fn formats_parse_by_name() {
+        assert_eq!("md".parse(), Ok(TranscriptFormat::Markdown));
+        assert_eq!("html".parse(), Ok(TranscriptFormat::Html));
+        assert!("pdf".parse::<TranscriptFormat>().is_err());
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::AddAssign;
+
+use async_openai::types::CompletionUsage;
+use serde::{Deserialize, Serialize};
+
+use crate::embeddings::DEFAULT_EMBEDDING_MODEL;
+use crate::error::AgentError;
+
+/// Tokens consumed by one or more requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+impl From<&CompletionUsage> for Usage {
+    fn from(usage: &CompletionUsage) -> Self {
+        Self {
+            prompt_tokens: usage.prompt_tokens.into(),
+            completion_tokens: usage.completion_tokens.into(),
+        }
+    }
+}
+
+/// What a model costs, in US dollars per million tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelPrice {
+    pub input_per_million: f64,
+    pub output_per_million: f64,
+}
+
+impl ModelPrice {
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        (usage.prompt_tokens as f64 * self.input_per_million
+            + usage.completion_tokens as f64 * self.output_per_million)
+            / 1_000_000.0
+    }
+}
+
+/// Prices by model name.
+///
+/// The defaults are DeepSeek's list prices with every prompt token billed as a
+/// cache miss, so estimates err on the high side.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PriceTable {
+    prices: BTreeMap<String, ModelPrice>,
+}
+
+impl Default for PriceTable {
+    fn default() -> Self {
+        let deepseek = ModelPrice {
+            input_per_million: 0.28,
+            output_per_million: 0.42,
+        };
+        let mut table = Self::empty();
+        table.set("deepseek-chat", deepseek);
+        table.set("deepseek-reasoner", deepseek);
+        table.set(
+            DEFAULT_EMBEDDING_MODEL,
+            ModelPrice {
+                input_per_million: 0.02,
+                output_per_million: 0.0,
+            },
+        );
+        table
+    }
+}
+
+impl PriceTable {
+    pub fn empty() -> Self {
+        Self {
+            prices: BTreeMap::new(),
+        }
+    }
+
+    /// Add or replace the price of `model`.
+    pub fn set(&mut self, model: &str, price: ModelPrice) {
+        self.prices.insert(model.to_string(), price);
+    }
+
+    pub fn get(&self, model: &str) -> Option<&ModelPrice> {
+        self.prices.get(model)
+    }
+}
+
+/// A cap on estimated spending, in US dollars. Only models with a price
+/// count towards it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Budget {
+    pub limit: f64,
+}
+
+impl Budget {
+    pub fn new(limit: f64) -> Self {
+        Self { limit }
+    }
+
+    /// Fail if a request estimated at `estimate` could take spending past the
+    /// limit, with `spent` spent already.
+    pub fn check(&self, spent: f64, estimate: f64) -> Result<(), AgentError> {
+        if spent + estimate > self.limit {
+            return Err(AgentError::BudgetExceeded {
+                spent,
+                estimate,
+                limit: self.limit,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Usage of one question, including any tool-call rounds, and its cost if the
+/// model has a price.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TurnUsage {
+    pub model: String,
+    pub usage: Usage,
+    pub cost: Option<f64>,
+}
+
+impl fmt::Display for TurnUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "usage: {} prompt + {} completion = {} tokens, ",
+            self.usage.prompt_tokens,
+            self.usage.completion_tokens,
+            self.usage.total_tokens()
+        )?;
+        match self.cost {
+            Some(cost) => write!(f, "~${:.6}", cost),
+            None => write!(f, "cost unknown (no price for {})", self.model),
+        }
+    }
+}
+
+/// What a session has used so far.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageTotals {
+    pub turns: u32,
+    #[serde(flatten)]
+    pub usage: Usage,
+    /// Estimated cost of every priced turn, in US dollars.
+    pub cost: f64,
+    #[serde(default)]
+    pub unpriced_turns: u32,
+}
+
+/// Running totals for a session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageTracker {
+    prices: PriceTable,
+    totals: UsageTotals,
+}
+
+impl UsageTracker {
+    pub fn new(prices: PriceTable) -> Self {
+        Self {
+            prices,
+            totals: UsageTotals::default(),
+        }
+    }
+
+    /// Add a turn's usage to the totals and price it.
+    pub fn record(&mut self, model: &str, usage: Usage) -> TurnUsage {
+        let cost = self.prices.get(model).map(|price| price.cost(&usage));
+        self.totals.turns += 1;
+        self.totals.usage += usage;
+        match cost {
+            Some(cost) => self.totals.cost += cost,
+            None => self.totals.unpriced_turns += 1,
+        }
+        TurnUsage {
+            model: model.to_string(),
+            usage,
+            cost,
+        }
+    }
+
+    /// Add usage that isn't a turn of its own, like an embedding request, to
+    /// the totals, priced if the model has a price.
+    pub fn add(&mut self, model: &str, usage: Usage) {
+        self.totals.usage += usage;
+        if let Some(price) = self.prices.get(model) {
+            self.totals.cost += price.cost(&usage);
+        }
+    }
+
+    pub fn turns(&self) -> u32 {
+        self.totals.turns
+    }
+
+    pub fn price(&self, model: &str) -> Option<&ModelPrice> {
+        self.prices.get(model)
+    }
+
+    pub fn usage(&self) -> Usage {
+        self.totals.usage
+    }
+
+    /// Estimated cost of every priced turn.
+    pub fn cost(&self) -> f64 {
+        self.totals.cost
+    }
+
+    pub fn totals(&self) -> &UsageTotals {
+        &self.totals
+    }
+
+    /// Continue from earlier totals, e.g. those of a resumed session.
+    pub fn restore(&mut self, totals: UsageTotals) {
+        self.totals = totals;
+    }
+}
+
+impl fmt::Display for UsageTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let totals = &self.totals;
+        write!(
+            f,
+            "session: {} turns, {} prompt + {} completion = {} tokens, ~${:.6}",
+            totals.turns,
+            totals.usage.prompt_tokens,
+            totals.usage.completion_tokens,
+            totals.usage.total_tokens(),
+            totals.cost
+        )?;
+        if totals.unpriced_turns > 0 {
+            write!(f, " ({} turns without a price)", totals.unpriced_turns)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(prompt_tokens: u64, completion_tokens: u64) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+        }
+    }
+
+    #[test]
+    fn cost_is_priced_per_million_tokens() {
+        let price = ModelPrice {
+            input_per_million: 0.5,
+            output_per_million: 2.0,
+        };
+        let cost = price.cost(&usage(1_000_000, 250_000));
+        assert!((cost - 1.0).abs() < 1e-12, "{cost}");
+    }
+
+    #[test]
+    fn tracker_accumulates_turns() {
+        let mut tracker = UsageTracker::new(PriceTable::default());
+        let first = tracker.record("deepseek-chat", usage(1_000, 500));
+        tracker.record("deepseek-reasoner", usage(2_000, 1_500));
+
+        assert_eq!(first.usage.total_tokens(), 1_500);
+        assert!((first.cost.unwrap() - 0.00049).abs() < 1e-12);
+        assert_eq!(tracker.turns(), 2);
+        assert_eq!(tracker.usage(), usage(3_000, 2_000));
+        assert!((tracker.cost() - 0.00168).abs() < 1e-12);
+    }
+
+    #[test]
+    fn unknown_models_count_tokens_but_not_cost() {
+        let mut tracker = UsageTracker::new(PriceTable::default());
+        let turn = tracker.record("llama3", usage(10, 5));
+
+        assert_eq!(turn.cost, None);
+        assert_eq!(tracker.usage(), usage(10, 5));
+        assert_eq!(tracker.cost(), 0.0);
+        assert!(tracker.to_string().ends_with("(1 turns without a price)"));
+        assert!(turn.to_string().contains("no price for llama3"));
+    }
+
+    #[test]
+    fn embedding_usage_counts_without_a_turn() {
+        let mut tracker = UsageTracker::new(PriceTable::default());
+        tracker.add(DEFAULT_EMBEDDING_MODEL, usage(1_000_000, 0));
+        tracker.add("nomic-embed-text", usage(10, 0));
+
+        assert_eq!(tracker.turns(), 0);
+        assert_eq!(tracker.usage(), usage(1_000_010, 0));
+        assert!((tracker.cost() - 0.02).abs() < 1e-12);
+    }
+
+    #[test]
+    fn budgets_refuse_requests_that_could_go_over() {
+        let budget = Budget::new(0.5);
+        assert!(budget.check(0.2, 0.3).is_ok());
+        let err = budget.check(0.25, 0.3).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                AgentError::BudgetExceeded { spent, estimate, limit }
+                    if spent == 0.25 && estimate == 0.3 && limit == 0.5
+            ),
+            "{err:?}"
+        );
+        assert!(err.to_string().contains("$0.5 budget"), "{err}");
+        assert_eq!(err.exit_code(), 6);
+    }
+
+    #[test]
+    fn overrides_replace_default_prices() {
+        let mut prices = PriceTable::default();
+        prices.set(
+            "deepseek-chat",
+            ModelPrice {
+                input_per_million: 1.0,
+                output_per_million: 1.0,
+            },
+        );
+        let mut tracker = UsageTracker::new(prices);
+        let turn = tracker.record("deepseek-chat", usage(500_000, 500_000));
+        assert!((turn.cost.unwrap() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn summaries_are_one_line() {
+        let mut tracker = UsageTracker::new(PriceTable::default());
+        let turn = tracker.record("deepseek-chat", usage(100, 50));
+        assert_eq!(
+            turn.to_string(),
+            "usage: 100 prompt + 50 completion = 150 tokens, ~$0.000049"
+        );
+        assert_eq!(
+            tracker.to_string(),
+            "session: 1 turns, 100 prompt + 50 completion = 150 tokens, ~$0.000049"
+        );
+    }
+
+    #[test]
+    fn converts_api_usage() {
+        let api = CompletionUsage {
+            prompt_tokens: 7,
+            completion_tokens: 3,
+            total_tokens: 10,
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        };
+        assert_eq!(Usage::from(&api), usage(7, 3));
+    }
+}
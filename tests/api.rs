@@ -0,0 +1,1594 @@
+//! The request and response path end to end, against a mock server.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use deepseek_tutor::batch::{self, BatchResult, BatchRunner};
+use deepseek_tutor::cache::ResponseCache;
+use deepseek_tutor::config::RequestParams;
+use deepseek_tutor::context::ContextManager;
+use deepseek_tutor::conversation::describe;
+use deepseek_tutor::doctor::{self, Status};
+use deepseek_tutor::embeddings::{self, Index, IndexedChunk};
+use deepseek_tutor::finish::ReplyIssue;
+use deepseek_tutor::models::{self, ModelCache};
+use deepseek_tutor::network::NetworkConfig;
+use deepseek_tutor::provider::{DEFAULT_AZURE_API_VERSION, Provider};
+use deepseek_tutor::retry::RetryPolicy;
+use deepseek_tutor::review::{self, Chunk};
+use deepseek_tutor::schema::JsonSchema;
+use deepseek_tutor::stream::Delta;
+use deepseek_tutor::usage::{Budget, ModelPrice, PriceTable};
+use deepseek_tutor::{AgentConfig, AgentError, DeepSeekAgent, ToolRegistry};
+use serde_json::{Value, json};
+use wiremock::matchers::{
+    body_partial_json, body_string_contains, header, method, path, query_param,
+};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const RESPONSE: &str = include_str!("fixtures/deepseek_response.json");
+const STREAM: &str = include_str!("fixtures/deepseek_stream.txt");
+
+fn config(server: &MockServer) -> AgentConfig {
+    AgentConfig {
+        base_url: format!("{}/v1", server.uri()),
+        retry: RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        },
+        ..AgentConfig::new("sk-test")
+    }
+}
+
+fn agent(server: &MockServer) -> DeepSeekAgent {
+    DeepSeekAgent::with_http_client(config(server), reqwest::Client::new()).unwrap()
+}
+
+async fn run_batch(server: &MockServer, input: &str, concurrency: usize) -> Vec<BatchResult> {
+    let runner = BatchRunner::with_http_client(config(server), concurrency, reqwest::Client::new());
+    let mut results = Vec::new();
+    runner
+        .run(batch::parse_items(input).unwrap(), |result| {
+            results.push(result.clone())
+        })
+        .await
+        .unwrap();
+    results
+}
+
+fn completions() -> wiremock::MockBuilder {
+    Mock::given(method("POST")).and(path("/v1/chat/completions"))
+}
+
+fn json_body(body: &str) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_raw(body, "application/json")
+}
+
+fn sse_body(body: &str) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_raw(body, "text/event-stream")
+}
+
+fn rate_limited() -> ResponseTemplate {
+    ResponseTemplate::new(429).set_body_json(json!({
+        "error": {
+            "message": "Rate limit reached for requests. Please try again in 5ms.",
+            "type": "rate_limit_error",
+            "param": null,
+            "code": "rate_limit_exceeded"
+        }
+    }))
+}
+
+/// Count retries as the agent reports them.
+fn count_retries(agent: &mut DeepSeekAgent) -> Arc<Mutex<Vec<Duration>>> {
+    let delays = Arc::new(Mutex::new(Vec::new()));
+    let seen = delays.clone();
+    agent.on_retry(move |attempt| seen.lock().unwrap().push(attempt.delay));
+    delays
+}
+
+async fn request_bodies(server: &MockServer) -> Vec<Value> {
+    server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .map(|request| request.body_json().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn ask_sends_the_conversation_and_returns_the_reply() {
+    let server = MockServer::start().await;
+    completions()
+        .and(header("authorization", "Bearer sk-test"))
+        .and(body_partial_json(json!({ "model": "deepseek-chat" })))
+        // DeepSeek sends blank lines to keep a slow request open
+        .respond_with(json_body(&format!("\n\n{}", RESPONSE)))
+        .expect(2)
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+
+    let answer = agent.ask("What is ownership?").await.unwrap();
+    assert_eq!(
+        answer,
+        "Ownership means every value has a single owner that frees it."
+    );
+    assert_eq!(agent.last_usage().unwrap().usage.total_tokens(), 37);
+    agent.ask("And borrowing?").await.unwrap();
+
+    let bodies = request_bodies(&server).await;
+    let roles: Vec<_> = bodies[1]["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["role"].as_str().unwrap())
+        .collect();
+    assert_eq!(roles, ["system", "user", "assistant", "user"]);
+    assert!(bodies[0].get("stream").is_none_or(|stream| stream == false));
+    assert_eq!(agent.conversation().len(), 4);
+}
+
+#[tokio::test]
+async fn streamed_replies_arrive_in_pieces() {
+    let server = MockServer::start().await;
+    completions()
+        .and(body_partial_json(json!({
+            "stream": true,
+            "stream_options": { "include_usage": true }
+        })))
+        .respond_with(sse_body(STREAM))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+
+    let mut pieces = Vec::new();
+    let answer = agent
+        .ask_streaming("What is borrowing?", |delta| match delta {
+            Delta::Content(text) => pieces.push(text.clone()),
+            Delta::Reasoning(_) => panic!("deepseek-chat doesn't reason"),
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(
+        pieces,
+        ["Borrowing", " lends a value", " without moving it."]
+    );
+    assert_eq!(answer, "Borrowing lends a value without moving it.");
+    assert_eq!(agent.last_usage().unwrap().usage.completion_tokens, 7);
+    assert_eq!(agent.conversation().len(), 2);
+}
+
+#[tokio::test]
+async fn prefills_are_continued_and_kept_as_one_answer() {
+    let server = MockServer::start().await;
+    completions()
+        .and(body_partial_json(json!({ "stop": ["\n\n", "END"] })))
+        .respond_with(sse_body(STREAM))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let mut config = config(&server);
+    config.prefill = Some("In short: ".to_string());
+    config.params.stop = vec!["\n\n".to_string(), "END".to_string()];
+    let mut agent = DeepSeekAgent::with_http_client(config, reqwest::Client::new()).unwrap();
+
+    let mut printed = String::new();
+    let answer = agent
+        .ask_streaming("What is borrowing?", |delta| {
+            if let Delta::Content(text) = delta {
+                printed.push_str(text);
+            }
+        })
+        .await
+        .unwrap();
+
+    let expected = "In short: Borrowing lends a value without moving it.";
+    assert_eq!(answer, expected);
+    assert_eq!(printed, expected);
+    let body = &request_bodies(&server).await[0];
+    let last = body["messages"].as_array().unwrap().last().unwrap();
+    assert_eq!(
+        last,
+        &json!({ "role": "assistant", "content": "In short: ", "prefix": true })
+    );
+    let (role, content) = describe(&agent.conversation().messages()[2]);
+    assert_eq!((role, content.as_str()), ("assistant", expected));
+    assert_eq!(agent.conversation().len(), 2);
+}
+
+#[tokio::test]
+async fn rate_limits_are_retried_after_the_hinted_wait() {
+    let server = MockServer::start().await;
+    completions()
+        .respond_with(rate_limited())
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+    completions()
+        .respond_with(json_body(RESPONSE))
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+    let retries = count_retries(&mut agent);
+
+    let answer = agent.ask("What is ownership?").await.unwrap();
+
+    assert!(answer.starts_with("Ownership"));
+    // without a Retry-After header, the hint comes from the message
+    assert_eq!(*retries.lock().unwrap(), [Duration::from_millis(5)]);
+    assert_eq!(server.received_requests().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn rate_limits_wait_as_long_as_retry_after_says() {
+    let server = MockServer::start().await;
+    completions()
+        .respond_with(rate_limited().insert_header("Retry-After", "2"))
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+    completions()
+        .respond_with(json_body(RESPONSE))
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+    let retries = count_retries(&mut agent);
+
+    let started = std::time::Instant::now();
+    agent.ask("What is ownership?").await.unwrap();
+
+    // the header wins over the message's "try again in 5ms"
+    assert_eq!(*retries.lock().unwrap(), [Duration::from_secs(2)]);
+    assert!(started.elapsed() >= Duration::from_secs(2));
+    assert_eq!(server.received_requests().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn client_errors_with_a_bare_body_are_not_retried() {
+    let server = MockServer::start().await;
+    completions()
+        .respond_with(
+            ResponseTemplate::new(400)
+                .set_body_json(json!({ "error": { "message": "bad request" } })),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+    let retries = count_retries(&mut agent);
+
+    let err = agent.ask("What is ownership?").await.unwrap_err();
+
+    assert!(matches!(err, AgentError::Api(_)), "{err:?}");
+    assert_eq!(err.to_string(), "API request failed: bad request");
+    assert!(retries.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn server_errors_are_retried_before_streaming() {
+    let server = MockServer::start().await;
+    completions()
+        .respond_with(ResponseTemplate::new(503).set_body_string("Service Unavailable"))
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+    completions()
+        .respond_with(sse_body(STREAM))
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+    let retries = count_retries(&mut agent);
+
+    let answer = agent
+        .ask_streaming("What is borrowing?", |_| {})
+        .await
+        .unwrap();
+
+    assert_eq!(answer, "Borrowing lends a value without moving it.");
+    assert_eq!(retries.lock().unwrap().len(), 1);
+    let metrics = agent.last_metrics().unwrap();
+    assert_eq!(metrics.retries, 1);
+    assert_eq!(metrics.requests, 1);
+    assert!(metrics.time_to_first_token.unwrap() <= metrics.latency);
+}
+
+#[tokio::test]
+async fn azure_requests_name_the_deployment_and_api_version() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/openai/deployments/deepseek-chat/chat/completions"))
+        .and(query_param("api-version", "2024-10-21"))
+        .and(header("api-key", "sk-test"))
+        .respond_with(json_body(RESPONSE))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let config = AgentConfig {
+        provider: Provider::AzureOpenAI {
+            api_version: DEFAULT_AZURE_API_VERSION.to_string(),
+        },
+        base_url: server.uri(),
+        ..config(&server)
+    };
+    let mut agent = DeepSeekAgent::with_http_client(config, reqwest::Client::new()).unwrap();
+
+    agent.ask("Hello").await.unwrap();
+    let requests = server.received_requests().await.unwrap();
+    assert!(requests[0].headers.get("authorization").is_none());
+    // the body is the same as for any other provider
+    let body: Value = serde_json::from_slice(&requests[0].body).unwrap();
+    assert_eq!(body["model"], "deepseek-chat");
+}
+
+#[tokio::test]
+async fn replies_are_timed_and_summed_over_the_session() {
+    let server = MockServer::start().await;
+    completions()
+        .and(body_partial_json(json!({ "stream": true })))
+        .respond_with(sse_body(STREAM).set_delay(Duration::from_millis(50)))
+        .mount(&server)
+        .await;
+    completions()
+        .respond_with(json_body(RESPONSE).set_delay(Duration::from_millis(50)))
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+
+    agent.ask("What is ownership?").await.unwrap();
+    let metrics = *agent.last_metrics().unwrap();
+    assert!(metrics.latency >= Duration::from_millis(50));
+    assert_eq!(metrics.time_to_first_token, None);
+    assert_eq!(
+        metrics.completion_tokens,
+        Some(agent.last_usage().unwrap().usage.completion_tokens)
+    );
+    assert_eq!((metrics.retries, metrics.requests), (0, 1));
+    assert!(metrics.tokens_per_second().unwrap() > 0.0);
+
+    agent
+        .ask_streaming("What is borrowing?", |_| {})
+        .await
+        .unwrap();
+    let metrics = agent.last_metrics().unwrap();
+    assert!(metrics.time_to_first_token.unwrap() >= Duration::from_millis(50));
+    assert_eq!(metrics.completion_tokens, Some(7));
+
+    let summary = agent.stats().summary().unwrap();
+    assert_eq!((summary.replies, summary.requests), (2, 2));
+    assert!(summary.p95_latency >= summary.p50_latency);
+    assert!(summary.tokens_per_second.is_some());
+}
+
+#[tokio::test]
+async fn retries_give_up_after_the_policy_allows() {
+    let server = MockServer::start().await;
+    completions()
+        .respond_with(rate_limited())
+        .expect(3)
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+    let retries = count_retries(&mut agent);
+
+    let err = agent.ask("What is ownership?").await.unwrap_err();
+
+    assert!(matches!(err, AgentError::Api(_)), "{err:?}");
+    assert!(err.to_string().contains("Rate limit"), "{err}");
+    assert_eq!(retries.lock().unwrap().len(), 2);
+    assert!(agent.conversation().is_empty());
+}
+
+#[tokio::test]
+async fn extra_body_fields_are_sent_and_rejections_are_api_errors() {
+    let server = MockServer::start().await;
+    completions()
+        .and(body_partial_json(
+            json!({ "thinking": { "type": "disabled" } }),
+        ))
+        .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+            "error": {
+                "message": "Unknown parameter: 'thinking'",
+                "type": "invalid_request_error",
+                "param": "thinking",
+                "code": "invalid_request_error"
+            }
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    completions()
+        .and(body_partial_json(
+            json!({ "logprobs": true, "temperature": 0.5 }),
+        ))
+        .respond_with(json_body(RESPONSE))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let extra_body = |extra: Value| RequestParams {
+        temperature: Some(1.0),
+        extra_body: extra.as_object().unwrap().clone(),
+        ..RequestParams::default()
+    };
+
+    let mut agent = DeepSeekAgent::new(AgentConfig {
+        params: extra_body(json!({ "logprobs": true, "temperature": 0.5 })),
+        ..config(&server)
+    })
+    .unwrap();
+    agent.ask("What is ownership?").await.unwrap();
+    let body = &request_bodies(&server).await[0];
+    assert_eq!(body["model"], "deepseek-chat");
+    assert_eq!(body["messages"][1]["content"], "What is ownership?");
+
+    let mut agent = DeepSeekAgent::new(AgentConfig {
+        params: extra_body(json!({ "thinking": { "type": "disabled" } })),
+        ..config(&server)
+    })
+    .unwrap();
+    let err = agent.ask("What is ownership?").await.unwrap_err();
+    assert!(matches!(err, AgentError::Api(_)), "{err:?}");
+    assert!(err.to_string().contains("Unknown parameter"), "{err}");
+}
+
+#[tokio::test]
+async fn client_errors_are_not_retried() {
+    let server = MockServer::start().await;
+    completions()
+        .respond_with(ResponseTemplate::new(401).set_body_raw(
+            include_str!("fixtures/errors/authentication.json"),
+            "application/json",
+        ))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+
+    let err = agent.ask("What is ownership?").await.unwrap_err();
+
+    assert!(matches!(err, AgentError::Auth(_)), "{err:?}");
+    assert!(err.to_string().contains("API key was rejected"), "{err}");
+    assert!(err.to_string().contains("Authentication Fails"), "{err}");
+}
+
+#[tokio::test]
+async fn exhausted_balances_are_reported_streaming_or_not() {
+    let server = MockServer::start().await;
+    completions()
+        .respond_with(ResponseTemplate::new(402).set_body_raw(
+            include_str!("fixtures/errors/insufficient_balance.json"),
+            "application/json",
+        ))
+        .expect(2)
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+
+    let err = agent.ask("What is ownership?").await.unwrap_err();
+    assert!(matches!(err, AgentError::Quota(_)), "{err:?}");
+    assert_eq!(err.api_failure().unwrap().message, "Insufficient Balance");
+
+    // a failed stream keeps only the status
+    let err = agent
+        .ask_streaming("What is ownership?", |_| {})
+        .await
+        .unwrap_err();
+    assert!(matches!(err, AgentError::Quota(_)), "{err:?}");
+    assert!(
+        err.to_string().starts_with("your balance is exhausted"),
+        "{err}"
+    );
+}
+
+#[tokio::test]
+async fn malformed_payloads_are_errors_not_retries() {
+    let server = MockServer::start().await;
+    completions()
+        .respond_with(json_body("{\"choices\": [{\"message\": "))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+
+    let err = agent.ask("What is ownership?").await.unwrap_err();
+
+    assert!(err.to_string().contains("deserialize"), "{err}");
+    assert!(agent.conversation().is_empty());
+}
+
+#[tokio::test]
+async fn malformed_stream_chunks_are_errors() {
+    let server = MockServer::start().await;
+    completions()
+        .respond_with(sse_body("data: {\"choices\": 7}\n\ndata: [DONE]\n\n"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+
+    let err = agent
+        .ask_streaming("What is ownership?", |_| {})
+        .await
+        .unwrap_err();
+
+    assert!(err.to_string().contains("deserialize"), "{err}");
+    assert!(agent.conversation().is_empty());
+}
+
+#[tokio::test]
+async fn deepseek_schema_differences_are_tolerated() {
+    let server = MockServer::start().await;
+    completions()
+        .respond_with(json_body(include_str!(
+            "fixtures/insufficient_resource.json"
+        )))
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+    completions()
+        .respond_with(json_body(include_str!("fixtures/sparse_response.json")))
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+
+    assert_eq!(
+        agent.ask("What does the borrow checker do?").await.unwrap(),
+        "The borrow checker enforces"
+    );
+    assert_eq!(agent.last_finish_reason(), None);
+    assert!(
+        agent
+            .ask("What are lifetimes?")
+            .await
+            .unwrap()
+            .starts_with("Lifetimes")
+    );
+    assert!(agent.last_usage().is_none());
+}
+
+#[tokio::test]
+async fn batches_stay_within_the_concurrency_limit() {
+    let server = MockServer::start().await;
+    completions()
+        .respond_with(json_body(RESPONSE).set_delay(Duration::from_millis(100)))
+        .expect(4)
+        .mount(&server)
+        .await;
+    let input = (1..=4)
+        .map(|id| format!("{{\"id\": {id}, \"prompt\": \"question {id}\"}}\n"))
+        .collect::<String>();
+
+    let started = std::time::Instant::now();
+    let results = run_batch(&server, &input, 2).await;
+
+    // two rounds of two
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    let mut ids: Vec<_> = results.iter().map(|result| result.id.clone()).collect();
+    ids.sort_by_key(|id| id.as_u64());
+    assert_eq!(ids, [json!(1), json!(2), json!(3), json!(4)]);
+    assert!(results.iter().all(|result| result.outcome.is_ok()));
+    assert_eq!(results[0].usage.as_ref().unwrap().usage.total_tokens(), 37);
+    assert!(results[0].latency >= Duration::from_millis(100));
+}
+
+#[tokio::test]
+async fn incomplete_answers_are_flagged_for_another_try() {
+    let server = MockServer::start().await;
+    completions()
+        .and(body_partial_json(json!({ "max_tokens": 8 })))
+        .respond_with(json_body(&RESPONSE.replace(
+            r#""finish_reason": "stop""#,
+            r#""finish_reason": "length""#,
+        )))
+        .mount(&server)
+        .await;
+    completions()
+        .respond_with(json_body(RESPONSE))
+        .mount(&server)
+        .await;
+
+    let results = run_batch(
+        &server,
+        r#"{"id": "short", "prompt": "What is ownership?", "max_tokens": 8}
+{"id": "whole", "prompt": "What is ownership?"}"#,
+        1,
+    )
+    .await;
+    let short = results.iter().find(|result| result.id == "short").unwrap();
+    assert_eq!(short.issue, Some(ReplyIssue::Truncated));
+    assert_eq!(short.to_json()["issue"], "truncated");
+    let whole = results.iter().find(|result| result.id == "whole").unwrap();
+    assert_eq!(whole.issue, None);
+
+    // a resumed batch asks the flagged one again
+    let output: String = results
+        .iter()
+        .map(|result| format!("{}\n", result.to_json()))
+        .collect();
+    assert_eq!(batch::completed_ids(&output), [json!("whole")]);
+}
+
+#[tokio::test]
+async fn batch_failures_are_recorded_per_item() {
+    let server = MockServer::start().await;
+    completions()
+        .and(body_partial_json(json!({ "model": "no-such-model" })))
+        .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+            "error": {
+                "message": "Model Not Exist",
+                "type": "invalid_request_error",
+                "param": null,
+                "code": "invalid_request_error"
+            }
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    completions()
+        .respond_with(ResponseTemplate::new(503).set_body_string("Service Unavailable"))
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+    completions()
+        .respond_with(json_body(RESPONSE))
+        .mount(&server)
+        .await;
+
+    let results = run_batch(
+        &server,
+        r#"{"id": "good", "prompt": "What is ownership?"}
+{"id": "bad", "prompt": "What is ownership?", "model": "no-such-model"}"#,
+        1,
+    )
+    .await;
+
+    let good = results.iter().find(|result| result.id == "good").unwrap();
+    assert!(good.outcome.as_ref().unwrap().starts_with("Ownership"));
+    let bad = results.iter().find(|result| result.id == "bad").unwrap();
+    assert_eq!(bad.model, "no-such-model");
+    let error = bad.outcome.as_ref().unwrap_err();
+    assert!(error.contains("Model Not Exist"), "{error}");
+    assert_eq!(bad.to_json()["content"], Value::Null);
+
+    let output: String = results
+        .iter()
+        .map(|result| format!("{}\n", result.to_json()))
+        .collect();
+    assert_eq!(batch::completed_ids(&output), [json!("good")]);
+}
+
+fn embeddings(vectors: &[(u32, [f32; 2])], prompt_tokens: u32) -> ResponseTemplate {
+    let data: Vec<Value> = vectors
+        .iter()
+        .map(|(index, embedding)| json!({ "object": "embedding", "index": index, "embedding": embedding }))
+        .collect();
+    ResponseTemplate::new(200).set_body_json(json!({
+        "object": "list",
+        "model": "embedder",
+        "data": data,
+        "usage": { "prompt_tokens": prompt_tokens, "total_tokens": prompt_tokens }
+    }))
+}
+
+#[tokio::test]
+async fn embeddings_are_retried_ordered_and_counted() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/embeddings"))
+        .respond_with(rate_limited())
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/v1/embeddings"))
+        .and(body_partial_json(
+            json!({ "model": "embedder", "input": ["a", "b"] }),
+        ))
+        .respond_with(embeddings(&[(1, [0.0, 1.0]), (0, [1.0, 0.0])], 6))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+    let retries = count_retries(&mut agent);
+
+    let vectors = agent
+        .embed("embedder", &["a".to_string(), "b".to_string()])
+        .await
+        .unwrap();
+
+    assert_eq!(vectors, [vec![1.0, 0.0], vec![0.0, 1.0]]);
+    assert_eq!(retries.lock().unwrap().len(), 1);
+    assert_eq!(agent.usage().usage().prompt_tokens, 6);
+    assert_eq!(agent.usage().turns(), 0);
+}
+
+#[tokio::test]
+async fn files_are_embedded_into_a_saved_index() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/embeddings"))
+        .respond_with(embeddings(&[(0, [1.0, 0.0])], 3))
+        .expect(3)
+        .mount(&server)
+        .await;
+    let dir = std::env::temp_dir().join(format!("deepseek_api_embed_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let (notes, todo) = (dir.join("notes.md"), dir.join("todo.md"));
+    std::fs::write(&notes, "Each value has one owner.\n").unwrap();
+    std::fs::write(&todo, "Read about lifetimes.\n").unwrap();
+    let index_path = dir.join("index.json");
+    let mut agent = agent(&server);
+
+    let mut index = Index::open(&index_path, "embedder", 1000, 200).unwrap();
+    let mut embedded = Vec::new();
+    embeddings::embed_files(
+        &mut agent,
+        &mut index,
+        &[notes.clone(), todo.clone()],
+        &index_path,
+        |source, chunks| embedded.push((source.to_string(), chunks)),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        embedded,
+        [
+            (notes.display().to_string(), 1),
+            (todo.display().to_string(), 1)
+        ]
+    );
+    assert_eq!(Index::load(&index_path).unwrap(), index);
+
+    // embedding a file again replaces its chunks
+    let mut index = Index::open(&index_path, "embedder", 1000, 200).unwrap();
+    embeddings::embed_files(&mut agent, &mut index, &[notes], &index_path, |_, _| {})
+        .await
+        .unwrap();
+    assert_eq!(Index::load(&index_path).unwrap().chunks.len(), 2);
+    let err = Index::open(&index_path, "other", 1000, 200).unwrap_err();
+    assert!(
+        err.to_string().contains("was embedded with embedder"),
+        "{err}"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn the_closest_chunks_go_into_the_system_prompt() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/embeddings"))
+        .respond_with(embeddings(&[(0, [1.0, 0.1])], 3))
+        .expect(2)
+        .mount(&server)
+        .await;
+    completions()
+        .respond_with(json_body(RESPONSE))
+        .expect(2)
+        .mount(&server)
+        .await;
+    let chunk = |source: &str, text: &str, embedding: [f32; 2]| IndexedChunk {
+        source: source.to_string(),
+        start_line: 1,
+        end_line: 3,
+        text: text.to_string(),
+        embedding: embedding.to_vec(),
+    };
+    let mut index = Index::new("embedder", 1000, 200);
+    index.chunks = vec![
+        chunk("ownership.md", "Each value has one owner.", [1.0, 0.0]),
+        chunk("async.md", "Futures are lazy.", [0.0, 1.0]),
+    ];
+    let mut agent = agent(&server);
+    agent.set_context_index(index, 1);
+
+    agent.ask("What is ownership?").await.unwrap();
+    agent.ask("And moves?").await.unwrap();
+
+    assert_eq!(agent.last_excerpts().len(), 1);
+    assert_eq!(agent.last_excerpts()[0].0, "ownership.md:1-3");
+    let bodies: Vec<_> = request_bodies(&server)
+        .await
+        .into_iter()
+        .filter(|body| body.get("messages").is_some())
+        .collect();
+    let system = bodies[1]["messages"][0]["content"].as_str().unwrap();
+    assert!(system.contains("--- ownership.md:1-3 ---\nEach value has one owner."));
+    assert!(!system.contains("Futures are lazy."));
+    // replaced, not piled up, on the second question
+    assert_eq!(system.matches("--- ownership.md").count(), 1);
+}
+
+fn model_list() -> ResponseTemplate {
+    // as DeepSeek sends it, without `created`
+    ResponseTemplate::new(200).set_body_json(json!({
+        "object": "list",
+        "data": [
+            { "id": "deepseek-reasoner", "object": "model", "owned_by": "deepseek" },
+            { "id": "deepseek-chat", "object": "model", "owned_by": "deepseek" }
+        ]
+    }))
+}
+
+#[tokio::test]
+async fn model_lists_are_cached_until_they_expire() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v1/models"))
+        .respond_with(model_list())
+        .expect(3)
+        .mount(&server)
+        .await;
+    let agent = agent(&server);
+    let dir = std::env::temp_dir().join(format!("deepseek_model_cache_{}", std::process::id()));
+    let cache_path = dir.join("models.json");
+
+    let (listed, cached) = models::list(&agent, Some(&cache_path), false)
+        .await
+        .unwrap();
+    assert!(!cached);
+    let ids: Vec<_> = listed.iter().map(|info| info.id.as_str()).collect();
+    assert_eq!(ids, ["deepseek-chat", "deepseek-reasoner"]);
+
+    let (again, cached) = models::list(&agent, Some(&cache_path), false)
+        .await
+        .unwrap();
+    assert!(cached);
+    assert_eq!(again, listed);
+
+    // refreshing skips the cache
+    let (_, cached) = models::list(&agent, Some(&cache_path), true).await.unwrap();
+    assert!(!cached);
+
+    // a list fetched over an hour ago is fetched again
+    let mut cache = ModelCache::load(&cache_path);
+    let stale = chrono::Utc::now() - chrono::Duration::minutes(61);
+    cache.insert(agent.base_url(), stale, listed.clone());
+    cache.save(&cache_path).unwrap();
+    let (_, cached) = models::list(&agent, Some(&cache_path), false)
+        .await
+        .unwrap();
+    assert!(!cached);
+    assert!(ModelCache::load(&cache_path).lists[agent.base_url()].fetched_at > stale);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn unknown_models_are_warned_about_before_chatting() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v1/models"))
+        .respond_with(model_list())
+        .mount(&server)
+        .await;
+    let mut config = config(&server);
+    config.model = "deepseek-chatt".to_string();
+    let agent = DeepSeekAgent::with_http_client(config, reqwest::Client::new()).unwrap();
+
+    let (listed, _) = models::list(&agent, None, false).await.unwrap();
+    let warning = models::unknown_model_warning(&listed, agent.model(), agent.base_url()).unwrap();
+    assert!(
+        warning.starts_with("model 'deepseek-chatt' is not listed by http://"),
+        "{warning}"
+    );
+    assert!(
+        warning.ends_with("available: deepseek-chat, deepseek-reasoner"),
+        "{warning}"
+    );
+    assert_eq!(
+        models::unknown_model_warning(&listed, "deepseek-chat", agent.base_url()),
+        None
+    );
+}
+
+#[tokio::test]
+async fn cached_replies_skip_the_request_and_cost_nothing() {
+    let server = MockServer::start().await;
+    completions()
+        .respond_with(json_body(RESPONSE))
+        .expect(2)
+        .mount(&server)
+        .await;
+    let dir = std::env::temp_dir().join(format!("deepseek_response_cache_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let cached_agent = |refresh| {
+        let config = AgentConfig {
+            cache: Some(ResponseCache {
+                refresh,
+                ..ResponseCache::new(&dir)
+            }),
+            ..config(&server)
+        };
+        DeepSeekAgent::with_http_client(config, reqwest::Client::new()).unwrap()
+    };
+
+    let mut first = cached_agent(false);
+    let answer = first.ask("What is ownership?").await.unwrap();
+    assert!(!first.last_reply_cached());
+    assert_eq!(first.usage().turns(), 1);
+
+    // streamed or not, the same question is answered from the cache
+    let mut second = cached_agent(false);
+    let mut pieces = Vec::new();
+    let again = second
+        .ask_streaming("What is ownership?", |delta| {
+            if let Delta::Content(text) = delta {
+                pieces.push(text.clone());
+            }
+        })
+        .await
+        .unwrap();
+    assert_eq!(again, answer);
+    assert_eq!(pieces, std::slice::from_ref(&answer));
+    assert!(second.last_reply_cached());
+    assert!(second.last_usage().is_none());
+    assert!(second.last_metrics().is_none());
+    assert_eq!(second.usage().turns(), 0);
+    assert_eq!(second.conversation().len(), 2);
+
+    // refreshing sends it again
+    let mut third = cached_agent(true);
+    third.ask("What is ownership?").await.unwrap();
+    assert!(!third.last_reply_cached());
+    assert_eq!(third.last_usage().unwrap().usage.total_tokens(), 37);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn fan_out_models() -> Vec<String> {
+    vec!["deepseek-chat".to_string(), "deepseek-reasoner".to_string()]
+}
+
+#[tokio::test]
+async fn several_models_answer_the_same_question_at_once() {
+    let server = MockServer::start().await;
+    completions()
+        .and(body_partial_json(json!({ "model": "deepseek-chat" })))
+        .respond_with(json_body(RESPONSE).set_delay(Duration::from_millis(200)))
+        .expect(1)
+        .mount(&server)
+        .await;
+    completions()
+        .and(body_partial_json(json!({ "model": "deepseek-reasoner" })))
+        .respond_with(
+            json_body(include_str!("fixtures/reasoner_response.json"))
+                .set_delay(Duration::from_millis(200)),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+
+    let started = std::time::Instant::now();
+    let answers = agent
+        .ask_models("What is ownership?", &fan_out_models(), "deepseek-chat")
+        .await
+        .unwrap();
+    assert!(
+        started.elapsed() < Duration::from_millis(400),
+        "asked one by one"
+    );
+
+    // in the order asked, each with its own usage
+    let models: Vec<&str> = answers.iter().map(|a| a.model.as_str()).collect();
+    assert_eq!(models, ["deepseek-chat", "deepseek-reasoner"]);
+    let reasoner = answers[1].reply.as_ref().unwrap();
+    assert_eq!(reasoner.content, "1013 is prime.");
+    assert!(reasoner.reasoning.is_some());
+    assert_eq!(answers[0].usage.as_ref().unwrap().usage.total_tokens(), 37);
+    assert_eq!(answers[1].usage.as_ref().unwrap().usage.total_tokens(), 110);
+    assert_eq!(agent.usage().turns(), 2);
+    assert_eq!(agent.usage().usage().total_tokens(), 147);
+
+    // both saw the same question, but only the history model's answer is kept
+    let bodies = request_bodies(&server).await;
+    assert_eq!(bodies[0]["messages"], bodies[1]["messages"]);
+    assert_eq!(agent.conversation().len(), 2);
+    let (role, content) = describe(agent.conversation().messages().last().unwrap());
+    assert_eq!(role, "assistant");
+    assert_eq!(content, answers[0].reply.as_ref().unwrap().content);
+    assert_eq!(agent.last_usage().unwrap().model, "deepseek-chat");
+}
+
+#[tokio::test]
+async fn one_model_failing_leaves_the_others_answers() {
+    let server = MockServer::start().await;
+    completions()
+        .and(body_partial_json(json!({ "model": "deepseek-chat" })))
+        .respond_with(json_body(RESPONSE))
+        .expect(2)
+        .mount(&server)
+        .await;
+    completions()
+        .and(body_partial_json(json!({ "model": "deepseek-reasoner" })))
+        .respond_with(ResponseTemplate::new(400).set_body_raw(
+            include_str!("fixtures/errors/unknown_parameter.json"),
+            "application/json",
+        ))
+        .expect(2)
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+
+    let answers = agent
+        .ask_models("What is ownership?", &fan_out_models(), "deepseek-chat")
+        .await
+        .unwrap();
+    assert!(answers[0].reply.is_ok());
+    assert!(
+        matches!(answers[1].reply, Err(AgentError::Api(_))),
+        "{:?}",
+        answers[1].reply
+    );
+    assert!(answers[1].usage.is_none());
+    assert_eq!(agent.usage().turns(), 1);
+    assert_eq!(agent.conversation().len(), 2);
+
+    // when the history model is the one that failed, nothing is kept
+    let answers = agent
+        .ask_models("And borrowing?", &fan_out_models(), "deepseek-reasoner")
+        .await
+        .unwrap();
+    assert!(answers[0].reply.is_ok());
+    assert!(answers[1].reply.is_err());
+    assert_eq!(agent.conversation().len(), 2);
+    assert!(agent.last_usage().is_none());
+
+    let err = agent
+        .ask_models("And lifetimes?", &fan_out_models(), "qwen")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, AgentError::InvalidConfig(_)), "{err:?}");
+}
+
+fn order_schema() -> JsonSchema {
+    JsonSchema::new(json!({
+        "type": "object",
+        "required": ["items"],
+        "properties": {
+            "items": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["name", "price"],
+                    "properties": { "price": { "type": "number" } }
+                }
+            }
+        }
+    }))
+    .unwrap()
+}
+
+/// A completion whose answer is `content`, costing 10 tokens.
+fn reply_with(content: &str) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({
+        "object": "chat.completion",
+        "model": "deepseek-chat",
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": content },
+            "finish_reason": "stop"
+        }],
+        "usage": { "prompt_tokens": 8, "completion_tokens": 2, "total_tokens": 10 }
+    }))
+}
+
+/// Answer each request with the next of `replies`.
+async fn mount_replies(server: &MockServer, replies: &[&str]) {
+    for reply in replies {
+        completions()
+            .respond_with(reply_with(reply))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(server)
+            .await;
+    }
+}
+
+#[tokio::test]
+async fn json_answers_are_repaired_until_they_match_the_schema() {
+    let server = MockServer::start().await;
+    mount_replies(
+        &server,
+        &[
+            "Here is the order:\n```json\n{\"items\": [{\"name\": \"tea\"}]}\n```",
+            "Sorry, I can't do that.",
+            "```json\n{\"items\": [{\"name\": \"tea\", \"price\": 3.5}]}\n```\nEnjoy!",
+        ],
+    )
+    .await;
+    let mut agent = DeepSeekAgent::with_http_client(
+        AgentConfig {
+            params: RequestParams {
+                json_mode: true,
+                ..RequestParams::default()
+            },
+            ..config(&server)
+        },
+        reqwest::Client::new(),
+    )
+    .unwrap();
+
+    let value = agent
+        .ask_json("Order a tea", &order_schema(), 2)
+        .await
+        .unwrap();
+    assert_eq!(value, json!({ "items": [{ "name": "tea", "price": 3.5 }] }));
+
+    let bodies = request_bodies(&server).await;
+    assert_eq!(bodies.len(), 3);
+    assert_eq!(
+        bodies[0]["response_format"],
+        json!({ "type": "json_object" })
+    );
+    let system = bodies[0]["messages"][0]["content"].as_str().unwrap();
+    assert!(system.contains("JSON Schema"), "{system}");
+    // each repair sends back the reply and what was wrong with it
+    let repair = &bodies[1]["messages"];
+    assert_eq!(repair.as_array().unwrap().len(), 4);
+    let complaint = repair[3]["content"].as_str().unwrap();
+    assert!(
+        complaint.contains("missing field `items[0].price`"),
+        "{complaint}"
+    );
+    let complaint = bodies[2]["messages"][5]["content"].as_str().unwrap();
+    assert!(complaint.contains("not JSON"), "{complaint}");
+
+    // only the question and the JSON that matched are kept, and every try is paid for
+    let roles: Vec<_> = agent
+        .conversation()
+        .messages()
+        .iter()
+        .map(|message| describe(message))
+        .collect();
+    assert_eq!(roles.len(), 3);
+    assert_eq!(roles[2], ("assistant", value.to_string()));
+    assert_eq!(agent.last_usage().unwrap().usage.total_tokens(), 30);
+
+    // the instructions aren't added twice
+    mount_replies(&server, &[r#"{"items": []}"#]).await;
+    agent.ask_json("Nothing", &order_schema(), 0).await.unwrap();
+    let bodies = request_bodies(&server).await;
+    assert_eq!(bodies[3]["messages"][0]["content"], system);
+}
+
+#[tokio::test]
+async fn json_answers_that_never_match_are_errors() {
+    let server = MockServer::start().await;
+    mount_replies(&server, &["{}", r#"{"items": "tea"}"#]).await;
+    let mut agent = agent(&server);
+
+    let err = agent
+        .ask_json("Order a tea", &order_schema(), 1)
+        .await
+        .unwrap_err();
+    let AgentError::SchemaMismatch {
+        attempts,
+        errors,
+        content,
+    } = &err
+    else {
+        panic!("{err:?}");
+    };
+    assert_eq!(*attempts, 2);
+    assert_eq!(content, r#"{"items": "tea"}"#);
+    assert!(errors[0].starts_with("`items`: "), "{errors:?}");
+    assert_eq!(err.exit_code(), 4);
+
+    // the question is dropped, but its tokens still count
+    assert!(agent.conversation().is_empty());
+    assert_eq!(agent.usage().usage().total_tokens(), 20);
+}
+
+/// A config that spends a cent a token, has a budget of `limit`, and asks
+/// short questions: "Answer briefly." and a 40-character prompt come to an
+/// estimated 14 tokens.
+fn budgeted(server: &MockServer, limit: f64) -> AgentConfig {
+    let mut prices = PriceTable::default();
+    prices.set(
+        "deepseek-chat",
+        ModelPrice {
+            input_per_million: 10_000.0,
+            output_per_million: 10_000.0,
+        },
+    );
+    AgentConfig {
+        system_prompt: "Answer briefly.".to_string(),
+        prices,
+        budget: Some(Budget::new(limit)),
+        ..config(server)
+    }
+}
+
+const QUESTION: &str = "What does the borrow checker check for??";
+
+#[tokio::test]
+async fn requests_that_could_go_over_the_budget_are_never_sent() {
+    let server = MockServer::start().await;
+    mount_replies(&server, &["ok"]).await;
+    let mut agent =
+        DeepSeekAgent::with_http_client(budgeted(&server, 0.3), reqwest::Client::new()).unwrap();
+
+    // ~$0.14 estimated, then 10 tokens spent
+    agent.ask(QUESTION).await.unwrap();
+    // the history makes the next one ~$0.25, on top of the $0.10
+    let err = agent.ask(QUESTION).await.unwrap_err();
+
+    let AgentError::BudgetExceeded {
+        spent,
+        estimate,
+        limit,
+    } = err
+    else {
+        panic!("{err:?}");
+    };
+    assert!((spent - 0.1).abs() < 1e-9, "{spent}");
+    assert!((estimate - 0.25).abs() < 1e-9, "{estimate}");
+    assert_eq!(limit, 0.3);
+    assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    assert_eq!(agent.conversation().len(), 2);
+}
+
+#[tokio::test]
+async fn batches_stop_starting_prompts_at_the_budget() {
+    let server = MockServer::start().await;
+    mount_replies(&server, &["ok", "ok"]).await;
+    let input = (1..=4)
+        .map(|id| format!("{{\"id\": {id}, \"prompt\": \"{QUESTION}\"}}\n"))
+        .collect::<String>();
+    // $0.10 a prompt, and each is estimated at $0.14 before it is sent
+    let runner = BatchRunner::with_http_client(budgeted(&server, 0.25), 1, reqwest::Client::new());
+
+    let mut results = Vec::new();
+    let err = runner
+        .run(batch::parse_items(&input).unwrap(), |result| {
+            results.push(result.clone())
+        })
+        .await
+        .unwrap_err();
+
+    assert!(
+        matches!(err, AgentError::BudgetExceeded { spent, .. } if (spent - 0.2).abs() < 1e-9),
+        "{err:?}"
+    );
+    // the third is refused with $0.05 left, and the fourth never started
+    assert_eq!(results.len(), 3);
+    assert!(results[..2].iter().all(|result| result.outcome.is_ok()));
+    let refused = results[2].outcome.as_ref().unwrap_err();
+    assert!(
+        refused.contains("$0.25 budget: ~$0.200000 spent"),
+        "{refused}"
+    );
+    assert_eq!(server.received_requests().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn requests_go_through_the_proxy() {
+    let proxy = MockServer::start().await;
+    completions()
+        .respond_with(json_body(RESPONSE))
+        .expect(1)
+        .mount(&proxy)
+        .await;
+    let config = AgentConfig {
+        // never resolved: the proxy is asked for it instead
+        base_url: "http://api.deepseek.invalid/v1".to_string(),
+        network: NetworkConfig {
+            proxy: Some(proxy.uri()),
+            ..NetworkConfig::default()
+        },
+        ..config(&proxy)
+    };
+    config.network.check_proxy().await.unwrap();
+    let mut agent = DeepSeekAgent::new(config).unwrap();
+
+    let reply = agent.ask("What is ownership?").await.unwrap();
+    assert!(reply.starts_with("Ownership"));
+    let requests = proxy.received_requests().await.unwrap();
+    assert_eq!(requests[0].url.host_str(), Some("api.deepseek.invalid"));
+}
+
+#[tokio::test]
+async fn dry_runs_build_the_request_that_is_sent() {
+    let server = MockServer::start().await;
+    mount_replies(&server, &["One.", "Two.", "Three."]).await;
+    let mut agent = DeepSeekAgent::with_http_client(
+        AgentConfig {
+            system_prompt: "Be brief.".to_string(),
+            params: RequestParams {
+                max_tokens: Some(64),
+                stop: vec!["END".to_string()],
+                ..RequestParams::default()
+            },
+            // so the oldest turn is dropped before the third question
+            context: ContextManager {
+                budget: 1,
+                keep_turns: 1,
+                ..ContextManager::default()
+            },
+            ..config(&server)
+        },
+        reqwest::Client::new(),
+    )
+    .unwrap();
+    agent.set_tools(ToolRegistry::builtin());
+    agent.ask("First?").await.unwrap();
+    agent.ask("Second?").await.unwrap();
+
+    let dry_run = agent.dry_run("Third?", false, None).await.unwrap();
+    assert_eq!(agent.conversation().len(), 4);
+    assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    agent.ask("Third?").await.unwrap();
+
+    let bodies = request_bodies(&server).await;
+    assert_eq!(dry_run.request, bodies[2]);
+    assert_eq!(dry_run.request["tools"].as_array().unwrap().len(), 2);
+    assert_eq!(dry_run.trimmed.unwrap().messages, 2);
+    assert_eq!(dry_run.base_url, format!("{}/v1", server.uri()));
+    assert_eq!(dry_run.model, "deepseek-chat");
+}
+
+#[tokio::test]
+async fn streamed_and_json_dry_runs_match_what_is_sent() {
+    let server = MockServer::start().await;
+    completions()
+        .and(body_partial_json(json!({ "stream": true })))
+        .respond_with(sse_body(STREAM))
+        .mount(&server)
+        .await;
+    completions()
+        .respond_with(reply_with(r#"{"items": []}"#))
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+    let schema = order_schema();
+
+    let streamed = agent
+        .dry_run("What is ownership?", true, None)
+        .await
+        .unwrap();
+    agent
+        .ask_streaming("What is ownership?", |_| {})
+        .await
+        .unwrap();
+    let json = agent
+        .dry_run("List the order.", false, Some(&schema))
+        .await
+        .unwrap();
+    agent.ask_json("List the order.", &schema, 0).await.unwrap();
+
+    let bodies = request_bodies(&server).await;
+    assert_eq!(streamed.request, bodies[0]);
+    assert_eq!(json.request, bodies[1]);
+}
+
+#[tokio::test]
+async fn batch_dry_runs_match_each_item_sent() {
+    let server = MockServer::start().await;
+    completions()
+        .respond_with(json_body(RESPONSE))
+        .mount(&server)
+        .await;
+    let input = r#"{"id": 1, "prompt": "What is ownership?"}
+{"id": 2, "prompt": "What is borrowing?", "model": "deepseek-reasoner", "temperature": 0.2}"#;
+    let runner = BatchRunner::with_http_client(config(&server), 1, reqwest::Client::new());
+    let items = batch::parse_items(input).unwrap();
+    let mut dry_runs = Vec::new();
+    for item in &items {
+        dry_runs.push(runner.dry_run(item).await.unwrap());
+    }
+    assert!(server.received_requests().await.unwrap().is_empty());
+    runner.run(items, |_| {}).await.unwrap();
+
+    let bodies = request_bodies(&server).await;
+    assert_eq!(dry_runs[0].request, bodies[0]);
+    assert_eq!(dry_runs[1].request, bodies[1]);
+    assert_eq!(dry_runs[1].model, "deepseek-reasoner");
+}
+
+#[tokio::test]
+async fn reviews_gather_findings_and_note_chunks_that_failed() {
+    let server = MockServer::start().await;
+    completions()
+        .and(body_string_contains("src/lib.rs"))
+        .respond_with(reply_with(
+            r#"{"findings": [{"file": "src/lib.rs", "line": 3, "severity": "critical", "title": "Panics on empty input"}]}"#,
+        ))
+        .mount(&server)
+        .await;
+    completions()
+        .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+            "error": { "message": "bad request", "type": "invalid_request_error" }
+        })))
+        .mount(&server)
+        .await;
+    let chunk = |file: &str| Chunk {
+        files: vec![file.to_string()],
+        text: format!("diff --git a/{file} b/{file}\n+x\n"),
+    };
+    let mut agent = DeepSeekAgent::with_http_client(
+        review::reviewer_config(config(&server)),
+        reqwest::Client::new(),
+    )
+    .unwrap();
+
+    let mut progress = Vec::new();
+    let review = review::review_chunks(
+        &mut agent,
+        &[chunk("src/lib.rs"), chunk("src/main.rs")],
+        Vec::new(),
+        |step| {
+            progress.push(match step {
+                review::Progress::Sending(i, _) => format!("sending {}", i),
+                review::Progress::Failed(i, _) => format!("failed {}", i),
+            })
+        },
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(progress, ["sending 0", "sending 1", "failed 1"]);
+    assert_eq!(review.reviewed, 1);
+    let markdown = review.report.to_markdown();
+    assert!(markdown.contains("Panics on empty input"), "{markdown}");
+    assert!(
+        markdown.contains("## Not reviewed\n\n- src/main.rs:"),
+        "{markdown}"
+    );
+    let bodies = request_bodies(&server).await;
+    assert_eq!(bodies[0]["response_format"]["type"], "json_object");
+    assert!(matches!(
+        review.verdict(),
+        Err(AgentError::CriticalFindings(1))
+    ));
+}
+
+fn unauthorized() -> ResponseTemplate {
+    ResponseTemplate::new(401).set_body_raw(
+        include_str!("fixtures/errors/authentication.json"),
+        "application/json",
+    )
+}
+
+#[tokio::test]
+async fn doctor_passes_a_working_key_and_model() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v1/models"))
+        .respond_with(model_list())
+        .mount(&server)
+        .await;
+    completions()
+        .and(body_partial_json(json!({ "max_tokens": 1 })))
+        .respond_with(json_body(RESPONSE))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let mut agent = agent(&server);
+
+    let auth = doctor::auth(&agent, true).await;
+    assert_eq!(auth.status, Status::Pass, "{auth:?}");
+    assert_eq!(
+        auth.detail,
+        "the key works; 2 models listed, deepseek-chat among them"
+    );
+    let chat = doctor::chat(&mut agent, true).await;
+    assert_eq!(chat.status, Status::Pass, "{chat:?}");
+    // the check leaves no history, nor its one-token limit
+    assert!(agent.conversation().is_empty());
+    assert_eq!(agent.params().max_tokens, None);
+
+    let base_url = agent.base_url().to_string();
+    let tls = doctor::tls(&base_url, &reqwest::Client::new()).await;
+    assert_eq!(tls.status, Status::Pass, "{tls:?}");
+    // wiremock answers with a Date header
+    let clock = doctor::clock(&base_url, &reqwest::Client::new()).await;
+    assert_eq!(clock.status, Status::Pass, "{clock:?}");
+}
+
+#[tokio::test]
+async fn doctor_fails_a_refused_key() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v1/models"))
+        .respond_with(unauthorized())
+        .mount(&server)
+        .await;
+    completions()
+        .respond_with(unauthorized())
+        .mount(&server)
+        .await;
+    let mut agent = DeepSeekAgent::with_http_client(
+        AgentConfig {
+            retry: RetryPolicy::none(),
+            ..config(&server)
+        },
+        reqwest::Client::new(),
+    )
+    .unwrap();
+
+    for result in [
+        doctor::auth(&agent, true).await,
+        doctor::chat(&mut agent, true).await,
+    ] {
+        assert!(result.is_critical_failure(), "{result:?}");
+        assert!(result.hint.unwrap().contains("API key"));
+    }
+    // without a key neither is tried
+    assert_eq!(doctor::auth(&agent, false).await.status, Status::Skip);
+    assert_eq!(server.received_requests().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn doctor_names_a_model_the_endpoint_does_not_serve() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v1/models"))
+        .respond_with(model_list())
+        .mount(&server)
+        .await;
+    completions()
+        .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+            "error": {
+                "message": "The model `deepseek-coder` does not exist",
+                "type": "invalid_request_error",
+                "param": null,
+                "code": "model_not_found"
+            }
+        })))
+        .mount(&server)
+        .await;
+    let mut agent = DeepSeekAgent::with_http_client(
+        AgentConfig {
+            model: "deepseek-coder".into(),
+            retry: RetryPolicy::none(),
+            ..config(&server)
+        },
+        reqwest::Client::new(),
+    )
+    .unwrap();
+
+    // an unlisted model is only a warning: some servers list a few of theirs
+    let auth = doctor::auth(&agent, true).await;
+    assert_eq!(auth.status, Status::Warn);
+    assert!(
+        auth.detail.contains("model 'deepseek-coder' is not listed"),
+        "{}",
+        auth.detail
+    );
+    let chat = doctor::chat(&mut agent, true).await;
+    assert!(chat.is_critical_failure(), "{chat:?}");
+    assert!(
+        chat.hint
+            .unwrap()
+            .starts_with("deepseek-coder isn't served at"),
+    );
+}
+
+#[tokio::test]
+async fn doctor_fails_an_endpoint_that_does_not_answer_in_time() {
+    let server = MockServer::start().await;
+    completions()
+        .respond_with(json_body(RESPONSE).set_delay(Duration::from_millis(500)))
+        .mount(&server)
+        .await;
+    let mut agent = DeepSeekAgent::with_http_client(
+        AgentConfig {
+            timeout: Duration::from_millis(50),
+            retry: RetryPolicy::none(),
+            ..config(&server)
+        },
+        reqwest::Client::new(),
+    )
+    .unwrap();
+
+    let chat = doctor::chat(&mut agent, true).await;
+    assert!(chat.is_critical_failure(), "{chat:?}");
+    assert!(chat.hint.unwrap().contains("--timeout-secs"));
+}